@@ -0,0 +1,62 @@
+//build.rs: Build script embedding the bundled frontend into the binary.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+//Find every file below `dir`, as pairs of their path relative to `root` and their absolute path. Hidden files such as
+//.gitignore are left out. Cargo is told to rebuild when any of the files change, or when files are added to or removed
+//from any of the directories.
+fn collect_files(root: &Path, dir: &Path, out: &mut Vec<(String, PathBuf)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    println!("cargo:rerun-if-changed={}", dir.display());
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(root, &path, out);
+        } else {
+            let name = path
+                .strip_prefix(root)
+                .unwrap()
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join("/");
+            println!("cargo:rerun-if-changed={}", path.display());
+            out.push((name, path.canonicalize().unwrap()));
+        }
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    //Without the embed-assets feature nothing is embedded and the frontend is served from dist at runtime.
+    let mut files = Vec::new();
+    if env::var_os("CARGO_FEATURE_EMBED_ASSETS").is_some() {
+        let dist = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("dist");
+        collect_files(&dist, &dist, &mut files);
+        if files.is_empty() {
+            panic!("The embed-assets feature needs the frontend to be built into dist first");
+        }
+        files.sort();
+    }
+
+    //Generate the list of embedded files, to be included in src/web/assets.rs.
+    let entries: Vec<String> = files
+        .iter()
+        .map(|(name, path)| format!("    ({:?}, include_bytes!({:?}) as &[u8]),", name, path))
+        .collect();
+    let code = format!("&[\n{}\n]\n", entries.join("\n"));
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("assets.rs");
+    fs::write(out, code).unwrap();
+}
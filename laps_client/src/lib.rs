@@ -0,0 +1,423 @@
+//laps_client/lib.rs: Entry point for the laps_client library.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+#![warn(missing_debug_implementations)]
+#![warn(missing_docs)]
+
+//!Typed client for the LAPS HTTP API.
+
+pub use laps_types::{
+    CoordinateSystem, JobOutcome, JobProgress, JobStatus, JobSubmission, MapTile, ModuleInfo,
+    Point, Vector, LATEST_VERSION,
+};
+use quick_error::quick_error;
+use reqwest::{multipart, StatusCode};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+//The shortest and longest time to wait between polls which return without the result.
+const MIN_POLL_DELAY: Duration = Duration::from_millis(250);
+const MAX_POLL_DELAY: Duration = Duration::from_secs(10);
+
+quick_error! {
+    #[derive(Debug)]
+    ///Error type for the client
+    pub enum ClientError {
+        ///An error occured while talking to the backend.
+        Http(err: reqwest::Error) {
+            from()
+            display("HTTP error: {}", err)
+        }
+        ///The backend rejected the request, explaining why.
+        Api(status: u16, code: String, message: String) {
+            display("Request failed with status {} ({}): {}", status, code, message)
+        }
+        ///The backend responded with a status which wasn't expected for the request.
+        Status(status: u16) {
+            display("Request failed with status {}", status)
+        }
+        ///The username, password or two-factor code was wrong, or the client is already logged in.
+        InvalidLogin {
+            display("Invalid login")
+        }
+        ///The admin has two-factor authentication enabled, so a code is required to log in.
+        TotpRequired {
+            display("A two-factor authentication code is required")
+        }
+        ///The job token doesn't exist, or it has expired.
+        UnknownToken {
+            display("Unknown job token")
+        }
+    }
+}
+
+///The result of a finished job.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct PathResult {
+    ///How the job ended.
+    pub outcome: JobOutcome,
+    ///True if the module failed, but reported a path before it did. `points` is then the best path it found.
+    #[serde(default)]
+    pub partial: bool,
+    ///The path, empty unless the job succeeded or the result is partial.
+    #[serde(default)]
+    pub points: Vec<Vector>,
+}
+
+///The state of a job when polling for its result.
+#[derive(Debug, PartialEq)]
+pub enum JobPoll {
+    ///The job is finished.
+    Done(PathResult),
+    ///The job didn't finish before the poll timed out.
+    Pending,
+}
+
+///A pathfinding module to upload.
+#[derive(Debug, Default, Clone)]
+pub struct ModuleUpload {
+    ///The name of the module.
+    pub name: String,
+    ///The version of the module.
+    pub version: String,
+    ///The module as a tar archive, containing a `module.py` when using the bundled Dockerfile.
+    pub tarball: Vec<u8>,
+    ///How many instances of the module to run at once. Defaults to 1.
+    pub workers: Option<u8>,
+    ///How long, in seconds, jobs for the module are cached. 0 disables caching.
+    pub cache_ttl: Option<u32>,
+    ///How long, in seconds, the module has to complete a job. 0 lets jobs run forever.
+    pub job_timeout: Option<u32>,
+    ///How long, in seconds, the module gets to shut down when stopped or restarted before it is killed.
+    pub stop_timeout: Option<u32>,
+    ///How long, in seconds, the module can go without jobs before it is stopped, if the backend stops idle modules.
+    pub idle_timeout: Option<u32>,
+    ///Whether the module needs access to the GPUs of the host. Defaults to false.
+    pub gpu: Option<bool>,
+    ///The command the module runs, split on whitespace, instead of `python3 main.py`. The arguments LAPS needs are
+    ///appended to it.
+    pub command: Option<String>,
+}
+
+//The body of every error response from the backend.
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetails,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetails {
+    code: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct MapList {
+    maps: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct StatusBody {
+    status: JobStatus,
+}
+
+//Build the error for an unexpected response, using the error message from the body when there is one.
+fn response_error(status: StatusCode, body: &[u8]) -> ClientError {
+    match serde_json::from_slice::<ErrorBody>(body) {
+        Ok(b) => ClientError::Api(status.as_u16(), b.error.code, b.error.message),
+        Err(_) => ClientError::Status(status.as_u16()),
+    }
+}
+
+//Interpret the response to polling for a job result.
+fn parse_poll(status: StatusCode, body: &[u8]) -> Result<JobPoll, ClientError> {
+    match status {
+        //Finished jobs, and failed jobs with a partial path
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT => match serde_json::from_slice(body) {
+            Ok(result) => Ok(JobPoll::Done(result)),
+            Err(_) => Err(ClientError::Status(status.as_u16())),
+        },
+        StatusCode::GATEWAY_TIMEOUT => Ok(JobPoll::Pending),
+        StatusCode::NOT_FOUND => Err(ClientError::UnknownToken),
+        _ => match response_error(status, body) {
+            //The module failed without giving a path, which is still a finished job.
+            ClientError::Api(_, code, _) if code == "job_failed" => Ok(JobPoll::Done(PathResult {
+                outcome: JobOutcome::Failure,
+                partial: false,
+                points: Vec::new(),
+            })),
+            e => Err(e),
+        },
+    }
+}
+
+///A client for a LAPS backend. Keeps the session cookie after logging in, so clones share the same session.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl Client {
+    ///Create a client for the backend at `base_url`, such as `https://laps.example.com`.
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        let http = reqwest::Client::builder().cookie_store(true).build()?;
+        Ok(Client {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    //Send `request`, turning any status but `expected` into an error. Returns the body.
+    async fn send(
+        request: reqwest::RequestBuilder,
+        expected: StatusCode,
+    ) -> Result<Vec<u8>, ClientError> {
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if status == expected {
+            Ok(body.to_vec())
+        } else {
+            Err(response_error(status, &body))
+        }
+    }
+
+    ///Log in as an admin, which is required for managing modules. `totp` is the two-factor authentication code
+    ///for admins who have enabled it.
+    pub async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        totp: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let mut form = vec![("username", username), ("password", password)];
+        if let Some(code) = totp {
+            form.push(("totp", code));
+        }
+        let request = self.http.post(&self.url("/login")).form(&form);
+        match Self::send(request, StatusCode::NO_CONTENT).await {
+            Ok(_) => Ok(()),
+            Err(ClientError::Status(401)) => Err(ClientError::TotpRequired),
+            Err(ClientError::Status(403)) => Err(ClientError::InvalidLogin),
+            Err(e) => Err(e),
+        }
+    }
+
+    ///Submit a job, returning the token to get its result with.
+    pub async fn submit_job(&self, job: &JobSubmission) -> Result<String, ClientError> {
+        let request = self.http.post(&self.url("/job")).json(job);
+        let token = Self::send(request, StatusCode::ACCEPTED).await?;
+        Ok(String::from_utf8_lossy(&token).into_owned())
+    }
+
+    ///Poll for the result of the job with `token` once. The backend waits for the result for up to `timeout`
+    ///seconds, or its own maximum if not given, before reporting the job as pending.
+    pub async fn poll_result(
+        &self,
+        token: &str,
+        timeout: Option<u32>,
+    ) -> Result<JobPoll, ClientError> {
+        let mut request = self.http.get(&self.url(&format!("/job/{}", token)));
+        if let Some(t) = timeout {
+            request = request.query(&[("timeout", t)]);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        parse_poll(status, &body)
+    }
+
+    ///Wait for the job with `token` to finish, polling until it does.
+    pub async fn wait_for_result(&self, token: &str) -> Result<PathResult, ClientError> {
+        //The backend holds each poll open until the result arrives, but a proxy in front of it may time out polls
+        //right away. Wait a little longer after each of those so that the client doesn't hammer the backend.
+        let mut delay = MIN_POLL_DELAY;
+        loop {
+            let started = Instant::now();
+            if let JobPoll::Done(result) = self.poll_result(token, None).await? {
+                return Ok(result);
+            }
+            if started.elapsed() < delay {
+                tokio::time::delay_for(delay).await;
+                delay = (delay * 2).min(MAX_POLL_DELAY);
+            } else {
+                delay = MIN_POLL_DELAY;
+            }
+        }
+    }
+
+    ///Get the progress the module has reported for the job with `token`, or None if it hasn't reported any yet.
+    pub async fn job_progress(&self, token: &str) -> Result<Option<JobProgress>, ClientError> {
+        let request = self
+            .http
+            .get(&self.url(&format!("/job/{}/progress", token)));
+        match Self::send(request, StatusCode::OK).await {
+            Ok(body) => serde_json::from_slice(&body)
+                .map(Some)
+                .map_err(|_| ClientError::Status(200)),
+            Err(ClientError::Status(204)) => Ok(None),
+            Err(ClientError::Status(404)) => Err(ClientError::UnknownToken),
+            Err(e) => Err(e),
+        }
+    }
+
+    ///Check whether the job with `token` has finished, without waiting for it or taking up a polling slot.
+    pub async fn job_status(&self, token: &str) -> Result<JobStatus, ClientError> {
+        let request = self.http.get(&self.url(&format!("/job/{}/status", token)));
+        match Self::send(request, StatusCode::OK).await {
+            Ok(body) => serde_json::from_slice::<StatusBody>(&body)
+                .map(|b| b.status)
+                .map_err(|_| ClientError::Status(200)),
+            Err(ClientError::Status(404)) => Ok(JobStatus::NotFound),
+            Err(e) => Err(e),
+        }
+    }
+
+    ///List the pathfinding modules which are available for jobs.
+    pub async fn list_algorithms(&self) -> Result<Vec<ModuleInfo>, ClientError> {
+        let request = self.http.get(&self.url("/algorithms"));
+        let body = Self::send(request, StatusCode::OK).await?;
+        serde_json::from_slice(&body).map_err(|_| ClientError::Status(200))
+    }
+
+    ///List the ids of the available maps, optionally only the ones imported together as `group`.
+    pub async fn list_maps(&self, group: Option<&str>) -> Result<Vec<i32>, ClientError> {
+        let mut request = self.http.get(&self.url("/maps"));
+        if let Some(g) = group {
+            request = request.query(&[("group", g)]);
+        }
+        let body = Self::send(request, StatusCode::OK).await?;
+        let list: MapList = serde_json::from_slice(&body).map_err(|_| ClientError::Status(200))?;
+        Ok(list.maps.iter().filter_map(|id| id.parse().ok()).collect())
+    }
+
+    ///Get the map with `id` as a PNG image, or None if it doesn't exist.
+    pub async fn get_map(&self, id: i32) -> Result<Option<Vec<u8>>, ClientError> {
+        let request = self.http.get(&self.url(&format!("/map/{}", id)));
+        match Self::send(request, StatusCode::OK).await {
+            Ok(data) => Ok(Some(data)),
+            Err(ClientError::Status(404)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    ///Upload and start a new pathfinding module. Requires being logged in.
+    pub async fn upload_module(&self, module: &ModuleUpload) -> Result<(), ClientError> {
+        let tarball = multipart::Part::bytes(module.tarball.clone())
+            .file_name("module.tar")
+            .mime_str("application/x-tar")?;
+        let mut form = multipart::Form::new()
+            .text("name", module.name.clone())
+            .text("version", module.version.clone())
+            .part("module", tarball);
+        if let Some(workers) = module.workers {
+            form = form.text("workers", workers.to_string());
+        }
+        if let Some(ttl) = module.cache_ttl {
+            form = form.text("cache_ttl", ttl.to_string());
+        }
+        if let Some(timeout) = module.job_timeout {
+            form = form.text("job_timeout", timeout.to_string());
+        }
+        if let Some(timeout) = module.stop_timeout {
+            form = form.text("stop_timeout", timeout.to_string());
+        }
+        if let Some(timeout) = module.idle_timeout {
+            form = form.text("idle_timeout", timeout.to_string());
+        }
+        if let Some(gpu) = module.gpu {
+            form = form.text("gpu", gpu.to_string());
+        }
+        if let Some(command) = &module.command {
+            form = form.text("command", command.clone());
+        }
+
+        let request = self.http.post(&self.url("/module")).multipart(form);
+        Self::send(request, StatusCode::CREATED).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn poll_responses() {
+        //Successful and cancelled jobs
+        let body = br#"{"outcome":"success","points":[{"x":1,"y":2},{"x":2,"y":2}]}"#;
+        assert_eq!(
+            parse_poll(StatusCode::OK, body).unwrap(),
+            JobPoll::Done(PathResult {
+                outcome: JobOutcome::Success,
+                partial: false,
+                points: vec![Vector { x: 1, y: 2 }, Vector { x: 2, y: 2 }],
+            })
+        );
+        let body = br#"{"outcome":"cancelled","points":[]}"#;
+        match parse_poll(StatusCode::OK, body).unwrap() {
+            JobPoll::Done(r) => assert_eq!(r.outcome, JobOutcome::Cancelled),
+            other => panic!("Expected a cancelled job, got {:?}", other),
+        }
+
+        //Failures, with and without a partial path
+        let body = br#"{"outcome":"failure","partial":true,"points":[{"x":1,"y":2}]}"#;
+        match parse_poll(StatusCode::PARTIAL_CONTENT, body).unwrap() {
+            JobPoll::Done(r) => {
+                assert_eq!(r.outcome, JobOutcome::Failure);
+                assert!(r.partial);
+                assert_eq!(r.points.len(), 1);
+            }
+            other => panic!("Expected a partial result, got {:?}", other),
+        }
+        let body = br#"{"error":{"code":"job_failed","message":"A pathfinding module failed to complete this job!"}}"#;
+        match parse_poll(StatusCode::INTERNAL_SERVER_ERROR, body).unwrap() {
+            JobPoll::Done(r) => {
+                assert_eq!(r.outcome, JobOutcome::Failure);
+                assert!(r.points.is_empty());
+            }
+            other => panic!("Expected a failed job, got {:?}", other),
+        }
+
+        //Pending jobs and unknown tokens
+        assert_eq!(
+            parse_poll(StatusCode::GATEWAY_TIMEOUT, b"").unwrap(),
+            JobPoll::Pending
+        );
+        match parse_poll(StatusCode::NOT_FOUND, b"") {
+            Err(ClientError::UnknownToken) => (),
+            other => panic!("Expected an unknown token, got {:?}", other),
+        }
+
+        //Other errors are passed on
+        let body = br#"{"error":{"code":"internal_error","message":"internal server error","request_id":"abc"}}"#;
+        match parse_poll(StatusCode::INTERNAL_SERVER_ERROR, body) {
+            Err(ClientError::Api(500, code, _)) => assert_eq!(code, "internal_error"),
+            other => panic!("Expected an API error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_responses() {
+        let body = br#"{"error":{"code":"invalid_job","message":"Invalid map id"}}"#;
+        match response_error(StatusCode::BAD_REQUEST, body) {
+            ClientError::Api(400, code, message) => {
+                assert_eq!(code, "invalid_job");
+                assert_eq!(message, "Invalid map id");
+            }
+            other => panic!("Expected an API error, got {:?}", other),
+        }
+        //Not every error has a body
+        match response_error(StatusCode::FORBIDDEN, b"") {
+            ClientError::Status(403) => (),
+            other => panic!("Expected a status error, got {:?}", other),
+        }
+    }
+}
@@ -12,9 +12,18 @@
 extern crate log;
 
 use gdal::raster::Dataset;
+pub use laps_types::MapType;
 use quick_error::quick_error;
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use std::{
+    convert::TryFrom,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 quick_error! {
     #[derive(Debug)]
@@ -33,28 +42,306 @@ quick_error! {
         NoBands {
             display("No raster bands found")
         }
+        ///The requested band does not exist in the dataset.
+        InvalidBand(band: usize, count: isize) {
+            display("Band {} does not exist, the dataset has {} bands", band, count)
+        }
+        ///The raster has no pixels.
+        EmptyRaster(width: usize, height: usize) {
+            display("The raster is empty, its size is {}px by {}px", width, height)
+        }
+        ///An error occured while encoding a PNG.
+        PngEncode(err: png::EncodingError) {
+            from()
+            display("PNG encoding error: {}", err)
+        }
+        ///An error occured while decoding a PNG.
+        PngDecode(err: png::DecodingError) {
+            from()
+            display("PNG decoding error: {}", err)
+        }
+        ///A PNG isn't 8-bit grayscale like the maps produced by this library.
+        NotGrayscale {
+            display("The PNG is not an 8-bit grayscale image")
+        }
+        ///A WebP image couldn't be read.
+        WebP(reason: &'static str) {
+            display("WebP error: {}", reason)
+        }
+        ///The raster is too large to be encoded as a PNG.
+        TooLarge(width: usize, height: usize) {
+            display("The raster is too large to encode, its size is {}px by {}px", width, height)
+        }
+        ///The conversion was cancelled through its [`CancelToken`](struct.CancelToken.html).
+        Cancelled {
+            display("The conversion was cancelled")
+        }
+        ///The conversion took longer than it was allowed to.
+        Timeout(timeout: Duration) {
+            display("The conversion did not finish within {} seconds", timeout.as_secs())
+        }
+        ///The geo-transform gives pixels without a usable size.
+        DegenerateGeoTransform(x_res: f64, y_res: f64) {
+            display("Invalid pixel size {} by {} in the geo-transform", x_res, y_res)
+        }
+        ///The raster can't be shrunk by a factor of 0.
+        InvalidDownsample(factor: u32) {
+            display("Invalid downsample factor {}, it must be at least 1", factor)
+        }
+        ///The percentiles to clip the heights to aren't two increasing percentiles between 0 and 100.
+        InvalidClip(low: f64, high: f64) {
+            display("Invalid percentiles {} and {} to clip to, they must be increasing and between 0 and 100", low, high)
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    ///Error type for importing maps
+    pub enum ImportError {
+        ///An error occured while talking to Redis.
+        Redis(err: darkredis::Error) {
+            from()
+            display("Redis error: {}", err)
+        }
+        ///The maximum number of maps are already imported.
+        TooManyMaps(max: usize) {
+            display("The maximum number of maps ({}) are already imported", max)
+        }
+        ///The maps being imported are bigger than the entire storage quota.
+        TooLarge(size: u64, max: u64) {
+            display("The map uses {} bytes, which is more than the storage quota of {} bytes", size, max)
+        }
+        ///Storing the map would exceed the storage quota.
+        StorageFull(used: u64, size: u64, max: u64) {
+            display("Storing {} more bytes would exceed the storage quota of {} bytes, {} are already used", size, max, used)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+///The format a raster is converted into.
+pub enum OutputFormat {
+    ///A grayscale PNG with the heights normalized to 0-255. This is the default format used for mapdata in LAPS.
+    #[default]
+    Png,
+    ///The same image as [`Png`](#variant.Png), but as a lossless WebP, which is usually smaller. LAPS can store maps
+    ///in either format. WebP limits images to 16383 pixels in each direction.
+    #[serde(rename = "webp")]
+    WebP,
+    ///An ESRI ASCII grid with the raw heights, positioned using the geo-transform of the input.
+    AsciiGrid,
+    ///The raw heights as little-endian 32-bit floats, row by row starting at the top.
+    RawFloat32,
+}
+
+impl OutputFormat {
+    ///The MIME type of data in this format.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::AsciiGrid => "text/plain",
+            OutputFormat::RawFloat32 => "application/octet-stream",
+        }
+    }
+
+    ///The file extension usually used for this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::WebP => "webp",
+            OutputFormat::AsciiGrid => "asc",
+            OutputFormat::RawFloat32 => "bin",
+        }
+    }
+
+    ///Parse the name of a format which maps can be stored in, either "png" or "webp".
+    pub fn from_map_name(name: &str) -> Option<OutputFormat> {
+        match name.to_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "webp" => Some(OutputFormat::WebP),
+            _ => None,
+        }
+    }
+
+    ///Tell the format of the map image `data` from its signature. Only PNG and WebP images are recognized.
+    pub fn detect(data: &[u8]) -> Option<OutputFormat> {
+        if data.starts_with(&PNG_SIGNATURE) {
+            Some(OutputFormat::Png)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(OutputFormat::WebP)
+        } else {
+            None
+        }
     }
 }
 
+//The first bytes of every PNG.
+const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+//The largest width and height of a WebP image.
+const WEBP_MAX_DIMENSION: usize = 16383;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+///How hard to compress PNG output, trading encoding speed against the size of the PNG.
+pub enum PngCompression {
+    ///Encode quickly at the cost of larger output, useful for large batch imports.
+    Fast,
+    ///The default balance between speed and size.
+    #[default]
+    Default,
+    ///Make the output as small as possible at the cost of slower encoding.
+    Best,
+}
+
+impl PngCompression {
+    ///Parse the name of a compression level, as used by [`name`](#method.name).
+    pub fn from_name(name: &str) -> Option<PngCompression> {
+        match name.to_lowercase().as_str() {
+            "fast" => Some(PngCompression::Fast),
+            "default" => Some(PngCompression::Default),
+            "best" => Some(PngCompression::Best),
+            _ => None,
+        }
+    }
+
+    ///The name of this compression level.
+    pub fn name(self) -> &'static str {
+        match self {
+            PngCompression::Fast => "fast",
+            PngCompression::Default => "default",
+            PngCompression::Best => "best",
+        }
+    }
+}
+
+impl From<PngCompression> for png::Compression {
+    fn from(compression: PngCompression) -> Self {
+        match compression {
+            PngCompression::Fast => png::Compression::Fast,
+            PngCompression::Default => png::Compression::Default,
+            PngCompression::Best => png::Compression::Best,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+///How the heights of a map are stretched over the 256 gray levels of a PNG.
+pub enum Normalization {
+    ///Stretch between the lowest and the highest point of the map.
+    #[default]
+    FullRange,
+    ///Stretch between two percentiles of the heights, clamping the heights outside of them to black and white. This
+    ///keeps a few outliers, such as spikes from measuring errors, from squashing the rest of the map into a handful of
+    ///gray levels.
+    PercentileClip {
+        ///The percentile shown as black.
+        low: f64,
+        ///The percentile shown as white.
+        high: f64,
+    },
+}
+
+impl Normalization {
+    ///Clipping the lowest and highest two percent, which is usually enough to get rid of outliers.
+    pub const STANDARD_CLIP: Normalization = Normalization::PercentileClip {
+        low: 2.0,
+        high: 98.0,
+    };
+
+    //Fail with `ConvertError::InvalidClip` if the percentiles to clip to don't make sense.
+    fn check(self) -> Result<(), ConvertError> {
+        match self {
+            Normalization::PercentileClip { low, high }
+                if !(0.0 <= low && low < high && high <= 100.0) =>
+            {
+                Err(ConvertError::InvalidClip(low, high))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+///Options controlling how a raster is converted.
+pub struct ConvertOptions {
+    ///The raster band to read, starting at 1. If not set, the dataset must have exactly one band.
+    pub band: Option<usize>,
+    ///The format to convert the raster into.
+    pub format: OutputFormat,
+    ///Also compute the slope of the map, see [`compute_slope`](fn.compute_slope.html).
+    pub slope: bool,
+    ///How hard to compress the PNGs, both the map itself and its slope.
+    pub compression: PngCompression,
+    ///How the heights are stretched when converting to PNG.
+    pub normalization: Normalization,
+    ///Shrink the raster by this factor while reading it. GDAL reads from the overviews of the raster when it has
+    ///fitting ones, like Cloud-Optimized GeoTIFFs do, so large rasters are never read at full resolution. Rasters
+    ///without overviews are read at full resolution and decimated.
+    pub downsample: Option<u32>,
+}
+
 #[derive(Debug)]
-///A fully converted image. As all mapdata is stored as PNG in LAPS, this struct stores the image.
+///A fully converted image. As all mapdata is stored as PNG in LAPS, this is usually a PNG image.
 pub struct ConvertedImage {
     ///The width of the image.
     pub width: usize,
     ///The height of the image.
     pub height: usize,
-    ///Raw, encoded PNG data.
+    ///The format of `data`.
+    pub format: OutputFormat,
+    ///Raw, encoded image data.
     pub data: Vec<u8>,
+    ///The slope of the map encoded as a PNG, if requested.
+    pub slope: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+///The heights of a raster exactly as they were read, before being normalized into an image.
+pub struct RasterGrid {
+    ///The width of the raster.
+    pub width: usize,
+    ///The height of the raster.
+    pub height: usize,
+    ///The heights row by row, starting at the top left.
+    pub data: Vec<f64>,
 }
 
-///Convert `input` from range [min, max] to [new_min, new_max]
+impl RasterGrid {
+    ///Encode the grid as a NumPy `.npy` file holding a `height` by `width` array of little-endian 64-bit floats.
+    pub fn to_npy(&self) -> Vec<u8> {
+        let mut header = format!(
+            "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+            self.height, self.width
+        );
+        //The magic string, version and header length take up 10 bytes, and the data must start at a multiple of 64.
+        let padding = 63 - (10 + header.len()) % 64;
+        header.extend(std::iter::repeat(' ').take(padding));
+        header.push('\n');
+
+        let mut out = Vec::with_capacity(10 + header.len() + self.data.len() * 8);
+        out.extend_from_slice(b"\x93NUMPY\x01\x00");
+        out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        out.extend_from_slice(header.as_bytes());
+        for height in &self.data {
+            out.extend_from_slice(&height.to_le_bytes());
+        }
+        out
+    }
+}
+
+///Convert `input` from range [min, max] to [new_min, new_max]. An empty range maps everything to `new_min`.
 fn convert_range(input: f64, max: f64, min: f64, new_min: f64, new_max: f64) -> f64 {
     let old_range = max - min;
+    if old_range == 0.0 {
+        return new_min;
+    }
     let new_range = new_max - new_min;
     ((input - min) * new_range / old_range) + new_min
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 ///Map metadata. The unit can vary, depending on the input map.
 pub struct ImageMetadata {
     ///The width of a pixel
@@ -67,17 +354,51 @@ pub struct ImageMetadata {
     pub max_height: f64,
     ///The average height for all points.
     pub average_height: f64,
+    ///Whether every point on the map has the same height. The PNG of a flat map is a uniform mid-gray.
+    #[serde(default)]
+    pub flat: bool,
+    ///The kind of data in the map. Maps imported before map types were recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub map_type: Option<MapType>,
+    ///The world coordinates of the top-left corner of the map, as `(x, y)`. Maps imported before the origin was
+    ///recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<(f64, f64)>,
+    ///The median height of all points. Maps imported before it was recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub median_height: Option<f64>,
+    ///Percentiles of the heights as `(percentile, height)` pairs, see [`RasterStats`](struct.RasterStats.html).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub height_percentiles: Vec<(f64, f64)>,
+    ///The width of the map in pixels. Maps imported before the dimensions were recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<usize>,
+    ///The height of the map in pixels. Maps imported before the dimensions were recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<usize>,
+    ///The heights shown as black and white in the PNG, as `(black, white)`, if they aren't the lowest and highest
+    ///points on the map because the heights were clipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalized_range: Option<(f64, f64)>,
+    ///The CRC32 of the PNG of the map, set when the map is imported. Maps imported before checksums were recorded
+    ///have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<u32>,
+    ///The format the map was converted into. Maps imported before the format was recorded have none, and are PNGs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<OutputFormat>,
 }
 
 impl ImageMetadata {
-    pub(crate) fn from_data(
-        dataset: &Dataset,
-        min_height: f64,
-        max_height: f64,
-        average_height: f64,
-    ) -> Result<Self, ConvertError> {
+    pub(crate) fn from_data(dataset: &Dataset, stats: &RasterStats) -> Result<Self, ConvertError> {
+        let (width, height) = dataset.size();
+        let (min_height, max_height, average_height) = (stats.min, stats.max, stats.mean);
         let [x, x_res, _, y, _, y_res] = dataset.geo_transform().map_err(ConvertError::GDal)?;
         debug!("X: {}, Y: {}, x_res: {}, y_res: {}", x, y, x_res, y_res);
+        //The slope and the ASCII grid depend on the pixel size, so it has to be something sensible.
+        if x_res == 0.0 || y_res == 0.0 || !x_res.is_finite() || !y_res.is_finite() {
+            return Err(ConvertError::DegenerateGeoTransform(x_res, y_res));
+        }
         debug!(
             "Min height {}, max: {}, avg: {}",
             min_height, max_height, average_height
@@ -89,65 +410,633 @@ impl ImageMetadata {
             min_height,
             max_height,
             average_height,
+            flat: max_height == min_height,
+            map_type: Some(MapType::Elevation),
+            origin: Some((x, y)),
+            median_height: Some(stats.median),
+            height_percentiles: stats.percentiles.clone(),
+            width: Some(width),
+            height: Some(height),
+            normalized_range: None,
+            checksum: None,
+            format: None,
         })
     }
+
+    ///Fill in the fields of the metadata which can be derived from `png`, the PNG or WebP of the map, if they are
+    ///missing.
+    ///This brings maps imported by older versions up to date without the original raster: the dimensions, and the
+    ///distribution of the heights as far as the 256 gray levels of the PNG can tell. The absolute heights and the
+    ///georeferencing can only come from the original raster, so they are left alone. A missing checksum is left
+    ///missing, since `png` may have been corrupted after the map was imported. Returns whether anything was filled in.
+    pub fn complete_from_png(&mut self, png: &[u8]) -> Result<bool, ConvertError> {
+        let (width, height) = map_dimensions(png)?;
+
+        let mut changed = false;
+        if self.width.is_none() || self.height.is_none() {
+            self.width = Some(width as usize);
+            self.height = Some(height as usize);
+            changed = true;
+        }
+        if self.median_height.is_none() || self.height_percentiles.is_empty() {
+            let (_, _, pixels) = decode_map(png)?;
+            let heights: Vec<f64> = pixels.iter().map(|p| self.gray_to_height(*p)).collect();
+            let stats = compute_statistics(&heights, &METADATA_PERCENTILES);
+            self.median_height = Some(stats.median);
+            self.height_percentiles = stats.percentiles;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    ///Check that `png` is the PNG the metadata was recorded for, by comparing it against the checksum. Returns None
+    ///if the metadata has no checksum to compare against.
+    pub fn verify_checksum(&self, png: &[u8]) -> Option<bool> {
+        self.checksum.map(|c| c == map_checksum(png))
+    }
+
+    ///Convert the gray level `gray` of a pixel in the PNG of the map back to the height it shows. Heights outside of
+    ///[`normalized_range`](#structfield.normalized_range) were clipped, so they come back as its bounds.
+    pub fn gray_to_height(&self, gray: u8) -> f64 {
+        let (black, white) = self
+            .normalized_range
+            .unwrap_or((self.min_height, self.max_height));
+        black + (white - black) * gray as f64 / u8::MAX as f64
+    }
+
+    ///Convert the world coordinates `x` and `y` to the position of the nearest pixel, which may lie outside of the
+    ///map. Returns None if the origin of the map isn't known.
+    pub fn world_to_pixel(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        let (origin_x, origin_y) = self.origin?;
+        Some((
+            ((x - origin_x) / self.x_res).round(),
+            ((y - origin_y) / self.y_res).round(),
+        ))
+    }
 }
 
 impl fmt::Display for ImageMetadata {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{}m by {}m resolution, lowest point: {}, highest point: {}, avg: {}",
-            self.x_res, self.y_res, self.min_height, self.max_height, self.average_height
+            "{}m by {}m resolution, lowest point: {}, highest point: {}, avg: {}{}",
+            self.x_res,
+            self.y_res,
+            self.min_height,
+            self.max_height,
+            self.average_height,
+            if self.flat { " (flat)" } else { "" }
         )
     }
 }
 
+///Compute the checksum stored in [`ImageMetadata::checksum`](struct.ImageMetadata.html#structfield.checksum) of
+///the PNG `png`.
+pub fn map_checksum(png: &[u8]) -> u32 {
+    crc32fast::hash(png)
+}
+
+///Get the width and height of `image`, a PNG or WebP map, by only reading its header.
+pub fn map_dimensions(image: &[u8]) -> Result<(u32, u32), ConvertError> {
+    if OutputFormat::detect(image) != Some(OutputFormat::WebP) {
+        let (info, _) = png::Decoder::new(image).read_info()?;
+        return Ok((info.width, info.height));
+    }
+
+    let invalid = ConvertError::WebP("Invalid header");
+    let bytes = |range: std::ops::Range<usize>| {
+        image
+            .get(range)
+            .ok_or(ConvertError::WebP("Truncated header"))
+    };
+    let le = |data: &[u8]| data.iter().rev().fold(0u32, |n, b| (n << 8) | *b as u32);
+    //The image is a RIFF container holding a single chunk, or an extended header chunk followed by the image.
+    match bytes(12..16)? {
+        //Lossless, which is what this library produces.
+        b"VP8L" => {
+            if bytes(20..21)? != [0x2f] {
+                return Err(invalid);
+            }
+            let bits = le(bytes(21..25)?);
+            Ok(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => Ok((le(bytes(24..27)?) + 1, le(bytes(27..30)?) + 1)),
+        //Lossy, which has a key frame start code before the dimensions.
+        b"VP8 " => {
+            if bytes(23..26)? != [0x9d, 0x01, 0x2a] {
+                return Err(invalid);
+            }
+            Ok((le(bytes(26..28)?) & 0x3fff, le(bytes(28..30)?) & 0x3fff))
+        }
+        _ => Err(invalid),
+    }
+}
+
+///Decode `image`, a PNG or WebP map, into its width, height and 8-bit gray levels. Fails with
+///`ConvertError::NotGrayscale` for images with colour.
+pub fn decode_map(image: &[u8]) -> Result<(u32, u32, Vec<u8>), ConvertError> {
+    if OutputFormat::detect(image) != Some(OutputFormat::WebP) {
+        let (info, mut reader) = png::Decoder::new(image).read_info()?;
+        if info.color_type != png::ColorType::Grayscale || info.bit_depth != png::BitDepth::Eight {
+            return Err(ConvertError::NotGrayscale);
+        }
+        let mut pixels = vec![0u8; info.buffer_size()];
+        reader.next_frame(&mut pixels)?;
+        return Ok((info.width, info.height, pixels));
+    }
+
+    //WebP has no grayscale images, so the gray levels are stored as equal red, green and blue values.
+    let decoded = webp::Decoder::new(image)
+        .decode()
+        .ok_or(ConvertError::WebP("Failed to decode the image"))?;
+    let channels = if decoded.is_alpha() { 4 } else { 3 };
+    let mut pixels = Vec::with_capacity(decoded.len() / channels);
+    for pixel in decoded.chunks(channels) {
+        if pixel[0] != pixel[1] || pixel[0] != pixel[2] {
+            return Err(ConvertError::NotGrayscale);
+        }
+        pixels.push(pixel[0]);
+    }
+    Ok((decoded.width(), decoded.height(), pixels))
+}
+
+//Use `options` to convert a map image, which can only be a PNG or a WebP.
+fn map_image_options(options: &ConvertOptions) -> ConvertOptions {
+    let format = match options.format {
+        OutputFormat::WebP => OutputFormat::WebP,
+        _ => OutputFormat::Png,
+    };
+    ConvertOptions {
+        format,
+        ..options.clone()
+    }
+}
+
 ///Convert a GDAL raster format file from `path` into a PNG. The image must have geospecial metadata in it.
 pub fn convert_to_png<P>(path: P) -> Result<(ConvertedImage, ImageMetadata), ConvertError>
 where
     P: AsRef<std::path::Path>,
 {
+    convert_to_png_with(path, &ConvertOptions::default())
+}
+
+///Convert a GDAL raster format file from `path` into a PNG using `options`, or a WebP if `options.format` asks for
+///one. Any other format is ignored. The image must have geospecial metadata in it.
+pub fn convert_to_png_with<P>(
+    path: P,
+    options: &ConvertOptions,
+) -> Result<(ConvertedImage, ImageMetadata), ConvertError>
+where
+    P: AsRef<std::path::Path>,
+{
+    convert(path, &map_image_options(options))
+}
+
+///Like [`convert_to_png_with`](fn.convert_to_png_with.html), but fails with `ConvertError::Cancelled` once `token` is
+///cancelled. This makes it possible to give up on a conversion running on another thread, see
+///[`convert_cancellable`](fn.convert_cancellable.html).
+pub fn convert_to_png_cancellable<P>(
+    path: P,
+    options: &ConvertOptions,
+    token: &CancelToken,
+) -> Result<(ConvertedImage, ImageMetadata), ConvertError>
+where
+    P: AsRef<std::path::Path>,
+{
+    convert_cancellable(path, &map_image_options(options), token)
+}
+
+#[derive(Debug, Clone, Default)]
+///A handle which can be used to cancel a conversion running on another thread.
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    ///Create a new token which has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Cancel the conversions using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    ///Check if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    //Fail with `ConvertError::Cancelled` if the token has been cancelled.
+    fn check(&self) -> Result<(), ConvertError> {
+        if self.is_cancelled() {
+            Err(ConvertError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+///Convert a GDAL raster format file from `path` into the format given in `options`.
+///The image must have geospecial metadata in it.
+pub fn convert<P>(
+    path: P,
+    options: &ConvertOptions,
+) -> Result<(ConvertedImage, ImageMetadata), ConvertError>
+where
+    P: AsRef<std::path::Path>,
+{
+    convert_cancellable(path, options, &CancelToken::new())
+}
+
+///Like [`convert`](fn.convert.html), but fails with `ConvertError::Cancelled` once `token` is cancelled.
+///Cancellation is checked between the phases of the conversion, as GDAL cannot be interrupted while reading.
+pub fn convert_cancellable<P>(
+    path: P,
+    options: &ConvertOptions,
+    token: &CancelToken,
+) -> Result<(ConvertedImage, ImageMetadata), ConvertError>
+where
+    P: AsRef<std::path::Path>,
+{
+    token.check()?;
     let dataset = Dataset::open(path.as_ref()).map_err(ConvertError::GDal)?;
-    match dataset.count() {
-        0 => Err(ConvertError::NoBands),
-        1 => Ok(()),
+    convert_dataset(&dataset, options, token)
+}
+
+///Like [`convert`](fn.convert.html), but also returns the heights exactly as they were read from the raster.
+///
+///The raw heights take up 8 bytes per pixel on top of the converted image, so only use this when they are needed.
+pub fn convert_with_raw<P>(
+    path: P,
+    options: &ConvertOptions,
+) -> Result<(ConvertedImage, ImageMetadata, RasterGrid), ConvertError>
+where
+    P: AsRef<std::path::Path>,
+{
+    let dataset = Dataset::open(path.as_ref()).map_err(ConvertError::GDal)?;
+    convert_dataset_raw(&dataset, options, &CancelToken::new())
+}
+
+//Convert the already opened `dataset`, see `convert_cancellable`.
+fn convert_dataset(
+    dataset: &Dataset,
+    options: &ConvertOptions,
+    token: &CancelToken,
+) -> Result<(ConvertedImage, ImageMetadata), ConvertError> {
+    convert_dataset_raw(dataset, options, token).map(|(image, metadata, _)| (image, metadata))
+}
+
+//Convert the already opened `dataset`, keeping the heights that were read from it.
+fn convert_dataset_raw(
+    dataset: &Dataset,
+    options: &ConvertOptions,
+    token: &CancelToken,
+) -> Result<(ConvertedImage, ImageMetadata, RasterGrid), ConvertError> {
+    options.normalization.check()?;
+    let band = match (dataset.count(), options.band) {
+        (0, _) => Err(ConvertError::NoBands),
+        //Any band can be picked out of a dataset as long as it exists.
+        (count, Some(band)) => {
+            if band >= 1 && band as isize <= count {
+                Ok(band as isize)
+            } else {
+                Err(ConvertError::InvalidBand(band, count))
+            }
+        }
+        (1, None) => Ok(1),
         //The count will never be less than zero, any value gotten here will be greater than zero.
         //We could match on the negative values too but that requires a nightly feature
         //TODO use exaustive_range_patterns feature when it arrives for correctness
-        _ => Err(ConvertError::MoreThanOneBand),
+        (_, None) => Err(ConvertError::MoreThanOneBand),
     }?;
 
     //Our data mostly consists of float32s hopefully, but in case we have other ones
     //just read the data as a double for simplicity. This works with all other data types
     //except the complex ones.
-    let (width, height) = dataset.size();
-    let data: Vec<f64> = dataset
-        .read_full_raster_as(1)
-        .map_err(ConvertError::GDal)?
-        .data;
+    let (full_width, full_height) = dataset.size();
+    let (width, height) = downsampled_size(full_width, full_height, options.downsample)?;
+    check_dimensions(width, height)?;
+    let data: Vec<f64> = if (width, height) == (full_width, full_height) {
+        dataset.read_full_raster_as(band)
+    } else {
+        //Reading into a smaller buffer makes GDAL use the closest overview, if the raster has any.
+        debug!(
+            "Reading band {} of size {}px by {}px at {}px by {}px",
+            band, full_width, full_height, width, height
+        );
+        dataset.read_raster_as(band, (0, 0), (full_width, full_height), (width, height))
+    }
+    .map_err(ConvertError::GDal)?
+    .data;
     debug!(
-        "Decoded raster data of size {}px by {}px with {} points",
+        "Decoded band {} raster data of size {}px by {}px with {} points",
+        band,
         width,
         height,
         data.len()
     );
 
-    //Find the highest and the lowest points on the map
+    token.check()?;
+
+    let mut percentiles = METADATA_PERCENTILES.to_vec();
+    if let Normalization::PercentileClip { low, high } = options.normalization {
+        percentiles.extend_from_slice(&[low, high]);
+    }
+    let stats = compute_statistics(&data, &percentiles);
+    let mut metadata = ImageMetadata::from_data(dataset, &stats)?;
+    //Every pixel of a downsampled raster covers several of the original pixels.
+    if (width, height) != (full_width, full_height) {
+        metadata.x_res *= full_width as f64 / width as f64;
+        metadata.y_res *= full_height as f64 / height as f64;
+        metadata.width = Some(width);
+        metadata.height = Some(height);
+    }
+    if metadata.flat {
+        warn!(
+            "Every point of the map has the same height of {}",
+            stats.min
+        );
+    }
+    metadata.format = Some(options.format);
+    let data_out = match options.format {
+        OutputFormat::Png | OutputFormat::WebP => {
+            let (low, high) = stretch_range(&stats, options.normalization);
+            if (low, high) != (stats.min, stats.max) {
+                debug!("Clipping heights to the range {} to {}", low, high);
+                metadata.normalized_range = Some((low, high));
+            }
+            let pixels = normalize(&data, low, high);
+            if options.format == OutputFormat::WebP {
+                encode_webp(&pixels, width, height)?
+            } else {
+                encode_grayscale(&pixels, width, height, options.compression)?
+            }
+        }
+        OutputFormat::AsciiGrid => {
+            let mut geo_transform = dataset.geo_transform().map_err(ConvertError::GDal)?;
+            geo_transform[1] = metadata.x_res;
+            geo_transform[5] = metadata.y_res;
+            encode_ascii_grid(&data, width, height, &geo_transform)
+        }
+        OutputFormat::RawFloat32 => data
+            .iter()
+            .flat_map(|point| (*point as f32).to_le_bytes().to_vec())
+            .collect(),
+    };
+
+    token.check()?;
+
+    let slope = if options.slope {
+        let pixels = slope_pixels(&data, width, height, metadata.x_res, metadata.y_res);
+        Some(encode_grayscale(
+            &pixels,
+            width,
+            height,
+            options.compression,
+        )?)
+    } else {
+        None
+    };
+
+    let out = ConvertedImage {
+        width,
+        height,
+        format: options.format,
+        data: data_out,
+        slope,
+    };
+    let grid = RasterGrid {
+        width,
+        height,
+        data,
+    };
+
+    Ok((out, metadata, grid))
+}
+
+//Get the size of a `width` by `height` raster once it is shrunk by `factor`, rounding up so no edge is lost.
+fn downsampled_size(
+    width: usize,
+    height: usize,
+    factor: Option<u32>,
+) -> Result<(usize, usize), ConvertError> {
+    match factor {
+        None => Ok((width, height)),
+        Some(0) => Err(ConvertError::InvalidDownsample(0)),
+        Some(factor) => {
+            let shrink = |size: usize| (size + factor as usize - 1) / factor as usize;
+            Ok((shrink(width), shrink(height)))
+        }
+    }
+}
+
+//Check that a raster of `width` by `height` pixels can be read into memory and encoded as a PNG.
+//The files come from users, so don't trust the dimensions to be sensible.
+fn check_dimensions(width: usize, height: usize) -> Result<(), ConvertError> {
+    if width == 0 || height == 0 {
+        return Err(ConvertError::EmptyRaster(width, height));
+    }
+    //PNGs store their dimensions as 32-bit integers, and every pixel is read as an f64.
+    let fits_png = u32::try_from(width).is_ok() && u32::try_from(height).is_ok();
+    let fits_memory = width
+        .checked_mul(height)
+        .and_then(|p| p.checked_mul(std::mem::size_of::<f64>()))
+        .is_some();
+    if fits_png && fits_memory {
+        Ok(())
+    } else {
+        Err(ConvertError::TooLarge(width, height))
+    }
+}
+
+//The percentiles recorded in the metadata of every map.
+const METADATA_PERCENTILES: [f64; 4] = [2.0, 5.0, 95.0, 98.0];
+
+#[derive(Debug, Clone, PartialEq)]
+///Statistics of the heights in a raster, see [`compute_statistics`](fn.compute_statistics.html).
+pub struct RasterStats {
+    ///The lowest height.
+    pub min: f64,
+    ///The highest height.
+    pub max: f64,
+    ///The average height.
+    pub mean: f64,
+    ///The median height.
+    pub median: f64,
+    ///The requested percentiles as `(percentile, height)` pairs, in the order they were requested.
+    pub percentiles: Vec<(f64, f64)>,
+}
+
+impl RasterStats {
+    ///Get the height at percentile `percentile`, if it was computed.
+    pub fn percentile(&self, percentile: f64) -> Option<f64> {
+        self.percentiles
+            .iter()
+            .find(|(p, _)| *p == percentile)
+            .map(|(_, h)| *h)
+    }
+}
+
+///Compute statistics of the heights in `data`, including each of the `percentiles` between 0 and 100.
+///Percentiles which fall between two heights are interpolated linearly, and every statistic is NaN if `data` is empty.
+pub fn compute_statistics(data: &[f64], percentiles: &[f64]) -> RasterStats {
+    let (min, max, mean) = if data.is_empty() {
+        (f64::NAN, f64::NAN, f64::NAN)
+    } else {
+        height_range(data)
+    };
+    let mut sorted = data.to_vec();
+    sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    RasterStats {
+        min,
+        max,
+        mean,
+        median: percentile_of_sorted(&sorted, 50.0),
+        percentiles: percentiles
+            .iter()
+            .map(|p| (*p, percentile_of_sorted(&sorted, *p)))
+            .collect(),
+    }
+}
+
+//Get the height at `percentile` in the already sorted `sorted`, clamping the percentile to 0-100.
+fn percentile_of_sorted(sorted: &[f64], percentile: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = percentile.clamp(0.0, 100.0) * (sorted.len() - 1) as f64 / 100.0;
+    let (below, above) = (sorted[rank.floor() as usize], sorted[rank.ceil() as usize]);
+    below + (above - below) * rank.fract()
+}
+
+//Find the heights to show as black and white in the PNG of a map with `stats`.
+fn stretch_range(stats: &RasterStats, normalization: Normalization) -> (f64, f64) {
+    match normalization {
+        Normalization::FullRange => (stats.min, stats.max),
+        Normalization::PercentileClip { low, high } => {
+            match (stats.percentile(low), stats.percentile(high)) {
+                (Some(low), Some(high)) if low < high => (low, high),
+                //Mostly flat maps have nothing to stretch between the percentiles, so show the outliers instead.
+                _ => (stats.min, stats.max),
+            }
+        }
+    }
+}
+
+//Find the lowest, highest and average height in `data`.
+fn height_range(data: &[f64]) -> (f64, f64, f64) {
     let mut min = f64::INFINITY;
     let mut max = f64::NEG_INFINITY;
 
     //Accumulator for calculating the average
     let mut average_acc = 0f64;
-    for point in &data {
+    for point in data {
+        //Not an else-if, the first point is both the lowest and the highest one so far.
         if *point < min {
             min = *point;
-        } else if *point > max {
+        }
+        if *point > max {
             max = *point;
         }
         average_acc += point;
     }
-    let average = average_acc / data.len() as f64;
+    (min, max, average_acc / data.len() as f64)
+}
+
+///Compute the slope of the height grid `data` using Horn's method, where `x_res` and `y_res` are the size of a pixel
+///in the same unit as the heights. Returns a grayscale PNG where 0 is flat and 255 is vertical, linear in degrees.
+///Pixels at the edges of the map use the closest pixel inside the map for their missing neighbours.
+///Fails if the map is too large to be encoded as a PNG.
+pub fn compute_slope(
+    data: &[f64],
+    width: usize,
+    height: usize,
+    x_res: f64,
+    y_res: f64,
+) -> Result<Vec<u8>, ConvertError> {
+    encode_grayscale(
+        &slope_pixels(data, width, height, x_res, y_res),
+        width,
+        height,
+        PngCompression::default(),
+    )
+}
+
+//Compute the grayscale slope pixels of `data`, see `compute_slope`.
+fn slope_pixels(data: &[f64], width: usize, height: usize, x_res: f64, y_res: f64) -> Vec<u8> {
+    let (x_res, y_res) = (x_res.abs(), y_res.abs());
+    //Get the height at (x, y), clamping the coordinates to the map.
+    let at = |x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        data[y * width + x]
+    };
+
+    let mut out_data = Vec::with_capacity(data.len());
+    for y in 0..height as isize {
+        for x in 0..width as isize {
+            //The 3x3 neighbourhood around the pixel, named as in Horn's paper:
+            // a b c
+            // d e f
+            // g h i
+            let (a, b, c) = (at(x - 1, y - 1), at(x, y - 1), at(x + 1, y - 1));
+            let (d, f) = (at(x - 1, y), at(x + 1, y));
+            let (g, h, i) = (at(x - 1, y + 1), at(x, y + 1), at(x + 1, y + 1));
+
+            let dz_dx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / (8.0 * x_res);
+            let dz_dy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / (8.0 * y_res);
+            let degrees = dz_dx.hypot(dz_dy).atan().to_degrees();
+            out_data.push(convert_range(degrees, 90.0, 0.0, 0.0, u8::MAX as f64) as u8);
+        }
+    }
+    out_data
+}
+
+//Encode 8-bit grayscale pixels as a PNG compressed with `compression`.
+fn encode_grayscale(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    compression: PngCompression,
+) -> Result<Vec<u8>, ConvertError> {
+    //PNGs store their dimensions as 32-bit integers, so check them instead of letting the cast wrap around.
+    let (png_width, png_height) = match (u32::try_from(width), u32::try_from(height)) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => return Err(ConvertError::TooLarge(width, height)),
+    };
+    let mut data_out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut data_out, png_width, png_height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::from(compression));
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(pixels)?;
+    }
+    Ok(data_out)
+}
+
+//Encode 8-bit grayscale pixels as a lossless WebP.
+fn encode_webp(pixels: &[u8], width: usize, height: usize) -> Result<Vec<u8>, ConvertError> {
+    if width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+        return Err(ConvertError::TooLarge(width, height));
+    }
+    //The encoder only takes colour images, so repeat every gray level for each channel.
+    let mut rgb = Vec::with_capacity(pixels.len() * 3);
+    for p in pixels {
+        rgb.extend_from_slice(&[*p; 3]);
+    }
+    let encoded = webp::Encoder::from_rgb(&rgb, width as u32, height as u32).encode_lossless();
+    Ok(encoded.to_vec())
+}
+
+//Normalize `data` from `min`-`max` to the gray levels 0-255, clamping heights outside of it.
+fn normalize(data: &[f64], min: f64, max: f64) -> Vec<u8> {
+    //There is nothing to normalize in a flat map, so just make it mid-gray.
+    if max == min {
+        return vec![u8::MAX / 2; data.len()];
+    }
 
     //pre-allocate buffer for grayscale data for output image.
     let mut out_data = vec![0u8; data.len()];
@@ -155,55 +1044,108 @@ where
     //Normalize the data
     let one_part = (max - min) / u8::MAX as f64;
     debug!("One part is: {}, max_min: {}", one_part, max - min);
-    for (index, point) in data.into_iter().enumerate() {
-        let normalized = convert_range(point, max, min, 0.0, u8::MAX as f64);
+    for (index, point) in data.iter().enumerate() {
+        let normalized =
+            convert_range(*point, max, min, 0.0, u8::MAX as f64).clamp(0.0, u8::MAX as f64);
         out_data[index] = normalized as u8;
     }
+    out_data
+}
 
-    //Encode data_out as a grayscale png
-    let mut data_out = Vec::new();
-    {
-        let mut encoder = png::Encoder::new(&mut data_out, width as u32, height as u32);
-        encoder.set_color(png::ColorType::Grayscale);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&out_data).unwrap();
+//Encode `data` as an ESRI ASCII grid placed using `geo_transform`.
+fn encode_ascii_grid(
+    data: &[f64],
+    width: usize,
+    height: usize,
+    geo_transform: &[f64; 6],
+) -> Vec<u8> {
+    use std::fmt::Write;
+
+    let [x, x_res, _, y, _, y_res] = *geo_transform;
+    //The grid is positioned by its lower left corner, while GDAL gives us the upper left one.
+    let lower_y = y + y_res * height as f64;
+    let mut out = String::new();
+    writeln!(out, "ncols {}", width).unwrap();
+    writeln!(out, "nrows {}", height).unwrap();
+    writeln!(out, "xllcorner {}", x).unwrap();
+    writeln!(out, "yllcorner {}", lower_y.min(y)).unwrap();
+    //Non-square cells can't be expressed with a single cell size, so use the common dx/dy extension for those.
+    if (x_res.abs() - y_res.abs()).abs() < f64::EPSILON {
+        writeln!(out, "cellsize {}", x_res.abs()).unwrap();
+    } else {
+        writeln!(out, "dx {}", x_res.abs()).unwrap();
+        writeln!(out, "dy {}", y_res.abs()).unwrap();
     }
 
-    //Finally, get metadata
-    let out = ConvertedImage {
-        width,
-        height,
-        data: data_out,
-    };
-    let metadata = ImageMetadata::from_data(&dataset, min, max, average)?;
+    //The rows are written from the top, the same order as GDAL reads them.
+    for row in data.chunks(width) {
+        let row: Vec<String> = row.iter().map(|p| p.to_string()).collect();
+        writeln!(out, "{}", row.join(" ")).unwrap();
+    }
+    out.into_bytes()
+}
 
-    Ok((out, metadata))
+///Limits on the mapdata stored in the system, checked before a map is imported. Every limit is unlimited by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MapQuota {
+    ///The maximum number of maps.
+    pub max_maps: Option<usize>,
+    ///The maximum number of bytes used by all maps, including their slope and metadata.
+    pub max_map_bytes: Option<u64>,
 }
 
-///Import `data` into the system as mapdata.
-///# Panics
-///Will panic if it tries to set a map id which already exists, probably from inputting it manually.
+///The hashes holding the parts of a map, as suffixes of the namespace of its type, each keyed by the map id. The image
+///comes first, as it tells whether a map exists.
+pub const MAP_HASHES: [&str; 4] = ["image", "slope", "meta", "group"];
+///How many of the first `MAP_HASHES` count towards the storage used by maps. The group is left out.
+pub const STORED_MAP_HASHES: usize = 3;
+
+///Get the Redis key prefix the maps of `map_type` are stored under, given `root`, the prefix of all mapdata such as
+///`laps.mapdata`. Every type has its own map ids. Elevation maps are stored right under `root`, where every map was
+///stored before maps had types, while the other types get their own namespace such as `laps.mapdata.rgb`.
+pub fn map_namespace(root: &str, map_type: MapType) -> String {
+    match map_type {
+        MapType::Elevation => root.to_string(),
+        other => format!("{}.{}", root, other),
+    }
+}
+
+///Import `data` into the system as mapdata of `map_type`, unless it would exceed `quota`.
 pub async fn import_data(
     conn: &mut darkredis::Connection,
+    map_type: MapType,
     image: ConvertedImage,
     metadata: ImageMetadata,
+    quota: &MapQuota,
+) -> Result<u32, ImportError> {
+    do_import("laps.mapdata", conn, map_type, image, metadata, quota).await
+}
+
+///Get the map id the next imported map of `map_type` will get, without modifying anything.
+pub async fn next_map_id(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
 ) -> Result<u32, darkredis::Error> {
-    do_import("laps.mapdata", conn, image, metadata).await
+    let namespace = map_namespace("laps.mapdata", map_type);
+    find_next_map_id(&format!("{}.image", namespace), conn).await
 }
 
-#[inline]
-async fn do_import(
-    map_key: &str,
+///Get the number of bytes used by all maps of every type, including their slope and metadata.
+pub async fn storage_used(conn: &mut darkredis::Connection) -> Result<u64, darkredis::Error> {
+    let mut total = 0;
+    for map_type in &MapType::ALL {
+        total += find_storage_used(&map_namespace("laps.mapdata", *map_type), conn).await?;
+    }
+    Ok(total)
+}
+
+//Get the biggest unused map id in `image_key`.
+async fn find_next_map_id(
+    image_key: &str,
     conn: &mut darkredis::Connection,
-    image: ConvertedImage,
-    metadata: ImageMetadata,
 ) -> Result<u32, darkredis::Error> {
-    let image_key = format!("{}.image", map_key);
-    let meta_key = format!("{}.meta", map_key);
-    //Get the biggest unused map id.
     let mut map_ids: Vec<u32> = conn
-        .hkeys(&image_key)
+        .hkeys(image_key)
         .await?
         .into_iter()
         .map(|s| {
@@ -214,36 +1156,912 @@ async fn do_import(
         .collect();
     map_ids.sort_unstable();
 
-    //Place map data into the system
-    let map_id = map_ids.last().unwrap_or(&0) + 1;
-    let map_id_string = map_id.to_string();
-    if !conn.hsetnx(image_key, &map_id_string, image.data).await? {
-        //Map data was already set!
-        panic!("Tried to set map field {}, but it already existed!", map_id);
+    Ok(map_ids.last().unwrap_or(&0) + 1)
+}
+
+//Get the number of bytes used by the maps in `map_key`. The total is kept in `<map_key>.bytes`, which is computed
+//from the stored maps the first time it's needed. That is done in a script to avoid a round-trip for every map, and
+//so that no map can be imported in the meantime.
+async fn find_storage_used(
+    map_key: &str,
+    conn: &mut darkredis::Connection,
+) -> Result<u64, darkredis::Error> {
+    const SCRIPT: &str = r#"
+local bytes = redis.call('GET', KEYS[4])
+if bytes then
+    return tonumber(bytes)
+end
+local total = 0
+for i = 1, 3 do
+    for _, field in ipairs(redis.call('HKEYS', KEYS[i])) do
+        total = total + redis.call('HSTRLEN', KEYS[i], field)
+    end
+end
+redis.call('SET', KEYS[4], total)
+return total
+"#;
+    //The stored hashes followed by the total.
+    let keys: Vec<String> = MAP_HASHES[..STORED_MAP_HASHES]
+        .iter()
+        .map(|suffix| format!("{}.{}", map_key, suffix))
+        .chain(std::iter::once(format!("{}.bytes", map_key)))
+        .collect();
+    let key_count = keys.len().to_string();
+    let mut command = darkredis::Command::new("EVAL").arg(&SCRIPT).arg(&key_count);
+    for key in &keys {
+        command = command.arg(key);
     }
+    Ok(conn.run_command(command).await?.unwrap_integer() as u64)
+}
 
-    //Set the metadata
-    let serialized = serde_json::to_vec(&metadata).unwrap();
-    if !conn.hsetnx(meta_key, &map_id_string, &serialized).await? {
-        panic!(
-            "Tried to set map metadata field {}, but it already existed!",
-            map_id
-        );
+//Stores maps, checking the quota and picking their ids in the same go. KEYS are the image, slope, metadata and group
+//hashes followed by the total number of bytes stored, all in the namespace of the maps, and then the image hash and
+//total of every namespace, as the quota covers the maps of every type. ARGV is the maximum number of maps and bytes,
+//-1 if unlimited, and the group of the maps, empty if none, followed by the image, slope and metadata of every map.
+//Maps without a slope have an empty one. Returns `ok` and the ids of the maps, or why they didn't fit in the quota.
+const STORE_MAPS_SCRIPT: &str = r#"
+local max_maps = tonumber(ARGV[1])
+local max_bytes = tonumber(ARGV[2])
+local group = ARGV[3]
+local count = (#ARGV - 3) / 3
+local stored = 0
+local used = 0
+for i = 6, #KEYS, 2 do
+    stored = stored + redis.call('HLEN', KEYS[i])
+    used = used + tonumber(redis.call('GET', KEYS[i + 1]) or '0')
+end
+if max_maps >= 0 and stored + count > max_maps then
+    return {'maps'}
+end
+local size = 0
+for i = 4, #ARGV do
+    size = size + string.len(ARGV[i])
+end
+if max_bytes >= 0 then
+    if size > max_bytes then
+        return {'large', size}
+    end
+    if used + size > max_bytes then
+        return {'full', used, size}
+    end
+end
+local next_id = 1
+for _, id in ipairs(redis.call('HKEYS', KEYS[1])) do
+    next_id = math.max(next_id, tonumber(id) + 1)
+end
+local reply = {'ok'}
+for i = 0, count - 1 do
+    local id = next_id + i
+    redis.call('HSET', KEYS[1], id, ARGV[4 + i * 3])
+    if ARGV[5 + i * 3] ~= '' then
+        redis.call('HSET', KEYS[2], id, ARGV[5 + i * 3])
+    end
+    redis.call('HSET', KEYS[3], id, ARGV[6 + i * 3])
+    if group ~= '' then
+        redis.call('HSET', KEYS[4], id, group)
+    end
+    table.insert(reply, id)
+end
+redis.call('INCRBY', KEYS[5], size)
+return reply
+"#;
+
+//Store `maps` as `map_type` maps under `root` as part of `group`, if any, unless they would exceed `quota`. Everything
+//is done in a single script, so concurrent imports can neither get the same ids nor exceed the quota together, and
+//the maps of a group only show up once all of them are stored.
+async fn store_maps(
+    root: &str,
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    maps: Vec<(ConvertedImage, ImageMetadata)>,
+    group: Option<&str>,
+    quota: &MapQuota,
+) -> Result<Vec<u32>, ImportError> {
+    //Make sure the totals are counted before checking and adding to them, otherwise the maps from before they existed
+    //are missed.
+    let namespaces: Vec<String> = MapType::ALL
+        .iter()
+        .map(|t| map_namespace(root, *t))
+        .collect();
+    for namespace in &namespaces {
+        find_storage_used(namespace, conn).await?;
     }
 
-    info!(
-        "Imported map {}: {}px by {}px image with metadata: {}",
-        map_id_string, image.width, image.height, metadata
-    );
+    let map_key = map_namespace(root, map_type);
+    let keys: Vec<String> = MAP_HASHES
+        .iter()
+        .map(|suffix| format!("{}.{}", map_key, suffix))
+        .chain(std::iter::once(format!("{}.bytes", map_key)))
+        .chain(
+            namespaces
+                .iter()
+                .flat_map(|n| vec![format!("{}.image", n), format!("{}.bytes", n)]),
+        )
+        .collect();
+    let limit = |max: Option<u64>| max.map_or(-1, |m| m as i64).to_string().into_bytes();
+    let mut args = vec![
+        limit(quota.max_maps.map(|m| m as u64)),
+        limit(quota.max_map_bytes),
+        group.unwrap_or("").as_bytes().to_vec(),
+    ];
+    let mut descriptions = Vec::with_capacity(maps.len());
+    for (image, mut metadata) in maps {
+        //Record the checksum of the image as it's stored, so that corrupted map data can be detected later.
+        metadata.checksum = Some(map_checksum(&image.data));
+        metadata.map_type = Some(map_type);
+        descriptions.push(format!(
+            "{}px by {}px image with metadata: {}",
+            image.width, image.height, metadata
+        ));
+        args.push(image.data);
+        args.push(image.slope.unwrap_or_default());
+        args.push(serde_json::to_vec(&metadata).unwrap());
+    }
 
-    Ok(map_id)
+    let key_count = keys.len().to_string();
+    let mut command = darkredis::Command::new("EVAL")
+        .arg(&STORE_MAPS_SCRIPT)
+        .arg(&key_count);
+    for key in &keys {
+        command = command.arg(key);
+    }
+    for arg in &args {
+        command = command.arg(arg);
+    }
+    let mut reply = conn.run_command(command).await?.unwrap_array().into_iter();
+    let status = reply.next().map(darkredis::Value::unwrap_string);
+    let mut numbers = reply.map(|v| v.unwrap_integer() as u64);
+    let mut number = || numbers.next().expect("map import script reply");
+    match status.as_deref() {
+        Some(b"ok") => {
+            let ids: Vec<u32> = (0..descriptions.len()).map(|_| number() as u32).collect();
+            for (id, description) in ids.iter().zip(descriptions) {
+                info!("Imported {} map {}: {}", map_type, id, description);
+            }
+            Ok(ids)
+        }
+        Some(b"maps") => Err(ImportError::TooManyMaps(quota.max_maps.unwrap_or(0))),
+        Some(b"large") => Err(ImportError::TooLarge(
+            number(),
+            quota.max_map_bytes.unwrap_or(0),
+        )),
+        Some(b"full") => {
+            let used = number();
+            Err(ImportError::StorageFull(
+                used,
+                number(),
+                quota.max_map_bytes.unwrap_or(0),
+            ))
+        }
+        _ => panic!("Unexpected reply from the map import script"),
+    }
+}
+
+#[inline]
+async fn do_import(
+    root: &str,
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    image: ConvertedImage,
+    metadata: ImageMetadata,
+    quota: &MapQuota,
+) -> Result<u32, ImportError> {
+    let ids = store_maps(root, conn, map_type, vec![(image, metadata)], None, quota).await?;
+    Ok(ids[0])
 }
 
 ///Import `image` and `metadata` into the system, but place the result in the testing key rather than the actual key.
 pub async fn import_data_test(
     conn: &mut darkredis::Connection,
+    map_type: MapType,
     image: ConvertedImage,
     metadata: ImageMetadata,
-) -> Result<u32, darkredis::Error> {
-    do_import("laps.testing.mapdata", conn, image, metadata).await
+    quota: &MapQuota,
+) -> Result<u32, ImportError> {
+    do_import(
+        "laps.testing.mapdata",
+        conn,
+        map_type,
+        image,
+        metadata,
+        quota,
+    )
+    .await
+}
+
+///Import every map in `maps` into the system as one group of `map_type` maps named `group`, returning the ids of the
+///new maps in the same order. Groups are stored in the `group` hash of the namespace of the type, such as
+///`laps.mapdata.group`, mapping each map id to the name of its group. The maps are stored all at once, so a group is
+///never seen half-imported, and none of them are stored if they would exceed `quota` together.
+pub async fn import_group(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    maps: Vec<(ConvertedImage, ImageMetadata)>,
+    group: &str,
+    quota: &MapQuota,
+) -> Result<Vec<u32>, ImportError> {
+    do_import_group("laps.mapdata", conn, map_type, maps, group, quota).await
+}
+
+async fn do_import_group(
+    root: &str,
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    maps: Vec<(ConvertedImage, ImageMetadata)>,
+    group: &str,
+    quota: &MapQuota,
+) -> Result<Vec<u32>, ImportError> {
+    let ids = store_maps(root, conn, map_type, maps, Some(group), quota).await?;
+    info!(
+        "Imported {} {} maps into group {}",
+        ids.len(),
+        map_type,
+        group
+    );
+    Ok(ids)
+}
+
+///Import `maps` as a group like [import_group](fn.import_group.html), but place the result in the testing key rather
+///than the actual key.
+pub async fn import_group_test(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    maps: Vec<(ConvertedImage, ImageMetadata)>,
+    group: &str,
+    quota: &MapQuota,
+) -> Result<Vec<u32>, ImportError> {
+    do_import_group("laps.testing.mapdata", conn, map_type, maps, group, quota).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    const TEST_MAP: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../test_data/height_data/dtm1.tif"
+    );
+
+    fn convert_test_map(format: OutputFormat) -> (ConvertedImage, ImageMetadata) {
+        let options = ConvertOptions {
+            format,
+            ..Default::default()
+        };
+        convert(TEST_MAP, &options).unwrap()
+    }
+
+    //Decode the pixels of a PNG.
+    fn decode_png(data: &[u8]) -> Vec<u8> {
+        let (info, mut reader) = png::Decoder::new(data).read_info().unwrap();
+        let mut pixels = vec![0u8; info.buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        pixels
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let (image, _) = convert_test_map(OutputFormat::Png);
+        assert_eq!(image.format.content_type(), "image/png");
+        let decoder = png::Decoder::new(image.data.as_slice());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        assert_eq!(info.width as usize, image.width);
+        assert_eq!(info.height as usize, image.height);
+        let mut pixels = vec![0u8; info.buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        //The heights are normalized to use the whole range.
+        assert_eq!(pixels.iter().min(), Some(&0));
+        assert_eq!(pixels.iter().max(), Some(&u8::MAX));
+    }
+
+    #[test]
+    fn webp_round_trip() {
+        let (png, png_metadata) = convert_test_map(OutputFormat::Png);
+        let (webp, metadata) = convert_test_map(OutputFormat::WebP);
+        assert_eq!(webp.format.content_type(), "image/webp");
+        assert_eq!(metadata.format, Some(OutputFormat::WebP));
+        assert_eq!(png_metadata.format, Some(OutputFormat::Png));
+        assert_eq!(OutputFormat::detect(&webp.data), Some(OutputFormat::WebP));
+        assert_eq!(OutputFormat::detect(&png.data), Some(OutputFormat::Png));
+        assert_eq!(OutputFormat::detect(b"not a map"), None);
+
+        //The WebP is lossless, so it holds exactly the same gray levels as the PNG.
+        let expected = (png.width as u32, png.height as u32);
+        assert_eq!(map_dimensions(&webp.data).unwrap(), expected);
+        assert_eq!(map_dimensions(&png.data).unwrap(), expected);
+        assert_eq!(
+            decode_map(&webp.data).unwrap(),
+            decode_map(&png.data).unwrap()
+        );
+        match map_dimensions(&webp.data[..20]) {
+            Err(ConvertError::WebP(_)) => (),
+            other => panic!("Expected WebP error, got {:?}", other),
+        }
+
+        //Metadata can be completed from either format.
+        let mut old = ImageMetadata {
+            width: None,
+            height: None,
+            median_height: None,
+            ..metadata.clone()
+        };
+        assert!(old.complete_from_png(&webp.data).unwrap());
+        assert_eq!(old.width, Some(webp.width));
+        assert_eq!(old.height, Some(webp.height));
+
+        //Only PNG and WebP can be used for maps.
+        let options = ConvertOptions {
+            format: OutputFormat::AsciiGrid,
+            ..Default::default()
+        };
+        let (image, _) = convert_to_png_with(TEST_MAP, &options).unwrap();
+        assert_eq!(image.format, OutputFormat::Png);
+        assert_eq!(
+            OutputFormat::from_map_name("WebP"),
+            Some(OutputFormat::WebP)
+        );
+        assert_eq!(OutputFormat::from_map_name("asc"), None);
+    }
+
+    #[test]
+    fn png_compression() {
+        let mut sizes = Vec::new();
+        let mut images = Vec::new();
+        for compression in &[
+            PngCompression::Fast,
+            PngCompression::Default,
+            PngCompression::Best,
+        ] {
+            let options = ConvertOptions {
+                compression: *compression,
+                ..Default::default()
+            };
+            let (image, _) = convert(TEST_MAP, &options).unwrap();
+            sizes.push(image.data.len());
+            images.push(decode_png(&image.data));
+        }
+
+        //Harder compression gives smaller output, but the same image.
+        assert!(sizes[0] >= sizes[1] && sizes[1] >= sizes[2]);
+        assert!(sizes[0] > sizes[2]);
+        assert!(images.iter().all(|i| *i == images[0]));
+
+        assert_eq!(
+            PngCompression::from_name("BEST"),
+            Some(PngCompression::Best)
+        );
+        assert_eq!(PngCompression::from_name("none"), None);
+    }
+
+    #[test]
+    fn ascii_grid_round_trip() {
+        let (image, metadata) = convert_test_map(OutputFormat::AsciiGrid);
+        let text = String::from_utf8(image.data).unwrap();
+
+        //The header lines are keyword-value pairs, followed by one line of values per row.
+        let mut header = HashMap::new();
+        let mut rows = Vec::new();
+        for line in text.lines() {
+            let mut parts = line.split_whitespace();
+            let first = parts.next().unwrap();
+            if first.parse::<f64>().is_err() {
+                header.insert(first.to_string(), parts.next().unwrap().to_string());
+            } else {
+                let row: Vec<f64> = line
+                    .split_whitespace()
+                    .map(|v| v.parse().unwrap())
+                    .collect();
+                assert_eq!(row.len(), image.width);
+                rows.push(row);
+            }
+        }
+        assert_eq!(header["ncols"], image.width.to_string());
+        assert_eq!(header["nrows"], image.height.to_string());
+        assert!(header.contains_key("xllcorner"));
+        assert!(header.contains_key("yllcorner"));
+        assert!(header.contains_key("cellsize") || header.contains_key("dx"));
+        assert_eq!(rows.len(), image.height);
+
+        //The values are the raw heights.
+        let values = rows.iter().flatten();
+        let min = values.clone().cloned().fold(f64::INFINITY, f64::min);
+        let max = values.cloned().fold(f64::NEG_INFINITY, f64::max);
+        assert!((min - metadata.min_height).abs() < 1e-9);
+        assert!((max - metadata.max_height).abs() < 1e-9);
+    }
+
+    #[test]
+    fn slope() {
+        //A flat map has no slope anywhere, including the edges.
+        let flat = vec![10.0; 16];
+        assert!(decode_png(&compute_slope(&flat, 4, 4, 1.0, 1.0).unwrap())
+            .iter()
+            .all(|p| *p == 0));
+
+        //A ramp rising one unit per pixel in x is 45 degrees, or half the range.
+        let ramp: Vec<f64> = (0..16).map(|i| (i % 4) as f64).collect();
+        let pixels = decode_png(&compute_slope(&ramp, 4, 4, 1.0, 1.0).unwrap());
+        //Only check the interior, as the clamped edges see half the rise.
+        assert_eq!(pixels[5], 127);
+        assert_eq!(pixels[6], 127);
+        //With twice the pixel size, the slope is gentler.
+        let pixels = decode_png(&compute_slope(&ramp, 4, 4, 2.0, -2.0).unwrap());
+        assert!(pixels[5] < 127);
+
+        //The slope is only computed when requested.
+        let (image, _) = convert_test_map(OutputFormat::Png);
+        assert!(image.slope.is_none());
+        let options = ConvertOptions {
+            slope: true,
+            ..Default::default()
+        };
+        let (image, _) = convert(TEST_MAP, &options).unwrap();
+        assert_eq!(
+            decode_png(&image.slope.unwrap()).len(),
+            image.width * image.height
+        );
+    }
+
+    //Create a 4x4 in-memory dataset with the heights `data` and the pixel size `resolution`.
+    fn create_dataset(data: Vec<u8>, resolution: f64) -> Dataset {
+        use gdal::raster::{Buffer, Driver};
+
+        let driver = Driver::get("MEM").unwrap();
+        let dataset = driver.create("", 4, 4, 1).unwrap();
+        dataset
+            .set_geo_transform(&[0.0, resolution, 0.0, 0.0, 0.0, -resolution])
+            .unwrap();
+        let buffer = Buffer { size: (4, 4), data };
+        dataset.write_raster(1, (0, 0), (4, 4), buffer).unwrap();
+        dataset
+    }
+
+    #[test]
+    fn png_encoding_errors() {
+        //Dimensions which don't fit in a PNG are rejected before anything is encoded.
+        let too_wide = u32::MAX as usize + 1;
+        match encode_grayscale(&[], too_wide, 1, PngCompression::Default) {
+            Err(ConvertError::TooLarge(width, height)) => {
+                assert_eq!((width, height), (too_wide, 1))
+            }
+            other => panic!("Expected too large error, got {:?}", other),
+        }
+        //Too little data for the dimensions is an encoding error rather than a panic.
+        match encode_grayscale(&[0; 4], 4, 4, PngCompression::Default) {
+            Err(ConvertError::PngEncode(_)) => (),
+            other => panic!("Expected encoding error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dimension_checks() {
+        assert!(check_dimensions(4, 4).is_ok());
+        assert!(check_dimensions(u32::MAX as usize, 1).is_ok());
+        match check_dimensions(0, 4) {
+            Err(ConvertError::EmptyRaster(0, 4)) => (),
+            other => panic!("Expected empty raster error, got {:?}", other),
+        }
+        //Either dimension can be too large for a PNG, and the pixel count can overflow even when both fit.
+        for &(width, height) in &[
+            (u32::MAX as usize + 1, 1),
+            (1, u32::MAX as usize + 1),
+            (u32::MAX as usize, u32::MAX as usize),
+        ] {
+            match check_dimensions(width, height) {
+                Err(ConvertError::TooLarge(w, h)) => assert_eq!((w, h), (width, height)),
+                other => panic!("Expected too large error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn cancellation() {
+        let token = CancelToken::new();
+        assert!(convert_cancellable(TEST_MAP, &ConvertOptions::default(), &token).is_ok());
+        token.cancel();
+        assert!(token.is_cancelled());
+        match convert_cancellable(TEST_MAP, &ConvertOptions::default(), &token) {
+            Err(ConvertError::Cancelled) => (),
+            other => panic!("Expected cancellation, got {:?}", other.map(|_| ())),
+        }
+
+        //Map images are cancelled the same way.
+        match convert_to_png_cancellable(TEST_MAP, &ConvertOptions::default(), &token) {
+            Err(ConvertError::Cancelled) => (),
+            other => panic!("Expected cancellation, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn statistics() {
+        let data: Vec<f64> = (0..=100).rev().map(|h| h as f64).collect();
+        let stats = compute_statistics(&data, &[0.0, 2.0, 97.5, 100.0]);
+        assert_eq!((stats.min, stats.max, stats.mean), (0.0, 100.0, 50.0));
+        assert_eq!(stats.median, 50.0);
+        assert_eq!(
+            stats.percentiles,
+            vec![(0.0, 0.0), (2.0, 2.0), (97.5, 97.5), (100.0, 100.0)]
+        );
+        assert_eq!(stats.percentile(2.0), Some(2.0));
+        assert_eq!(stats.percentile(50.0), None);
+
+        //The median of an even number of heights lies between the middle two.
+        assert_eq!(compute_statistics(&[4.0, 1.0, 3.0, 2.0], &[]).median, 2.5);
+        assert!(compute_statistics(&[], &[50.0]).percentiles[0].1.is_nan());
+    }
+
+    #[test]
+    fn percentile_clip() {
+        //Count the gray levels used by a PNG, a rough measure of its contrast.
+        fn gray_levels(mut pixels: Vec<u8>) -> usize {
+            pixels.sort_unstable();
+            pixels.dedup();
+            pixels.len()
+        }
+
+        //A smooth slope with one percent of the points being huge spikes.
+        let mut data: Vec<f64> = (0..990).map(|h| h as f64).collect();
+        data.extend_from_slice(&[100_000.0; 10]);
+        let stats = compute_statistics(&data, &[2.0, 98.0]);
+
+        //Stretching over the full range squashes the slope into a few levels, while clipping the outliers uses them
+        //all.
+        let (min, max) = stretch_range(&stats, Normalization::FullRange);
+        assert_eq!((min, max), (0.0, 100_000.0));
+        let full = normalize(&data, min, max);
+        let (low, high) = stretch_range(&stats, Normalization::STANDARD_CLIP);
+        assert!(low > 0.0 && high < 1000.0);
+        let clipped = normalize(&data, low, high);
+        assert!(gray_levels(full) < 5);
+        assert_eq!(gray_levels(clipped), 256);
+
+        //Maps where the percentiles are the same fall back to the full range.
+        let mut flat = vec![1.0; 100];
+        flat[0] = 0.0;
+        let stats = compute_statistics(&flat, &[2.0, 98.0]);
+        assert_eq!(
+            stretch_range(&stats, Normalization::STANDARD_CLIP),
+            (0.0, 1.0)
+        );
+
+        //The clipped range is recorded in the metadata.
+        let (_, metadata) = convert_test_map(OutputFormat::Png);
+        assert!(metadata.normalized_range.is_none());
+        let median = metadata.median_height.unwrap();
+        assert!(metadata.min_height <= median && median <= metadata.max_height);
+        assert_eq!(
+            metadata.height_percentiles.len(),
+            METADATA_PERCENTILES.len()
+        );
+        let options = ConvertOptions {
+            normalization: Normalization::STANDARD_CLIP,
+            ..Default::default()
+        };
+        let (_, metadata) = convert(TEST_MAP, &options).unwrap();
+        let (black, white) = metadata.normalized_range.unwrap();
+        assert!(metadata.min_height <= black && black < white && white <= metadata.max_height);
+    }
+
+    #[test]
+    fn clipped_conversion() {
+        //A ramp with a single spike from a sensor error.
+        let mut data: Vec<u8> = (0..15).collect();
+        data.push(255);
+        let dataset = create_dataset(data.clone(), 1.0);
+        let (full, full_metadata) =
+            convert_dataset(&dataset, &ConvertOptions::default(), &CancelToken::new()).unwrap();
+        let options = ConvertOptions {
+            normalization: Normalization::PercentileClip {
+                low: 0.0,
+                high: 90.0,
+            },
+            ..Default::default()
+        };
+        let (clipped, metadata) = convert_dataset(&dataset, &options, &CancelToken::new()).unwrap();
+
+        //The spike squashes the ramp into the darkest gray levels, unless it is clipped away and saturates to white.
+        let full = decode_png(&full.data);
+        let clipped = decode_png(&clipped.data);
+        assert_eq!(full[14], 14);
+        assert!(clipped[13] > 240);
+        assert_eq!(clipped[14], u8::MAX);
+        assert_eq!(clipped[15], u8::MAX);
+        assert_eq!(metadata.normalized_range, Some((0.0, 13.5)));
+        assert!(full_metadata.normalized_range.is_none());
+
+        //The heights within the clipped range can still be read back from the gray levels.
+        let step = 13.5 / u8::MAX as f64;
+        for (height, gray) in data.iter().zip(&clipped).take(14) {
+            assert!((metadata.gray_to_height(*gray) - *height as f64).abs() <= step);
+        }
+        assert_eq!(metadata.gray_to_height(u8::MAX), 13.5);
+        assert_eq!(full_metadata.gray_to_height(u8::MAX), 255.0);
+
+        //Percentiles which don't make sense are rejected.
+        for (low, high) in &[(50.0, 50.0), (-1.0, 50.0), (10.0, 101.0)] {
+            let options = ConvertOptions {
+                normalization: Normalization::PercentileClip {
+                    low: *low,
+                    high: *high,
+                },
+                ..Default::default()
+            };
+            match convert_dataset(&dataset, &options, &CancelToken::new()) {
+                Err(ConvertError::InvalidClip(_, _)) => (),
+                other => panic!("Expected invalid clip error, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_from_png() {
+        let (image, metadata) = convert_test_map(OutputFormat::Png);
+        assert_eq!(metadata.width, Some(image.width));
+        assert_eq!(metadata.height, Some(image.height));
+
+        //Metadata from before the dimensions and percentiles were recorded.
+        let mut old = ImageMetadata {
+            median_height: None,
+            height_percentiles: Vec::new(),
+            width: None,
+            height: None,
+            ..metadata.clone()
+        };
+        assert!(old.complete_from_png(&image.data).unwrap());
+        assert_eq!(old.width, metadata.width);
+        assert_eq!(old.height, metadata.height);
+        //The gray levels are only precise to within a step.
+        let step = (metadata.max_height - metadata.min_height) / u8::MAX as f64;
+        assert!((old.median_height.unwrap() - metadata.median_height.unwrap()).abs() <= step);
+        assert_eq!(
+            old.height_percentiles.len(),
+            metadata.height_percentiles.len()
+        );
+        let pairs = old
+            .height_percentiles
+            .iter()
+            .zip(&metadata.height_percentiles);
+        for ((p, recomputed), (q, original)) in pairs {
+            assert_eq!(p, q);
+            assert!((recomputed - original).abs() <= step);
+        }
+        //The heights themselves are left alone.
+        assert_eq!(old.min_height, metadata.min_height);
+        assert_eq!(old.origin, metadata.origin);
+
+        //The PNG isn't trusted to be intact, so the checksum isn't filled in.
+        assert_eq!(old.checksum, None);
+
+        //Complete metadata is kept as it is.
+        let mut complete = ImageMetadata {
+            checksum: Some(map_checksum(&image.data)),
+            ..metadata.clone()
+        };
+        assert!(!complete.complete_from_png(&image.data).unwrap());
+        assert_eq!(complete.median_height, metadata.median_height);
+
+        //Only PNGs can be read.
+        match old.complete_from_png(b"not a png") {
+            Err(ConvertError::PngDecode(_)) => (),
+            other => panic!("Expected decoding error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        let (image, metadata) = convert_test_map(OutputFormat::Png);
+        assert_eq!(metadata.verify_checksum(&image.data), None);
+
+        let metadata = ImageMetadata {
+            checksum: Some(map_checksum(&image.data)),
+            ..metadata
+        };
+        assert_eq!(metadata.verify_checksum(&image.data), Some(true));
+        //A single flipped bit is caught.
+        let mut corrupted = image.data.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 1;
+        assert_eq!(metadata.verify_checksum(&corrupted), Some(false));
+        assert_eq!(metadata.verify_checksum(&image.data[1..]), Some(false));
+    }
+
+    #[test]
+    fn height_ranges() {
+        //The highest point comes first in a decreasing sequence, so it must be counted as the maximum right away.
+        let descending: Vec<f64> = (0..10).rev().map(|h| h as f64).collect();
+        assert_eq!(height_range(&descending), (0.0, 9.0, 4.5));
+        let ascending: Vec<f64> = (0..10).map(|h| h as f64).collect();
+        assert_eq!(height_range(&ascending), (0.0, 9.0, 4.5));
+        assert_eq!(height_range(&[3.0]), (3.0, 3.0, 3.0));
+
+        //Also through a whole conversion.
+        let descending = (0..16).rev().collect();
+        let dataset = create_dataset(descending, 1.0);
+        let options = ConvertOptions::default();
+        let (_, metadata) = convert_dataset(&dataset, &options, &CancelToken::new()).unwrap();
+        assert_eq!(metadata.min_height, 0.0);
+        assert_eq!(metadata.max_height, 15.0);
+        assert!(!metadata.flat);
+    }
+
+    #[test]
+    fn map_types() {
+        //Converted maps are always elevation data.
+        let dataset = create_dataset((0..16).collect(), 1.0);
+        let options = ConvertOptions::default();
+        let (_, metadata) = convert_dataset(&dataset, &options, &CancelToken::new()).unwrap();
+        assert_eq!(metadata.map_type, Some(MapType::Elevation));
+
+        //Metadata stored before map types existed has no type.
+        let old =
+            r#"{"x_res":1.0,"y_res":1.0,"min_height":0.0,"max_height":1.0,"average_height":0.5}"#;
+        let metadata: ImageMetadata = serde_json::from_str(old).unwrap();
+        assert_eq!(metadata.map_type, None);
+        assert_eq!(metadata.origin, None);
+        assert_eq!(metadata.world_to_pixel(1.0, 1.0), None);
+        assert!(!serde_json::to_string(&metadata)
+            .unwrap()
+            .contains("map_type"));
+
+        assert_eq!("rgb".parse(), Ok(MapType::Rgb));
+        assert_eq!("slope".parse(), Ok(MapType::Slope));
+        assert_eq!("elevation".parse(), Ok(MapType::Elevation));
+        assert_eq!("height".parse::<MapType>(), Err("height".to_string()));
+        assert_eq!(MapType::names(), "[elevation, rgb, slope]");
+        assert_eq!(
+            map_namespace("laps.mapdata", MapType::Elevation),
+            "laps.mapdata"
+        );
+        assert_eq!(
+            map_namespace("laps.mapdata", MapType::Rgb),
+            "laps.mapdata.rgb"
+        );
+    }
+
+    #[test]
+    fn georeferencing() {
+        //The test dataset has its origin at (0, 0) with 2 by 2 unit pixels, and y decreasing downwards.
+        let dataset = create_dataset((0..16).collect(), 2.0);
+        let (_, metadata) =
+            convert_dataset(&dataset, &ConvertOptions::default(), &CancelToken::new()).unwrap();
+        assert_eq!(metadata.origin, Some((0.0, 0.0)));
+        assert_eq!(metadata.world_to_pixel(0.0, 0.0), Some((0.0, 0.0)));
+        //Points are rounded to the nearest pixel.
+        assert_eq!(metadata.world_to_pixel(2.9, -5.1), Some((1.0, 3.0)));
+        assert_eq!(metadata.world_to_pixel(-0.9, 0.9), Some((0.0, 0.0)));
+        assert_eq!(metadata.world_to_pixel(5.9, -6.2), Some((3.0, 3.0)));
+        //Points outside of the map end up outside of the image.
+        assert_eq!(metadata.world_to_pixel(-1.2, 1.2), Some((-1.0, -1.0)));
+        assert_eq!(metadata.world_to_pixel(8.0, -8.0), Some((4.0, 4.0)));
+    }
+
+    #[test]
+    fn raw_heights() {
+        let ramp: Vec<u8> = (0..16).collect();
+        let dataset = create_dataset(ramp.clone(), 1.0);
+        let (image, _, grid) =
+            convert_dataset_raw(&dataset, &ConvertOptions::default(), &CancelToken::new()).unwrap();
+        assert_eq!((grid.width, grid.height), (image.width, image.height));
+        let heights: Vec<f64> = ramp.iter().map(|h| *h as f64).collect();
+        assert_eq!(grid.data, heights);
+
+        //The .npy header is padded so that the heights start at a multiple of 64 bytes.
+        let npy = grid.to_npy();
+        assert!(npy.starts_with(b"\x93NUMPY\x01\x00"));
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert!(header.contains("'descr': '<f8'"));
+        assert!(header.contains("'shape': (4, 4)"));
+        assert!(header.ends_with('\n'));
+        let data: Vec<f64> = npy[10 + header_len..]
+            .chunks(8)
+            .map(|c| f64::from_le_bytes(<[u8; 8]>::try_from(c).unwrap()))
+            .collect();
+        assert_eq!(data, heights);
+    }
+
+    #[test]
+    fn downsampling() {
+        let ramp: Vec<u8> = (0..16).collect();
+        let dataset = create_dataset(ramp.clone(), 1.0);
+        let token = CancelToken::new();
+        let options = ConvertOptions {
+            downsample: Some(2),
+            ..Default::default()
+        };
+        let (image, metadata, grid) = convert_dataset_raw(&dataset, &options, &token).unwrap();
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!((metadata.width, metadata.height), (Some(2), Some(2)));
+        //The pixels cover the same area as before, so they are twice as big.
+        assert_eq!((metadata.x_res, metadata.y_res), (2.0, -2.0));
+        assert_eq!(grid.data.len(), 4);
+        assert!(grid.data.iter().all(|h| ramp.contains(&(*h as u8))));
+
+        //Odd sizes are rounded up, and factors larger than the raster leave a single pixel.
+        assert_eq!(downsampled_size(5, 4, Some(2)).unwrap(), (3, 2));
+        assert_eq!(downsampled_size(5, 4, Some(64)).unwrap(), (1, 1));
+        assert_eq!(downsampled_size(5, 4, Some(1)).unwrap(), (5, 4));
+        let options = ConvertOptions {
+            downsample: Some(0),
+            ..Default::default()
+        };
+        match convert_dataset(&dataset, &options, &token) {
+            Err(ConvertError::InvalidDownsample(0)) => (),
+            other => panic!("Expected invalid downsample error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn overview_reading() {
+        //An 8 by 8 raster of the heights 0 to 63, with a 4 by 4 overview of the heights 1000 to 1015. The overview
+        //doesn't match the raster, so that it's obvious which of them was read.
+        const OVERVIEW_MAP: &str = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../test_data/height_data/overviews.tif"
+        );
+
+        let (image, metadata) = convert(OVERVIEW_MAP, &ConvertOptions::default()).unwrap();
+        assert_eq!((image.width, image.height), (8, 8));
+        assert_eq!((metadata.min_height, metadata.max_height), (0.0, 63.0));
+
+        //Shrinking the raster to the size of the overview reads the overview rather than decimating the raster.
+        let options = ConvertOptions {
+            downsample: Some(2),
+            ..Default::default()
+        };
+        let (image, metadata) = convert(OVERVIEW_MAP, &options).unwrap();
+        assert_eq!((image.width, image.height), (4, 4));
+        assert_eq!((metadata.min_height, metadata.max_height), (1000.0, 1015.0));
+        assert_eq!((metadata.x_res, metadata.y_res), (2.0, -2.0));
+    }
+
+    #[test]
+    fn degenerate_maps() {
+        let options = ConvertOptions::default();
+        let token = CancelToken::new();
+        let ramp: Vec<u8> = (0..16).collect();
+        assert!(convert_dataset(&create_dataset(ramp.clone(), 1.0), &options, &token).is_ok());
+
+        //Flat maps become a uniform mid-gray and are marked as flat.
+        let flat = create_dataset(vec![7; 16], 1.0);
+        let (image, metadata) = convert_dataset(&flat, &options, &token).unwrap();
+        assert!(metadata.flat);
+        assert_eq!(metadata.min_height, 7.0);
+        assert_eq!(metadata.max_height, 7.0);
+        let decoder = png::Decoder::new(image.data.as_slice());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut pixels = vec![0u8; info.buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert!(pixels.iter().all(|p| *p == 127));
+
+        //Pixels without a size are rejected regardless of the format.
+        let options = ConvertOptions {
+            format: OutputFormat::RawFloat32,
+            ..Default::default()
+        };
+        match convert_dataset(&create_dataset(ramp, 0.0), &options, &token) {
+            Err(ConvertError::DegenerateGeoTransform(x_res, _)) => assert_eq!(x_res, 0.0),
+            other => panic!("Expected geo-transform error, got {:?}", other.map(|_| ())),
+        }
+
+        //The normalization never produces NaN, even for an empty range.
+        assert_eq!(convert_range(5.0, 5.0, 5.0, 0.0, 255.0), 0.0);
+    }
+
+    #[test]
+    fn raw_float32_round_trip() {
+        let (image, metadata) = convert_test_map(OutputFormat::RawFloat32);
+        assert_eq!(image.data.len(), image.width * image.height * 4);
+
+        let values: Vec<f32> = image
+            .data
+            .chunks(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        assert!((min - metadata.min_height as f32).abs() < 1e-3);
+        assert!((max - metadata.max_height as f32).abs() < 1e-3);
+    }
 }
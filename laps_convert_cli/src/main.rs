@@ -6,9 +6,17 @@
 #[macro_use]
 extern crate log;
 
-use laps_convert::{ConvertError, ConvertedImage, ImageMetadata};
-use std::path::PathBuf;
+use laps_convert::{
+    ConvertError, ConvertOptions, ConvertedImage, ImageMetadata, MapQuota, MapType, Normalization,
+    OutputFormat, PngCompression,
+};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 use structopt::StructOpt;
+use tempfile::TempPath;
 use tokio::io::AsyncWriteExt;
 
 #[derive(StructOpt, Debug)]
@@ -18,6 +26,10 @@ struct Options {
     #[structopt(short, long)]
     import: bool,
 
+    ///Convert the files and print what would be imported without changing anything. Requires --import.
+    #[structopt(long, requires = "import")]
+    dry_run: bool,
+
     ///The directory to output the converted PNG files to. Ignored when importing data into the system.
     #[structopt(short, long, default_value = ".", parse(from_os_str))]
     output_dir: PathBuf,
@@ -30,27 +42,291 @@ struct Options {
     #[structopt(short = "-p", long)]
     redis_password: Option<String>,
 
+    ///Import the maps as one group with this name, so they can be listed and deleted together. Defaults to the
+    ///name of the directory when importing a single directory.
+    #[structopt(short, long, requires = "import")]
+    group: Option<String>,
+
+    ///The type of the imported maps, one of elevation, rgb or slope. Every type of map has its own map ids. Ignored
+    ///unless importing.
+    #[structopt(long, default_value = "elevation")]
+    map_type: MapType,
+
+    ///Refuse to import more maps once the system has this many maps.
+    #[structopt(long, requires = "import")]
+    max_maps: Option<usize>,
+
+    ///Refuse to import maps which would make all maps in the system use more than this many bytes.
+    #[structopt(long, requires = "import")]
+    max_map_bytes: Option<u64>,
+
     ///Database to use when imporpting mapdata.
     #[structopt(short = "-d", long)]
     redis_db: Option<u8>,
 
-    ///GDAL compatible raster files to import.
+    ///The raster band to convert, starting at 1. Required for datasets with more than one band.
+    #[structopt(short, long)]
+    band: Option<usize>,
+
+    ///Also compute the slope of each map, written next to each PNG as <file>.slope.png when not importing.
+    #[structopt(short, long)]
+    slope: bool,
+
+    ///How hard to compress the PNGs. "fast" speeds up large batch imports, while "best" minimizes the storage used.
+    #[structopt(
+        long,
+        default_value = "default",
+        possible_values = &["fast", "default", "best"],
+        parse(try_from_str = parse_compression)
+    )]
+    compression: PngCompression,
+
+    ///Convert the maps into lossless WebP images instead of PNGs, which are usually smaller.
+    #[structopt(long)]
+    webp: bool,
+
+    ///Also write the heights exactly as they were read next to each map as <file>.npy, for use with NumPy. These
+    ///files take up 8 bytes per pixel.
+    #[structopt(long, conflicts_with = "import")]
+    emit_raw: bool,
+
+    ///Stretch the heights between two percentiles instead of the lowest and highest point, which keeps a few outliers
+    ///from washing out the rest of the map. Clipping to 2 and 98 is usually enough to get rid of them.
+    #[structopt(long, number_of_values = 2, value_names = &["LOW", "HIGH"])]
+    percentile_clip: Option<Vec<f64>>,
+
+    ///Shrink each map by this factor while converting it. Rasters with overviews, such as Cloud-Optimized GeoTIFFs,
+    ///are read from the overview closest to the shrunk size instead of at full resolution.
+    #[structopt(long)]
+    downsample: Option<u32>,
+
+    ///Increase the verbosity of the output. Give twice for even more output.
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    ///Only output errors.
+    #[structopt(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    ///GDAL compatible raster files to import. http:// and https:// URLs are downloaded before converting, and
+    ///every file in a directory is converted.
     #[structopt(name = "INPUT", required = true, min_values = 1, parse(from_os_str))]
     files: Vec<PathBuf>,
 }
 
-fn convert_files(files: &[PathBuf]) -> Vec<Result<(ConvertedImage, ImageMetadata), ConvertError>> {
+//Convert every file in `files`, reporting progress along the way. `names` are the names of each file as given by
+//the user, which differ from the paths in `files` for downloaded files. If `raw_outputs` is given, the raw heights of
+//each file are written to the corresponding path as soon as it is converted, so that they aren't all kept in memory.
+fn convert_files(
+    files: &[PathBuf],
+    names: &[PathBuf],
+    options: &ConvertOptions,
+    raw_outputs: Option<&[PathBuf]>,
+) -> Result<Vec<Result<(ConvertedImage, ImageMetadata), ConvertError>>, String> {
+    let total_start = Instant::now();
+    let mut out = Vec::new();
+    for (index, (f, name)) in files.iter().zip(names).enumerate() {
+        let progress = format!("[{}/{}]", index + 1, files.len());
+        info!("{} Converting {}...", progress, name.display());
+        let start = Instant::now();
+        let result = match raw_outputs {
+            Some(paths) => match laps_convert::convert_with_raw(f, options) {
+                Ok((image, metadata, grid)) => {
+                    std::fs::write(&paths[index], grid.to_npy()).map_err(|e| {
+                        format!("Couldn't write to {}: {}", paths[index].display(), e)
+                    })?;
+                    Ok((image, metadata))
+                }
+                Err(e) => Err(e),
+            },
+            None => laps_convert::convert_to_png_with(f, options),
+        };
+        match &result {
+            Ok((image, _)) => info!(
+                "{} Converted {}: {}px by {}px in {:.2?}",
+                progress,
+                name.display(),
+                image.width,
+                image.height,
+                start.elapsed()
+            ),
+            Err(e) => warn!(
+                "{} Failed to convert {} after {:.2?}: {}",
+                progress,
+                name.display(),
+                start.elapsed(),
+                e
+            ),
+        }
+        out.push(result)
+    }
+
+    let failed = out.iter().filter(|r| r.is_err()).count();
+    info!(
+        "{} succeeded, {} failed, total time {:.2?}",
+        out.len() - failed,
+        failed,
+        total_start.elapsed()
+    );
+    Ok(out)
+}
+
+//Initialize logging with the verbosity requested in `options`.
+fn setup_logging(options: &Options) {
+    use log::LevelFilter;
+
+    let level = if options.quiet {
+        LevelFilter::Error
+    } else {
+        match options.verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+    //Only our own output is interesting by default, so keep everything else at warnings unless quiet.
+    let other_level = if options.quiet {
+        LevelFilter::Error
+    } else {
+        LevelFilter::Warn
+    };
+    env_logger::Builder::new()
+        .filter_level(other_level)
+        .filter_module("laps_convert", level)
+        .filter_module("laps_convert_cli", level)
+        .init();
+}
+
+//Return true if `file` should be downloaded rather than read from disk.
+fn is_url(file: &Path) -> bool {
+    let file = file.to_string_lossy();
+    file.starts_with("http://") || file.starts_with("https://")
+}
+
+//Replace every directory in `files` with the files inside it, sorted by name. Also returns the name of the
+//directory if exactly one was given, which is the default group name when importing.
+fn expand_directories(files: &[PathBuf]) -> Result<(Vec<PathBuf>, Option<String>), String> {
     let mut out = Vec::new();
+    let mut directories = Vec::new();
     for f in files {
-        out.push(laps_convert::convert_to_png(f))
+        if !is_url(f) && f.is_dir() {
+            let mut entries = std::fs::read_dir(f)
+                .and_then(|dir| {
+                    dir.map(|e| e.map(|e| e.path()))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .map_err(|e| format!("Failed to read directory {}: {}", f.display(), e))?;
+            entries.retain(|p| p.is_file());
+            entries.sort();
+            debug!("Found {} files in {}", entries.len(), f.display());
+            out.append(&mut entries);
+            directories.push(f);
+        } else {
+            out.push(f.clone());
+        }
     }
-    out
+
+    let group = match directories.as_slice() {
+        [directory] => directory
+            .canonicalize()
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned())),
+        _ => None,
+    };
+    Ok((out, group))
+}
+
+//Download `url` into a temporary file, keeping the extension so GDAL can tell what the format is.
+async fn download(url: &str) -> Result<TempPath, String> {
+    info!("Downloading {}...", url);
+    let data = reqwest::get(url)
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("Failed to download {}: {}", url, e))?
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to download {}: {}", url, e))?;
+
+    //Strip any query string from the last path segment before looking for the extension.
+    let suffix = url
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.split('?').next())
+        .and_then(|name| Path::new(name).extension())
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let (mut file, path) = tempfile::Builder::new()
+        .suffix(&suffix)
+        .tempfile()
+        .map_err(|e| format!("Failed to create temporary file for {}: {}", url, e))?
+        .into_parts();
+    file.write_all(&data)
+        .map_err(|e| format!("Failed to write {} to temporary file: {}", url, e))?;
+    debug!(
+        "Downloaded {} bytes from {} into {:?}",
+        data.len(),
+        url,
+        path
+    );
+
+    Ok(path)
+}
+
+//Download every remote file in `files`. Returns the local paths to convert in the same order as `files`,
+//along with the temporary files which are deleted once dropped.
+async fn fetch_files(files: &[PathBuf]) -> Result<(Vec<PathBuf>, Vec<TempPath>), String> {
+    let mut paths = Vec::new();
+    let mut temporary = Vec::new();
+    for f in files {
+        if is_url(f) {
+            let path = download(&f.to_string_lossy()).await?;
+            paths.push(path.to_path_buf());
+            temporary.push(path);
+        } else {
+            paths.push(f.clone());
+        }
+    }
+    Ok((paths, temporary))
+}
+
+fn parse_compression(name: &str) -> Result<PngCompression, String> {
+    PngCompression::from_name(name).ok_or_else(|| format!("Unknown compression level {}", name))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), String> {
-    env_logger::init();
     let options = Options::from_args();
+    setup_logging(&options);
+
+    let (files, directory_group) = expand_directories(&options.files)?;
+    if files.is_empty() {
+        return Err("No files to convert".to_string());
+    }
+    let group = options.group.clone().or(directory_group);
+    if group.as_deref() == Some("") {
+        return Err("The group name can't be empty".to_string());
+    }
+
+    //Keep the temporary files around until we're done with them.
+    let (inputs, _temporary_files) = fetch_files(&files).await?;
+    let convert_options = ConvertOptions {
+        band: options.band,
+        format: if options.webp {
+            OutputFormat::WebP
+        } else {
+            OutputFormat::Png
+        },
+        slope: options.slope,
+        compression: options.compression,
+        normalization: match &options.percentile_clip {
+            Some(p) => Normalization::PercentileClip {
+                low: p[0],
+                high: p[1],
+            },
+            None => Normalization::FullRange,
+        },
+        downsample: options.downsample,
+    };
 
     if options.import {
         //Connect to Redis, optionally select the correct database
@@ -68,48 +344,113 @@ async fn main() -> Result<(), String> {
                 .await
                 .map_err(|e| format!("Failed to select database: {}", e))?;
         }
+        conn.run_command(darkredis::Command::new("PING"))
+            .await
+            .map_err(|e| format!("Failed to ping Redis: {}", e))?;
 
         //Perform the conversion and store the result
-        let converted = convert_files(&options.files);
+        let converted = convert_files(&inputs, &files, &convert_options, None)?;
+        if options.dry_run {
+            //Report what would be imported, but don't stop on the first failure so every problem gets reported.
+            let mut map_id = laps_convert::next_map_id(&mut conn, options.map_type)
+                .await
+                .map_err(|e| format!("Failed to get next map id: {}", e))?;
+            let mut failures = 0;
+            for (index, result) in converted.into_iter().enumerate() {
+                let file = files[index].as_os_str().to_string_lossy();
+                match result {
+                    Ok((image, metadata)) => {
+                        println!(
+                            "{} would be imported as {} map {}: {}px by {}px, {}",
+                            file, options.map_type, map_id, image.width, image.height, metadata
+                        );
+                        map_id += 1;
+                    }
+                    Err(e) => {
+                        error!("Failed to convert {}: {}", file, e);
+                        failures += 1;
+                    }
+                }
+            }
+            if failures > 0 {
+                return Err(format!("{} files failed to convert", failures));
+            }
+            if let Some(group) = group {
+                println!("The maps would be imported as group {}", group);
+            }
+            return Ok(());
+        }
+
+        let mut maps = Vec::with_capacity(converted.len());
         for (index, result) in converted.into_iter().enumerate() {
-            let (image, metadata) = result.map_err(|e| {
+            maps.push(result.map_err(|e| {
                 format!(
                     "Failed to convert {}: {}",
-                    options.files[index].as_os_str().to_string_lossy(),
+                    files[index].as_os_str().to_string_lossy(),
                     e
                 )
-            })?;
-            laps_convert::import_data(&mut conn, image, metadata)
+            })?);
+        }
+        let quota = MapQuota {
+            max_maps: options.max_maps,
+            max_map_bytes: options.max_map_bytes,
+        };
+        if let Some(group) = group {
+            //Groups are imported all at once, so that a failure doesn't leave only some of the maps behind.
+            laps_convert::import_group(&mut conn, options.map_type, maps, &group, &quota)
                 .await
-                .unwrap();
+                .map_err(|e| format!("Failed to import group {}: {}", group, e))?;
+        } else {
+            for (index, (image, metadata)) in maps.into_iter().enumerate() {
+                laps_convert::import_data(&mut conn, options.map_type, image, metadata, &quota)
+                    .await
+                    .map_err(|e| {
+                        format!(
+                            "Failed to import {}: {}",
+                            files[index].as_os_str().to_string_lossy(),
+                            e
+                        )
+                    })?;
+            }
         }
     } else {
         if options.output_dir.is_file() {
             return Err("output-dir must be a directory!".to_string());
         }
         //Create list of output file names
-        let output_files: Vec<PathBuf> = options
-            .files
+        let output_files: Vec<PathBuf> = files
             .clone()
             .into_iter()
             .map(|p| {
-                //Convert a path like /path/to/file/file.tif into <output_dir>/file.png
+                //Convert a path like /path/to/file/file.tif into <output_dir>/file.png, or file.webp
                 let stem = p.file_stem().unwrap();
                 let mut buf = PathBuf::new();
                 buf.push(&options.output_dir);
                 buf.push(stem);
-                buf.set_extension("png");
+                buf.set_extension(convert_options.format.extension());
                 buf
             })
             .collect();
 
+        //Put the raw heights next to each map as <output_dir>/file.npy
+        let raw_outputs: Option<Vec<PathBuf>> = if options.emit_raw {
+            Some(
+                output_files
+                    .iter()
+                    .map(|f| f.with_extension("npy"))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
         //Do the conversion and write the files to disk
-        let converted = convert_files(&options.files);
+        let converted = convert_files(&inputs, &files, &convert_options, raw_outputs.as_deref())?;
         for (index, image) in converted.into_iter().enumerate() {
             let (image, _) = image.map_err(|e| {
                 format!(
                     "Failed to convert file {}: {}",
-                    options.files[index].as_os_str().to_string_lossy(),
+                    files[index].as_os_str().to_string_lossy(),
                     e
                 )
             })?;
@@ -120,6 +461,17 @@ async fn main() -> Result<(), String> {
             file.write_all(&image.data)
                 .await
                 .map_err(|e| format!("Couldn't write to file: {}", e))?;
+
+            //Put the slope next to the map as <output_dir>/file.slope.png
+            if let Some(slope) = image.slope {
+                let path = output_files[index].with_extension("slope.png");
+                let mut file = tokio::fs::File::create(&path)
+                    .await
+                    .map_err(|e| format!("Failed to create file: {}", e))?;
+                file.write_all(&slope)
+                    .await
+                    .map_err(|e| format!("Couldn't write to file: {}", e))?;
+            }
         }
     }
 
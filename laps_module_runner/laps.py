@@ -8,6 +8,7 @@ import json
 import signal, sys
 import time
 from datetime import datetime
+import threading
 import traceback
 
 # Re-usable command line arguments for LAPS modules. Mostly useful for the backend, and perhaps while
@@ -32,6 +33,12 @@ args = parser.parse_args()
 
 g_running = True
 
+# How often(in seconds) the module refreshes its heartbeat, and how long the heartbeat is valid for.
+# The backend unregisters modules whose heartbeat expires, so the TTL has to be comfortably larger than
+# the interval.
+HEARTBEAT_INTERVAL = 10
+HEARTBEAT_TTL = 30
+
 # Class to be used when a job fails.
 class JobFailure(Exception):
     pass
@@ -55,6 +62,8 @@ class Runner:
             self.log_key = "laps.moduleLogs"
 
         self.job_key = self.create_redis_key("work")
+        self.heartbeat_key = self.__create_backend_redis_key(
+            "module-heartbeat.{0}:{1}".format(self.name, self.version))
 
 
     def __enter__(self):
@@ -73,15 +82,23 @@ class Runner:
                 self.log_error(string)
             
 
+    # Get the key holding a part of every map of the type used by a job. Elevation maps are stored right under
+    # laps.mapdata, while the other types have their own namespace.
+    def __map_key(self, job, part):
+        map_type = job.get("map_type", "elevation")
+        if map_type == "elevation":
+            return "laps.mapdata.{}".format(part)
+        return "laps.mapdata.{}.{}".format(map_type, part)
+
     # Get the map data from a job. 
     def get_map_data(self, job):
-        data = self.redis.hget("laps.mapdata.image", job["map_id"])
+        data = self.redis.hget(self.__map_key(job, "image"), job["map_id"])
         if data is None:
             raise JobFailure("Map {} is missing!".format(job["map_id"]))
         return data
 
     def get_map_metadata(self, job):
-        data = self.redis.hget("laps.mapdata.meta", job["map_id"])
+        data = self.redis.hget(self.__map_key(job, "meta"), job["map_id"])
         if data is None:
             raise JobFailure("Map {} metadata is missing!".format(job["map_id"]))
         return json.loads(data)
@@ -101,6 +118,16 @@ class Runner:
         self.log_info("Registered as {0}:{1}".format(self.name, self.version))
         self.registered = True
 
+        # Keep the heartbeat alive in the background, as the main loop blocks while waiting for jobs.
+        heartbeat = threading.Thread(target=self.__heartbeat_loop, daemon=True)
+        heartbeat.start()
+
+    # Periodically tell the backend that the module is still alive.
+    def __heartbeat_loop(self):
+        while True:
+            self.redis.set(self.heartbeat_key, int(time.time()), ex=HEARTBEAT_TTL)
+            time.sleep(HEARTBEAT_INTERVAL)
+
     # Main module loop
     def run(self, handler):
         # Register self here as ready to accept jobs.
@@ -163,6 +190,20 @@ class Runner:
                 self.__fail_job(job_id)
                 raise exp
 
+    # Report the progress of `job`, where `fraction` is how much of the job is done from 0 to 1.
+    # `path_length` is the length of the best path found so far, if any, and `points` the path itself.
+    # If the job fails or times out, the last reported path is returned to the user as a partial result.
+    def report_progress(self, job, fraction, path_length=None, points=None):
+        progress = {"fraction": fraction, "path_length": path_length}
+        if points is not None:
+            progress["points"] = points
+        # The backend expires the progress together with the job result, this is just a fallback.
+        self.redis.set(
+            self.__create_backend_redis_key("job-progress.{}".format(job["job_id"])),
+            json.dumps(progress),
+            ex=3600
+        )
+
     def __fail_job(self, job_id):
         message = {"job_id": job_id, "outcome": "failure"}
         self.redis.lpush(self.__create_backend_redis_key("path-results"), json.dumps(message))
@@ -0,0 +1,360 @@
+//laps_types/lib.rs: Entry point for the laps_types library.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+#![warn(missing_debug_implementations)]
+#![warn(missing_docs)]
+
+//!Types shared between the LAPS backend and its clients, making up the wire format of the HTTP API.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+///General vector type, used for points on a map.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Vector {
+    ///The horizontal position.
+    pub x: u32,
+    ///The vertical position.
+    pub y: u32,
+}
+
+///A point of a job, in the coordinate system the job is given in. Pixels have to be whole and non-negative, while
+///world coordinates can be negative or fractional.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Point {
+    ///The horizontal position.
+    pub x: f64,
+    ///The vertical position.
+    pub y: f64,
+}
+
+impl Point {
+    ///Get the pixel at this point, or None if it isn't a whole, non-negative position which fits in a
+    ///[`Vector`](struct.Vector.html).
+    pub fn to_pixel(&self) -> Option<Vector> {
+        let valid = |n: f64| n >= 0.0 && n <= u32::MAX as f64 && n.fract() == 0.0;
+        if valid(self.x) && valid(self.y) {
+            Some(Vector {
+                x: self.x as u32,
+                y: self.y as u32,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl From<Vector> for Point {
+    fn from(v: Vector) -> Self {
+        Point {
+            x: v.x as f64,
+            y: v.y as f64,
+        }
+    }
+}
+
+///The outcome of a Job.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum JobOutcome {
+    ///The module found a path.
+    Success,
+    ///The module failed to find a path, or didn't finish in time.
+    Failure,
+    ///The job was cancelled before it finished.
+    Cancelled,
+}
+
+///The output of a pathfinding job, as reported by the pathfinding module.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobResult {
+    ///The ID of the job.
+    pub job_id: i32,
+    ///The outcome of this job
+    pub outcome: JobOutcome,
+    ///The list of points containing the path of the job.
+    #[serde(default)]
+    pub points: Vec<Vector>,
+}
+
+///Intermediate progress of a running job, as reported by the pathfinding module.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobProgress {
+    ///How much of the job is done, from 0 to 1.
+    pub fraction: f64,
+    ///The length of the best path found so far, if the module has one.
+    #[serde(default)]
+    pub path_length: Option<f64>,
+    ///The best path found so far, returned to the user if the job fails to complete.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<Vector>,
+}
+
+///The state of a job, as reported without waiting for it to finish.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    ///The job hasn't finished yet.
+    Pending,
+    ///The job finished, and its result can be retrieved.
+    Ready,
+    ///The job failed.
+    Error,
+    ///There is no job with this token, or it has expired.
+    NotFound,
+}
+
+///A pathfinding module, identified by its name and version.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ModuleInfo {
+    ///The name of the module.
+    pub name: String,
+    ///The version of the module.
+    pub version: String,
+}
+
+impl fmt::Display for ModuleInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.version)
+    }
+}
+
+///An additional map which is stitched together with the main map of a job.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct MapTile {
+    ///The id of the map.
+    pub map_id: i32,
+    ///The position of the top-left corner of this map in the stitched grid, in pixels.
+    ///The main map of the job is always placed at (0, 0).
+    pub offset: Vector,
+}
+
+///The version which resolves to the highest registered version of a module.
+pub const LATEST_VERSION: &str = "latest";
+
+///The kind of data a map contains. Every type of map is stored with its own map ids, and pathfinding modules can limit
+///which kinds of maps they accept.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MapType {
+    ///Grayscale height data, which is what laps_convert produces. Maps were only elevation maps before they had
+    ///types, so that is what maps without one are.
+    #[default]
+    Elevation,
+    ///Colour imagery such as orthophotos.
+    Rgb,
+    ///Grayscale slope data.
+    Slope,
+}
+
+impl MapType {
+    ///Every type of map.
+    pub const ALL: [MapType; 3] = [MapType::Elevation, MapType::Rgb, MapType::Slope];
+
+    ///The name of the type, as parsed by `from_str` and used in the API.
+    pub fn name(self) -> &'static str {
+        match self {
+            MapType::Elevation => "elevation",
+            MapType::Rgb => "rgb",
+            MapType::Slope => "slope",
+        }
+    }
+
+    ///The names of every type, as a list like `[elevation, rgb, slope]` for error messages.
+    pub fn names() -> String {
+        let names: Vec<&str> = MapType::ALL.iter().map(|t| t.name()).collect();
+        format!("[{}]", names.join(", "))
+    }
+
+    ///Whether this is the default type, so that it can be left out when serializing.
+    pub fn is_elevation(&self) -> bool {
+        *self == MapType::Elevation
+    }
+}
+
+impl std::fmt::Display for MapType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for MapType {
+    ///The unrecognized name.
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MapType::ALL
+            .iter()
+            .find(|t| t.name() == s)
+            .copied()
+            .ok_or_else(|| s.to_string())
+    }
+}
+
+///The coordinate system the points of a job are given in.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CoordinateSystem {
+    ///Pixels of the map, with (0, 0) in the top-left corner.
+    #[default]
+    Pixel,
+    ///The georeferenced coordinates of the map, in the units of the map. Points are rounded to the nearest pixel,
+    ///so they have to lie within the map.
+    World,
+}
+
+impl CoordinateSystem {
+    ///Whether these are pixel coordinates, the default, so that they can be left out when serializing.
+    pub fn is_pixel(&self) -> bool {
+        *self == CoordinateSystem::Pixel
+    }
+}
+
+///A request to find a path.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JobSubmission {
+    ///Where the path starts.
+    pub start: Point,
+    ///Where the path ends.
+    pub stop: Point,
+    ///The map to find a path on.
+    pub map_id: i32,
+    ///The type of `map_id` and the tiles, elevation by default. Each type of map has its own map ids.
+    #[serde(default, skip_serializing_if = "MapType::is_elevation")]
+    pub map_type: MapType,
+    ///The module to find the path with. The version may be left out or set to [`LATEST_VERSION`](constant.LATEST_VERSION.html)
+    ///to use the highest registered version of the module.
+    #[serde(deserialize_with = "deserialize_algorithm")]
+    pub algorithm: ModuleInfo,
+    ///Optional factor to downsample the map by for quick, low-resolution previews. Must be a power of two.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downsample: Option<u32>,
+    ///Optional adjacent maps to stitch together with `map_id` when a path crosses map boundaries.
+    ///`start` and `stop` are then in the coordinates of the stitched grid, where `map_id` is at (0, 0).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tiles: Vec<MapTile>,
+    ///The coordinate system of `start` and `stop`, pixels by default. World coordinates are relative to the
+    ///georeferenced position of `map_id`.
+    #[serde(default, skip_serializing_if = "CoordinateSystem::is_pixel")]
+    pub coords: CoordinateSystem,
+}
+
+//Deserialize the requested module, using the latest version if the version is missing.
+fn deserialize_algorithm<'de, D>(deserializer: D) -> Result<ModuleInfo, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    fn latest() -> String {
+        LATEST_VERSION.to_string()
+    }
+
+    #[derive(Deserialize)]
+    struct Algorithm {
+        name: String,
+        #[serde(default = "latest")]
+        version: String,
+    }
+
+    let algorithm = Algorithm::deserialize(deserializer)?;
+    Ok(ModuleInfo {
+        name: algorithm.name,
+        version: algorithm.version,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn submission_round_trip() {
+        let submission = JobSubmission {
+            start: Vector { x: 1, y: 2 }.into(),
+            stop: Vector { x: 3, y: 4 }.into(),
+            map_id: 1,
+            map_type: MapType::Elevation,
+            algorithm: ModuleInfo {
+                name: "test".into(),
+                version: "0.1.0".into(),
+            },
+            downsample: None,
+            tiles: Vec::new(),
+            coords: CoordinateSystem::Pixel,
+        };
+        let json = serde_json::to_value(&submission).unwrap();
+        //Optional fields are left out
+        assert!(json.get("map_type").is_none());
+        assert!(json.get("downsample").is_none());
+        assert!(json.get("tiles").is_none());
+        assert!(json.get("coords").is_none());
+        let parsed: JobSubmission = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.algorithm, submission.algorithm);
+        assert_eq!(parsed.start, submission.start);
+
+        //The module version defaults to the latest one
+        let json = serde_json::json!({
+            "start": { "x": 1, "y": 2 },
+            "stop": { "x": 3, "y": 4 },
+            "map_id": 1,
+            "algorithm": { "name": "test" }
+        });
+        let parsed: JobSubmission = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.algorithm.version, LATEST_VERSION);
+        assert_eq!(parsed.map_type, MapType::Elevation);
+        assert_eq!(parsed.coords, CoordinateSystem::Pixel);
+        assert_eq!(parsed.start.to_pixel(), Some(Vector { x: 1, y: 2 }));
+
+        //World coordinates can be negative or fractional, but pixels can't.
+        let json = serde_json::json!({
+            "start": { "x": -12.5, "y": 3.25 },
+            "stop": { "x": 3, "y": 4 },
+            "map_id": 1,
+            "algorithm": { "name": "test" },
+            "coords": "world"
+        });
+        let parsed: JobSubmission = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.coords, CoordinateSystem::World);
+        assert_eq!(parsed.start, Point { x: -12.5, y: 3.25 });
+        assert_eq!(parsed.start.to_pixel(), None);
+        assert_eq!(Point { x: 1.5, y: 2.0 }.to_pixel(), None);
+    }
+
+    //The results and progress reports are written by pathfinding modules, so their format can't change.
+    #[test]
+    fn module_wire_format() {
+        let result: JobResult = serde_json::from_str(
+            r#"{"job_id": 3, "outcome": "success", "points": [{"x": 1, "y": 2}]}"#,
+        )
+        .unwrap();
+        assert_eq!(result.job_id, 3);
+        assert_eq!(result.outcome, JobOutcome::Success);
+        assert_eq!(result.points, vec![Vector { x: 1, y: 2 }]);
+        //Failed jobs don't need to give any points
+        let result: JobResult =
+            serde_json::from_str(r#"{"job_id": 3, "outcome": "failure"}"#).unwrap();
+        assert!(result.points.is_empty());
+        assert_eq!(
+            serde_json::to_string(&JobOutcome::Cancelled).unwrap(),
+            r#""cancelled""#
+        );
+
+        let progress: JobProgress = serde_json::from_str(r#"{"fraction": 0.5}"#).unwrap();
+        assert_eq!(
+            progress,
+            JobProgress {
+                fraction: 0.5,
+                path_length: None,
+                points: Vec::new(),
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&progress).unwrap(),
+            r#"{"fraction":0.5,"path_length":null}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&JobStatus::NotFound).unwrap(),
+            r#""not_found""#
+        );
+    }
+}
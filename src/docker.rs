@@ -0,0 +1,636 @@
+//src/docker.rs: The Docker operations used to manage modules
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use crate::types::BackendError;
+use bollard::{
+    container::{
+        Config, CreateContainerOptions, HostConfig, ListContainersOptions, RemoveContainerOptions,
+        RestartContainerOptions, StartContainerOptions, StopContainerOptions,
+    },
+    errors::ErrorKind,
+    image::{
+        BuildImageOptions, BuildImageResults, ListImagesOptions, RemoveImageOptions,
+        RemoveImageResults,
+    },
+    Docker,
+};
+use futures::stream::StreamExt;
+use std::sync::Arc;
+
+//The container runtime which gives containers access to the GPUs of the host.
+const GPU_RUNTIME: &str = "nvidia";
+
+//The Docker backend shared between the request handlers.
+pub type SharedDocker = Arc<dyn DockerBackend>;
+
+//Share `docker` between the request handlers.
+pub fn shared(docker: impl DockerBackend + 'static) -> SharedDocker {
+    Arc::new(docker)
+}
+
+//A container, with only the fields module management cares about.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Container {
+    pub id: String,
+    //The names of the container, without the leading `/` Docker adds.
+    pub names: Vec<String>,
+    //The tag of the image the container was created from.
+    pub image: String,
+    //The state of the container, such as "running" or "exited".
+    pub state: String,
+    //Human readable status, such as "Exited (1) 2 minutes ago".
+    pub status: String,
+    //The size of the files written by the container, only set if sizes were requested.
+    pub size_rw: Option<u64>,
+}
+
+//How to create a worker container.
+pub struct ContainerSpec<'a> {
+    pub name: &'a str,
+    //The tag of the image to run.
+    pub image: &'a str,
+    pub cmd: Vec<&'a str>,
+    //Environment variables in the `KEY=VALUE` form.
+    pub env: Vec<&'a str>,
+    //Give the container access to every GPU of the host.
+    pub gpu: bool,
+}
+
+//Why building an image failed.
+pub enum BuildFailure {
+    //The Docker daemon or the network failed, so the build may succeed if retried.
+    Transient(String),
+    //The image itself is broken, retrying won't help.
+    Permanent(String),
+}
+
+//The Docker operations used by the module management endpoints. Implemented by the real Docker client, and by an
+//in-memory fake in tests so that module management can be tested without a Docker daemon.
+#[rocket::async_trait]
+pub trait DockerBackend: Send + Sync {
+    //List containers. Only running and paused containers are listed unless `all` is set.
+    async fn list_containers(&self, all: bool, size: bool) -> Result<Vec<Container>, BackendError>;
+    //Get the tags of every image.
+    async fn list_image_tags(&self) -> Result<Vec<String>, BackendError>;
+    //Get the tag and size in bytes of every image, including the layers it shares with other images.
+    async fn list_image_sizes(&self) -> Result<Vec<(String, u64)>, BackendError>;
+    //Build an image tagged `tag` from the build context in `tarball`.
+    async fn build_image(&self, tag: &str, tarball: &[u8]) -> Result<(), BuildFailure>;
+    async fn create_container(&self, spec: ContainerSpec<'_>) -> Result<(), BackendError>;
+    async fn start_container(&self, name: &str) -> Result<(), BackendError>;
+    //Stop a container, killing it if it hasn't exited after `timeout` seconds.
+    async fn stop_container(&self, name: &str, timeout: i64) -> Result<(), BackendError>;
+    //Restart a container, killing it if it hasn't exited after `timeout` seconds.
+    async fn restart_container(&self, name: &str, timeout: i64) -> Result<(), BackendError>;
+    //Freeze every process in a container without stopping it.
+    async fn pause_container(&self, name: &str) -> Result<(), BackendError>;
+    //Let the processes of a paused container continue where they left off.
+    async fn unpause_container(&self, name: &str) -> Result<(), BackendError>;
+    //Remove a container by name or id. Running containers are only removed if `force` is set.
+    async fn remove_container(&self, id: &str, force: bool) -> Result<(), BackendError>;
+    //Forcefully remove the image tagged `tag`.
+    async fn remove_image(&self, tag: &str) -> Result<(), BackendError>;
+}
+
+#[rocket::async_trait]
+impl DockerBackend for Docker {
+    async fn list_containers(&self, all: bool, size: bool) -> Result<Vec<Container>, BackendError> {
+        let options = ListContainersOptions::<String> {
+            all,
+            size,
+            ..Default::default()
+        };
+        Ok(Docker::list_containers(self, Some(options))
+            .await?
+            .into_iter()
+            .map(|c| Container {
+                id: c.id,
+                names: c
+                    .names
+                    .into_iter()
+                    .map(|n| n.trim_start_matches('/').to_string())
+                    .collect(),
+                image: c.image,
+                state: c.state,
+                status: c.status,
+                size_rw: c.size_rw,
+            })
+            .collect())
+    }
+
+    async fn list_image_tags(&self) -> Result<Vec<String>, BackendError> {
+        Ok(Docker::list_images(self, None::<ListImagesOptions<String>>)
+            .await?
+            .into_iter()
+            .filter_map(|i| i.repo_tags)
+            .flatten()
+            .collect())
+    }
+
+    async fn list_image_sizes(&self) -> Result<Vec<(String, u64)>, BackendError> {
+        Ok(Docker::list_images(self, None::<ListImagesOptions<String>>)
+            .await?
+            .into_iter()
+            .flat_map(|i| {
+                let size = i.size as u64;
+                i.repo_tags
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(move |t| (t, size))
+            })
+            .collect())
+    }
+
+    async fn build_image(&self, tag: &str, tarball: &[u8]) -> Result<(), BuildFailure> {
+        //Docker reports errors from pulling base images as regular build errors, so look for network errors in the
+        //message.
+        const TRANSIENT_MESSAGES: &[&str] = &[
+            "timeout",
+            "timed out",
+            "connection reset",
+            "connection refused",
+            "temporary failure",
+            "tls handshake",
+            "net/http",
+            "toomanyrequests",
+        ];
+
+        let options = BuildImageOptions {
+            t: tag.to_string(),
+            rm: true,
+            forcerm: true,
+            ..Default::default()
+        };
+        let mut stream = Docker::build_image(self, options, None, Some(tarball.to_vec().into()));
+        while let Some(update) = stream.next().await {
+            let update = update.map_err(|e| {
+                error!("Error getting image build output: {:?}", e);
+                //Only failing to reach the daemon is worth retrying. Errors the daemon responds with, such as a
+                //rejected Dockerfile or build context, happen again on every attempt.
+                match e.kind() {
+                    ErrorKind::HyperResponseError { .. } | ErrorKind::RequestTimeoutError => {
+                        BuildFailure::Transient(e.to_string())
+                    }
+                    _ => BuildFailure::Permanent(e.to_string()),
+                }
+            })?;
+
+            debug!("Building {}: {:?}", tag, update);
+            if let BuildImageResults::BuildImageError {
+                error,
+                error_detail,
+            } = update
+            {
+                let msg = format!(
+                    "Module import error: {}\nDetails: {:?}",
+                    error, error_detail
+                );
+                let lowercase = error.to_lowercase();
+                return if TRANSIENT_MESSAGES.iter().any(|m| lowercase.contains(m)) {
+                    Err(BuildFailure::Transient(msg))
+                } else {
+                    Err(BuildFailure::Permanent(msg))
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn create_container(&self, spec: ContainerSpec<'_>) -> Result<(), BackendError> {
+        //Docker only says that the runtime is unknown when creating the container, which doesn't tell admins what is
+        //missing, so ask the daemon which runtimes it has first.
+        if spec.gpu && !Docker::info(self).await?.runtimes.contains_key(GPU_RUNTIME) {
+            error!(
+                "Cannot create GPU container {}: the Docker daemon has no {} runtime",
+                spec.name, GPU_RUNTIME
+            );
+            return Err(BackendError::GpuUnavailable);
+        }
+
+        //The NVIDIA runtime picks the GPUs to give the container from its environment.
+        let mut env = spec.env;
+        if spec.gpu {
+            env.push("NVIDIA_VISIBLE_DEVICES=all");
+            env.push("NVIDIA_DRIVER_CAPABILITIES=compute,utility");
+        }
+        let host_config = HostConfig {
+            network_mode: Some("host"),
+            runtime: if spec.gpu { Some(GPU_RUNTIME) } else { None },
+            ..Default::default()
+        };
+        let config = Config {
+            image: Some(spec.image),
+            cmd: Some(spec.cmd),
+            env: Some(env),
+            host_config: Some(host_config),
+            stop_signal: Some("SIGINT"),
+            ..Default::default()
+        };
+        let options = CreateContainerOptions { name: spec.name };
+        let result = Docker::create_container(self, Some(options), config).await?;
+        debug!("Successfully created container {}:{}", spec.name, result.id);
+        //Print any warnings
+        let id = &result.id;
+        if let Some(w) = result.warnings {
+            w.into_iter().for_each(|w| warn!("Container {}: {}", id, w));
+        }
+        Ok(())
+    }
+
+    async fn start_container(&self, name: &str) -> Result<(), BackendError> {
+        Docker::start_container(self, name, None::<StartContainerOptions<String>>).await?;
+        Ok(())
+    }
+
+    async fn stop_container(&self, name: &str, timeout: i64) -> Result<(), BackendError> {
+        let options = StopContainerOptions { t: timeout };
+        Docker::stop_container(self, name, Some(options)).await?;
+        Ok(())
+    }
+
+    async fn restart_container(&self, name: &str, timeout: i64) -> Result<(), BackendError> {
+        let options = RestartContainerOptions {
+            t: timeout as isize,
+        };
+        Docker::restart_container(self, name, Some(options)).await?;
+        Ok(())
+    }
+
+    async fn pause_container(&self, name: &str) -> Result<(), BackendError> {
+        Docker::pause_container(self, name).await?;
+        Ok(())
+    }
+
+    async fn unpause_container(&self, name: &str) -> Result<(), BackendError> {
+        Docker::unpause_container(self, name).await?;
+        Ok(())
+    }
+
+    async fn remove_container(&self, id: &str, force: bool) -> Result<(), BackendError> {
+        let options = RemoveContainerOptions {
+            force,
+            ..Default::default()
+        };
+        Docker::remove_container(self, id, Some(options)).await?;
+        Ok(())
+    }
+
+    async fn remove_image(&self, tag: &str) -> Result<(), BackendError> {
+        let options = RemoveImageOptions {
+            force: true,
+            noprune: false,
+        };
+        let image_deletions = Docker::remove_image(self, tag, Some(options), None).await?;
+        //Output the deletions if debug log is active
+        if log_enabled!(log::Level::Debug) {
+            for deletion in image_deletions {
+                match deletion {
+                    RemoveImageResults::RemoveImageUntagged { untagged } => {
+                        debug!("Untagged {}", untagged);
+                    }
+                    RemoveImageResults::RemoveImageDeleted { deleted } => {
+                        debug!("Deleted {}", deleted);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//In-memory stand-in for Docker. Containers change state instantly and never run anything, so tests using it are
+//deterministic and don't need a Docker daemon.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeDocker {
+    state: std::sync::Mutex<FakeState>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct FakeState {
+    images: Vec<String>,
+    containers: Vec<Container>,
+    //Used to give every container a unique id.
+    created: usize,
+    //If set, every build fails with this.
+    build_failure: Option<(bool, String)>,
+    //The number of builds attempted, including the failed ones.
+    builds: usize,
+    //How long every build takes after tagging the image.
+    build_delay: Option<std::time::Duration>,
+    //The container and timeout of every stop and restart, in order.
+    stop_timeouts: Vec<(String, i64)>,
+    //Whether the host has the GPU runtime.
+    gpu_runtime: bool,
+    //The names of the containers created with access to the GPUs.
+    gpu_containers: Vec<String>,
+    //The name and command of every container created.
+    commands: Vec<(String, Vec<String>)>,
+    //The name and environment variables of every container created.
+    environments: Vec<(String, Vec<String>)>,
+    //The size of the images which have been given one, the rest are empty.
+    image_sizes: std::collections::HashMap<String, u64>,
+    //The names of the containers which fail to be created.
+    create_failures: Vec<String>,
+}
+
+#[cfg(test)]
+impl FakeDocker {
+    //Create a fake with the images tagged `tags` already built.
+    pub fn with_images(tags: &[&str]) -> Self {
+        let docker = Self::default();
+        docker.state.lock().unwrap().images = tags.iter().map(|t| t.to_string()).collect();
+        docker
+    }
+
+    //Set the size reported for the image tagged `tag`.
+    pub fn set_image_size(&self, tag: &str, bytes: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .image_sizes
+            .insert(tag.to_string(), bytes);
+    }
+
+    //Give the host the GPU runtime, so that containers can be created with access to the GPUs.
+    pub fn enable_gpus(&self) {
+        self.state.lock().unwrap().gpu_runtime = true;
+    }
+
+    //Get the names of the containers created with access to the GPUs.
+    pub fn gpu_containers(&self) -> Vec<String> {
+        self.state.lock().unwrap().gpu_containers.clone()
+    }
+
+    //Get the command the latest container called `name` was created with.
+    pub fn container_command(&self, name: &str) -> Option<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        state
+            .commands
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, c)| c.clone())
+    }
+
+    //Get the environment variables the latest container called `name` was created with.
+    pub fn container_env(&self, name: &str) -> Option<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        state
+            .environments
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, e)| e.clone())
+    }
+
+    //Make every following build fail with `message`, as a transient failure if `transient` is set.
+    pub fn fail_builds(&self, transient: bool, message: &str) {
+        self.state.lock().unwrap().build_failure = Some((transient, message.to_string()));
+    }
+
+    //Get the number of builds attempted so far, including the failed ones.
+    pub fn builds(&self) -> usize {
+        self.state.lock().unwrap().builds
+    }
+
+    //Make every following build take `delay` longer to finish after the image has been tagged, like a build whose
+    //last steps are slow.
+    pub fn slow_builds(&self, delay: std::time::Duration) {
+        self.state.lock().unwrap().build_delay = Some(delay);
+    }
+
+    //Make creating the container called `name` fail.
+    pub fn fail_creating(&self, name: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .create_failures
+            .push(name.to_string());
+    }
+
+    //Get the container and timeout of every stop and restart so far.
+    pub fn stop_timeouts(&self) -> Vec<(String, i64)> {
+        self.state.lock().unwrap().stop_timeouts.clone()
+    }
+
+    //Get every container, including stopped ones.
+    pub fn containers(&self) -> Vec<Container> {
+        self.state.lock().unwrap().containers.clone()
+    }
+
+    //Make the container called `name` exit with `exit_code`, as if it crashed.
+    pub fn exit_container(&self, name: &str, exit_code: i32) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(c) = state.containers.iter_mut().find(|c| c.names[0] == name) {
+            c.state = "exited".into();
+            c.status = format!("Exited ({}) Less than a second ago", exit_code);
+        }
+    }
+
+    fn record_stop(&self, name: &str, timeout: i64) {
+        let mut state = self.state.lock().unwrap();
+        state.stop_timeouts.push((name.to_string(), timeout));
+    }
+
+    //Run `f` on the container with id or name `id`, failing like Docker if there is none.
+    fn with_container<T>(
+        &self,
+        id: &str,
+        f: impl FnOnce(&mut Container) -> Result<T, BackendError>,
+    ) -> Result<T, BackendError> {
+        let mut state = self.state.lock().unwrap();
+        match state
+            .containers
+            .iter_mut()
+            .find(|c| c.id == id || c.names.iter().any(|n| n == id))
+        {
+            Some(c) => f(c),
+            None => Err(BackendError::Other(format!("No such container: {}", id))),
+        }
+    }
+}
+
+#[cfg(test)]
+fn set_running(container: &mut Container) {
+    container.state = "running".into();
+    container.status = "Up Less than a second".into();
+}
+
+#[cfg(test)]
+#[rocket::async_trait]
+impl DockerBackend for FakeDocker {
+    async fn list_containers(&self, all: bool, size: bool) -> Result<Vec<Container>, BackendError> {
+        Ok(self
+            .containers()
+            .into_iter()
+            .filter(|c| all || c.state == "running" || c.state == "paused")
+            .map(|c| Container {
+                size_rw: if size { Some(1024) } else { None },
+                ..c
+            })
+            .collect())
+    }
+
+    async fn list_image_tags(&self) -> Result<Vec<String>, BackendError> {
+        Ok(self.state.lock().unwrap().images.clone())
+    }
+
+    async fn list_image_sizes(&self) -> Result<Vec<(String, u64)>, BackendError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .images
+            .iter()
+            .map(|i| (i.clone(), state.image_sizes.get(i).copied().unwrap_or(0)))
+            .collect())
+    }
+
+    async fn build_image(&self, tag: &str, _tarball: &[u8]) -> Result<(), BuildFailure> {
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+            state.builds += 1;
+            match &state.build_failure {
+                Some((true, msg)) => return Err(BuildFailure::Transient(msg.clone())),
+                Some((false, msg)) => return Err(BuildFailure::Permanent(msg.clone())),
+                None => (),
+            }
+            if !state.images.iter().any(|i| i == tag) {
+                state.images.push(tag.to_string());
+            }
+            state.build_delay
+        };
+        if let Some(delay) = delay {
+            tokio::time::delay_for(delay).await;
+        }
+        Ok(())
+    }
+
+    async fn create_container(&self, spec: ContainerSpec<'_>) -> Result<(), BackendError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.images.iter().any(|i| i == spec.image) {
+            return Err(BackendError::Other(format!(
+                "No such image: {}",
+                spec.image
+            )));
+        }
+        if state.create_failures.iter().any(|n| n == spec.name) {
+            return Err(BackendError::Other(format!(
+                "Failed to create container {}",
+                spec.name
+            )));
+        }
+        if state.containers.iter().any(|c| c.names[0] == spec.name) {
+            return Err(BackendError::Other(format!(
+                "Conflict. The container name {} is already in use",
+                spec.name
+            )));
+        }
+        if spec.gpu {
+            if !state.gpu_runtime {
+                return Err(BackendError::GpuUnavailable);
+            }
+            state.gpu_containers.push(spec.name.to_string());
+        }
+        state.commands.push((
+            spec.name.to_string(),
+            spec.cmd.iter().map(|s| s.to_string()).collect(),
+        ));
+        state.environments.push((
+            spec.name.to_string(),
+            spec.env.iter().map(|s| s.to_string()).collect(),
+        ));
+        state.created += 1;
+        let container = Container {
+            id: format!("fake-{}", state.created),
+            names: vec![spec.name.to_string()],
+            image: spec.image.to_string(),
+            state: "created".into(),
+            status: "Created".into(),
+            size_rw: None,
+        };
+        state.containers.push(container);
+        Ok(())
+    }
+
+    async fn start_container(&self, name: &str) -> Result<(), BackendError> {
+        self.with_container(name, |c| {
+            set_running(c);
+            Ok(())
+        })
+    }
+
+    async fn stop_container(&self, name: &str, timeout: i64) -> Result<(), BackendError> {
+        self.record_stop(name, timeout);
+        self.with_container(name, |c| {
+            c.state = "exited".into();
+            c.status = "Exited (0) Less than a second ago".into();
+            Ok(())
+        })
+    }
+
+    async fn restart_container(&self, name: &str, timeout: i64) -> Result<(), BackendError> {
+        self.record_stop(name, timeout);
+        self.with_container(name, |c| {
+            set_running(c);
+            Ok(())
+        })
+    }
+
+    async fn pause_container(&self, name: &str) -> Result<(), BackendError> {
+        self.with_container(name, |c| {
+            if c.state != "running" {
+                return Err(BackendError::Other(format!(
+                    "Container {} is not running",
+                    name
+                )));
+            }
+            c.state = "paused".into();
+            c.status = "Up Less than a second (Paused)".into();
+            Ok(())
+        })
+    }
+
+    async fn unpause_container(&self, name: &str) -> Result<(), BackendError> {
+        self.with_container(name, |c| {
+            if c.state != "paused" {
+                return Err(BackendError::Other(format!(
+                    "Container {} is not paused",
+                    name
+                )));
+            }
+            set_running(c);
+            Ok(())
+        })
+    }
+
+    async fn remove_container(&self, id: &str, force: bool) -> Result<(), BackendError> {
+        let running = self.with_container(id, |c| Ok(c.state == "running"))?;
+        if running && !force {
+            return Err(BackendError::Other(format!(
+                "You cannot remove a running container {}",
+                id
+            )));
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .containers
+            .retain(|c| c.id != id && c.names.iter().all(|n| n != id));
+        Ok(())
+    }
+
+    async fn remove_image(&self, tag: &str) -> Result<(), BackendError> {
+        let mut state = self.state.lock().unwrap();
+        match state.images.iter().position(|i| i == tag) {
+            Some(i) => {
+                state.images.remove(i);
+                Ok(())
+            }
+            None => Err(BackendError::Other(format!("No such image: {}", tag))),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//src/history.rs: History of the most recently submitted jobs, kept for debugging and usage statistics.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use crate::{
+    module_handling::ModuleInfo,
+    types::{BackendError, JobOutcome},
+    util::{get_job_history_key, get_job_history_record_key},
+};
+use chrono::Utc;
+use darkredis::{Command, CommandList, Value};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+//A submitted job, as kept in the job history.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct JobRecord {
+    pub job_id: i32,
+    pub map_id: i32,
+    pub algorithm: ModuleInfo,
+    //UNIX timestamp in milliseconds of when the job was submitted.
+    pub submitted: i64,
+    //How the job ended, or None while it is still running.
+    pub outcome: Option<JobOutcome>,
+    //Milliseconds from submitting the job until its result arrived.
+    pub duration: Option<i64>,
+    //The admin who submitted the job, if it was submitted while logged in.
+    pub username: Option<String>,
+}
+
+//Add `record` to the history, dropping the oldest records beyond the history length.
+//The history is a list of job ids, newest first, with every record stored under its own key so it can be updated.
+pub async fn record_submission(
+    conn: &mut darkredis::Connection,
+    record: &JobRecord,
+) -> Result<(), BackendError> {
+    let length = crate::CONFIG.jobs.history_length;
+    if length == 0 {
+        return Ok(());
+    }
+
+    conn.set(
+        get_job_history_record_key(record.job_id),
+        serde_json::to_vec(record)?,
+    )
+    .await?;
+    let key = get_job_history_key();
+    let job_id = record.job_id.to_string();
+    let start = length.to_string();
+    let end = (length - 1).to_string();
+    let commands = CommandList::new("MULTI")
+        .command("LPUSH")
+        .arg(&key)
+        .arg(&job_id)
+        .command("LRANGE")
+        .arg(&key)
+        .arg(&start)
+        .arg(b"-1")
+        .command("LTRIM")
+        .arg(&key)
+        .arg(b"0")
+        .arg(&end)
+        .command("EXEC");
+    let results: Vec<Value> = conn.run_commands(commands).await?.try_collect().await?;
+    //The EXEC result is last, and holds the results of LPUSH, LRANGE and LTRIM.
+    let dropped: Vec<String> = results
+        .into_iter()
+        .last()
+        .map(Value::unwrap_array)
+        .and_then(|r| r.into_iter().nth(1))
+        .map(Value::unwrap_array)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|id| {
+            let id = String::from_utf8_lossy(&id.unwrap_string()).into_owned();
+            get_job_history_record_key(id.parse().unwrap_or(0))
+        })
+        .collect();
+    if !dropped.is_empty() {
+        conn.del_slice(&dropped).await?;
+    }
+    Ok(())
+}
+
+//Record the outcome of the job `job_id` in its history record, if it still has one.
+pub async fn record_result(
+    conn: &mut darkredis::Connection,
+    job_id: i32,
+    outcome: JobOutcome,
+) -> Result<(), BackendError> {
+    let key = get_job_history_record_key(job_id);
+    let mut record: JobRecord = match conn.get(&key).await? {
+        Some(r) => serde_json::from_slice(&r)?,
+        None => return Ok(()),
+    };
+    record.outcome = Some(outcome);
+    record.duration = Some(Utc::now().timestamp_millis() - record.submitted);
+    conn.set(&key, serde_json::to_vec(&record)?).await?;
+    Ok(())
+}
+
+//Get the history record of the job `job_id`, if it's still in the history.
+pub async fn get_record(
+    conn: &mut darkredis::Connection,
+    job_id: i32,
+) -> Result<Option<JobRecord>, BackendError> {
+    match conn.get(get_job_history_record_key(job_id)).await? {
+        Some(r) => Ok(Some(serde_json::from_slice(&r)?)),
+        None => Ok(None),
+    }
+}
+
+//Get up to `limit` of the most recently submitted jobs, newest first.
+pub async fn get_history(
+    conn: &mut darkredis::Connection,
+    limit: usize,
+) -> Result<Vec<JobRecord>, BackendError> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    let ids = conn
+        .lrange(get_job_history_key(), 0, limit as isize - 1)
+        .await?;
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let keys: Vec<String> = ids
+        .iter()
+        .map(|id| get_job_history_record_key(String::from_utf8_lossy(id).parse().unwrap_or(0)))
+        .collect();
+    let command = keys
+        .iter()
+        .fold(Command::new("MGET"), |command, key| command.arg(key));
+    let mut records = Vec::with_capacity(keys.len());
+    for value in conn.run_command(command).await?.unwrap_array() {
+        //Records can disappear if they are dropped while being read.
+        if let Value::String(data) = value {
+            records.push(serde_json::from_slice(&data)?);
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial_test::serial;
+
+    fn record(job_id: i32) -> JobRecord {
+        JobRecord {
+            job_id,
+            map_id: 1,
+            algorithm: ModuleInfo {
+                name: "dummy".into(),
+                version: "0.1.0".into(),
+            },
+            submitted: Utc::now().timestamp_millis() - 1000,
+            outcome: None,
+            duration: None,
+            username: None,
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn job_history() {
+        let pool = crate::create_redis_pool().await;
+        let mut conn = pool.get().await;
+        crate::test::clear_redis(&mut conn).await;
+        let length = crate::CONFIG.jobs.history_length;
+
+        assert!(get_history(&mut conn, 10).await.unwrap().is_empty());
+
+        //Only the most recent jobs are kept, newest first.
+        let count = length as i32 + 2;
+        for job_id in 1..=count {
+            record_submission(&mut conn, &record(job_id)).await.unwrap();
+        }
+        let history = get_history(&mut conn, 100).await.unwrap();
+        let ids: Vec<i32> = history.iter().map(|r| r.job_id).collect();
+        let expected: Vec<i32> = (3..=count).rev().collect();
+        assert_eq!(ids, expected);
+        //The records of the dropped jobs are gone too.
+        assert!(!conn.exists(get_job_history_record_key(1)).await.unwrap());
+        assert_eq!(get_history(&mut conn, 2).await.unwrap().len(), 2);
+
+        //Results complete the record of their job.
+        record_result(&mut conn, count, JobOutcome::Success)
+            .await
+            .unwrap();
+        let newest = &get_history(&mut conn, 1).await.unwrap()[0];
+        assert_eq!(newest.outcome, Some(JobOutcome::Success));
+        assert!(newest.duration.unwrap() >= 1000);
+        //Results for jobs which are no longer in the history are ignored.
+        record_result(&mut conn, 1, JobOutcome::Failure)
+            .await
+            .unwrap();
+        assert!(!conn.exists(get_job_history_record_key(1)).await.unwrap());
+    }
+}
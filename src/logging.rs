@@ -0,0 +1,95 @@
+//src/logging.rs: Structured log output for log aggregators.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use chrono::prelude::*;
+use std::io::Write;
+
+//The environment variable which selects the log format, either "text" (the default) or "json".
+pub const LOG_FORMAT_VAR: &str = "LAPS_LOG_FORMAT";
+
+//Check if the logs should be written as JSON.
+pub fn use_json_format() -> bool {
+    std::env::var(LOG_FORMAT_VAR)
+        .map(|f| f.trim().eq_ignore_ascii_case("json"))
+        .unwrap_or(false)
+}
+
+//Split the request id off `message` if it has one. Messages about a request are prefixed with "[<id>] ".
+fn split_request_id(message: &str) -> (Option<&str>, &str) {
+    if message.starts_with('[') {
+        if let Some(end) = message.find("] ") {
+            let id = &message[1..end];
+            if !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()) {
+                return (Some(id), &message[end + 2..]);
+            }
+        }
+    }
+    (None, message)
+}
+
+//Build the JSON object for a single log record.
+fn record_to_json(
+    level: log::Level,
+    target: &str,
+    message: &str,
+    timestamp: DateTime<Utc>,
+) -> serde_json::Value {
+    let (request_id, message) = split_request_id(message);
+    let mut out = serde_json::json!({
+        "timestamp": timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": level.to_string(),
+        "target": target,
+        "message": message,
+    });
+    if let Some(id) = request_id {
+        out["request_id"] = id.into();
+    }
+    out
+}
+
+//Format function for env_logger which writes every record as a single line of JSON.
+pub fn format_json(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    let message = record.args().to_string();
+    let json = record_to_json(record.level(), record.target(), &message, Utc::now());
+    writeln!(buf, "{}", json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_records() {
+        let timestamp = Utc.ymd(2020, 5, 1).and_hms_milli(12, 30, 0, 250);
+        let json = record_to_json(log::Level::Info, "laps::web", "Hello \"world\"", timestamp);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": "2020-05-01T12:30:00.250Z",
+                "level": "INFO",
+                "target": "laps::web",
+                "message": "Hello \"world\"",
+            })
+        );
+
+        //Request ids are moved into their own field.
+        let json = record_to_json(
+            log::Level::Debug,
+            "laps::web::request_id",
+            "[0a1b2c3d] GET /map/all",
+            timestamp,
+        );
+        assert_eq!(json["request_id"], "0a1b2c3d");
+        assert_eq!(json["message"], "GET /map/all");
+
+        //Other bracketed prefixes are left alone.
+        let json = record_to_json(log::Level::Warn, "laps", "[not an id] message", timestamp);
+        assert!(json.get("request_id").is_none());
+        assert_eq!(json["message"], "[not an id] message");
+    }
+}
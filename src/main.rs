@@ -16,8 +16,15 @@ extern crate rocket;
 use bollard::Docker;
 use config::Config;
 use darkredis::ConnectionPool;
-use rocket::config::{Environment, LoggingLevel};
+use rocket::{
+    config::{Environment, LoggingLevel},
+    http::SameSite,
+};
+use std::collections::HashMap;
 
+mod docker;
+mod history;
+mod logging;
 mod module_handling;
 mod types;
 mod util;
@@ -33,6 +40,99 @@ struct Configuration {
     pub jobs: JobConfig,
     pub login: LoginConfig,
     pub module: ModuleConfig,
+    pub maps: MapConfig,
+    pub web: WebConfig,
+}
+
+impl Configuration {
+    //Check the settings which parse fine but make no sense, either on their own or together with other settings.
+    //The error names the offending setting.
+    fn validate(&self) -> Result<(), String> {
+        let jobs = &self.jobs;
+        //Redis treats a blocking timeout of 0 as waiting forever, and refuses to expire keys after 0 seconds.
+        for (name, value) in &[
+            ("token_timeout", jobs.token_timeout),
+            ("poll_timeout", jobs.poll_timeout),
+            ("result_timeout", jobs.result_timeout),
+            ("max_polling_clients", jobs.max_polling_clients),
+        ] {
+            if *value == 0 {
+                return Err(format!("jobs.{} must be at least 1", name));
+            }
+        }
+
+        let login = &self.login;
+        if login.minimum_password_length > login.maximum_password_length {
+            return Err(format!(
+                "login.minimum_password_length ({}) is greater than login.maximum_password_length ({})",
+                login.minimum_password_length, login.maximum_password_length
+            ));
+        }
+        if login.maximum_password_length == 0 {
+            return Err("login.maximum_password_length must be at least 1".into());
+        }
+
+        let module = &self.module;
+        if module.max_workers_per_module == 0 {
+            return Err("module.max_workers_per_module must be at least 1".into());
+        }
+        if module.build_attempts == 0 {
+            return Err("module.build_attempts must be at least 1".into());
+        }
+        //Logs are trimmed from a negative index, and trimming to -0 would keep every line forever.
+        if module.max_log_lines == 0 {
+            return Err("module.max_log_lines must be at least 1".into());
+        }
+        if module.stop_timeout > module.max_stop_timeout {
+            return Err(format!(
+                "module.stop_timeout ({}) is greater than module.max_stop_timeout ({})",
+                module.stop_timeout, module.max_stop_timeout
+            ));
+        }
+        if module.stop_idle_modules && module.idle_check_interval == 0 {
+            return Err("module.idle_check_interval must be at least 1 second".into());
+        }
+        for pattern in &module.ignore {
+            if let Err(e) = globset::Glob::new(pattern) {
+                return Err(format!(
+                    "module.ignore contains the invalid pattern \"{}\": {}",
+                    pattern, e
+                ));
+            }
+        }
+
+        self.maps
+            .format()
+            .map_err(|e| format!("maps.format: {}", e))?;
+
+        let web = &self.web;
+        web.cookie
+            .same_site()
+            .map_err(|e| format!("web.cookie.same_site: {}", e))?;
+        web.compression
+            .encodings()
+            .map_err(|e| format!("web.compression.encodings: {}", e))?;
+        //Waiting for a result may take up to the poll timeout to get a connection and then as long again for the result,
+        //which mustn't be cut short by the request timeout.
+        for route in &["result", "submit_and_wait"] {
+            let timeout = web.timeouts.for_route(route);
+            let wait = 2 * jobs.poll_timeout as u64;
+            if timeout != 0 && timeout <= wait {
+                return Err(format!(
+                    "web.timeouts.routes.{} ({}) must be longer than twice jobs.poll_timeout ({})",
+                    route, timeout, wait
+                ));
+            }
+        }
+        if let Some(tls) = &web.tls {
+            for path in &[&tls.certs, &tls.key] {
+                if !std::path::Path::new(path).is_file() {
+                    return Err(format!("web.tls: file \"{}\" does not exist", path));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -47,9 +147,22 @@ struct JobConfig {
     token_timeout: u32,  // the timeout for a token mapping key
     poll_timeout: u32,   // the amount of time a user can poll a running job
     result_timeout: u32, // how long the results of a pathfinding job is kept
+    job_timeout: u32,    // how long a module has to complete a job before it's failed, 0 to disable
 
     //Maximum number of clients who can poll for jobs at once. Creates this many Redis connections.
     max_polling_clients: u32,
+
+    //Fail results whose consecutive points aren't adjacent. Disable for modules which return sparse waypoints.
+    check_path_contiguity: bool,
+
+    //Fail results with more points than this rather than storing them.
+    max_result_points: usize,
+
+    //Number of submitted jobs to keep in the job history, 0 to disable it.
+    history_length: usize,
+
+    //Jobs each client can submit per minute, 0 to disable the limit.
+    max_submissions_per_minute: u32,
 }
 
 #[derive(serde::Deserialize)]
@@ -60,12 +173,185 @@ struct LoginConfig {
     minimum_password_length: u8,
     //Maximum password length
     maximum_password_length: u8,
+    //Require passwords to contain both uppercase and lowercase letters
+    #[serde(default)]
+    require_mixed_case: bool,
+    //Require passwords to contain at least one digit
+    #[serde(default)]
+    require_digit: bool,
+    //Require passwords to contain at least one symbol
+    #[serde(default)]
+    require_symbol: bool,
 }
 
 #[derive(serde::Deserialize)]
 struct ModuleConfig {
-    //Images to ignore in the admin panel list.
+    //Images to ignore in the admin panel list, as glob patterns matched against the module name.
     ignore: Vec<String>,
+    //Seconds a resumed module has to send its first heartbeat before it is considered dead.
+    heartbeat_timeout: u32,
+    //How many times to try building a module image before giving up on transient errors.
+    build_attempts: u32,
+    //Seconds to wait before retrying a failed build, doubled for every attempt.
+    build_retry_delay: u64,
+    //Seconds a started module has to register all of its workers when the restart request waits for it.
+    startup_timeout: u64,
+    //The most workers a single module can be uploaded with.
+    max_workers_per_module: u8,
+    //How many of the most recent log lines to keep for each module.
+    max_log_lines: u32,
+    //Seconds workers get to shut down when stopped or restarted before they are killed, unless the module overrides it.
+    stop_timeout: u32,
+    //The longest stop timeout a module can be uploaded with.
+    max_stop_timeout: u32,
+    //Stop running modules which haven't been sent a job within their idle timeout. Only modules uploaded with an
+    //idle timeout are ever stopped.
+    stop_idle_modules: bool,
+    //Seconds between each check for idle modules.
+    idle_check_interval: u64,
+    //Start modules which were stopped for being idle when a valid job is submitted to them, rather than rejecting the
+    //job.
+    auto_start_modules: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct MapConfig {
+    //Seconds an uploaded map may take to convert before the upload is rejected.
+    conversion_timeout: u64,
+    //The most maps which can be stored, unlimited if not set.
+    max_maps: Option<usize>,
+    //The most bytes all maps can use together, unlimited if not set.
+    max_map_bytes: Option<u64>,
+    //Check every map against its checksum before sending it, failing the request if the map is corrupted.
+    verify_checksums: bool,
+    //The format uploaded maps are stored in, "png" or "webp".
+    format: String,
+}
+
+impl MapConfig {
+    //Parse the format uploaded maps are stored in.
+    fn format(&self) -> Result<laps_convert::OutputFormat, String> {
+        laps_convert::OutputFormat::from_map_name(&self.format).ok_or_else(|| {
+            format!(
+                "Invalid map format \"{}\", expected \"png\" or \"webp\"",
+                self.format
+            )
+        })
+    }
+
+    //The limits checked when importing a map.
+    fn quota(&self) -> laps_convert::MapQuota {
+        laps_convert::MapQuota {
+            max_maps: self.max_maps,
+            max_map_bytes: self.max_map_bytes,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct WebConfig {
+    cookie: CookieConfig,
+    //Serve HTTPS directly rather than behind a reverse proxy.
+    tls: Option<TlsConfig>,
+    compression: CompressionConfig,
+    timeouts: TimeoutConfig,
+    assets: AssetConfig,
+    //Bearer token which lets scrapers read /metrics without logging in as an administrator.
+    metrics_token: Option<String>,
+    //The addresses of reverse proxies which are trusted to give the address of the client in X-Real-IP.
+    trusted_proxies: Vec<std::net::IpAddr>,
+}
+
+impl WebConfig {
+    //Whether the session cookie should only be sent over HTTPS.
+    //Defaults to true when serving HTTPS directly or in production, and false otherwise.
+    fn secure_cookies(&self) -> bool {
+        self.cookie.secure.unwrap_or_else(|| {
+            self.tls.is_some() || Environment::active().map(|e| e.is_prod()).unwrap_or(false)
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CompressionConfig {
+    //Responses smaller than this many bytes are sent uncompressed.
+    min_size: usize,
+    //The encodings to compress responses with, in order of preference. Empty to disable compression.
+    encodings: Vec<String>,
+}
+
+impl CompressionConfig {
+    //Parse the enabled encodings.
+    fn encodings(&self) -> Result<Vec<web::compression::Encoding>, String> {
+        self.encodings
+            .iter()
+            .map(|name| {
+                web::compression::Encoding::from_name(name).ok_or_else(|| {
+                    format!(
+                        "Invalid encoding \"{}\", expected \"br\", \"gzip\" or \"deflate\"",
+                        name
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct AssetConfig {
+    //Serve the frontend from this directory rather than the copy embedded in the binary.
+    directory: Option<String>,
+    //Seconds browsers may use the frontend files before checking for new versions.
+    max_age: u32,
+}
+
+#[derive(serde::Deserialize)]
+struct TimeoutConfig {
+    //Seconds a request may take before it is aborted with 504 Gateway Timeout. 0 disables the timeout.
+    default: u64,
+    //Timeouts for routes which are expected to take longer than the default, by the name of their handler.
+    #[serde(default)]
+    routes: HashMap<String, u64>,
+}
+
+impl TimeoutConfig {
+    //The timeout of the route handled by `name`.
+    fn for_route(&self, name: &str) -> u64 {
+        self.routes.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TlsConfig {
+    //Path to the PEM encoded certificate chain.
+    certs: String,
+    //Path to the PEM encoded private key.
+    key: String,
+}
+
+#[derive(serde::Deserialize)]
+struct CookieConfig {
+    //Only send the session cookie over HTTPS, see `WebConfig::secure_cookies` for the default.
+    secure: Option<bool>,
+    //The SameSite attribute of the session cookie, one of "strict", "lax" or "none".
+    same_site: String,
+    //The domain to set the session cookie for. Defaults to the host of the request.
+    domain: Option<String>,
+}
+
+impl CookieConfig {
+    //Parse the SameSite attribute.
+    fn same_site(&self) -> Result<SameSite, String> {
+        match self.same_site.to_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            s => Err(format!(
+                "Invalid same_site value \"{}\", expected one of \"strict\", \"lax\" or \"none\"",
+                s
+            )),
+        }
+    }
 }
 
 lazy_static! {
@@ -91,8 +377,12 @@ lazy_static! {
             s.merge(config::File::with_name("config/test.toml").required(false)).unwrap();
         }
 
-        match s.try_into() {
+        match s.try_into::<Configuration>() {
             Ok(conf) => {
+                if let Err(e) = conf.validate() {
+                    error!("Invalid configuration: {}", e);
+                    std::process::exit(2);
+                }
                 info!("Successfully loaded configuration!");
                 conf
             }
@@ -175,9 +465,14 @@ fn setup_logging() {
     }
     std::env::set_var("RUST_LOG", &log_value);
 
-    env_logger::Builder::from_default_env()
-        .format_timestamp_secs()
-        .init();
+    //Human readable logs by default, or one JSON object per line for log aggregators.
+    let mut builder = env_logger::Builder::from_default_env();
+    if logging::use_json_format() {
+        builder.format(logging::format_json);
+    } else {
+        builder.format_timestamp_secs();
+    }
+    builder.init();
 
     info!("Successfully initialized logging!");
 }
@@ -189,3 +484,61 @@ async fn main() {
     info!("Starting up...");
     web::run().await
 }
+
+#[cfg(test)]
+mod config_test {
+    use super::*;
+
+    //Load the default configuration with `key` set to `value`.
+    fn config_with<T: Into<config::Value>>(key: &str, value: T) -> Configuration {
+        let mut s = Config::new();
+        s.merge(config::File::with_name("config/default.toml"))
+            .unwrap();
+        s.set(key, value).unwrap();
+        s.try_into().unwrap()
+    }
+
+    #[test]
+    fn config_validation() {
+        assert_eq!(config_with("jobs.poll_timeout", 1i64).validate(), Ok(()));
+
+        //Every error names the setting which is wrong.
+        let invalid: Vec<(&str, config::Value)> = vec![
+            ("jobs.token_timeout", 0i64.into()),
+            ("jobs.poll_timeout", 0i64.into()),
+            ("jobs.result_timeout", 0i64.into()),
+            ("jobs.max_polling_clients", 0i64.into()),
+            ("login.minimum_password_length", 200i64.into()),
+            ("login.maximum_password_length", 4i64.into()),
+            ("module.max_workers_per_module", 0i64.into()),
+            ("module.build_attempts", 0i64.into()),
+            ("module.max_log_lines", 0i64.into()),
+            ("module.stop_timeout", 1000i64.into()),
+            ("module.ignore", vec!["laps-[".to_string()].into()),
+            ("web.cookie.same_site", "sometimes".into()),
+            ("web.compression.encodings", vec!["zip".to_string()].into()),
+            ("maps.format", "tiff".into()),
+            ("web.timeouts.routes.result", 60i64.into()),
+            ("web.timeouts.routes.submit_and_wait", 60i64.into()),
+        ];
+        for (key, value) in invalid {
+            let error = config_with(key, value).validate().unwrap_err();
+            assert!(error.contains(key), "{}: {}", key, error);
+        }
+
+        //The idle check interval only matters when idle modules are stopped.
+        let mut config = config_with("module.idle_check_interval", 0i64);
+        assert_eq!(config.validate(), Ok(()));
+        config.module.stop_idle_modules = true;
+        let error = config.validate().unwrap_err();
+        assert!(error.contains("module.idle_check_interval"), "{}", error);
+
+        let mut config = config_with("jobs.poll_timeout", 1i64);
+        config.web.tls = Some(TlsConfig {
+            certs: "does/not/exist.pem".into(),
+            key: "config/default.toml".into(),
+        });
+        let error = config.validate().unwrap_err();
+        assert!(error.contains("web.tls"), "{}", error);
+    }
+}
@@ -4,17 +4,27 @@
 //Distributed under the zlib licence, see LICENCE.
 
 use crate::{
-    types::{BackendError, JobOutcome, JobResult},
+    history::record_result,
+    types::{BackendError, JobOutcome, JobResult, MapExtent, Vector},
     util::{
-        create_redis_backend_key, create_redis_key, get_job_key, get_module_log_key,
+        create_redis_backend_key, create_redis_key, delete_matching_keys,
+        get_heartbeat_modules_key, get_job_deadlines_key, get_job_downsample_key,
+        get_job_extents_key, get_job_key, get_job_progress_key, get_module_cache_pattern,
+        get_module_events_channel, get_module_heartbeat_key, get_module_log_key,
+        get_module_map_types_key, get_module_paused_key, get_module_registrations_key,
         get_module_work_key, get_module_workers_key, get_registered_module_workers_key,
     },
     web::job::JobInfo,
 };
 use chrono::prelude::*;
-use futures::StreamExt;
+use darkredis::{Command, CommandList, Value};
+use futures::TryStreamExt;
+use laps_convert::MapType;
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use std::{cmp::Ordering, time::Duration};
+
+//Information that a module registers and de-registers itself with, shared with clients.
+pub use laps_types::ModuleInfo;
 
 //Handle any modules unregistrering themselves in a loop, forever.
 async fn unregister_loop(pool: darkredis::ConnectionPool) {
@@ -35,7 +45,6 @@ async fn unregister_loop(pool: darkredis::ConnectionPool) {
 
         match shutdown {
             Ok(info) => {
-                use std::cmp::Ordering;
                 //Only remove a module from the active module set if *all* the workers are shut down.
                 let remaining_workers = conn
                     .decr(get_registered_module_workers_key(&info))
@@ -53,65 +62,9 @@ async fn unregister_loop(pool: darkredis::ConnectionPool) {
                     }
                     Ordering::Equal => {
                         info!("Module {} shut down", info);
-
-                        //Now that the module is shut down, cancel any job it may have queued up.
-                        let work_key = get_module_work_key(&info);
-                        let results_key = create_redis_backend_key("path-results");
-                        let results: Vec<Vec<u8>> = conn
-                            .lrange(&work_key, 0, -1)
-                            .await
-                            .expect("getting module work queue")
-                            .into_iter()
-                            .map(|s| {
-                                let job = serde_json::from_slice::<JobInfo>(&s).unwrap();
-                                serde_json::to_vec(&JobResult {
-                                    job_id: job.job_id,
-                                    outcome: JobOutcome::Cancelled,
-                                    points: Vec::new(),
-                                })
-                                .unwrap()
-                            })
-                            .collect();
-                        if !results.is_empty() {
-                            conn.rpush_slice(&results_key, &results).await.unwrap();
-                        }
-
-                        //Delete the job queue such that if the module is started again, it does not try to do these
-                        //stale jobs.
-                        conn.del(&work_key).await.unwrap();
-
-                        info!("Canceled {} jobs from {}'s job queue", results.len(), info);
-
-                        //Also delete the entire job cache for the module, so that every new job submitted to the module will
-                        //get rejected instead of giving a potentially confusing cancellation message every time.
-                        let pattern = create_redis_backend_key(&format!("cache.{}.*", info)); //cache key always starts with the module info first.
-                        let caches = conn
-                            .scan()
-                            .pattern(&pattern)
-                            .run()
-                            .collect::<Vec<Vec<u8>>>()
+                        remove_module(&mut conn, &info, &data).await;
+                        publish_module_event(&mut conn, &info, ModuleEventState::Unregistered)
                             .await;
-                        if !caches.is_empty() {
-                            conn.del_slice(&caches)
-                                .await
-                                .expect("deleting cache entries");
-                        }
-                        info!(
-                            "Deleted {} cache entries which came from {}",
-                            caches.len(),
-                            info
-                        );
-
-                        //Remove from the registered_modules set.
-                        //Rely on modules sending the exact same shutdown data as they sent registration data.
-                        if !conn
-                            .srem(create_redis_backend_key("registered_modules"), &data)
-                            .await
-                            .expect("Removing from registered-modules set")
-                        {
-                            error!("Module {} {} wasn't registered!", info.name, info.version);
-                            trace!("Raw module info: {}", String::from_utf8_lossy(&data));
-                        }
                     }
                 }
             }
@@ -120,18 +73,163 @@ async fn unregister_loop(pool: darkredis::ConnectionPool) {
     }
 }
 
-//Information that a module registers and de-registers itself with.
+//Cancel every queued job of `info`, delete its job cache and remove it from the set of registered modules.
+//`data` has to be the exact registration data of the module as it is stored in the registered module set.
+async fn remove_module(conn: &mut darkredis::Connection, info: &ModuleInfo, data: &[u8]) {
+    //Now that the module is shut down, cancel any job it may have queued up such that if the module is started
+    //again, it does not try to do these stale jobs.
+    let cancelled = cancel_queued_jobs(conn, info)
+        .await
+        .expect("cancelling queued jobs");
+    info!("Canceled {} jobs from {}'s job queue", cancelled, info);
+
+    //Also delete the entire job cache for the module, so that every new job submitted to the module will
+    //get rejected instead of giving a potentially confusing cancellation message every time.
+    let deleted = delete_matching_keys(conn, &get_module_cache_pattern(info))
+        .await
+        .expect("deleting cache entries");
+    info!("Deleted {} cache entries which came from {}", deleted, info);
+
+    //Remove from the registered_modules set.
+    //Rely on modules sending the exact same shutdown data as they sent registration data.
+    if !conn
+        .srem(create_redis_backend_key("registered_modules"), data)
+        .await
+        .expect("Removing from registered-modules set")
+    {
+        error!("Module {} {} wasn't registered!", info.name, info.version);
+        trace!("Raw module info: {}", String::from_utf8_lossy(data));
+    }
+    //If it's started again, it has to send a heartbeat again before it can be reaped.
+    conn.srem(get_heartbeat_modules_key(), info.to_string())
+        .await
+        .expect("removing from heartbeat-modules set");
+}
+
+//Empty the work queue of `info`, failing every job in it as cancelled. Returns the number of cancelled jobs.
+pub async fn cancel_queued_jobs(
+    conn: &mut darkredis::Connection,
+    info: &ModuleInfo,
+) -> Result<usize, BackendError> {
+    //Read and delete the queue at once, so that a job can't be both picked up by a worker and cancelled.
+    let work_key = get_module_work_key(info);
+    let commands = CommandList::new("MULTI")
+        .command("LRANGE")
+        .arg(&work_key)
+        .arg(b"0")
+        .arg(b"-1")
+        .command("DEL")
+        .arg(&work_key)
+        .command("EXEC");
+    let results: Vec<Value> = conn.run_commands(commands).await?.try_collect().await?;
+    let queued = results
+        .into_iter()
+        .last()
+        .map(Value::unwrap_array)
+        .and_then(|r| r.into_iter().next())
+        .map(Value::unwrap_array)
+        .unwrap_or_default();
+
+    //The cancellations go through the result listener like any other result, so polling clients get them.
+    let mut cancellations = Vec::with_capacity(queued.len());
+    for job in queued {
+        //The queue is gone either way, so a corrupt entry mustn't keep the rest of the jobs from being cancelled.
+        let job: JobInfo = match serde_json::from_slice(&job.unwrap_string()) {
+            Ok(j) => j,
+            Err(e) => {
+                warn!("Skipping invalid job in the queue of {}: {}", info, e);
+                continue;
+            }
+        };
+        cancellations.push(serde_json::to_vec(&JobResult {
+            job_id: job.job_id,
+            outcome: JobOutcome::Cancelled,
+            points: Vec::new(),
+        })?);
+    }
+    if !cancellations.is_empty() {
+        conn.rpush_slice(create_redis_backend_key("path-results"), &cancellations)
+            .await?;
+    }
+    Ok(cancellations.len())
+}
+
+//A state a module can move to, as announced on the module event channel.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum ModuleEventState {
+    //The first worker of the module registered, so it accepts jobs.
+    Registered,
+    //Every worker of the module shut down.
+    Unregistered,
+    //The module stopped sending heartbeats without shutting down.
+    Failed,
+}
+
+//Published on the module event channel whenever a module changes state.
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
-pub struct ModuleInfo {
-    pub name: String,
-    pub version: String,
+pub struct ModuleEvent {
+    pub module: ModuleInfo,
+    pub state: ModuleEventState,
+    //UNIX timestamp of the state change.
+    pub timestamp: i64,
 }
 
-impl fmt::Display for ModuleInfo {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}", self.name, self.version)
+//Announce that `module` moved to `state`. Nothing depends on the events being delivered, so errors are only logged.
+async fn publish_module_event(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+    state: ModuleEventState,
+) {
+    let event = ModuleEvent {
+        module: module.clone(),
+        state,
+        timestamp: Utc::now().timestamp(),
+    };
+    let data = serde_json::to_vec(&event).unwrap();
+    if let Err(e) = conn.publish(get_module_events_channel(), data).await {
+        error!("Failed to publish event for module {}: {}", module, e);
+    }
+}
+
+//Compare two module version strings using semantic versioning. If either isn't a valid semantic version,
+//dot-separated components are compared numerically when both are numbers, such that 0.10 comes after 0.9,
+//and lexically otherwise.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    if let (Ok(a), Ok(b)) = (semver::Version::parse(a), semver::Version::parse(b)) {
+        return a.cmp(&b);
+    }
+
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+    loop {
+        match (a_parts.next(), b_parts.next()) {
+            (Some(x), Some(y)) => {
+                let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+                    (Ok(x), Ok(y)) => x.cmp(&y),
+                    _ => x.cmp(y),
+                };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (None, None) => return Ordering::Equal,
+        }
     }
 }
+//Find the highest version of the module called `name` in `modules`.
+pub fn find_latest_version<'a, I>(modules: I, name: &str) -> Option<&'a ModuleInfo>
+where
+    I: IntoIterator<Item = &'a ModuleInfo>,
+{
+    modules
+        .into_iter()
+        .filter(|m| m.name == name)
+        .max_by(|a, b| compare_versions(&a.version, &b.version))
+}
+
 //The listener which listens for pathfinding results
 async fn result_listener(pool: darkredis::ConnectionPool) {
     let mut conn = pool.spawn("result-listener").await.unwrap();
@@ -145,7 +243,7 @@ async fn result_listener(pool: darkredis::ConnectionPool) {
             .expect("popping path results")
             .unwrap();
 
-        let deserialized: JobResult = match serde_json::from_slice(&value) {
+        let mut deserialized: JobResult = match serde_json::from_slice(&value) {
             Ok(s) => s,
             Err(e) => {
                 error!(
@@ -158,15 +256,214 @@ async fn result_listener(pool: darkredis::ConnectionPool) {
         };
         let key = get_job_key(deserialized.job_id);
 
+        //Don't pass on paths which are broken or too large to handle, fail the job instead.
+        let mut value = value;
+        let max_points = crate::CONFIG.jobs.max_result_points;
+        if deserialized.points.len() > max_points {
+            error!(
+                "Failing job {}, the module returned {} points which is more than the limit of {}",
+                deserialized.job_id,
+                deserialized.points.len(),
+                max_points
+            );
+            deserialized.outcome = JobOutcome::Failure;
+            deserialized.points = Vec::new();
+            value = serde_json::to_vec(&deserialized).unwrap();
+        } else if deserialized.outcome == JobOutcome::Success {
+            match check_result_path(&mut conn, &deserialized).await {
+                Ok(Some(reason)) => {
+                    error!(
+                        "Failing job {}, the module returned an invalid path: {}",
+                        deserialized.job_id, reason
+                    );
+                    deserialized.outcome = JobOutcome::Failure;
+                    deserialized.points.clear();
+                    value = serde_json::to_vec(&deserialized).unwrap();
+                }
+                Ok(None) => (),
+                Err(e) => error!(
+                    "Failed to check the path of job {}: {}",
+                    deserialized.job_id, e
+                ),
+            }
+        }
+
+        //The job is done, so it can no longer time out.
+        clear_job_deadline(&mut conn, deserialized.job_id)
+            .await
+            .expect("clearing job deadline");
+
         //Expire after a given period if the result has not been retrieved by the user
         //TODO: Maybe set the mapping key timeout to match the result timeout
         conn.lpush(&key, &value).await.unwrap();
         conn.expire_seconds(&key, crate::CONFIG.jobs.result_timeout)
             .await
             .unwrap();
+        //Keep the last progress report and the downsample factor around for as long as the result.
+        for key in &[
+            get_job_progress_key(deserialized.job_id),
+            get_job_downsample_key(deserialized.job_id),
+        ] {
+            conn.expire_seconds(key, crate::CONFIG.jobs.result_timeout)
+                .await
+                .unwrap();
+        }
+
+        if let Err(e) = record_result(&mut conn, deserialized.job_id, deserialized.outcome).await {
+            error!(
+                "Failed to record the result of job {} in the job history: {}",
+                deserialized.job_id, e
+            );
+        }
     }
 }
 
+//Check the path of `result` against the maps of its job. Returns the reason the path is invalid, if it is.
+//Jobs whose map extents aren't known, for instance because they have expired, are not checked.
+async fn check_result_path(
+    conn: &mut darkredis::Connection,
+    result: &JobResult,
+) -> Result<Option<String>, BackendError> {
+    let extents: Vec<MapExtent> = match conn.get(get_job_extents_key(result.job_id)).await? {
+        Some(e) => serde_json::from_slice(&e)
+            .map_err(|e| BackendError::Other(format!("Invalid job extents: {}", e)))?,
+        None => return Ok(None),
+    };
+    //Preview jobs are pathfound on a downsampled map, so scale the points before comparing them to the maps.
+    let factor = match conn.get(get_job_downsample_key(result.job_id)).await? {
+        Some(f) => String::from_utf8_lossy(&f)
+            .parse::<u32>()
+            .map_err(|e| BackendError::Other(format!("Invalid downsample factor: {}", e)))?,
+        None => 1,
+    };
+    Ok(find_path_violation(
+        &result.points,
+        &extents,
+        factor,
+        crate::CONFIG.jobs.check_path_contiguity,
+    ))
+}
+
+//Find the first problem with `points`, which are downsampled by `factor`, if any.
+fn find_path_violation(
+    points: &[Vector],
+    extents: &[MapExtent],
+    factor: u32,
+    check_contiguity: bool,
+) -> Option<String> {
+    for point in points {
+        let scaled = Vector {
+            x: point.x.saturating_mul(factor),
+            y: point.y.saturating_mul(factor),
+        };
+        if !extents.iter().any(|e| e.contains(&scaled)) {
+            return Some(format!("({},{}) is outside the map", point.x, point.y));
+        }
+    }
+
+    //Consecutive points have to be neighbours on the grid, diagonals included.
+    if check_contiguity {
+        for pair in points.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            if (a.x as i64 - b.x as i64).abs() > 1 || (a.y as i64 - b.y as i64).abs() > 1 {
+                return Some(format!(
+                    "({},{}) and ({},{}) are not adjacent",
+                    a.x, a.y, b.x, b.y
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+//Record that the job `job_id` has to be completed within `timeout` seconds from now.
+pub async fn set_job_deadline(
+    conn: &mut darkredis::Connection,
+    job_id: i32,
+    timeout: u32,
+) -> Result<(), BackendError> {
+    let deadline = (Utc::now().timestamp() + timeout as i64).to_string();
+    let command = Command::new("ZADD")
+        .arg(&get_job_deadlines_key())
+        .arg(&deadline)
+        .arg(&job_id.to_string());
+    conn.run_command(command).await?;
+    Ok(())
+}
+
+//Remove the deadline of `job_id`. Returns true if the job had a deadline.
+pub async fn clear_job_deadline(
+    conn: &mut darkredis::Connection,
+    job_id: i32,
+) -> Result<bool, BackendError> {
+    let command = Command::new("ZREM")
+        .arg(&get_job_deadlines_key())
+        .arg(&job_id.to_string());
+    Ok(conn.run_command(command).await?.unwrap_integer() == 1)
+}
+
+//Fail every job which hasn't gotten a result before its deadline, such that users polling for it get a definitive answer.
+async fn deadline_sweeper(pool: darkredis::ConnectionPool) {
+    let mut conn = pool.spawn("deadline-sweeper").await.unwrap();
+    let deadlines_key = get_job_deadlines_key();
+    let results_key = create_redis_backend_key("path-results");
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        let now = Utc::now().timestamp().to_string();
+        let command = Command::new("ZRANGEBYSCORE")
+            .arg(&deadlines_key)
+            .arg(b"-inf")
+            .arg(&now);
+        let expired = conn
+            .run_command(command)
+            .await
+            .expect("getting expired jobs")
+            .unwrap_array();
+
+        for job in expired {
+            let job_id = match String::from_utf8_lossy(&job.unwrap_string()).parse::<i32>() {
+                Ok(id) => id,
+                Err(e) => {
+                    error!("Invalid job id in job deadlines: {}", e);
+                    continue;
+                }
+            };
+            //The result may have arrived since we got the expired jobs, so only fail it if we were the ones to remove the deadline.
+            if clear_job_deadline(&mut conn, job_id)
+                .await
+                .expect("clearing job deadline")
+            {
+                warn!("Job {} did not complete before its deadline", job_id);
+                let result = serde_json::to_vec(&JobResult {
+                    job_id,
+                    outcome: JobOutcome::Failure,
+                    points: Vec::new(),
+                })
+                .unwrap();
+                conn.rpush(&results_key, result)
+                    .await
+                    .expect("failing timed out job");
+            }
+        }
+    }
+}
+
+//Get the time a line of a module log was logged, as stored by `process_log_entry`.
+pub fn log_line_time(line: &[u8]) -> Option<DateTime<Utc>> {
+    let line = std::str::from_utf8(line).ok()?;
+    if !line.starts_with('[') {
+        return None;
+    }
+    let stamp = line[1..].split(' ').next()?;
+    DateTime::parse_from_rfc3339(stamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
 //A log message received from a module worker.
 #[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 struct ModuleLog {
@@ -182,52 +479,134 @@ struct ModuleLog {
     pub worker: u8,
 }
 
-//Listen and report module logs.
+//The most log entries stored in a single round-trip.
+const LOG_BATCH_SIZE: isize = 100;
+
+//Pop up to `count` entries off the list at `key` without blocking. Uses LRANGE and LTRIM in a transaction rather
+//than LPOP with a count, which needs Redis 6.2.
+async fn pop_many(
+    conn: &mut darkredis::Connection,
+    key: &str,
+    count: isize,
+) -> Result<Vec<Vec<u8>>, darkredis::Error> {
+    let end = (count - 1).to_string();
+    let start = count.to_string();
+    let commands = CommandList::new("MULTI")
+        .command("LRANGE")
+        .arg(&key)
+        .arg(b"0")
+        .arg(&end)
+        .command("LTRIM")
+        .arg(&key)
+        .arg(&start)
+        .arg(b"-1")
+        .command("EXEC");
+    let results: Vec<Value> = conn.run_commands(commands).await?.try_collect().await?;
+    //The EXEC result is last, and holds the results of LRANGE and LTRIM.
+    let popped = results
+        .into_iter()
+        .last()
+        .map(Value::unwrap_array)
+        .and_then(|r| r.into_iter().next())
+        .map(Value::unwrap_array)
+        .unwrap_or_default();
+    Ok(popped.into_iter().map(Value::unwrap_string).collect())
+}
+
+//Format `entry` the way it is stored in the module log, and print it to the server log.
+fn process_log_entry(entry: &ModuleLog) -> String {
+    let log_message = format!(
+        "Module {}[{}]: {}",
+        entry.module, entry.worker, entry.message
+    );
+
+    //Print out the message into the server logs
+    match entry.level.as_str() {
+        "info" => info!("{}", log_message),
+        "error" => error!("{}", log_message),
+        "warn" => warn!("{}", log_message),
+        "debug" => debug!("{}", log_message),
+        _ => {
+            warn!("Unknown module log level {}", entry.level);
+            info!("{}", log_message)
+        }
+    }
+
+    //Store the log entry as a simple string.
+    let time = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(entry.instant, 0), Utc);
+    format!(
+        "[{} {} worker:{}] {}",
+        time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        entry.level,
+        entry.worker,
+        entry.message
+    )
+}
+
+//Listen and report module logs. Entries are stored in batches so that chatty modules don't need a round-trip per
+//line, and only the last `max_log_lines` lines of each module are kept.
 pub async fn log_listener(pool: darkredis::ConnectionPool) {
     let mut conn = pool.spawn("log-listener").await.unwrap();
 
     let listen_key = create_redis_key("moduleLogs"); // the key to listen for module logs
 
+    //Keep the last `max_log_lines` lines by trimming from a negative index.
+    let trim_start = format!("-{}", crate::CONFIG.module.max_log_lines);
+
     loop {
+        //Block until there's something to do so that we don't spin while modules are idle.
         //Ok to use expect and unwrap as something would probably have gone very wrong.
-        let (_, value) = conn
+        let (_, first) = conn
             .blpop(&[&listen_key], 0)
             .await
             .expect("listening for module logs")
             .unwrap();
-        let entry: ModuleLog = serde_json::from_slice(&value).expect("deserializing module log");
-
-        //We have deserialized the log entry, now store it.
-        let log_key = get_module_log_key(&entry.module);
-        let time = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(entry.instant, 0), Utc);
-        //Store the log entry as a simple string.
-        let stored_entry = format!(
-            "[{} {} worker:{}] {}",
-            time.to_rfc3339_opts(SecondsFormat::Secs, true),
-            entry.level,
-            entry.worker,
-            entry.message
-        );
-        conn.rpush(log_key, stored_entry)
-            .await
-            .expect("pushing module logs");
-
-        let log_message = format!(
-            "Module {}[{}]: {}",
-            entry.module, entry.worker, entry.message
+        //Then grab whatever else has arrived in the meantime.
+        let mut values = vec![first];
+        values.extend(
+            pop_many(&mut conn, &listen_key, LOG_BATCH_SIZE - 1)
+                .await
+                .expect("popping module logs"),
         );
 
-        //Print out the message into the server logs
-        match entry.level.as_str() {
-            "info" => info!("{}", log_message),
-            "error" => error!("{}", log_message),
-            "warn" => warn!("{}", log_message),
-            "debug" => debug!("{}", log_message),
-            _ => {
-                warn!("Unknown module log level {}", entry.level);
-                info!("{}", log_message)
+        //Group the entries by module, keeping them in the order they arrived.
+        let mut batches: Vec<(String, Vec<String>)> = Vec::new();
+        for value in values {
+            let entry: ModuleLog =
+                serde_json::from_slice(&value).expect("deserializing module log");
+            let log_key = get_module_log_key(&entry.module);
+            let stored_entry = process_log_entry(&entry);
+            match batches.iter_mut().find(|(k, _)| *k == log_key) {
+                Some((_, entries)) => entries.push(stored_entry),
+                None => batches.push((log_key, vec![stored_entry])),
             }
         }
+
+        //Store every batch in one go, dropping the oldest lines of modules with too many.
+        let commands = batches
+            .iter()
+            .fold(None, |commands: Option<CommandList>, (key, entries)| {
+                let command = match commands {
+                    Some(c) => c.command("RPUSH"),
+                    None => CommandList::new("RPUSH"),
+                };
+                Some(
+                    command
+                        .arg(key)
+                        .args(entries)
+                        .command("LTRIM")
+                        .arg(key)
+                        .arg(&trim_start)
+                        .arg(b"-1"),
+                )
+            })
+            .unwrap();
+        conn.run_commands(commands)
+            .await
+            .expect("pushing module logs")
+            .try_collect::<Vec<Value>>()
+            .await
+            .expect("pushing module logs");
     }
 }
 
@@ -249,6 +628,9 @@ async fn registration_loop(pool: darkredis::ConnectionPool) {
             .incr(get_registered_module_workers_key(&metadata))
             .await
             .expect("updating module worker count");
+        conn.incr(get_module_registrations_key(&metadata))
+            .await
+            .expect("counting module registrations");
 
         //Only bother adding the module to the registered set it the module was registered for the first time.
         if workers > 1 {
@@ -273,10 +655,66 @@ async fn registration_loop(pool: darkredis::ConnectionPool) {
                 "Registered module {} version {}",
                 metadata.name, metadata.version
             );
+            publish_module_event(&mut conn, &metadata, ModuleEventState::Registered).await;
         }
     }
 }
 
+//Remove every registered module whose heartbeat has expired, i.e modules which were killed without shutting down
+//properly. Returns the number of modules removed.
+async fn reap_stale_modules(conn: &mut darkredis::Connection) -> Result<usize, BackendError> {
+    let mut reaped = 0;
+    let modules = conn
+        .smembers(create_redis_backend_key("registered_modules"))
+        .await?;
+    for data in modules {
+        let info: ModuleInfo = match serde_json::from_slice(&data) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Failed to parse registered module: {}", e);
+                continue;
+            }
+        };
+
+        //Paused workers can't send heartbeats, but they are still there.
+        if conn.exists(get_module_paused_key(&info)).await? {
+            continue;
+        }
+        //Modules built with a laps.py from before heartbeats were added never send one, so only the modules which
+        //have sent a heartbeat are expected to keep sending them. The others are only removed when they shut down.
+        let heartbeat_modules = get_heartbeat_modules_key();
+        if conn.exists(get_module_heartbeat_key(&info)).await? {
+            conn.sadd(&heartbeat_modules, info.to_string()).await?;
+        } else if conn.sismember(&heartbeat_modules, info.to_string()).await? {
+            warn!(
+                "Module {} stopped sending heartbeats, removing it from the registered modules",
+                info
+            );
+            remove_module(conn, &info, &data).await;
+            //None of the workers are alive anymore.
+            conn.set(get_registered_module_workers_key(&info), "0")
+                .await?;
+            publish_module_event(conn, &info, ModuleEventState::Failed).await;
+            reaped += 1;
+        }
+    }
+
+    Ok(reaped)
+}
+
+//Periodically remove modules which have stopped sending heartbeats.
+async fn heartbeat_reaper(pool: darkredis::ConnectionPool) {
+    let mut conn = pool.spawn("heartbeat-reaper").await.unwrap();
+
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        reap_stale_modules(&mut conn)
+            .await
+            .expect("reaping stale modules");
+    }
+}
+
 //Launch the module handling loops.
 pub async fn run(pool: darkredis::ConnectionPool) {
     //Run the registration loop
@@ -287,6 +725,21 @@ pub async fn run(pool: darkredis::ConnectionPool) {
     tokio::spawn(result_listener(pool.clone()));
     //run the log listener
     tokio::spawn(log_listener(pool.clone()));
+    //Fail jobs which take too long
+    tokio::spawn(deadline_sweeper(pool.clone()));
+    //Remove modules which have died without shutting down
+    tokio::spawn(heartbeat_reaper(pool.clone()));
+}
+
+//Get the kinds of maps `module` accepts, or None if it accepts any map.
+pub async fn get_module_map_types(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+) -> Result<Option<Vec<MapType>>, BackendError> {
+    match conn.get(get_module_map_types_key(module)).await? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None => Ok(None),
+    }
 }
 
 //Get a list of every single pathfinding module which has been registered thus far.
@@ -312,20 +765,53 @@ pub async fn get_registered_modules(
 
 #[cfg(test)]
 mod test {
-    use super::ModuleInfo;
+    use super::{ModuleEvent, ModuleEventState, ModuleInfo};
     use crate::{
         types::{JobOutcome, JobResult, Vector},
         util::{
-            create_redis_backend_key, get_job_cache_key, get_module_work_key,
+            create_redis_backend_key, get_heartbeat_modules_key, get_job_cache_key,
+            get_job_deadlines_key, get_job_key, get_module_events_channel,
+            get_module_heartbeat_key, get_module_paused_key, get_module_work_key,
             get_module_workers_key, get_registered_module_workers_key,
         },
-        web::job::{JobInfo, JobSubmission},
+        web::job::{CoordinateSystem, JobInfo, JobSubmission},
     };
     use futures::StreamExt;
     use serial_test::serial;
     use std::time::Duration;
     use tokio::time;
 
+    #[test]
+    fn version_ordering() {
+        use super::compare_versions;
+        use std::cmp::Ordering;
+
+        assert_eq!(compare_versions("0.9.0", "0.10.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0.0", "0.10.0"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0", "1.0.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0.0", "1.0.0"), Ordering::Equal);
+        assert_eq!(compare_versions("alpha", "beta"), Ordering::Less);
+        //Pre-releases come before the release
+        assert_eq!(compare_versions("1.0.0-rc.1", "1.0.0"), Ordering::Less);
+
+        let modules = vec![
+            ModuleInfo {
+                name: "a".into(),
+                version: "0.9.0".into(),
+            },
+            ModuleInfo {
+                name: "a".into(),
+                version: "0.10.0".into(),
+            },
+            ModuleInfo {
+                name: "b".into(),
+                version: "2.0.0".into(),
+            },
+        ];
+        assert_eq!(super::find_latest_version(&modules, "a"), Some(&modules[1]));
+        assert_eq!(super::find_latest_version(&modules, "c"), None);
+    }
+
     #[tokio::test]
     #[serial]
     async fn module_registration() {
@@ -336,6 +822,13 @@ mod test {
         crate::test::clear_redis(&mut conn).await;
 
         let module_key = create_redis_backend_key("registered_modules");
+        let events = pool
+            .spawn("test-module-events")
+            .await
+            .unwrap()
+            .subscribe(&[get_module_events_channel()])
+            .await
+            .unwrap();
 
         //Register a fake module
         let module_info = br#"{"name": "test_module", "version": "1.0.0"}"#.to_vec();
@@ -353,6 +846,24 @@ mod test {
             .unwrap();
         time::delay_for(Duration::from_millis(100)).await; //We have to yield to let the registration code run.
         assert!(!conn.sismember(&module_key, &module_info).await.unwrap());
+
+        //Both state changes were announced
+        let events: Vec<ModuleEvent> = time::timeout(
+            Duration::from_secs(1),
+            events
+                .take(2)
+                .map(|m| serde_json::from_slice(&m.message).unwrap())
+                .collect(),
+        )
+        .await
+        .unwrap();
+        let module: ModuleInfo = serde_json::from_slice(&module_info).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].module, module);
+        assert_eq!(events[0].state, ModuleEventState::Registered);
+        assert_eq!(events[1].module, module);
+        assert_eq!(events[1].state, ModuleEventState::Unregistered);
+        assert!(events[0].timestamp <= events[1].timestamp);
     }
 
     //Test that a module's queue is cancelled when it shuts down.
@@ -391,14 +902,19 @@ mod test {
         let mut job = JobInfo {
             start: Vector { x: 1, y: 1 },
             map_id: 1,
+            map_type: laps_convert::MapType::Elevation,
             job_id: 1,
             stop: Vector { x: 2, y: 2 },
+            downsample: None,
+            tiles: Vec::new(),
         };
         let mut jobs = Vec::new();
         for i in 0..JOB_COUNT {
             job.job_id = i;
             jobs.push(serde_json::to_vec(&job).unwrap());
         }
+        //A corrupt entry doesn't keep the other jobs from being cancelled.
+        jobs.insert(2, b"not a job".to_vec());
         conn.lpush_slice(&work_key, &jobs).await.unwrap();
 
         //Add a couple of dummy cache entries. It doesn't matter whether or not these are correct as long
@@ -407,9 +923,13 @@ mod test {
             job.job_id = i;
             let submission = JobSubmission {
                 map_id: 1,
-                start: Vector { x: 1, y: 1 },
-                stop: Vector { x: 2, y: 2 },
+                map_type: laps_convert::MapType::Elevation,
+                start: Vector { x: 1, y: 1 }.into(),
+                stop: Vector { x: 2, y: 2 }.into(),
                 algorithm: module_info.clone(),
+                downsample: None,
+                tiles: Vec::new(),
+                coords: CoordinateSystem::Pixel,
             };
             let cache_key = get_job_cache_key(&submission);
             conn.set(&cache_key, b"").await.unwrap();
@@ -427,6 +947,7 @@ mod test {
         tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
 
         //Check that each job was cancelled by verifying that the path results backlog is big enough.
+        assert!(!conn.exists(&work_key).await.unwrap());
         let path_results_key = create_redis_backend_key("path-results");
         assert_eq!(
             conn.llen(&path_results_key).await.unwrap().unwrap(),
@@ -453,6 +974,296 @@ mod test {
         assert!(caches.is_empty());
     }
 
+    #[test]
+    fn path_violations() {
+        use super::find_path_violation;
+        use crate::types::MapExtent;
+
+        let extents = [
+            MapExtent {
+                offset: Vector { x: 0, y: 0 },
+                width: 10,
+                height: 10,
+            },
+            MapExtent {
+                offset: Vector { x: 10, y: 0 },
+                width: 5,
+                height: 5,
+            },
+        ];
+        let path = |points: &[(u32, u32)]| {
+            points
+                .iter()
+                .map(|&(x, y)| Vector { x, y })
+                .collect::<Vec<Vector>>()
+        };
+
+        //Paths may cross into adjacent tiles, diagonally or not.
+        let valid = path(&[(8, 3), (9, 4), (10, 4), (11, 4)]);
+        assert_eq!(find_path_violation(&valid, &extents, 1, true), None);
+        assert!(find_path_violation(&path(&[(10, 5)]), &extents, 1, true).is_some());
+        assert!(find_path_violation(&path(&[(0, 10)]), &extents, 1, true).is_some());
+
+        //Downsampled points are scaled before checking them.
+        assert_eq!(
+            find_path_violation(&path(&[(4, 4)]), &extents, 2, true),
+            None
+        );
+        assert!(find_path_violation(&path(&[(5, 3)]), &extents, 2, true).is_some());
+
+        //Gaps are only a problem when checking contiguity.
+        let sparse = path(&[(0, 0), (5, 5), (9, 9)]);
+        assert!(find_path_violation(&sparse, &extents, 1, true).is_some());
+        assert_eq!(find_path_violation(&sparse, &extents, 1, false), None);
+    }
+
+    //Test that results with paths outside the map are turned into failures.
+    #[tokio::test]
+    #[serial]
+    async fn invalid_result_path() {
+        use crate::{types::MapExtent, util::get_job_extents_key};
+
+        let pool = crate::create_redis_pool().await;
+        let mut conn = pool.get().await;
+        crate::test::clear_redis(&mut conn).await;
+        tokio::spawn(super::result_listener(pool.clone()));
+
+        let extents = vec![MapExtent {
+            offset: Vector { x: 0, y: 0 },
+            width: 10,
+            height: 10,
+        }];
+        for job_id in 1..=2 {
+            conn.set(
+                get_job_extents_key(job_id),
+                serde_json::to_vec(&extents).unwrap(),
+            )
+            .await
+            .unwrap();
+        }
+        let valid = JobResult {
+            job_id: 1,
+            outcome: JobOutcome::Success,
+            points: vec![Vector { x: 0, y: 0 }, Vector { x: 1, y: 1 }],
+        };
+        let invalid = JobResult {
+            job_id: 2,
+            outcome: JobOutcome::Success,
+            points: vec![Vector { x: 9, y: 9 }, Vector { x: 10, y: 10 }],
+        };
+        for result in [valid, invalid].iter() {
+            conn.rpush(
+                create_redis_backend_key("path-results"),
+                serde_json::to_vec(result).unwrap(),
+            )
+            .await
+            .unwrap();
+        }
+
+        //Results are only kept for a second in test mode, so check them straight away.
+        time::delay_for(Duration::from_millis(100)).await;
+        let get_result = |values: Vec<Vec<u8>>| {
+            assert_eq!(values.len(), 1);
+            serde_json::from_slice::<JobResult>(&values[0]).unwrap()
+        };
+        let first = get_result(conn.lrange(get_job_key(1), 0, -1).await.unwrap());
+        assert_eq!(first.outcome, JobOutcome::Success);
+        assert_eq!(first.points.len(), 2);
+        let second = get_result(conn.lrange(get_job_key(2), 0, -1).await.unwrap());
+        assert_eq!(second.outcome, JobOutcome::Failure);
+        assert!(second.points.is_empty());
+    }
+
+    //Test that results with more points than allowed fail the job instead of being stored.
+    #[tokio::test]
+    #[serial]
+    async fn oversized_result() {
+        let pool = crate::create_redis_pool().await;
+        let mut conn = pool.get().await;
+        crate::test::clear_redis(&mut conn).await;
+        tokio::spawn(super::result_listener(pool.clone()));
+
+        let max = crate::CONFIG.jobs.max_result_points;
+        let path = |len: usize| {
+            (0..len as u32)
+                .map(|x| Vector { x, y: 0 })
+                .collect::<Vec<_>>()
+        };
+        let largest = JobResult {
+            job_id: 1,
+            outcome: JobOutcome::Success,
+            points: path(max),
+        };
+        let oversized = JobResult {
+            job_id: 2,
+            outcome: JobOutcome::Success,
+            points: path(max + 1),
+        };
+        for result in [largest, oversized].iter() {
+            conn.rpush(
+                create_redis_backend_key("path-results"),
+                serde_json::to_vec(result).unwrap(),
+            )
+            .await
+            .unwrap();
+        }
+
+        //Results are only kept for a second in test mode, so check them straight away.
+        time::delay_for(Duration::from_millis(200)).await;
+        let get_result = |values: Vec<Vec<u8>>| {
+            assert_eq!(values.len(), 1);
+            serde_json::from_slice::<JobResult>(&values[0]).unwrap()
+        };
+        let first = get_result(conn.lrange(get_job_key(1), 0, -1).await.unwrap());
+        assert_eq!(first.outcome, JobOutcome::Success);
+        assert_eq!(first.points.len(), max);
+        let second = get_result(conn.lrange(get_job_key(2), 0, -1).await.unwrap());
+        assert_eq!(second.outcome, JobOutcome::Failure);
+        assert!(second.points.is_empty());
+    }
+
+    //Test that jobs which never get a result are failed once their deadline passes.
+    #[tokio::test]
+    #[serial]
+    async fn job_deadline() {
+        let pool = crate::create_redis_pool().await;
+        let mut conn = pool.get().await;
+        crate::test::clear_redis(&mut conn).await;
+        tokio::spawn(super::result_listener(pool.clone()));
+        tokio::spawn(super::deadline_sweeper(pool.clone()));
+
+        //One job which is completed in time and one which never is.
+        super::set_job_deadline(&mut conn, 1, 1).await.unwrap();
+        super::set_job_deadline(&mut conn, 2, 1).await.unwrap();
+        let result = JobResult {
+            job_id: 1,
+            outcome: JobOutcome::Success,
+            points: vec![Vector { x: 0, y: 0 }],
+        };
+        conn.rpush(
+            create_redis_backend_key("path-results"),
+            serde_json::to_vec(&result).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        //Results are only kept for a second in test mode, so check the first one straight away.
+        time::delay_for(Duration::from_millis(100)).await;
+        let get_outcome = |values: Vec<Vec<u8>>| {
+            assert_eq!(values.len(), 1);
+            serde_json::from_slice::<JobResult>(&values[0])
+                .unwrap()
+                .outcome
+        };
+        let first = conn.lrange(get_job_key(1), 0, -1).await.unwrap();
+        assert_eq!(get_outcome(first), JobOutcome::Success);
+
+        //Wait for the deadline to pass and the sweeper to fail the second job.
+        let mut second = Vec::new();
+        for _ in 0..30 {
+            time::delay_for(Duration::from_millis(100)).await;
+            second = conn.lrange(get_job_key(2), 0, -1).await.unwrap();
+            if !second.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(get_outcome(second), JobOutcome::Failure);
+
+        //Neither job should have a deadline anymore.
+        let deadlines = conn
+            .run_command(darkredis::Command::new("ZCARD").arg(&get_job_deadlines_key()))
+            .await
+            .unwrap()
+            .unwrap_integer();
+        assert_eq!(deadlines, 0);
+    }
+
+    //Test that modules which stop sending heartbeats are removed.
+    #[tokio::test]
+    #[serial]
+    async fn heartbeat_reaping() {
+        let pool = crate::create_redis_pool().await;
+        let mut conn = pool.get().await;
+        crate::test::clear_redis(&mut conn).await;
+
+        let module_key = create_redis_backend_key("registered_modules");
+        let alive = ModuleInfo {
+            name: "alive".into(),
+            version: "1.0.0".into(),
+        };
+        let dead = ModuleInfo {
+            name: "dead".into(),
+            version: "1.0.0".into(),
+        };
+        let paused = ModuleInfo {
+            name: "paused".into(),
+            version: "1.0.0".into(),
+        };
+        let legacy = ModuleInfo {
+            name: "legacy".into(),
+            version: "1.0.0".into(),
+        };
+
+        //Pretend every module is registered with two workers, but only one is sending heartbeats.
+        for module in &[&alive, &dead, &paused, &legacy] {
+            conn.sadd(&module_key, serde_json::to_vec(module).unwrap())
+                .await
+                .unwrap();
+            conn.set(get_registered_module_workers_key(module), "2")
+                .await
+                .unwrap();
+        }
+        conn.set_and_expire_seconds(get_module_heartbeat_key(&alive), "0", 10)
+            .await
+            .unwrap();
+        //The dead module used to send heartbeats, unlike the legacy one which never has.
+        let heartbeat_modules = get_heartbeat_modules_key();
+        conn.sadd(&heartbeat_modules, dead.to_string())
+            .await
+            .unwrap();
+        //Paused modules can't send heartbeats, so they are left alone.
+        conn.set(get_module_paused_key(&paused), "1").await.unwrap();
+
+        assert_eq!(super::reap_stale_modules(&mut conn).await.unwrap(), 1);
+        assert!(conn
+            .sismember(&module_key, serde_json::to_vec(&legacy).unwrap())
+            .await
+            .unwrap());
+        //The alive module is remembered as sending heartbeats, and the dead one is forgotten.
+        assert!(conn
+            .sismember(&heartbeat_modules, alive.to_string())
+            .await
+            .unwrap());
+        assert!(!conn
+            .sismember(&heartbeat_modules, dead.to_string())
+            .await
+            .unwrap());
+        assert!(conn
+            .sismember(&module_key, serde_json::to_vec(&paused).unwrap())
+            .await
+            .unwrap());
+        assert!(conn
+            .sismember(&module_key, serde_json::to_vec(&alive).unwrap())
+            .await
+            .unwrap());
+        assert!(!conn
+            .sismember(&module_key, serde_json::to_vec(&dead).unwrap())
+            .await
+            .unwrap());
+        assert_eq!(
+            conn.get(get_registered_module_workers_key(&dead))
+                .await
+                .unwrap(),
+            Some("0".into())
+        );
+        assert_eq!(
+            conn.get(get_registered_module_workers_key(&alive))
+                .await
+                .unwrap(),
+            Some("2".into())
+        );
+    }
+
     #[tokio::test]
     #[serial]
     //Test that concurrent modules are handled properly.
@@ -522,4 +1333,109 @@ mod test {
             Some("0".into())
         ); //count check
     }
+
+    //A debug log entry for message number `n` from the first worker of `module`, as sent by the module runner.
+    fn log_entry(module: &ModuleInfo, n: usize) -> Vec<u8> {
+        let entry = super::ModuleLog {
+            module: module.clone(),
+            message: format!("message {}", n),
+            level: "debug".into(),
+            instant: 0,
+            worker: 0,
+        };
+        serde_json::to_vec(&entry).unwrap()
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn log_burst() {
+        use crate::util::{create_redis_key, get_module_log_key};
+
+        let pool = crate::create_redis_pool().await;
+        let mut conn = pool.get().await;
+        crate::test::clear_redis(&mut conn).await;
+
+        //Queue up a burst of interleaved messages from two modules before the listener starts, so that it has to
+        //handle more than one batch worth at once.
+        const MESSAGES: usize = 250;
+        let modules: Vec<ModuleInfo> = ["chatty", "noisy"]
+            .iter()
+            .map(|name| ModuleInfo {
+                name: name.to_string(),
+                version: "1.0.0".into(),
+            })
+            .collect();
+        let entries: Vec<Vec<u8>> = (0..MESSAGES)
+            .flat_map(|n| modules.iter().map(move |module| log_entry(module, n)))
+            .collect();
+        conn.rpush_slice(create_redis_key("moduleLogs"), &entries)
+            .await
+            .unwrap();
+        tokio::spawn(super::log_listener(pool.clone()));
+
+        //Wait for every message to be stored.
+        let deadline = time::Instant::now() + Duration::from_secs(5);
+        for module in &modules {
+            let log_key = get_module_log_key(module);
+            while conn.llen(&log_key).await.unwrap().unwrap_or(0) < MESSAGES as isize {
+                assert!(time::Instant::now() < deadline, "timed out storing logs");
+                time::delay_for(Duration::from_millis(10)).await;
+            }
+
+            let stored = conn.lrange(&log_key, 0, -1).await.unwrap();
+            assert_eq!(stored.len(), MESSAGES);
+            for (n, line) in stored.iter().enumerate() {
+                assert_eq!(
+                    String::from_utf8_lossy(line),
+                    format!("[1970-01-01T00:00:00Z debug worker:0] message {}", n)
+                );
+            }
+        }
+        //Nothing should be left in the queue.
+        assert_eq!(
+            conn.llen(create_redis_key("moduleLogs")).await.unwrap(),
+            Some(0)
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn log_trimming() {
+        use crate::util::{create_redis_key, get_module_log_key};
+
+        let pool = crate::create_redis_pool().await;
+        let mut conn = pool.get().await;
+        crate::test::clear_redis(&mut conn).await;
+        tokio::spawn(super::log_listener(pool.clone()));
+
+        //Push more lines than are kept.
+        let max = crate::CONFIG.module.max_log_lines as usize;
+        let module = ModuleInfo {
+            name: "verbose".into(),
+            version: "1.0.0".into(),
+        };
+        let entries: Vec<Vec<u8>> = (0..max + 100).map(|n| log_entry(&module, n)).collect();
+        conn.rpush_slice(create_redis_key("moduleLogs"), &entries)
+            .await
+            .unwrap();
+
+        //Wait until the last entry is stored and the list has been trimmed after it.
+        let log_key = get_module_log_key(&module);
+        let last = format!("message {}", max + 99);
+        let deadline = time::Instant::now() + Duration::from_secs(5);
+        let stored = loop {
+            let stored = conn.lrange(&log_key, 0, -1).await.unwrap();
+            let done = stored
+                .last()
+                .is_some_and(|l| String::from_utf8_lossy(l).ends_with(&last));
+            if done && stored.len() == max {
+                break stored;
+            }
+            assert!(time::Instant::now() < deadline, "timed out storing logs");
+            time::delay_for(Duration::from_millis(10)).await;
+        };
+
+        //Only the most recent lines are left.
+        assert!(String::from_utf8_lossy(&stored[0]).ends_with("message 100"));
+    }
 }
@@ -4,6 +4,12 @@
 //Distributed under the zlib licence, see LICENCE.
 
 //Test utility functions and such
+use crate::{
+    module_handling::ModuleInfo,
+    types::{JobOutcome, JobResult, Vector},
+    util,
+    web::job::JobInfo,
+};
 use bollard::{image::RemoveImageOptions, Docker};
 use multipart::client::lazy::Multipart;
 use rocket::{
@@ -18,13 +24,57 @@ pub async fn insert_test_mapdata(conn: &mut darkredis::Connection) -> (u32, u32)
     let (image, metadata) = laps_convert::convert_to_png(path).unwrap();
 
     let (width, height) = (image.width as u32, image.height as u32);
-    laps_convert::import_data_test(conn, image, metadata)
-        .await
-        .unwrap();
+    laps_convert::import_data_test(
+        conn,
+        laps_convert::MapType::Elevation,
+        image,
+        metadata,
+        &Default::default(),
+    )
+    .await
+    .unwrap();
 
     (width, height)
 }
 
+//Register `module` as if it was running, so that jobs can be submitted to it.
+pub async fn register_module(conn: &mut darkredis::Connection, module: &ModuleInfo) {
+    conn.sadd(
+        util::create_redis_backend_key("registered_modules"),
+        serde_json::to_vec(module).unwrap(),
+    )
+    .await
+    .unwrap();
+}
+
+//Pretend to be `module`, taking the next job sent to it and completing it with `outcome` and `points`. The result is
+//stored where the result listener would put it, and returned.
+pub async fn complete_next_job(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+    outcome: JobOutcome,
+    points: Vec<Vector>,
+) -> JobResult {
+    let (_, data) = conn
+        .blpop(&[util::get_module_work_key(module)], 5)
+        .await
+        .unwrap()
+        .expect("no job was sent to the module");
+    let info: JobInfo = serde_json::from_slice(&data).unwrap();
+    let result = JobResult {
+        job_id: info.job_id,
+        outcome,
+        points,
+    };
+    conn.lpush(
+        util::get_job_key(info.job_id),
+        serde_json::to_vec(&result).unwrap(),
+    )
+    .await
+    .unwrap();
+    result
+}
+
 //A nice function for resetting only the test part of the database.
 pub async fn clear_redis(conn: &mut darkredis::Connection) {
     use futures::StreamExt;
@@ -81,7 +131,21 @@ pub async fn upload_test_image<'a>(
     version: &'a str,
     workers: Option<u8>,
 ) -> LocalResponse<'a> {
-    //Create the multipart form with the optional worker field.
+    let workers = workers.map(|w| w.to_string());
+    let extra: Vec<(&str, &str)> = workers.iter().map(|w| ("workers", w.as_str())).collect();
+    upload_test_image_with(client, cookies, tarball, name, version, &extra).await
+}
+
+//Like `upload_test_image`, but with arbitrary extra text fields added to the form.
+pub async fn upload_test_image_with<'a>(
+    client: &'a Client,
+    cookies: &'a Vec<Cookie<'a>>,
+    tarball: &'a [u8],
+    name: &'a str,
+    version: &'a str,
+    extra: &[(&str, &str)],
+) -> LocalResponse<'a> {
+    //Create the multipart form with the extra fields.
     let mut multipart = Multipart::new();
     multipart
         .add_stream::<&str, &[u8], &str>(
@@ -92,8 +156,8 @@ pub async fn upload_test_image<'a>(
         )
         .add_text("version", version)
         .add_text("name", name);
-    if let Some(w) = workers {
-        multipart.add_text("workers", w.to_string());
+    for (field, value) in extra {
+        multipart.add_text(field.to_string(), value.to_string());
     }
 
     //Finalise the form and send it
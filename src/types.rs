@@ -3,9 +3,12 @@
 //Copyright (c) 2020 LAPS Group
 //Distributed under the zlib licence, see LICENCE.
 
-use crate::web::multipart::FormError;
+use crate::web::{multipart::FormError, request_id::RequestId};
+use laps_convert::ImportError;
+//The types making up the API are shared with clients.
+pub use laps_types::{JobOutcome, JobProgress, JobResult, JobStatus, Point, Vector};
 use rocket::{
-    http::Status,
+    http::{ContentType, Status},
     request::Request,
     response::{self, Responder},
     Response,
@@ -13,31 +16,53 @@ use rocket::{
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 
-//General vector type to be used internally
+//The area covered by one map in the grid of a job, in pixels.
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
-pub struct Vector {
-    pub x: u32,
-    pub y: u32,
+pub struct MapExtent {
+    //The position of the top-left corner of the map in the grid.
+    pub offset: Vector,
+    pub width: u32,
+    pub height: u32,
 }
 
-//The outcome of a Job.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-#[serde(rename_all = "camelCase")]
-pub enum JobOutcome {
-    Success,
-    Failure,
-    Cancelled,
+impl MapExtent {
+    //Check if `point` lies within this map.
+    pub fn contains(&self, point: &Vector) -> bool {
+        //Use u64 to avoid overflow with large offsets
+        point.x >= self.offset.x
+            && point.y >= self.offset.y
+            && ((point.x - self.offset.x) as u64) < self.width as u64
+            && ((point.y - self.offset.y) as u64) < self.height as u64
+    }
+
+    //Check if this map overlaps with `other`.
+    pub fn overlaps(&self, other: &MapExtent) -> bool {
+        (self.offset.x as u64) < other.offset.x as u64 + other.width as u64
+            && (other.offset.x as u64) < self.offset.x as u64 + self.width as u64
+            && (self.offset.y as u64) < other.offset.y as u64 + other.height as u64
+            && (other.offset.y as u64) < self.offset.y as u64 + self.height as u64
+    }
 }
-//Struct for storing the ouptut of a pathfinding job.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct JobResult {
-    //The ID of the job.
-    pub job_id: i32,
-    //The outcome of this job
-    pub outcome: JobOutcome,
-    //The list of points containing the path of the job.
-    #[serde(default)]
-    pub points: Vec<Vector>,
+
+//Build an error response with the JSON body shared by all endpoints:
+//`{"error": {"code": <code>, "message": <message>}}`, plus the request id when there is one.
+pub async fn error_response<'r>(
+    status: Status,
+    code: &str,
+    message: &str,
+    request_id: Option<&RequestId>,
+) -> Response<'r> {
+    let mut error = serde_json::json!({ "code": code, "message": message });
+    if let Some(id) = request_id {
+        error["request_id"] = id.to_string().into();
+    }
+    let body = serde_json::json!({ "error": error }).to_string();
+    Response::build()
+        .status(status)
+        .header(ContentType::JSON)
+        .sized_body(Cursor::new(body))
+        .await
+        .finalize()
 }
 
 quick_error::quick_error! {
@@ -62,6 +87,14 @@ quick_error::quick_error! {
         Io(err: std::io::Error) {
             from()
         }
+        //The stored data of a map doesn't match the checksum recorded when it was imported.
+        CorruptMap(id: String) {
+            display("The data of map {} doesn't match its checksum", id)
+        }
+        //A module needs GPUs, but the Docker host has no runtime to provide them.
+        GpuUnavailable {
+            display("The Docker host can't run modules using GPUs, as it doesn't have the NVIDIA container runtime")
+        }
         //Something wrong happened that can't be handled
         Other(msg: String) {
             display("Other error: {}", msg)
@@ -69,17 +102,65 @@ quick_error::quick_error! {
     }
 }
 
+//Check if `err` happened because the Docker daemon couldn't be reached at all, as opposed to Docker rejecting the request.
+pub fn is_docker_unavailable(err: &bollard::errors::Error) -> bool {
+    use bollard::errors::ErrorKind;
+    use std::io::ErrorKind as IoErrorKind;
+
+    match err.kind() {
+        ErrorKind::HyperResponseError { err } => err.is_connect(),
+        ErrorKind::RequestTimeoutError => true,
+        ErrorKind::IOError { err } => matches!(
+            err.kind(),
+            IoErrorKind::NotFound
+                | IoErrorKind::ConnectionRefused
+                | IoErrorKind::ConnectionReset
+                | IoErrorKind::ConnectionAborted
+                | IoErrorKind::BrokenPipe
+                | IoErrorKind::PermissionDenied
+        ),
+        _ => false,
+    }
+}
+
 #[rocket::async_trait]
 #[allow(clippy::needless_lifetimes)]
 impl<'r> Responder<'r> for BackendError {
-    async fn respond_to(self, _: &'r Request<'_>) -> response::Result<'r> {
-        let error_message = Cursor::new("internal server error");
-        error!("An internal error occurred: {}", self);
-        Ok(Response::build()
-            .status(Status::InternalServerError)
-            .sized_body(error_message)
-            .await
-            .finalize())
+    async fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        //Include the request id so users can refer to it when reporting the error.
+        let id = RequestId::of(request);
+        //Tell admins when Docker is down instead of hiding it behind a generic error, there's nothing wrong with the backend.
+        if let BackendError::Docker(e) = &self {
+            if is_docker_unavailable(e) {
+                error!("[{}] Docker daemon unavailable: {}", id, e);
+                return Ok(error_response(
+                    Status::ServiceUnavailable,
+                    "docker_unavailable",
+                    "Docker daemon unavailable",
+                    Some(id),
+                )
+                .await);
+            }
+        }
+        //Hosts without GPU support need to be set up differently, which admins should be told.
+        if let BackendError::GpuUnavailable = &self {
+            error!("[{}] {}", id, self);
+            return Ok(error_response(
+                Status::ServiceUnavailable,
+                "gpu_unavailable",
+                &self.to_string(),
+                Some(id),
+            )
+            .await);
+        }
+        error!("[{}] An internal error occurred: {}", id, self);
+        Ok(error_response(
+            Status::InternalServerError,
+            "internal_error",
+            "internal server error",
+            Some(id),
+        )
+        .await)
     }
 }
 
@@ -89,6 +170,8 @@ quick_error::quick_error! {
     pub enum UserError {
         Internal(err: BackendError) {
             from()
+            from(err: darkredis::Error) -> (BackendError::Redis(err))
+            from(err: serde_json::Error) -> (BackendError::JsonError(err))
             display("Internal server error")
         }
         BadType(got: String, allowed: String) {
@@ -105,6 +188,10 @@ quick_error::quick_error! {
         ModuleImport(err: String) {
             display("Importing module image: {}", err)
         }
+        MapImport(err: laps_convert::ImportError) {
+            from()
+            display("Importing map: {}", err)
+        }
     }
 }
 
@@ -112,20 +199,26 @@ quick_error::quick_error! {
 #[allow(clippy::needless_lifetimes)]
 impl<'r> Responder<'r> for UserError {
     async fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
-        let message = std::io::Cursor::new(format!("{}", &self));
-        let status_code = match self {
+        let id = RequestId::of(request);
+        let message = self.to_string();
+        let (status_code, code) = match self {
             UserError::Internal(e) => {
                 return e.respond_to(request).await;
             }
-            UserError::MapConvert(_) => Status::UnprocessableEntity,
-            UserError::BadType(_, _) | UserError::BadForm(_) => Status::BadRequest,
-            UserError::ModuleImport(_) => Status::BadRequest,
+            UserError::MapConvert(_) => (Status::UnprocessableEntity, "map_convert"),
+            UserError::BadType(_, _) => (Status::BadRequest, "bad_type"),
+            UserError::BadForm(_) => (Status::BadRequest, "bad_form"),
+            UserError::ModuleImport(_) => (Status::BadRequest, "module_import"),
+            UserError::MapImport(ImportError::Redis(e)) => {
+                return BackendError::Redis(e).respond_to(request).await;
+            }
+            UserError::MapImport(ImportError::TooLarge(_, _)) => {
+                (Status::PayloadTooLarge, "map_quota")
+            }
+            UserError::MapImport(_) => (Status::InsufficientStorage, "map_quota"),
         };
+        info!("[{}] Rejected request: {}", id, message);
 
-        Ok(Response::build()
-            .status(status_code)
-            .sized_body(message)
-            .await
-            .finalize())
+        Ok(error_response(status_code, code, &message, Some(id)).await)
     }
 }
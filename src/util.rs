@@ -3,7 +3,12 @@
 //Copyright (c) 2020 LAPS Group
 //Distributed under the zlib licence, see LICENCE.
 
-use crate::{module_handling::ModuleInfo, web::job::JobSubmission};
+use crate::{
+    module_handling::ModuleInfo,
+    web::job::{CoordinateSystem, JobSubmission},
+};
+use futures::StreamExt;
+use laps_convert::MapType;
 use rand::{thread_rng, RngCore};
 
 ///Create a general Redis key to be used in the system.
@@ -29,6 +34,13 @@ pub fn create_redis_backend_key(name: &str) -> String {
     format!("laps.testing.backend.{}", name)
 }
 
+//Get the key of the hash holding the `part` of every map of `map_type`, such as "image" or "meta", named the same way
+//as by the importer.
+pub fn get_mapdata_key(map_type: MapType, part: &str) -> String {
+    let namespace = laps_convert::map_namespace(&create_redis_key("mapdata"), map_type);
+    format!("{}.{}", namespace, part)
+}
+
 //Get the job queue key for `module`.
 pub fn get_module_work_key(module: &ModuleInfo) -> String {
     let prefix = create_redis_key("runner");
@@ -52,6 +64,30 @@ pub fn get_job_key(job_id: i32) -> String {
     format!("{}.{}", prefix, job_id)
 }
 
+//Get the key where modules report the latest progress of the job `job_id`.
+pub fn get_job_progress_key(job_id: i32) -> String {
+    let prefix = create_redis_backend_key("job-progress");
+    format!("{}.{}", prefix, job_id)
+}
+
+//Get the key where the jobs of the comparison with `token` are stored.
+pub fn get_job_comparison_key(token: &str) -> String {
+    let prefix = create_redis_backend_key("job_comparison");
+    format!("{}.{}", prefix, token)
+}
+
+//Get the key where the downsample factor of the preview job `job_id` is stored.
+pub fn get_job_downsample_key(job_id: i32) -> String {
+    let prefix = create_redis_backend_key("job_downsample");
+    format!("{}.{}", prefix, job_id)
+}
+
+//Get the key where the extents of the maps used by the job `job_id` are stored.
+pub fn get_job_extents_key(job_id: i32) -> String {
+    let prefix = create_redis_backend_key("job_extents");
+    format!("{}.{}", prefix, job_id)
+}
+
 //Get the administrator entry key
 pub fn get_admin_key(username: &str) -> String {
     let prefix = create_redis_backend_key("admin");
@@ -77,8 +113,73 @@ pub fn get_session_key(token: &str) -> String {
 //Get a job cache key
 pub fn get_job_cache_key(job: &JobSubmission) -> String {
     let prefix = create_redis_backend_key("cache");
-    //We want the key to have the same format every time
-    format!("{}.{}", prefix, job.cache_key())
+    //We want the key to have the same format every time, so each field is written out explicitly such that each
+    //field has a defined ordering.
+    //The map id gets its own map segment so that every cached job for a map can be found with a pattern.
+    let start_string = format!("({},{})", job.start.x, job.start.y);
+    let stop_string = format!("({},{})", job.stop.x, job.stop.y);
+    let mut key = format!(
+        "{}.{}.{}.{}.{}",
+        prefix,
+        job.algorithm,
+        map_segment(job.map_type, job.map_id),
+        start_string,
+        stop_string
+    );
+    //Use the same map segment format for tiles so that they are also found by the map cache pattern.
+    for tile in &job.tiles {
+        key += &format!(
+            ".tile.{}.({},{})",
+            map_segment(job.map_type, tile.map_id),
+            tile.offset.x,
+            tile.offset.y
+        );
+    }
+    if let Some(factor) = job.downsample {
+        key += &format!(".downsample-{}", factor);
+    }
+    //The points are converted after the cache lookup, so keep world coordinates apart from pixels.
+    if job.coords == CoordinateSystem::World {
+        key += ".world";
+    }
+    key
+}
+
+//Get a pattern matching every job cache key for jobs submitted to `module`.
+pub fn get_module_cache_pattern(module: &ModuleInfo) -> String {
+    //The cache key always starts with the module info first.
+    create_redis_backend_key(&format!("cache.{}.*", module))
+}
+
+//The segment of a job cache key naming the map `map_id` of `map_type`. Elevation maps keep the segment they had
+//before maps had types, `map-<id>`, while the other types are named like `map-rgb-<id>`.
+fn map_segment(map_type: MapType, map_id: i32) -> String {
+    match map_type {
+        MapType::Elevation => format!("map-{}", map_id),
+        other => format!("map-{}-{}", other, map_id),
+    }
+}
+
+//Get a pattern matching every job cache key for jobs run on the map `map_id` of `map_type`.
+pub fn get_map_cache_pattern(map_type: MapType, map_id: i32) -> String {
+    create_redis_backend_key(&format!("cache.*.{}.*", map_segment(map_type, map_id)))
+}
+
+//Delete every key matching `pattern`. Returns the number of keys deleted.
+pub async fn delete_matching_keys(
+    conn: &mut darkredis::Connection,
+    pattern: &str,
+) -> Result<usize, darkredis::Error> {
+    let keys = conn
+        .scan()
+        .pattern(&pattern)
+        .run()
+        .collect::<Vec<Vec<u8>>>()
+        .await;
+    if !keys.is_empty() {
+        conn.del_slice(&keys).await?;
+    }
+    Ok(keys.len())
 }
 
 //Get the key where we store the number of workers we can create of this module type.
@@ -87,8 +188,138 @@ pub fn get_module_workers_key(module: &ModuleInfo) -> String {
     format!("{}.{}", prefix, module)
 }
 
+//Get the key where the job cache timeout for `module` is stored, if it overrides the global one.
+pub fn get_module_cache_ttl_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-cache-ttl");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key where the job timeout for `module` is stored, if it overrides the global one.
+pub fn get_module_job_timeout_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-job-timeout");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key where the stop timeout for `module` is stored, if it overrides the global one.
+pub fn get_module_stop_timeout_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-stop-timeout");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key where the idle timeout of `module` is stored, if it has opted in to being stopped while idle.
+pub fn get_module_idle_timeout_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-idle-timeout");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key where the UNIX timestamp of the last job submitted to `module` is stored.
+pub fn get_module_last_used_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-last-used");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key which is set while `module` is stopped because it was idle.
+pub fn get_module_idle_stopped_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-idle-stopped");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key which is set while the workers of `module` are paused.
+pub fn get_module_paused_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-paused");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key which is set while `module` is being started for a submitted job.
+pub fn get_module_starting_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-starting");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key which is set if `module` was built from its own Dockerfile instead of the bundled one.
+pub fn get_module_custom_dockerfile_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-custom-dockerfile");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key which is set if the containers of `module` get access to the GPUs of the host.
+pub fn get_module_gpu_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-gpu");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key of the base command the containers of `module` run, stored as a JSON array.
+pub fn get_module_command_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-command");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key of the hash containing the environment variables passed to the containers of `module`.
+pub fn get_module_env_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-env");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key where the kinds of maps `module` accepts are stored as a JSON array. Modules without it accept any map.
+pub fn get_module_map_types_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-map-types");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the key of the sorted set containing the deadline of every running job, scored by UNIX timestamp.
+pub fn get_job_deadlines_key() -> String {
+    create_redis_backend_key("job-deadlines")
+}
+
+//Get the key counting the jobs submitted by `client` during the minute `minute`, counted from the UNIX epoch.
+pub fn get_submission_rate_key(client: &str, minute: i64) -> String {
+    let prefix = create_redis_backend_key("submission-rate");
+    format!("{}.{}.{}", prefix, client, minute)
+}
+
+//Get the key where the latest storage usage report is cached.
+pub fn get_storage_report_key() -> String {
+    create_redis_backend_key("storage-report")
+}
+
+//Get the key of the list of the most recently submitted job ids, newest first.
+pub fn get_job_history_key() -> String {
+    create_redis_backend_key("job-history")
+}
+
+//Get the key where the job history record of the job `job_id` is stored.
+pub fn get_job_history_record_key(job_id: i32) -> String {
+    let prefix = create_redis_backend_key("job-history");
+    format!("{}.{}", prefix, job_id)
+}
+
+//Get the key which the workers of `module` periodically refresh to show that the module is still alive.
+//Modules which let it expire are considered dead and get unregistered.
+pub fn get_module_heartbeat_key(module: &ModuleInfo) -> String {
+    let prefix = create_redis_backend_key("module-heartbeat");
+    format!("{}.{}", prefix, module)
+}
+
+//Get the set of modules which have sent a heartbeat. Only these are expected to keep sending them, as modules built with
+//an older laps.py never do.
+pub fn get_heartbeat_modules_key() -> String {
+    create_redis_backend_key("heartbeat-modules")
+}
+
+//Get the pub/sub channel module state changes are published on.
+pub fn get_module_events_channel() -> String {
+    create_redis_backend_key("module-events")
+}
+
 //Get the key where we keep the counter to how many workers are actually running for `module`.
 pub fn get_registered_module_workers_key(module: &ModuleInfo) -> String {
     let prefix = get_module_workers_key(module);
     format!("{}.active", prefix)
 }
+
+//Get the key counting every worker registration of `module`. Unlike the number of running workers it never goes down,
+//so a restart can tell the workers registering again from the ones which haven't shut down yet.
+pub fn get_module_registrations_key(module: &ModuleInfo) -> String {
+    let prefix = get_module_workers_key(module);
+    format!("{}.registrations", prefix)
+}
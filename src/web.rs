@@ -3,8 +3,13 @@
 //Copyright (c) 2020 LAPS Group
 //Distributed under the zlib licence, see LICENCE.
 
-use rocket::response::NamedFile;
-use rocket_contrib::serve::StaticFiles;
+use assets::{load_asset, Asset};
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    Request,
+};
+use std::path::PathBuf;
 
 //Export the admin module as pub if in test mode so any other tests which require a login can do so.
 #[cfg(test)]
@@ -13,20 +18,87 @@ pub mod admin;
 mod admin;
 
 mod algorithms;
+mod assets;
+pub mod compression;
+mod etag;
 pub mod job;
 mod map;
+mod metrics;
 mod mime_consts;
 pub mod multipart;
+mod range;
+pub mod request_id;
+mod sse;
+mod timeout;
 
 //Index stuff
 #[get("/")]
-fn index() -> Option<NamedFile> {
-    NamedFile::open("dist/index.html").ok()
+async fn index() -> Option<Asset> {
+    load_asset("index.html").await
 }
 
 #[get("/index.js")]
-fn index_js() -> Option<NamedFile> {
-    NamedFile::open("dist/index.js").ok()
+async fn index_js() -> Option<Asset> {
+    load_asset("index.js").await
+}
+
+//Images used by the frontend. Rocket refuses paths which would leave the directory.
+#[get("/images/<path..>")]
+async fn images(path: PathBuf) -> Option<Asset> {
+    let path = path
+        .iter()
+        .map(|p| p.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    load_asset(&format!("images/{}", path)).await
+}
+
+//An error which Rocket caught because the handler or a request guard only gave a status, such as a request without a
+//session or for something which doesn't exist. Responds with the same JSON body as every other error.
+struct CaughtError {
+    status: Status,
+    code: &'static str,
+    message: &'static str,
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r> for CaughtError {
+    async fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        let id = request_id::RequestId::of(request);
+        Ok(crate::types::error_response(self.status, self.code, self.message, Some(id)).await)
+    }
+}
+
+#[catch(401)]
+fn unauthorized() -> CaughtError {
+    CaughtError {
+        status: Status::Unauthorized,
+        code: "unauthorized",
+        message: "Authentication is required",
+    }
+}
+
+#[catch(403)]
+fn forbidden() -> CaughtError {
+    CaughtError {
+        status: Status::Forbidden,
+        code: "forbidden",
+        message: "You are not allowed to do that",
+    }
+}
+
+#[catch(404)]
+fn not_found() -> CaughtError {
+    CaughtError {
+        status: Status::NotFound,
+        code: "not_found",
+        message: "Not found",
+    }
+}
+
+//The catchers giving errors without a body of their own the JSON error body.
+pub(crate) fn catchers() -> Vec<rocket::Catcher> {
+    catchers![unauthorized, forbidden, not_found]
 }
 
 //Launch the rocket instance
@@ -35,45 +107,110 @@ pub async fn run() {
     //Create the specialized pool for getting connection results
     let result_pool = job::create_result_redis_pool().await;
     //Connect to Docker
-    let docker = crate::connect_to_docker().await;
+    let docker = crate::docker::shared(crate::connect_to_docker().await);
     //Launch module handlers
     tokio::spawn(crate::module_handling::run(pool.clone()));
+    if crate::CONFIG.module.stop_idle_modules {
+        tokio::spawn(admin::idle_module_stopper(pool.clone(), docker.clone()));
+    }
+    //Shared between the fairing recording requests and the endpoint exposing them.
+    let metrics = std::sync::Arc::new(metrics::Metrics::new(
+        crate::CONFIG.web.metrics_token.clone(),
+    ));
+
+    //Rocket.toml and the ROCKET_* environment variables still configure the server. Enabling TLS in our own
+    //configuration only adds the certificate on top of that, replacing any TLS settings from Rocket.toml.
+    let mut rocket = rocket::ignite();
+    if let Some(tls) = &crate::CONFIG.web.tls {
+        let mut config = rocket.config().clone();
+        if let Err(e) = config.set_tls(&tls.certs, &tls.key) {
+            error!("Failed to load the TLS certificate or key: {}", e);
+            std::process::exit(2);
+        }
+        info!("Serving HTTPS with the certificate in {}", tls.certs);
+        rocket = rocket::custom(config);
+    }
+
+    assets::preload_assets();
 
     info!("Starting Rocket...");
-    rocket::ignite()
+    let timeouts = &crate::CONFIG.web.timeouts;
+    rocket
         .mount(
             "/",
-            routes![
-                admin::delete_map,
-                admin::delete_module,
-                admin::get_all_modules,
-                admin::get_me,
-                admin::get_module_logs,
-                admin::index,
-                admin::index_js,
-                admin::index_no_session,
-                admin::login,
-                admin::login_attempt_with_session,
-                admin::login_index,
-                admin::login_index_js,
-                admin::login_with_session,
-                admin::new_map,
-                admin::register_admin,
-                admin::register_super_admin,
-                admin::restart_module,
-                admin::stop_module,
-                admin::upload_module,
-                algorithms::list,
-                index,
-                index_js,
-                job::result,
-                job::submit,
-                map::get_map,
-                map::get_map_metadata,
-                map::get_maps,
-            ],
+            timeout::with_timeouts(
+                routes![
+                    admin::delete_map,
+                    admin::delete_maps,
+                    admin::delete_map_group,
+                    admin::delete_module,
+                    admin::drain_module,
+                    admin::enroll_2fa,
+                    admin::flush_map_cache,
+                    admin::flush_module_cache,
+                    admin::get_all_modules,
+                    admin::get_latest_module,
+                    admin::get_me,
+                    admin::get_module,
+                    admin::get_module_logs,
+                    admin::get_queued_jobs,
+                    admin::index,
+                    admin::index_js,
+                    admin::index_no_session,
+                    admin::job_history,
+                    admin::login,
+                    admin::login_attempt_with_session,
+                    admin::login_index,
+                    admin::login_index_js,
+                    admin::login_with_session,
+                    admin::module_events,
+                    admin::new_map,
+                    admin::pause_module,
+                    admin::prune_modules,
+                    admin::recompute_map_metadata,
+                    admin::register_admin,
+                    admin::register_super_admin,
+                    admin::restart_module,
+                    admin::resume_module,
+                    admin::stop_module,
+                    admin::storage_usage,
+                    admin::upload_module,
+                    admin::verify_2fa,
+                    admin::verify_map,
+                    admin::verify_maps,
+                    algorithms::list,
+                    images,
+                    index,
+                    index_js,
+                    job::capacity,
+                    job::comparison_results,
+                    job::progress,
+                    job::result,
+                    job::status,
+                    job::submit,
+                    job::submit_and_wait,
+                    job::submit_comparison,
+                    job::validate,
+                    map::get_map,
+                    map::get_map_metadata,
+                    map::get_map_slope,
+                    map::get_maps,
+                    map::head_map,
+                    metrics::metrics,
+                ],
+                timeouts.default,
+                &timeouts.routes,
+            ),
         )
-        .mount("/images", StaticFiles::from("dist/images"))
+        .register(catchers())
+        .attach(request_id::RequestIdFairing)
+        .attach(metrics::MetricsFairing(metrics.clone()))
+        .attach(compression::CompressionFairing {
+            min_size: crate::CONFIG.web.compression.min_size,
+            //Validated when loading the configuration.
+            encodings: crate::CONFIG.web.compression.encodings().unwrap(),
+        })
+        .manage(metrics)
         .manage(pool)
         .manage(result_pool)
         .manage(docker)
@@ -3,29 +3,37 @@
 //Copyright (c) 2020 LAPS Group
 //Distributed under the zlib licence, see LICENCE.
 
-use rocket::response::{NamedFile, Redirect};
+use super::assets::{load_asset, Asset};
+use rocket::response::Redirect;
 use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
 
 mod adminsession;
 use super::mime_consts;
-use adminsession::AdminSession;
+pub use adminsession::{AdminSession, Permissions};
 
+mod jobs;
 mod login;
 mod map;
 mod modules;
+mod storage;
+mod twofactor;
 
 //Export all routes
+pub use jobs::*;
 pub use login::*;
 pub use map::*;
 pub use modules::*;
+pub use storage::*;
+pub use twofactor::*;
 
 #[cfg(test)]
 pub mod test;
 
 //Admin index with session: Show the page
 #[get("/admin")]
-pub async fn index(_session: AdminSession) -> Option<NamedFile> {
-    NamedFile::open("dist/admin.html").ok()
+pub async fn index(_session: AdminSession) -> Option<Asset> {
+    load_asset("admin.html").await
 }
 //Without the session: redirect to the login page
 #[get("/admin", rank = 2)]
@@ -34,11 +42,23 @@ pub async fn index_no_session() -> Redirect {
 }
 
 #[get("/admin.js")]
-pub fn index_js() -> Option<NamedFile> {
-    NamedFile::open("dist/admin.js").ok()
+pub async fn index_js() -> Option<Asset> {
+    load_asset("admin.js").await
+}
+
+//The signed in admin, along with what they are allowed to do.
+#[derive(Serialize, Deserialize)]
+pub struct Me {
+    #[serde(flatten)]
+    pub session: AdminSession,
+    pub permissions: Permissions,
 }
 
 #[get("/admin/me")]
-pub async fn get_me(session: AdminSession) -> Json<AdminSession> {
-    Json(session)
+pub async fn get_me(session: AdminSession) -> Json<Me> {
+    let permissions = session.permissions();
+    Json(Me {
+        session,
+        permissions,
+    })
 }
@@ -18,6 +18,31 @@ pub struct AdminSession {
     pub is_super: bool,
 }
 
+//What an admin is allowed to do, so that clients only show the controls which will work.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Permissions {
+    //Registering new admins.
+    pub can_manage_users: bool,
+    pub can_upload_modules: bool,
+    pub can_delete_maps: bool,
+    //Removing the stopped containers of every module at once.
+    pub can_prune_modules: bool,
+    pub can_view_storage: bool,
+}
+
+impl AdminSession {
+    //Get what the admin is allowed to do. Every admin manages modules and maps, while the rest is up to super admins.
+    pub fn permissions(&self) -> Permissions {
+        Permissions {
+            can_manage_users: self.is_super,
+            can_upload_modules: true,
+            can_delete_maps: true,
+            can_prune_modules: self.is_super,
+            can_view_storage: self.is_super,
+        }
+    }
+}
+
 #[rocket::async_trait]
 impl<'a, 'r> FromRequest<'a, 'r> for AdminSession {
     type Error = BackendError;
@@ -0,0 +1,30 @@
+//src/web/admin/jobs.rs: Admin endpoints for inspecting submitted jobs
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use super::AdminSession;
+use crate::{
+    history::{get_history, JobRecord},
+    types::BackendError,
+};
+use darkredis::ConnectionPool;
+use rocket::request::State;
+use rocket_contrib::json::Json;
+
+//The number of jobs listed when no limit is given.
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+//List up to `limit` of the most recently submitted jobs, newest first.
+#[get("/jobs/history?<limit>")]
+pub async fn job_history(
+    pool: State<'_, ConnectionPool>,
+    limit: Option<usize>,
+    _session: AdminSession,
+) -> Result<Json<Vec<JobRecord>>, BackendError> {
+    let limit = limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .min(crate::CONFIG.jobs.history_length);
+    let mut conn = pool.get().await;
+    Ok(Json(get_history(&mut conn, limit).await?))
+}
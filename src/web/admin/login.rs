@@ -4,22 +4,28 @@
 //Distributed under the zlib licence, see LICENCE.
 
 use super::AdminSession;
-use crate::{types::BackendError, util};
+use crate::{
+    types::{error_response, BackendError},
+    util,
+    web::{
+        assets::{load_asset, Asset},
+        request_id::RequestId,
+    },
+};
 use darkredis::{Command, Connection, ConnectionPool, MSetBuilder, Value};
 use futures::stream::StreamExt;
 use rand::RngCore;
 use rocket::{
     http::{Cookie, Cookies, SameSite, Status},
     request::{Form, State},
-    response::{NamedFile, Redirect},
+    response::Redirect,
     Response,
 };
-use std::io::Cursor;
 
 //Index stuff
 #[get("/login", rank = 2)]
-pub fn login_index() -> Option<NamedFile> {
-    NamedFile::open("dist/login.html").ok()
+pub async fn login_index() -> Option<Asset> {
+    load_asset("login.html").await
 }
 
 //For when the user is logged in, but tries to log in anyway.
@@ -30,14 +36,16 @@ pub fn login_with_session(_session: AdminSession) -> Redirect {
 }
 
 #[get("/login.js")]
-pub fn login_index_js() -> Option<NamedFile> {
-    NamedFile::open("dist/login.js").ok()
+pub async fn login_index_js() -> Option<Asset> {
+    load_asset("login.js").await
 }
 
 #[derive(FromForm)]
 pub struct AdminLogin {
     username: String,
     password: String,
+    //Only required for admins with two-factor authentication enabled.
+    totp: Option<String>,
 }
 
 //There's no reason to allow a user to log in if they already are logged in.
@@ -60,7 +68,11 @@ pub async fn login(
 
     let key = util::get_admin_key(&login.username);
     //TODO Replace with hmget builder in darkredis when that comes along
-    let command = Command::new("HMGET").arg(&key).arg(b"hash").arg(b"super");
+    let command = Command::new("HMGET")
+        .arg(&key)
+        .arg(b"hash")
+        .arg(b"super")
+        .arg(b"totp_secret");
 
     //Get the results
     let mut iter = conn.run_command(command).await?.unwrap_array().into_iter();
@@ -82,10 +94,38 @@ pub async fn login(
         .parse::<isize>()
         .unwrap()
         != 0;
+    //The TOTP secret only exists if the admin has enabled two-factor authentication.
+    let totp_secret = match iter.next().unwrap() {
+        Value::Nil => None,
+        v => Some(String::from_utf8_lossy(&v.unwrap_string()).into_owned()),
+    };
 
     //Verify that the password matches
     match argon2::verify_encoded(&hash, login.password.as_bytes()) {
         Ok(true) => {
+            //Verify the second factor as well if the admin has one.
+            if let Some(secret) = totp_secret {
+                let step = match &login.totp {
+                    Some(code) => super::verify_code(&secret, code),
+                    None => {
+                        //Let the client know that it has to ask for a code.
+                        return Ok(Status::Unauthorized);
+                    }
+                };
+                //Each code can only be used once, so a code which was already used counts as a failed attempt.
+                let accepted = match step {
+                    Some(step) => super::use_step(&mut conn, &key, step).await?,
+                    None => false,
+                };
+                if !accepted {
+                    warn!(
+                        "Failed two-factor authentication attempt for user {}",
+                        login.username
+                    );
+                    return Ok(Status::Forbidden);
+                }
+            }
+
             //yay!
             info!("Successfully authenticated admin {}", login.username);
 
@@ -113,12 +153,16 @@ pub async fn login(
             )
             .await?;
 
-            //Create and set session cookie
-            let cookie = Cookie::build("session-token", token)
+            //Create and set session cookie. The SameSite value is validated when loading the configuration.
+            let cookie_config = &crate::CONFIG.web.cookie;
+            let mut cookie = Cookie::build("session-token", token)
                 .http_only(true)
-                .same_site(SameSite::Strict)
-                .finish();
-            cookies.add_private(cookie);
+                .same_site(cookie_config.same_site().unwrap_or(SameSite::Strict))
+                .secure(crate::CONFIG.web.secure_cookies());
+            if let Some(domain) = &cookie_config.domain {
+                cookie = cookie.domain(domain.clone());
+            }
+            cookies.add_private(cookie.finish());
 
             //Done logging in!
             Ok(Status::NoContent)
@@ -150,26 +194,49 @@ async fn has_any_admins(conn: &mut Connection) -> Result<bool, BackendError> {
     Ok(!admins.is_empty())
 }
 
+//Check that `password` fulfills the password requirements in `config`. Returns which rule failed if it doesn't.
+pub(super) fn validate_password(
+    password: &str,
+    config: &crate::LoginConfig,
+) -> Result<(), &'static str> {
+    //Check that the password is not too long nor too short
+    if password.len() < config.minimum_password_length as usize {
+        Err("Password is too short!")
+    } else if password.len() > config.maximum_password_length as usize {
+        Err("Password is too long!")
+    } else if config.require_mixed_case
+        && !(password.chars().any(char::is_uppercase) && password.chars().any(char::is_lowercase))
+    {
+        Err("Password must contain both uppercase and lowercase letters!")
+    } else if config.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+        Err("Password must contain a digit!")
+    } else if config.require_symbol
+        && !password
+            .chars()
+            .any(|c| !c.is_alphanumeric() && !c.is_whitespace())
+    {
+        Err("Password must contain a symbol!")
+    } else {
+        Ok(())
+    }
+}
+
 //Insert an admin into the database, checking that the password is within the required limits.
 async fn insert_admin(
     conn: &mut Connection,
     username: &str,
     password: &str,
     is_super: bool,
+    request_id: &RequestId,
 ) -> Result<Response<'static>, BackendError> {
-    //Check that the password is not too long nor too short
-    let response = if password.len() < crate::CONFIG.login.minimum_password_length as usize {
-        Response::build()
-            .status(Status::BadRequest)
-            .sized_body(Cursor::new("Password is too short!"))
-            .await
-            .finalize()
-    } else if password.len() > crate::CONFIG.login.maximum_password_length as usize {
-        Response::build()
-            .status(Status::BadRequest)
-            .sized_body(Cursor::new("Password is too long!"))
-            .await
-            .finalize()
+    let response = if let Err(message) = validate_password(password, &crate::CONFIG.login) {
+        error_response(
+            Status::BadRequest,
+            "invalid_password",
+            message,
+            Some(request_id),
+        )
+        .await
     } else {
         let admin_key = util::get_admin_key(username);
         let config = argon2::Config::default();
@@ -191,15 +258,29 @@ async fn insert_admin(
 pub async fn register_super_admin(
     pool: State<'_, ConnectionPool>,
     login: Form<AdminLogin>,
-) -> Result<Response<'_>, BackendError> {
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
     let mut conn = pool.get().await;
     if has_any_admins(&mut conn).await? {
         //This endpoint may only be used by a non-admin during first time setup.
         warn!("Attempt to register a super admin, but we already have one!");
-        let response = Response::build().status(Status::Forbidden).finalize();
+        let response = error_response(
+            Status::Forbidden,
+            "forbidden",
+            "An administrator has already been registered",
+            Some(request_id),
+        )
+        .await;
         Ok(response)
     } else {
-        let response = insert_admin(&mut conn, &login.username, &login.password, true).await?;
+        let response = insert_admin(
+            &mut conn,
+            &login.username,
+            &login.password,
+            true,
+            request_id,
+        )
+        .await?;
         Ok(response)
     }
 }
@@ -209,9 +290,10 @@ pub async fn register_admin(
     pool: State<'_, ConnectionPool>,
     session: AdminSession,
     login: Form<AdminLogin>,
-) -> Result<Response<'_>, BackendError> {
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
     //This endpoint requires the admin to be a super admin.
-    if session.is_super {
+    if session.permissions().can_manage_users {
         let key = util::get_admin_key(&login.username);
         let mut conn = pool.get().await;
         //If the admin already exists, do not overwrite the existing account
@@ -220,18 +302,33 @@ pub async fn register_admin(
                 "Attempt to register admin {} which already exists!",
                 session.username
             );
-            Response::build()
-                .status(Status::Conflict)
-                .sized_body(Cursor::new("Admin already exists with that name."))
-                .await
-                .finalize()
+            error_response(
+                Status::Conflict,
+                "admin_exists",
+                "Admin already exists with that name.",
+                Some(request_id),
+            )
+            .await
         } else {
             //All is good, create a new admin, but do not make him a super admin.
             info!("Registed new admin {}", login.username);
-            insert_admin(&mut conn, &login.username, &login.password, false).await?
+            insert_admin(
+                &mut conn,
+                &login.username,
+                &login.password,
+                false,
+                request_id,
+            )
+            .await?
         };
         Ok(response)
     } else {
-        Ok(Response::build().status(Status::Forbidden).finalize())
+        Ok(error_response(
+            Status::Forbidden,
+            "forbidden",
+            "Only super admins can register admins",
+            Some(request_id),
+        )
+        .await)
     }
 }
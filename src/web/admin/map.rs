@@ -8,13 +8,24 @@ use super::AdminSession;
 use crate::{
     types::{BackendError, UserError},
     util,
-    web::multipart::MultipartForm,
+    web::{
+        map::{check_map_integrity, MapTypeSelector},
+        multipart::MultipartForm,
+    },
 };
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
-use darkredis::ConnectionPool;
-use rocket::{http::Status, request::State};
+use darkredis::{Command, CommandList, ConnectionPool, Value};
+use futures::TryStreamExt;
+use laps_convert::{
+    map_checksum, CancelToken, ConvertError, ImageMetadata, MapType, STORED_MAP_HASHES,
+};
+use rocket::{
+    http::Status,
+    request::{LenientForm, State},
+};
 use rocket_contrib::json::Json;
-use std::io::Write;
+use serde::{Deserialize, Serialize};
+use std::{io::Write, time::Duration};
 
 fn has_valid_tiff_header(input: &[u8]) -> bool {
     //Instead of verifying everything in the TIFF file to be valid, just check if the TIFF header is there.
@@ -45,14 +56,39 @@ fn has_valid_tiff_header(input: &[u8]) -> bool {
     }
 }
 
+//The response to uploading a map, including its metadata so that clients can render it right away.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewMap {
+    pub id: u32,
+    pub metadata: ImageMetadata,
+}
+
 #[post("/map", data = "<upload>")]
 pub async fn new_map(
     pool: State<'_, ConnectionPool>,
     mut upload: MultipartForm,
     session: AdminSession,
-) -> Result<Json<u32>, UserError> {
+) -> Result<Json<NewMap>, UserError> {
     let mut conn = pool.get().await;
     let data = upload.get_file(&mime_consts::IMAGE_TIFF, "data")?;
+    //Optionally compute the slope of the map as well.
+    let options = laps_convert::ConvertOptions {
+        format: crate::CONFIG.maps.format().unwrap(),
+        slope: upload
+            .get_text("slope")
+            .map(|s| s.trim() == "true")
+            .unwrap_or(false),
+        ..Default::default()
+    };
+    //The type of the map picks the ids it's given, elevation unless told otherwise.
+    let map_type = match upload.get_text("type") {
+        Ok(name) => name
+            .trim()
+            .to_lowercase()
+            .parse::<MapType>()
+            .map_err(|name| UserError::BadType(name, MapType::names()))?,
+        Err(_) => MapType::Elevation,
+    };
 
     //Do a quick and dirty check that the file has the TIF image header
     if !has_valid_tiff_header(&data) {
@@ -62,60 +98,483 @@ pub async fn new_map(
     //Put the map into a temporary file. This is needed because GDAL does not allow us to give it a buffer, it has
     //to be put into some sort of file, which is reflected in the laps_convert API.
     //Using blocking IO for this because it is literally a thousand times faster than tokio::fs::File.
-    let (image, metadata) =
-        tokio::task::spawn_blocking(move || {
-            match tempfile::NamedTempFile::new()
-                .map_err(|e| UserError::Internal(BackendError::Io(e)))
-            {
-                Ok(o) => {
-                    let (mut file, path) = o.into_parts();
-                    file.write_all(data.as_slice())
-                        .expect("writing map data to temporary file");
-
-                    laps_convert::convert_to_png(path).map_err(UserError::MapConvert)
-                }
-                Err(e) => Err(e),
-            }
-        })
-        .await
-        .expect("spawn_blocking")?;
+    let path = tokio::task::spawn_blocking(move || {
+        let (mut file, path) = tempfile::NamedTempFile::new()?.into_parts();
+        file.write_all(data.as_slice())?;
+        Ok::<_, std::io::Error>(path)
+    })
+    .await
+    .expect("spawn_blocking")
+    .map_err(|e| UserError::Internal(BackendError::Io(e)))?;
+
+    //Bound the time spent converting so that huge maps can't tie up the blocking threads forever.
+    let timeout = Duration::from_secs(crate::CONFIG.maps.conversion_timeout);
+    let (image, mut metadata) = convert_with_timeout(
+        move |token| laps_convert::convert_to_png_cancellable(path, &options, token),
+        timeout,
+    )
+    .await
+    .map_err(UserError::MapConvert)?;
+    //The importer records the type as well, but the metadata is also sent back as is.
+    metadata.map_type = Some(map_type);
 
     //Use the proper testing keys in test mode
-    let result = if cfg!(test) {
-        laps_convert::import_data_test(&mut conn, image, metadata)
-            .await
-            .expect("importing map data")
+    let quota = crate::CONFIG.maps.quota();
+    let id = if cfg!(test) {
+        laps_convert::import_data_test(&mut conn, map_type, image, metadata.clone(), &quota).await?
     } else {
-        laps_convert::import_data(&mut conn, image, metadata)
-            .await
-            .expect("importing map data")
+        laps_convert::import_data(&mut conn, map_type, image, metadata.clone(), &quota).await?
     };
 
     info!(
-        "Admin {} uploaded a new map with ID {}",
-        session.username, result
+        "Admin {} uploaded a new {} map with ID {}",
+        session.username, map_type, id
+    );
+
+    Ok(Json(NewMap { id, metadata }))
+}
+
+//Run `convert` on a blocking thread, failing with `ConvertError::Timeout` if it takes longer than `timeout`. The token
+//given to `convert` is cancelled when the timeout expires or the returned future is dropped. GDAL cannot be
+//interrupted while it is reading the raster, so the blocking thread may keep running for a while afterwards.
+pub(super) async fn convert_with_timeout<T, F>(
+    convert: F,
+    timeout: Duration,
+) -> Result<T, ConvertError>
+where
+    F: FnOnce(&CancelToken) -> Result<T, ConvertError> + Send + 'static,
+    T: Send + 'static,
+{
+    //Cancels the conversion when dropped, which happens when the future is dropped before it completes.
+    struct CancelOnDrop(CancelToken);
+    impl Drop for CancelOnDrop {
+        fn drop(&mut self) {
+            self.0.cancel();
+        }
+    }
+
+    let token = CancelToken::new();
+    let guard = CancelOnDrop(token.clone());
+    let task = tokio::task::spawn_blocking(move || convert(&token));
+    let result = match tokio::time::timeout(timeout, task).await {
+        Ok(result) => result.expect("spawn_blocking"),
+        Err(_) => {
+            warn!(
+                "Conversion did not finish within {:?}, cancelling it",
+                timeout
+            );
+            Err(ConvertError::Timeout(timeout))
+        }
+    };
+    drop(guard);
+    result
+}
+
+//The Redis keys of every hash holding part of a map of `map_type`.
+fn map_hash_keys(map_type: MapType) -> Vec<String> {
+    laps_convert::MAP_HASHES
+        .iter()
+        .map(|suffix| util::get_mapdata_key(map_type, suffix))
+        .collect()
+}
+
+//Get the number of bytes stored for each map of `map_type` in `ids`, counting the image, slope and metadata.
+pub(super) async fn map_sizes(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    ids: &[String],
+) -> Result<Vec<isize>, BackendError> {
+    let mut keys = map_hash_keys(map_type);
+    keys.truncate(STORED_MAP_HASHES);
+    let mut pairs = ids
+        .iter()
+        .flat_map(|id| keys.iter().map(move |key| (key, id)));
+    let (key, id) = match pairs.next() {
+        Some(p) => p,
+        None => return Ok(Vec::new()),
+    };
+    let commands = pairs.fold(
+        CommandList::new("HSTRLEN").arg(key).arg(id),
+        |commands, (key, id)| commands.command("HSTRLEN").arg(key).arg(id),
     );
+    let sizes: Vec<isize> = conn
+        .run_commands(commands)
+        .await?
+        .map_ok(Value::unwrap_integer)
+        .try_collect()
+        .await?;
 
-    Ok(Json(result))
+    Ok(sizes.chunks(keys.len()).map(|c| c.iter().sum()).collect())
 }
 
-#[delete("/map/<id>")]
+//Subtract `bytes` from the storage used by maps of `map_type`. If the total hasn't been counted yet it's left alone,
+//as the next import counts it from scratch.
+async fn release_map_storage(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    bytes: isize,
+) -> Result<(), BackendError> {
+    let key = util::get_mapdata_key(map_type, "bytes");
+    if bytes > 0 && conn.exists(&key).await? {
+        let command = Command::new("DECRBY").arg(&key).arg(&bytes.to_string());
+        conn.run_command(command).await?;
+    }
+    Ok(())
+}
+
+#[delete("/map/<id>?<selector..>")]
 pub async fn delete_map(
     pool: State<'_, ConnectionPool>,
     session: AdminSession,
     id: i32,
-) -> Result<Status, BackendError> {
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Status, UserError> {
     //We're already authenticated, just get rid of the map in question.
+    let map_type = selector.map_type()?;
     let mut conn = pool.get().await;
-    let image_key = util::create_redis_key("mapdata.image");
-    let meta_key = util::create_redis_key("mapdata.meta");
-    let id = id.to_string();
-    if conn.hdel(image_key, &id).await? {
-        //Don't really care what the result of this is
-        let _ = conn.hdel(meta_key, &id).await?;
-        info!("Map {} deleted by {}", id, session.username);
+    let out = delete_map_ids(&mut conn, map_type, vec![id], &session.username).await?;
+    if out[0].deleted {
         Ok(Status::NoContent)
     } else {
         Ok(Status::NotFound)
     }
 }
+
+//Fill in the metadata fields of a map which were added after it was imported, as far as they can be derived from its
+//PNG. Responds with the updated metadata.
+//A missing checksum is only computed from the stored PNG if `trust` is set, as the admin vouching for the current data
+//being intact. Otherwise a map which was corrupted before it got a checksum would be reported as intact from then on.
+#[post("/map/<id>/recompute-meta?<trust>&<selector..>")]
+pub async fn recompute_map_metadata(
+    pool: State<'_, ConnectionPool>,
+    session: AdminSession,
+    id: i32,
+    trust: Option<bool>,
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Option<Json<ImageMetadata>>, UserError> {
+    let trust = trust.unwrap_or(false);
+    let map_type = selector.map_type()?;
+    let mut conn = pool.get().await;
+    let image_key = util::get_mapdata_key(map_type, "image");
+    let meta_key = util::get_mapdata_key(map_type, "meta");
+    let id = id.to_string();
+    let (image, old) = match (
+        conn.hget(&image_key, &id).await?,
+        conn.hget(&meta_key, &id).await?,
+    ) {
+        (Some(image), Some(meta)) => (image, meta),
+        _ => return Ok(None),
+    };
+
+    //Decoding large maps takes a while, so keep it off the async threads.
+    let mut metadata: ImageMetadata = serde_json::from_slice(&old)?;
+    let (metadata, changed) = tokio::task::spawn_blocking(move || {
+        let mut changed = metadata.complete_from_png(&image)?;
+        if trust && metadata.checksum.is_none() {
+            metadata.checksum = Some(map_checksum(&image));
+            changed = true;
+        }
+        Ok::<_, ConvertError>((metadata, changed))
+    })
+    .await
+    .expect("spawn_blocking")
+    .map_err(|e| BackendError::Other(format!("Invalid image for map {}: {}", id, e)))?;
+
+    if changed {
+        let new = serde_json::to_vec(&metadata)?;
+        conn.hset(&meta_key, &id, &new).await?;
+        //Keep the storage used by maps in step with the size of the metadata.
+        let bytes_key = util::get_mapdata_key(map_type, "bytes");
+        let growth = new.len() as isize - old.len() as isize;
+        if growth != 0 && conn.exists(&bytes_key).await? {
+            let command = Command::new("INCRBY")
+                .arg(&bytes_key)
+                .arg(&growth.to_string());
+            conn.run_command(command).await?;
+        }
+        info!(
+            "Metadata of {} map {} recomputed by {}, trusting its data: {}",
+            map_type, id, session.username, trust
+        );
+    }
+
+    Ok(Some(Json(metadata)))
+}
+
+//The maps to delete in a bulk deletion.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MapDeletionRequest {
+    pub ids: Vec<i32>,
+}
+
+//Whether a single map of a bulk deletion was deleted, or didn't exist in the first place.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MapDeletionResult {
+    pub id: i32,
+    pub deleted: bool,
+}
+
+//Get the integer replies of the commands in a transaction from `reply`, the reply to EXEC. Fails unless there is one
+//for each of the `expected` commands, such as when the transaction was aborted.
+pub(super) fn exec_integers(
+    reply: Option<Value>,
+    expected: usize,
+) -> Result<Vec<isize>, BackendError> {
+    let replies = match reply {
+        Some(Value::Array(replies)) => replies,
+        other => {
+            return Err(BackendError::Other(format!(
+                "Unexpected reply to EXEC: {:?}",
+                other
+            )))
+        }
+    };
+    let integers = replies
+        .into_iter()
+        .map(|v| match v {
+            Value::Integer(i) => Ok(i),
+            other => Err(BackendError::Other(format!(
+                "Unexpected reply in transaction: {:?}",
+                other
+            ))),
+        })
+        .collect::<Result<Vec<isize>, BackendError>>()?;
+    if integers.len() != expected {
+        return Err(BackendError::Other(format!(
+            "Expected {} replies in transaction, got {}",
+            expected,
+            integers.len()
+        )));
+    }
+    Ok(integers)
+}
+
+//Delete every map of `map_type` in `ids` along with every cached job which ran on them, reporting which maps existed.
+async fn delete_map_ids(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    ids: Vec<i32>,
+    username: &str,
+) -> Result<Vec<MapDeletionResult>, BackendError> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let keys = map_hash_keys(map_type);
+    let fields: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+
+    //Measure and delete every part of the maps in a single transaction, so that a concurrent deletion can't leave a
+    //map half removed or have its storage released twice.
+    let mut commands = CommandList::new("MULTI");
+    for field in &fields {
+        for key in &keys[..STORED_MAP_HASHES] {
+            commands = commands.command("HSTRLEN").arg(key).arg(field);
+        }
+    }
+    for field in &fields {
+        for key in &keys {
+            commands = commands.command("HDEL").arg(key).arg(field);
+        }
+    }
+    commands = commands.command("EXEC");
+    let reply = conn
+        .run_commands(commands)
+        .await?
+        .try_collect::<Vec<Value>>()
+        .await?
+        .pop();
+    let results = exec_integers(reply, fields.len() * (STORED_MAP_HASHES + keys.len()))?;
+    let (sizes, deletions) = results.split_at(fields.len() * STORED_MAP_HASHES);
+    let sizes = sizes
+        .chunks(STORED_MAP_HASHES)
+        .map(|c| c.iter().sum::<isize>());
+    //The first deletion of each map is its image, which tells whether it existed.
+    let existed = deletions.chunks(keys.len()).map(|c| c[0] == 1);
+
+    let mut out = Vec::with_capacity(ids.len());
+    let mut released = 0;
+    for ((id, deleted), size) in ids.into_iter().zip(existed).zip(sizes) {
+        if deleted {
+            released += size;
+            let flushed =
+                util::delete_matching_keys(conn, &util::get_map_cache_pattern(map_type, id))
+                    .await?;
+            info!(
+                "{} map {} deleted by {}, along with {} cache entries",
+                map_type, id, username, flushed
+            );
+        }
+        out.push(MapDeletionResult { id, deleted });
+    }
+    release_map_storage(conn, map_type, released).await?;
+
+    Ok(out)
+}
+
+//Delete several maps at once, along with every cached job which ran on them.
+#[post("/maps/delete?<selector..>", format = "json", data = "<request>")]
+pub async fn delete_maps(
+    pool: State<'_, ConnectionPool>,
+    session: AdminSession,
+    request: Json<MapDeletionRequest>,
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Json<Vec<MapDeletionResult>>, UserError> {
+    let map_type = selector.map_type()?;
+    //Only delete each map once, otherwise a repeated id would be reported as both deleted and missing.
+    let mut ids: Vec<i32> = Vec::with_capacity(request.ids.len());
+    for id in &request.ids {
+        if !ids.contains(id) {
+            ids.push(*id);
+        }
+    }
+
+    let mut conn = pool.get().await;
+    let out = delete_map_ids(&mut conn, map_type, ids, &session.username).await?;
+    Ok(Json(out))
+}
+
+//Delete every map in `group` at once, along with every cached job which ran on them.
+#[delete("/maps/group/<group>?<selector..>")]
+pub async fn delete_map_group(
+    pool: State<'_, ConnectionPool>,
+    session: AdminSession,
+    group: String,
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Option<Json<Vec<MapDeletionResult>>>, UserError> {
+    let map_type = selector.map_type()?;
+    let mut conn = pool.get().await;
+    let ids: Vec<i32> = crate::web::map::get_group_maps(&mut conn, map_type, &group)
+        .await?
+        .into_iter()
+        .filter_map(|id| id.parse().ok())
+        .collect();
+    if ids.is_empty() {
+        return Ok(None);
+    }
+
+    let out = delete_map_ids(&mut conn, map_type, ids, &session.username).await?;
+    info!(
+        "{} map group {} with {} maps deleted by {}",
+        map_type,
+        group,
+        out.len(),
+        session.username
+    );
+    Ok(Some(Json(out)))
+}
+
+//Delete every cached job which ran on the map `id`, returning the number of deleted cache entries.
+#[delete("/map/<id>/cache?<selector..>")]
+pub async fn flush_map_cache(
+    pool: State<'_, ConnectionPool>,
+    session: AdminSession,
+    id: i32,
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Json<usize>, UserError> {
+    let map_type = selector.map_type()?;
+    let mut conn = pool.get().await;
+    let pattern = util::get_map_cache_pattern(map_type, id);
+    let deleted = util::delete_matching_keys(&mut conn, &pattern).await?;
+    info!(
+        "{} flushed {} cache entries for {} map {}",
+        session.username, deleted, map_type, id
+    );
+    Ok(Json(deleted))
+}
+
+//Whether the stored data of a map matches the checksum recorded when it was imported.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MapIntegrity {
+    Intact,
+    Corrupted,
+    //The map has no checksum to check it against, because it was imported before checksums were recorded and nobody
+    //has vouched for its data since.
+    Unverified,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MapIntegrityResult {
+    pub id: String,
+    pub integrity: MapIntegrity,
+}
+
+//Check the stored data of map `id` of `map_type` against its checksum, returning None if the map doesn't exist.
+async fn verify_map_id(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    id: String,
+) -> Result<Option<MapIntegrityResult>, BackendError> {
+    let data = match conn
+        .hget(util::get_mapdata_key(map_type, "image"), &id)
+        .await?
+    {
+        Some(d) => d,
+        None => return Ok(None),
+    };
+    let integrity = match check_map_integrity(conn, map_type, &id, &data).await? {
+        Some(true) => MapIntegrity::Intact,
+        Some(false) => {
+            error!(
+                "The data of {} map {} doesn't match its checksum",
+                map_type, id
+            );
+            MapIntegrity::Corrupted
+        }
+        None => MapIntegrity::Unverified,
+    };
+    Ok(Some(MapIntegrityResult { id, integrity }))
+}
+
+//Check the stored data of a map against the checksum recorded when it was imported.
+#[get("/map/<id>/verify?<selector..>")]
+pub async fn verify_map(
+    pool: State<'_, ConnectionPool>,
+    _session: AdminSession,
+    id: i32,
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Option<Json<MapIntegrityResult>>, UserError> {
+    let map_type = selector.map_type()?;
+    let mut conn = pool.get().await;
+    Ok(verify_map_id(&mut conn, map_type, id.to_string())
+        .await?
+        .map(Json))
+}
+
+//Check the stored data of every map of a type against its checksum. Reads every map, so it can take a while.
+#[get("/maps/verify?<selector..>")]
+pub async fn verify_maps(
+    pool: State<'_, ConnectionPool>,
+    session: AdminSession,
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Json<Vec<MapIntegrityResult>>, UserError> {
+    let map_type = selector.map_type()?;
+    let mut conn = pool.get().await;
+    let mut ids: Vec<i32> = conn
+        .hkeys(util::get_mapdata_key(map_type, "image"))
+        .await?
+        .into_iter()
+        .filter_map(|id| String::from_utf8_lossy(&id).parse().ok())
+        .collect();
+    ids.sort_unstable();
+
+    let mut out = Vec::with_capacity(ids.len());
+    for id in ids {
+        //Maps can be deleted while the others are checked.
+        if let Some(result) = verify_map_id(&mut conn, map_type, id.to_string()).await? {
+            out.push(result);
+        }
+    }
+    let corrupted = out
+        .iter()
+        .filter(|r| r.integrity == MapIntegrity::Corrupted)
+        .count();
+    info!(
+        "{} verified {} {} maps, {} of which are corrupted",
+        session.username,
+        out.len(),
+        map_type,
+        corrupted
+    );
+    Ok(Json(out))
+}
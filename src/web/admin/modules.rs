@@ -6,26 +6,25 @@
 use super::mime_consts;
 use super::AdminSession;
 use crate::{
-    module_handling::ModuleInfo,
-    types::{BackendError, UserError},
-    util,
-    web::multipart::{FormError, MultipartForm},
-};
-use bollard::{
-    container::{
-        APIContainers, Config, CreateContainerOptions, HostConfig, InspectContainerOptions,
-        ListContainersOptions, RemoveContainerOptions, RestartContainerOptions,
-        StartContainerOptions, StopContainerOptions,
+    docker::{BuildFailure, Container, ContainerSpec, DockerBackend, SharedDocker},
+    module_handling::{
+        cancel_queued_jobs, clear_job_deadline, compare_versions, find_latest_version,
+        get_module_map_types, log_line_time, set_job_deadline, ModuleInfo,
     },
-    errors::ErrorKind,
-    image::{
-        APIImages, BuildImageOptions, BuildImageResults, ListImagesOptions, RemoveImageOptions,
-        RemoveImageResults,
+    types::{error_response, BackendError, UserError},
+    util,
+    web::{
+        job::{get_module_timeout, JobInfo},
+        multipart::{FormError, MultipartForm},
+        request_id::RequestId,
+        sse::{self, EventStream},
     },
-    Docker,
 };
-use darkredis::ConnectionPool;
+use chrono::{DateTime, SecondsFormat, Utc};
+use darkredis::{Command, ConnectionPool, MSetBuilder, Value};
 use futures::stream::{StreamExt, TryStreamExt};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use laps_convert::MapType;
 use rocket::{
     http::{ContentType, Status},
     request::State,
@@ -33,41 +32,86 @@ use rocket::{
 };
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
-use std::io::Cursor;
+use std::{io::Cursor, time::Duration};
+
+//The header holding the time of the newest line of a module log.
+const LAST_LOG_TIME_HEADER: &str = "X-Last-Log-Time";
 
-#[get("/module/<name>/<version>/logs")]
+//The response for a request about a module which doesn't exist.
+async fn module_not_found(module: &ModuleInfo, request_id: &RequestId) -> Response<'static> {
+    let message = format!("Module {} does not exist", module);
+    error_response(Status::NotFound, "not_found", &message, Some(request_id)).await
+}
+
+//Get the logs of a module. Only the last `max_log_lines` lines are kept, older lines are dropped.
+//With `since`, an RFC 3339 timestamp, only the lines logged at or after it are returned. The time of the newest line is
+//sent in the X-Last-Log-Time header, so a client can fetch new lines by passing it back as `since`. Log times are only
+//precise to the second, so lines logged in the same second as the newest line are returned again, and clients have to
+//drop the lines they already have.
+#[get("/module/<name>/<version>/logs?<since>")]
 pub async fn get_module_logs<'a>(
     pool: State<'a, ConnectionPool>,
-    docker: State<'a, Docker>,
+    docker: State<'a, SharedDocker>,
     name: String,
     version: String,
+    since: Option<String>,
     _session: AdminSession,
+    request_id: &'a RequestId,
 ) -> Result<Response<'a>, BackendError> {
+    let since = match since.map(|s| DateTime::parse_from_rfc3339(&s)) {
+        Some(Ok(t)) => Some(t.with_timezone(&Utc)),
+        Some(Err(e)) => {
+            let message = format!("Invalid timestamp: {}", e);
+            return Ok(error_response(
+                Status::BadRequest,
+                "invalid_since",
+                &message,
+                Some(request_id),
+            )
+            .await);
+        }
+        None => None,
+    };
+
     //Find out if the module exists
     let module = ModuleInfo { name, version };
-    if module_exists(&docker, &module).await? {
+    if module_exists(&**docker, &module).await? {
         let mut conn = pool.get().await;
         let log_key = util::get_module_log_key(&module);
-        //Get all the elements of the log and concatenate them.
-        let out =
-            conn.lrange(log_key, 0, -1)
-                .await?
-                .into_iter()
-                .fold(Vec::new(), |mut out, mut x| {
-                    out.append(&mut x);
-                    out.push(b'\n');
-                    out
-                });
+        let lines = conn.lrange(log_key, 0, -1).await?;
+        let last_time = lines.last().map(Vec::as_slice).and_then(log_line_time);
+
+        //Lines are appended as they are logged, so the new lines are the ones after the last line which is older.
+        let start = match since {
+            Some(since) => lines
+                .iter()
+                .rposition(|l| log_line_time(l).map_or(true, |t| t < since))
+                .map_or(0, |i| i + 1),
+            None => 0,
+        };
+        //Concatenate the lines.
+        let out = lines[start..].iter().fold(Vec::new(), |mut out, x| {
+            out.extend_from_slice(x);
+            out.push(b'\n');
+            out
+        });
 
         let cursor = Cursor::new(out);
-        Ok(Response::build()
+        let mut response = Response::build()
             .status(Status::Ok)
             .header(ContentType::Plain)
             .sized_body(cursor)
             .await
-            .finalize())
+            .finalize();
+        if let Some(time) = last_time {
+            response.set_raw_header(
+                LAST_LOG_TIME_HEADER,
+                time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            );
+        }
+        Ok(response)
     } else {
-        Ok(Response::build().status(Status::NotFound).finalize())
+        Ok(module_not_found(&module, request_id).await)
     }
 }
 
@@ -77,6 +121,8 @@ pub async fn get_module_logs<'a>(
 #[serde(tag = "state")]
 pub enum ModuleState {
     Running,
+    //The workers are paused, and pick up their queued jobs once resumed.
+    Paused,
     Stopped,
     Failed { exit_code: i32 },
     //A module that is partially stopped or failed.
@@ -90,9 +136,13 @@ pub struct PathModule {
     pub state: ModuleState,
     #[serde(flatten)]
     pub module: ModuleInfo,
+    //How many workers the module was configured to run with.
+    pub configured_workers: u8,
+    //How many workers are currently registered.
+    pub active_workers: i64,
 }
 
-fn extract_module_info_from_tag(tag: &str) -> Option<ModuleInfo> {
+pub(super) fn extract_module_info_from_tag(tag: &str) -> Option<ModuleInfo> {
     //A valid tag will always have the format "a:b"
     tag.find(':')
         .map(|s| {
@@ -111,9 +161,9 @@ fn extract_module_info_from_tag(tag: &str) -> Option<ModuleInfo> {
 }
 
 //Get a list of the running modules
-async fn running_modules(docker: &Docker) -> Result<Vec<ModuleInfo>, BackendError> {
+async fn running_modules(docker: &dyn DockerBackend) -> Result<Vec<ModuleInfo>, BackendError> {
     Ok(docker
-        .list_containers(None::<ListContainersOptions<String>>)
+        .list_containers(false, false)
         .await?
         .into_iter()
         .map(|s| extract_module_info_from_tag(&s.image).unwrap())
@@ -122,49 +172,60 @@ async fn running_modules(docker: &Docker) -> Result<Vec<ModuleInfo>, BackendErro
 
 //Get all modules along with their container options.
 async fn list_all_modules(
-    docker: &Docker,
-) -> Result<Vec<(ModuleInfo, APIContainers)>, BackendError> {
-    let options = ListContainersOptions::<String> {
-        all: true,
-        ..Default::default()
-    };
+    docker: &dyn DockerBackend,
+) -> Result<Vec<(ModuleInfo, Container)>, BackendError> {
     Ok(docker
-        .list_containers(Some(options))
+        .list_containers(true, false)
         .await?
         .into_iter()
         .filter_map(|m| extract_module_info_from_tag(&m.image).map(|i| (i, m)))
         .collect())
 }
 
+//Get the module info of every tagged image.
+async fn list_module_images(docker: &dyn DockerBackend) -> Result<Vec<ModuleInfo>, BackendError> {
+    Ok(docker
+        .list_image_tags()
+        .await?
+        .into_iter()
+        .filter_map(|t| extract_module_info_from_tag(&t))
+        .collect())
+}
+
 //Check if a module exists.
-pub async fn module_exists(docker: &Docker, module: &ModuleInfo) -> Result<bool, BackendError> {
-    //Get a list of all modules
-    let images: Vec<APIImages> = docker
-        .list_images(None::<ListImagesOptions<String>>)
-        .await
-        .map_err(BackendError::Docker)?;
-    //Figure out if module with name `name` and version `version` is in that list.
-    Ok(images.into_iter().any(|i| {
-        if let Some(t) = i.repo_tags {
-            t.into_iter()
-                .map(|s| extract_module_info_from_tag(&s))
-                .any(|s| s.as_ref() == Some(module))
-        } else {
-            false
-        }
-    }))
+pub async fn module_exists(
+    docker: &dyn DockerBackend,
+    module: &ModuleInfo,
+) -> Result<bool, BackendError> {
+    //Figure out if module with name `name` and version `version` is in the list of all modules.
+    Ok(list_module_images(docker).await?.contains(module))
+}
+
+//Get the highest version of the module called `name`.
+#[get("/module/<name>/latest")]
+pub async fn get_latest_module(
+    docker: State<'_, SharedDocker>,
+    name: String,
+    _session: AdminSession,
+) -> Result<Option<Json<ModuleInfo>>, BackendError> {
+    let modules = list_module_images(&**docker).await?;
+    Ok(find_latest_version(&modules, &name).cloned().map(Json))
 }
 
 //Check if a module is running
-pub async fn module_is_running(docker: &Docker, module: &ModuleInfo) -> Result<bool, BackendError> {
-    let running_modules = running_modules(&docker).await?;
+pub async fn module_is_running(
+    docker: &dyn DockerBackend,
+    module: &ModuleInfo,
+) -> Result<bool, BackendError> {
+    let running_modules = running_modules(docker).await?;
     Ok(running_modules.iter().any(|m| m == module))
 }
 
 //Get a pathfinding module's state from `container`.
-fn get_container_state(container: &APIContainers) -> ModuleState {
+fn get_container_state(container: &Container) -> ModuleState {
     match container.state.as_str() {
         "running" => ModuleState::Running,
+        "paused" => ModuleState::Paused,
         "exited" => {
             //If exited, check the exit code. There doesn't seem to be a good way to do this,
             //so assume that the format won't change.
@@ -196,113 +257,498 @@ fn get_container_state(container: &APIContainers) -> ModuleState {
     }
 }
 
+//Compile the module ignore list, where each entry is a glob pattern matched against module names.
+pub(super) fn build_ignore_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+lazy_static! {
+    //The patterns are validated when the configuration is loaded, so this can't fail.
+    static ref IGNORED_MODULES: GlobSet =
+        build_ignore_set(&crate::CONFIG.module.ignore).expect("compiling module ignore list");
+}
+
+//Check whether `module` is hidden from the admin panel module list.
+pub(super) fn is_ignored(module: &ModuleInfo) -> bool {
+    IGNORED_MODULES.is_match(&module.name)
+}
+
+//Combine the states of every container of a module into the state of the module.
+fn aggregate_module_state(states: Vec<ModuleState>) -> ModuleState {
+    //If we found no containers, the module was never started.
+    if states.is_empty() {
+        ModuleState::Stopped
+    } else {
+        //If all containers have the same state we can just forward that.
+        let last = states.first().unwrap(); // already did the bounds check.
+        if states.iter().all(|s| s == last) {
+            last.clone()
+        } else {
+            //If not we have to build the response string.
+            //Struct containing the state of all the containers.
+            #[derive(Default)]
+            struct ContainerStates {
+                running: i32,
+                paused: i32,
+                stopped: i32,
+                failed: i32,
+                exit_codes: Vec<i32>,
+            };
+            let mut states =
+                states
+                    .into_iter()
+                    .fold(ContainerStates::default(), |mut acc, state| {
+                        match state {
+                            ModuleState::Running => acc.running += 1,
+                            ModuleState::Paused => acc.paused += 1,
+                            ModuleState::Stopped => acc.stopped += 1,
+                            ModuleState::Failed { exit_code } => {
+                                acc.failed += 1;
+                                acc.exit_codes.push(exit_code);
+                            }
+                            //The only way for this to happen is if the get_container_state function is broken
+                            _ => unreachable!(),
+                        }
+                        acc
+                    });
+            //Avoid duplicates in the exit codes
+            states.exit_codes.sort_unstable();
+            states.exit_codes.dedup();
+
+            //Convert the states into a nice string
+            let workers = states.running + states.paused + states.stopped + states.failed;
+            let mut message = format!("{}/{} running", states.running, workers);
+            if states.paused > 0 {
+                message += &format!(", {} paused", states.paused);
+            }
+            if states.stopped > 0 {
+                message += &format!(", {} stopped", states.stopped);
+            }
+            if states.failed > 0 {
+                message += &format!(
+                    ", {} failures with exit codes {:?}",
+                    states.failed, states.exit_codes
+                );
+            }
+            ModuleState::Other { message }
+        }
+    }
+}
+
+//Stream module state changes to the admin panel as server-sent events, see `ModuleEvent` for the payload.
+#[get("/module/events")]
+pub async fn module_events(
+    pool: State<'_, ConnectionPool>,
+    session: AdminSession,
+) -> Result<Response<'static>, BackendError> {
+    //Subscribing takes over the connection, so every client needs a connection of its own.
+    let conn = pool.spawn("module-events").await?;
+    let mut messages = conn.subscribe(&[util::get_module_events_channel()]).await?;
+    let (mut sender, stream) = EventStream::new();
+
+    info!("{} subscribed to module events", session.username);
+    tokio::spawn(async move {
+        let mut keepalive = tokio::time::interval(sse::KEEPALIVE_INTERVAL);
+        loop {
+            let event = tokio::select! {
+                message = messages.next() => match message {
+                    Some(m) => sse::format_event(&String::from_utf8_lossy(&m.message)),
+                    None => break,
+                },
+                _ = keepalive.tick() => sse::keepalive(),
+            };
+            //Sending only fails once the client has disconnected. The keepalives make sure that this is noticed, and
+            //the subscription closed, even when no modules change state.
+            if sender.send(event).await.is_err() {
+                break;
+            }
+        }
+        debug!("{} unsubscribed from module events", session.username);
+    });
+
+    Ok(stream.into_response())
+}
+
 #[get("/module/all")]
 pub async fn get_all_modules(
-    docker: State<'_, Docker>,
+    docker: State<'_, SharedDocker>,
+    pool: State<'_, ConnectionPool>,
     _session: AdminSession,
 ) -> Result<Json<Vec<PathModule>>, BackendError> {
     //Mostly just list available docker images to create
-    let images: Vec<APIImages> = docker
-        .list_images(None::<ListImagesOptions<String>>)
-        .await?;
+    let tags = docker.list_image_tags().await?;
 
-    let all_modules = list_all_modules(&docker).await?;
+    let all_modules = list_all_modules(&**docker).await?;
 
     let mut out = Vec::new();
-    for image in images {
-        //For each tag, grab the module information so that we display all modules, even those with identical images.
-        if let Some(tags) = image.repo_tags {
-            for tag in tags {
-                //If there is no module info for this image, this can fail. `ApiImage::repo_tags`
-                //has a confusing type signature for sure...
-                let module = match extract_module_info_from_tag(&tag) {
-                    Some(m) => m,
-                    None => continue,
-                };
-
-                //Skip this module if it is in the ignore list.
-                if (*crate::CONFIG).module.ignore.contains(&module.name) {
-                    continue;
-                }
+    //Go through every tag so that we display all modules, even those with identical images.
+    for tag in tags {
+        //Untagged images have no module info.
+        let module = match extract_module_info_from_tag(&tag) {
+            Some(m) => m,
+            None => continue,
+        };
 
-                //Get the state of all containers with this tag, i.e all containers created from the same module image.
-                //And fold it into  a containerstates struct.
-                let states: Vec<ModuleState> = all_modules
-                    .iter()
-                    .filter_map(|(m, container)| {
-                        if m == &module {
-                            Some(get_container_state(&container))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-                //If we found no containers, the module was never started.
-                let state = if states.is_empty() {
-                    ModuleState::Stopped
+        //Skip this module if it is in the ignore list.
+        if is_ignored(&module) {
+            continue;
+        }
+
+        //Get the state of all containers with this tag, i.e all containers created from the same module image.
+        //And fold it into  a containerstates struct.
+        let states: Vec<ModuleState> = all_modules
+            .iter()
+            .filter_map(|(m, container)| {
+                if m == &module {
+                    Some(get_container_state(&container))
                 } else {
-                    //If all containers have the same state we can just forward that.
-                    let last = states.first().unwrap(); // already did the bounds check.
-                    if states.iter().all(|s| s == last) {
-                        last.clone()
-                    } else {
-                        //If not we have to build the response string.
-                        //Struct containing the state of all the containers.
-                        #[derive(Default)]
-                        struct ContainerStates {
-                            running: i32,
-                            stopped: i32,
-                            failed: i32,
-                            exit_codes: Vec<i32>,
-                        };
-                        let mut states = states.into_iter().fold(
-                            ContainerStates::default(),
-                            |mut acc, state| {
-                                match state {
-                                    ModuleState::Running => acc.running += 1,
-                                    ModuleState::Stopped => acc.stopped += 1,
-                                    ModuleState::Failed { exit_code } => {
-                                        acc.failed += 1;
-                                        acc.exit_codes.push(exit_code);
-                                    }
-                                    //The only way for this to happen is if the get_container_state function is broken
-                                    _ => unreachable!(),
-                                }
-                                acc
-                            },
-                        );
-                        //Avoid duplicates in the exit codes
-                        states.exit_codes.sort_unstable();
-                        states.exit_codes.dedup();
-
-                        //Convert the states into a nice string
-                        let workers = states.running + states.stopped + states.failed;
-                        let mut message = format!("{}/{} running", states.running, workers);
-                        if states.stopped > 0 {
-                            message += &format!(", {} stopped", states.stopped);
-                        }
-                        if states.failed > 0 {
-                            message += &format!(
-                                ", {} failures with exit codes {:?}",
-                                states.failed, states.exit_codes
-                            );
-                        }
-                        ModuleState::Other { message }
-                    }
-                };
+                    None
+                }
+            })
+            .collect();
+        let state = aggregate_module_state(states);
 
-                out.push(PathModule { module, state });
-            }
+        out.push(PathModule {
+            module,
+            state,
+            configured_workers: 0,
+            active_workers: 0,
+        });
+    }
+
+    //Docker lists images in no particular order, so sort them to keep the list stable.
+    out.sort_by(|a, b| {
+        a.module
+            .name
+            .cmp(&b.module.name)
+            .then_with(|| compare_versions(&a.module.version, &b.module.version))
+    });
+
+    //Fetch the worker counts of every module in a single round-trip rather than one per module.
+    if !out.is_empty() {
+        let keys: Vec<String> = out
+            .iter()
+            .flat_map(|m| {
+                vec![
+                    util::get_module_workers_key(&m.module),
+                    util::get_registered_module_workers_key(&m.module),
+                ]
+            })
+            .collect();
+        let command = keys
+            .iter()
+            .fold(Command::new("MGET"), |command, key| command.arg(key));
+        let mut conn = pool.get().await;
+        let values = conn.run_command(command).await?.unwrap_array();
+        //Missing keys are returned as Nil, which means that there are no such workers.
+        let parse = |value: &Value| match value {
+            Value::String(s) => String::from_utf8_lossy(s).parse::<i64>().unwrap_or(0),
+            _ => 0,
+        };
+        for (module, counts) in out.iter_mut().zip(values.chunks(2)) {
+            module.configured_workers = parse(&counts[0]) as u8;
+            module.active_workers = parse(&counts[1]);
         }
     }
     Ok(Json(out))
 }
 
+//Detailed information about a single module.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ModuleDetails {
+    #[serde(flatten)]
+    pub state: ModuleState,
+    #[serde(flatten)]
+    pub module: ModuleInfo,
+    //How many workers the module was configured to run with.
+    pub workers: u8,
+    //How many workers are currently registered.
+    pub active_workers: i64,
+    //How many jobs are waiting in the module's job queue.
+    pub queued_jobs: isize,
+    //Whether the module is hidden from the module list.
+    pub ignored: bool,
+    //The names of the environment variables passed to the module. Values are left out as they may be secrets.
+    pub environment: Vec<String>,
+    //The kinds of maps the module accepts, or None if it accepts any map.
+    pub map_types: Option<Vec<MapType>>,
+    //How many seconds the module can go without jobs before it is stopped, if it opted in to that.
+    pub idle_timeout: Option<u32>,
+    //Whether the module was stopped for being idle, and has to be started again before it accepts jobs.
+    pub idle_stopped: bool,
+    //Whether the containers of the module get access to the GPUs of the host.
+    pub gpu: bool,
+    //The base command the module runs, or None if it runs the default one.
+    pub command: Option<Vec<String>>,
+}
+
+//Get the details of a single module. Ranked below `get_latest_module` as the paths overlap.
+#[get("/module/<name>/<version>", rank = 2)]
+pub async fn get_module(
+    docker: State<'_, SharedDocker>,
+    pool: State<'_, ConnectionPool>,
+    name: String,
+    version: String,
+    _session: AdminSession,
+) -> Result<Option<Json<ModuleDetails>>, BackendError> {
+    let module = ModuleInfo { name, version };
+    if !module_exists(&**docker, &module).await? {
+        return Ok(None);
+    }
+
+    let states = list_all_modules(&**docker)
+        .await?
+        .into_iter()
+        .filter(|(m, _)| m == &module)
+        .map(|(_, container)| get_container_state(&container))
+        .collect();
+    let state = aggregate_module_state(states);
+
+    let mut conn = pool.get().await;
+    let workers = conn
+        .get(util::get_module_workers_key(&module))
+        .await?
+        .map(|s| String::from_utf8_lossy(&s).parse::<u8>().unwrap())
+        .unwrap_or(0);
+    let active_workers = conn
+        .get(util::get_registered_module_workers_key(&module))
+        .await?
+        .map(|s| String::from_utf8_lossy(&s).parse::<i64>().unwrap())
+        .unwrap_or(0);
+    let queued_jobs = conn
+        .llen(util::get_module_work_key(&module))
+        .await?
+        .unwrap_or(0);
+    let mut environment: Vec<String> = conn
+        .hkeys(util::get_module_env_key(&module))
+        .await?
+        .into_iter()
+        .map(|k| String::from_utf8_lossy(&k).into_owned())
+        .collect();
+    environment.sort();
+    let map_types = get_module_map_types(&mut conn, &module).await?;
+    let idle_timeout = conn
+        .get(util::get_module_idle_timeout_key(&module))
+        .await?
+        .map(|s| String::from_utf8_lossy(&s).parse::<u32>().unwrap());
+    let idle_stopped = conn
+        .exists(util::get_module_idle_stopped_key(&module))
+        .await?;
+    let gpu = conn.exists(util::get_module_gpu_key(&module)).await?;
+    let command = get_module_command(&mut conn, &module).await?;
+
+    Ok(Some(Json(ModuleDetails {
+        ignored: is_ignored(&module),
+        state,
+        module,
+        workers,
+        active_workers,
+        queued_jobs,
+        environment,
+        map_types,
+        idle_timeout,
+        idle_stopped,
+        gpu,
+        command,
+    })))
+}
+
+//The longest module name accepted, leaving room for the version and worker number in container names.
+const MAX_MODULE_NAME_LENGTH: usize = 64;
+//The longest module version accepted, which is the longest tag Docker accepts.
+const MAX_MODULE_VERSION_LENGTH: usize = 128;
+
+//Check that `name` is a valid module name, following Docker's rules for repository names apart from case: runs of
+//letters and digits separated by a single '.' or '_', two '_' or any number of '-'.
+pub(super) fn validate_module_name(name: &str) -> Result<(), UserError> {
+    if name.is_empty() {
+        return Err(UserError::ModuleImport(
+            "The module name cannot be empty".into(),
+        ));
+    }
+    if name.len() > MAX_MODULE_NAME_LENGTH {
+        return Err(UserError::ModuleImport(format!(
+            "The module name can be at most {} characters long",
+            MAX_MODULE_NAME_LENGTH
+        )));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !"._-".contains(*c))
+    {
+        return Err(UserError::ModuleImport(format!(
+            "The module name cannot contain '{}', only letters, digits, '.', '_' and '-'",
+            c
+        )));
+    }
+
+    let is_separator = |c: char| !c.is_ascii_alphanumeric();
+    if name.starts_with(is_separator) || name.ends_with(is_separator) {
+        return Err(UserError::ModuleImport(
+            "The module name must start and end with a letter or digit".into(),
+        ));
+    }
+    let valid_separator =
+        |s: &str| s == "." || s == "_" || s == "__" || s.chars().all(|c| c == '-');
+    if let Some(separator) = name
+        .split(|c: char| c.is_ascii_alphanumeric())
+        .find(|s| !s.is_empty() && !valid_separator(s))
+    {
+        return Err(UserError::ModuleImport(format!(
+            "The module name cannot contain '{}', words can only be separated by '.', '_', '__' or dashes",
+            separator
+        )));
+    }
+    Ok(())
+}
+
+//Check that `version` is a valid module version, following Docker's rules for tags.
+pub(super) fn validate_module_version(version: &str) -> Result<(), UserError> {
+    if version.is_empty() {
+        return Err(UserError::ModuleImport(
+            "The module version cannot be empty".into(),
+        ));
+    }
+    if version.len() > MAX_MODULE_VERSION_LENGTH {
+        return Err(UserError::ModuleImport(format!(
+            "The module version can be at most {} characters long",
+            MAX_MODULE_VERSION_LENGTH
+        )));
+    }
+    if let Some(c) = version
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !"._-".contains(*c))
+    {
+        return Err(UserError::ModuleImport(format!(
+            "The module version cannot contain '{}', only letters, digits, '.', '_' and '-'",
+            c
+        )));
+    }
+    if version.starts_with(|c| c == '.' || c == '-') {
+        return Err(UserError::ModuleImport(
+            "The module version cannot start with '.' or '-'".into(),
+        ));
+    }
+    Ok(())
+}
+
+//Parse environment variables given as one `KEY=VALUE` pair per line. Empty lines and lines starting with '#' are skipped.
+fn parse_module_env(text: &str) -> Result<Vec<(String, String)>, UserError> {
+    let mut out: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let split = line.find('=').ok_or_else(|| {
+            UserError::ModuleImport(format!("Missing '=' in environment variable '{}'", line))
+        })?;
+        let (key, value) = (line[..split].trim(), &line[split + 1..]);
+        //Use the same rules for names as POSIX shells so that the variables can be used from scripts.
+        let valid = key
+            .chars()
+            .next()
+            .map(|c| c.is_ascii_alphabetic() || c == '_')
+            .unwrap_or(false)
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(UserError::ModuleImport(format!(
+                "Invalid environment variable name '{}'",
+                key
+            )));
+        }
+        if out.iter().any(|(k, _)| k == key) {
+            return Err(UserError::ModuleImport(format!(
+                "Environment variable '{}' is set more than once",
+                key
+            )));
+        }
+        out.push((key.to_string(), value.to_string()));
+    }
+    Ok(out)
+}
+
+//Parse a comma separated list of map types, ignoring duplicates. At least one type is required.
+pub(super) fn parse_map_types(text: &str) -> Result<Vec<MapType>, UserError> {
+    let mut out = Vec::new();
+    for name in text.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let map_type = name.to_lowercase().parse().map_err(|n| {
+            UserError::ModuleImport(format!(
+                "Unknown map type '{}', expected one of {}",
+                n,
+                MapType::names()
+            ))
+        })?;
+        if !out.contains(&map_type) {
+            out.push(map_type);
+        }
+    }
+    if out.is_empty() {
+        Err(UserError::ModuleImport(
+            "A module has to accept at least one map type".into(),
+        ))
+    } else {
+        Ok(out)
+    }
+}
+
+//Get the environment variables of `module` in the `KEY=VALUE` form Docker expects.
+async fn get_module_env(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+) -> Result<Vec<String>, darkredis::Error> {
+    let command = Command::new("HGETALL").arg(&util::get_module_env_key(module));
+    let values = conn.run_command(command).await?.unwrap_array();
+    Ok(values
+        .chunks(2)
+        .map(|pair| {
+            let key = String::from_utf8_lossy(&pair[0].clone().unwrap_string()).into_owned();
+            let value = String::from_utf8_lossy(&pair[1].clone().unwrap_string()).into_owned();
+            format!("{}={}", key, value)
+        })
+        .collect())
+}
+
+//Get the base command `module` was uploaded with, or None if it runs the default one.
+async fn get_module_command(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+) -> Result<Option<Vec<String>>, BackendError> {
+    match conn.get(util::get_module_command_key(module)).await? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None => Ok(None),
+    }
+}
+
+//Parse the optional numeric text field `field` in `form`, returning None if it is missing.
+fn get_optional_number<T>(form: &mut MultipartForm, field: &str) -> Result<Option<T>, UserError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match form.get_text(field).map(|s| s.trim().parse::<T>()) {
+        Ok(Ok(n)) => Ok(Some(n)),
+        Ok(Err(e)) => {
+            warn!("Failed to parse {}: {}", field, e);
+            Err(UserError::BadForm(FormError::Other(format!(
+                "Invalid value for field '{}'",
+                field
+            ))))
+        }
+        Err(FormError::MissingText(_)) => Ok(None),
+        Err(e) => Err(UserError::BadForm(e)),
+    }
+}
+
 #[post("/module", data = "<form>")]
 pub async fn upload_module(
     mut form: MultipartForm,
     pool: State<'_, ConnectionPool>,
-    docker: State<'_, Docker>,
+    docker: State<'_, SharedDocker>,
     session: AdminSession,
 ) -> Result<Status, UserError> {
     //Include the module runner dependencies into the executable to make managing them easier.
@@ -321,40 +767,157 @@ pub async fn upload_module(
 
     //This field is optional and determines how many instances of the module we can run at once.
     //If the field doesn't exist, assume 1.
-    let concurrent_workers = match form.get_text("workers").map(|s| s.parse::<u8>()) {
-        Ok(Ok(n)) => n,
-        Ok(Err(e)) => {
-            warn!("Failed to parse worker count: {}", e);
-            return Err(UserError::BadForm(FormError::Other(
-                "Invalid worker count".into(),
-            )));
-        }
-        Err(FormError::MissingText(_)) => 1,
+    let concurrent_workers = get_optional_number::<u8>(&mut form, "workers")?.unwrap_or(1);
+
+    //This field is optional and overrides how long, in seconds, jobs submitted to this module are cached.
+    //If the field doesn't exist, the global token timeout is used. A value of 0 disables caching for the module.
+    let cache_ttl = get_optional_number::<u32>(&mut form, "cache_ttl")?;
+
+    //This field is optional and overrides how long, in seconds, the module has to complete a job before it's failed.
+    //If the field doesn't exist, the global job timeout is used. A value of 0 lets jobs run forever.
+    let job_timeout = get_optional_number::<u32>(&mut form, "job_timeout")?;
+
+    //This field is optional and sets how long, in seconds, the workers get to shut down when the module is stopped or
+    //restarted before they are killed. If the field doesn't exist, the global stop timeout is used.
+    let stop_timeout = get_optional_number::<u32>(&mut form, "stop_timeout")?;
+
+    //This field is optional and opts the module in to being stopped once it hasn't been sent a job for this many
+    //seconds, if idle modules are stopped at all. If the field doesn't exist, the module is never stopped for being idle.
+    let idle_timeout = get_optional_number::<u32>(&mut form, "idle_timeout")?;
+
+    //This field is optional and gives the containers of the module access to the GPUs of the host when set to "true",
+    //while "false" is the same as leaving it out. The host needs the NVIDIA container runtime for this, which is
+    //checked when the module is started.
+    let gpu = match form.get_text("gpu") {
+        Ok(g) => match g.trim() {
+            "true" => true,
+            "false" => false,
+            _ => {
+                return Err(UserError::ModuleImport(
+                    "The gpu field must be either true or false".into(),
+                ))
+            }
+        },
+        Err(FormError::MissingText(_)) => false,
+        Err(e) => return Err(UserError::BadForm(e)),
+    };
 
-        Err(e) => {
-            return Err(UserError::BadForm(e));
+    //This field is optional and replaces `python3 main.py` as the command the module runs, split on whitespace. The
+    //arguments LAPS needs to register the workers are appended to it.
+    let command = match form.get_text("command") {
+        Ok(c) => {
+            let command: Vec<String> = c.split_whitespace().map(|s| s.to_string()).collect();
+            if command.is_empty() {
+                return Err(UserError::ModuleImport(
+                    "The command cannot be empty".into(),
+                ));
+            }
+            Some(command)
         }
+        Err(FormError::MissingText(_)) => None,
+        Err(e) => return Err(UserError::BadForm(e)),
+    };
+
+    //This field is optional and replaces the bundled Dockerfile, allowing modules which aren't Python scripts.
+    //laps.py is still included in the build context for modules which want to use it.
+    let dockerfile = match form.get_text("dockerfile") {
+        Ok(d) => Some(d),
+        Err(FormError::MissingText(_)) => None,
+        Err(e) => return Err(UserError::BadForm(e)),
+    };
+
+    //This field is optional and contains environment variables to pass to the module's containers, one `KEY=VALUE` per line.
+    //The values can only be written, never read back through the API.
+    let env = match form.get_text("env") {
+        Ok(e) => parse_module_env(&e)?,
+        Err(FormError::MissingText(_)) => Vec::new(),
+        Err(e) => return Err(UserError::BadForm(e)),
+    };
+
+    //This field is optional and limits which kinds of maps the module accepts, as a comma separated list of
+    //"elevation", "rgb" and "slope". If the field doesn't exist, the module accepts any map.
+    let map_types = match form.get_text("map_types") {
+        Ok(t) => Some(parse_map_types(&t)?),
+        Err(FormError::MissingText(_)) => None,
+        Err(e) => return Err(UserError::BadForm(e)),
     };
 
     //Accept only .tar
     let module = form.get_file(&mime_consts::X_TAR, "module")?;
 
     //Validation
-    //Check the name and version for invalid characters
-    if name.chars().any(|c| c == ':') || version.chars().any(|c| c == ':') {
+    //The name and version make up the image tag and container names, so they must be valid in both.
+    validate_module_name(&name)?;
+    validate_module_version(&version)?;
+
+    //Guard against exhausting the host's resources by creating too many containers.
+    let max_workers = crate::CONFIG.module.max_workers_per_module;
+    if concurrent_workers == 0 {
+        return Err(UserError::ModuleImport(
+            "A module needs at least 1 worker".into(),
+        ));
+    } else if concurrent_workers > max_workers {
+        return Err(UserError::ModuleImport(format!(
+            "A module can have at most {} workers, got {}",
+            max_workers, concurrent_workers
+        )));
+    }
+
+    //Killing workers right away or never would defeat the purpose of the grace period.
+    let max_stop_timeout = crate::CONFIG.module.max_stop_timeout;
+    if let Some(timeout) = stop_timeout {
+        if timeout == 0 || timeout > max_stop_timeout {
+            return Err(UserError::ModuleImport(format!(
+                "The stop timeout must be between 1 and {} seconds, got {}",
+                max_stop_timeout, timeout
+            )));
+        }
+    }
+
+    if idle_timeout == Some(0) {
         return Err(UserError::ModuleImport(
-            "Neither name nor version cannot contain ':'".into(),
+            "The idle timeout must be at least 1 second".into(),
         ));
     }
 
     //Check that there's no image with the same name and version currently
-    //Docker only accepts lowercase names so do that automatically.
+    //Docker only accepts lowercase names so do that automatically, always lowercasing the version too so that modules
+    //only differing in case are the same module.
     let info = ModuleInfo {
         name: name.to_lowercase(),
         version: version.to_lowercase(),
     };
-    if module_exists(&docker, &info).await? {
-        return Err(UserError::ModuleImport("Module already exists".into()));
+    if info.name != name || info.version != version {
+        warn!(
+            "Module {}:{} was uploaded with uppercase letters, storing it as {}",
+            name, version, info
+        );
+    }
+    if module_exists(&**docker, &info).await? {
+        return Err(UserError::ModuleImport(format!(
+            "Module {} already exists",
+            info
+        )));
+    }
+
+    //Only check that a custom Dockerfile looks like one, as only admins can upload modules anyway.
+    if let Some(d) = &dockerfile {
+        let has_from = d
+            .lines()
+            .map(|l| l.trim_start())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .any(|l| l.to_uppercase().starts_with("FROM "));
+        if !has_from {
+            return Err(UserError::ModuleImport(
+                "The Dockerfile has no FROM instruction".into(),
+            ));
+        }
+    }
+
+    //Containers can be left behind by an earlier attempt at this module, remove them so they don't get mixed up with the new image.
+    let orphans = remove_module_containers(&**docker, &info).await?;
+    if orphans > 0 {
+        warn!("Removed {} orphaned containers of module {}", orphans, info);
     }
 
     //Time to create the image, pack it all into a tar:
@@ -363,10 +926,14 @@ pub async fn upload_module(
         //use an inner scope to drop `builder` when we're done.
         let mut builder = tar::Builder::new(&mut tarball);
         //Insert LAPS files. Insertion of data cannot fail because we are writing directly to memory.
+        let dockerfile = dockerfile
+            .as_ref()
+            .map(|d| d.as_bytes())
+            .unwrap_or(MODULE_DOCKERFILE);
         let mut header = tar::Header::new_gnu();
-        header.set_size(MODULE_DOCKERFILE.len() as u64);
+        header.set_size(dockerfile.len() as u64);
         builder
-            .append_data(&mut header, "Dockerfile", MODULE_DOCKERFILE)
+            .append_data(&mut header, "Dockerfile", dockerfile)
             .unwrap();
         header.set_size(MODULE_LAPS_PY.len() as u64);
         builder
@@ -382,90 +949,512 @@ pub async fn upload_module(
         builder.finish().expect("writing image tarball");
     }
 
-    //Build the image
-    let options = BuildImageOptions {
-        t: format!("{}:{}", info.name, info.version),
-        rm: true,
-        forcerm: true,
-        ..Default::default()
+    //Uploads of large modules can be aborted by the request timeout while the image is being built or the settings are
+    //stored, so remove whatever was left behind unless the upload completes.
+    let mut cleanup = UploadCleanup {
+        docker: docker.inner().clone(),
+        pool: pool.inner().clone(),
+        module: Some(info.clone()),
     };
-    let mut stream = docker.build_image(options, None, Some(tarball.into()));
-    while let Some(update) = stream.next().await {
-        let update = update.map_err(|e| {
-            error!("Error getting image build output: {:?}", e);
-            UserError::ModuleImport(e.to_string())
-        })?;
 
-        debug!("Importing {}: {:?}", info, update);
-        if let BuildImageResults::BuildImageError {
-            error,
-            error_detail,
-        } = update
-        {
-            return Err(UserError::ModuleImport(format!(
-                "Module import error: {}\nDetails: {:?}",
-                error, error_detail
-            )));
+    //Build the image, retrying with an increasing delay if the build fails for reasons outside of the module's control.
+    let config = &crate::CONFIG.module;
+    let mut delay = Duration::from_secs(config.build_retry_delay);
+    let mut attempt = 1;
+    loop {
+        info!(
+            "Building image for module {}, attempt {}/{}",
+            info, attempt, config.build_attempts
+        );
+        match docker.build_image(&info.to_string(), &tarball).await {
+            Ok(()) => break,
+            Err(BuildFailure::Transient(msg)) if attempt < config.build_attempts => {
+                warn!(
+                    "Building module {} failed, retrying in {} seconds: {}",
+                    info,
+                    delay.as_secs(),
+                    msg
+                );
+                tokio::time::delay_for(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(BuildFailure::Transient(msg)) | Err(BuildFailure::Permanent(msg)) => {
+                error!("Failed to build module {}: {}", info, msg);
+                cleanup.disarm();
+                return Err(UserError::ModuleImport(msg));
+            }
         }
     }
 
-    //Now that everything has succeeded, store the number of jobs we can use in the database.
-    //This shouldn't fail, but if it does, return an error.
+    //Now that everything has succeeded, store the module settings in the database.
+    //This shouldn't fail, but if it does, the image is removed again by `cleanup`.
     let mut redis = pool.get().await;
-    let key = util::get_module_workers_key(&info);
-    match redis.set(&key, concurrent_workers.to_string()).await {
-        Ok(()) => (),
-        Err(e) => {
-            error!("Failed to set worker count for {}: {}", info, e);
-            return Err(UserError::Internal(BackendError::Redis(e)));
-        }
+    let settings = ModuleSettings {
+        concurrent_workers,
+        cache_ttl,
+        job_timeout,
+        stop_timeout,
+        idle_timeout,
+        custom_dockerfile: dockerfile.is_some(),
+        gpu,
+        command,
+        env,
+        map_types,
     };
+    if let Err(e) = store_module_settings(&mut redis, &info, &settings).await {
+        error!("Failed to store settings for {}: {}", info, e);
+        return Err(UserError::Internal(BackendError::Redis(e)));
+    }
+    cleanup.disarm();
 
     info!("{} imported module {}", session.username, info);
     Ok(Status::Created)
 }
 
-#[post("/module/<name>/<version>/restart")]
+//Removes the image and settings of a module when dropped, unless disarmed. Used to clean up after uploads which don't
+//complete, including those whose handler is dropped when the request times out.
+struct UploadCleanup {
+    docker: SharedDocker,
+    pool: ConnectionPool,
+    module: Option<ModuleInfo>,
+}
+
+impl UploadCleanup {
+    //Keep what was uploaded.
+    fn disarm(&mut self) {
+        self.module = None;
+    }
+}
+
+impl Drop for UploadCleanup {
+    fn drop(&mut self) {
+        let module = match self.module.take() {
+            Some(m) => m,
+            None => return,
+        };
+        //Drop can't wait for the cleanup, so leave it to a task of its own.
+        let docker = self.docker.clone();
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            warn!("Upload of module {} did not complete, removing it", module);
+            if let Err(e) = pool.get().await.del_slice(&module_keys(&module)).await {
+                error!("Failed to remove settings of module {}: {}", module, e);
+            }
+            //Docker stops building once the build request is dropped, so the image may not exist.
+            if let Err(e) = remove_module_image(&*docker, &module).await {
+                debug!("Did not remove image of module {}: {}", module, e);
+            }
+        });
+    }
+}
+
+//The settings given when uploading a module.
+struct ModuleSettings {
+    concurrent_workers: u8,
+    cache_ttl: Option<u32>,
+    job_timeout: Option<u32>,
+    stop_timeout: Option<u32>,
+    idle_timeout: Option<u32>,
+    //Whether the module was built from its own Dockerfile rather than the bundled one.
+    custom_dockerfile: bool,
+    //Whether the module's containers get access to the GPUs of the host.
+    gpu: bool,
+    //The base command of the module's containers, if it isn't the default one.
+    command: Option<Vec<String>>,
+    //Environment variables passed to the module's containers.
+    env: Vec<(String, String)>,
+    //The kinds of maps the module accepts, if it is limited.
+    map_types: Option<Vec<MapType>>,
+}
+
+//Store the settings given when uploading `info`.
+async fn store_module_settings(
+    redis: &mut darkredis::Connection,
+    info: &ModuleInfo,
+    settings: &ModuleSettings,
+) -> Result<(), darkredis::Error> {
+    redis
+        .set(
+            util::get_module_workers_key(info),
+            settings.concurrent_workers.to_string(),
+        )
+        .await?;
+    if settings.custom_dockerfile {
+        redis
+            .set(util::get_module_custom_dockerfile_key(info), "1")
+            .await?;
+    }
+    if settings.gpu {
+        redis.set(util::get_module_gpu_key(info), "1").await?;
+    }
+    if let Some(command) = &settings.command {
+        redis
+            .set(
+                util::get_module_command_key(info),
+                serde_json::to_vec(command).unwrap(),
+            )
+            .await?;
+    }
+    if !settings.env.is_empty() {
+        let builder = settings
+            .env
+            .iter()
+            .fold(MSetBuilder::new(), |builder, (key, value)| {
+                builder.set(key, value)
+            });
+        redis
+            .hset_many(util::get_module_env_key(info), builder)
+            .await?;
+    }
+    if let Some(map_types) = &settings.map_types {
+        redis
+            .set(
+                util::get_module_map_types_key(info),
+                serde_json::to_vec(map_types).unwrap(),
+            )
+            .await?;
+    }
+    if let Some(ttl) = settings.cache_ttl {
+        redis
+            .set(util::get_module_cache_ttl_key(info), ttl.to_string())
+            .await?;
+    }
+    if let Some(timeout) = settings.job_timeout {
+        redis
+            .set(util::get_module_job_timeout_key(info), timeout.to_string())
+            .await?;
+    }
+    if let Some(timeout) = settings.stop_timeout {
+        redis
+            .set(util::get_module_stop_timeout_key(info), timeout.to_string())
+            .await?;
+    }
+    if let Some(timeout) = settings.idle_timeout {
+        redis
+            .set(util::get_module_idle_timeout_key(info), timeout.to_string())
+            .await?;
+    }
+    Ok(())
+}
+
+//Create, if `create` is set, and start the worker containers of `module` with `settings`. The names of the
+//containers are added to `created` as they are created, so that they can be removed again if a later step fails.
+async fn create_and_start_workers(
+    docker: &dyn DockerBackend,
+    module: &ModuleInfo,
+    settings: &StartSettings,
+    create: bool,
+    created: &mut Vec<String>,
+) -> Result<(), BackendError> {
+    let concurrent_workers = settings.workers;
+    let container_name = module.to_string().replace(":", "-");
+    if create {
+        //No containers have been created yet, build them up
+        debug!("Creating containers for module {}", container_name);
+        let redis = &crate::CONFIG.redis.address;
+        //For Redis to succeed in connecting the format of the address field must be <host>:<port>
+        let split = redis.find(':').unwrap();
+        let redis_host = &redis[..split];
+        let redis_port = &redis[split + 1..];
+
+        for worker_number in (0..concurrent_workers).map(|w| w.to_string()) {
+            //Run the module's own command if it has one, otherwise a default set of commands. Modules with their own
+            //Dockerfile and no command get the arguments passed to their entrypoint instead.
+            let mut command: Vec<&str> = match &settings.command {
+                Some(c) => c.iter().map(|s| s.as_str()).collect(),
+                None if settings.custom_dockerfile => Vec::new(),
+                None => vec!["python3", "main.py"],
+            };
+            command.extend_from_slice(&[
+                module.name.as_str(),
+                module.version.as_str(),
+                "--redis_host",
+                redis_host,
+                "--port",
+                redis_port,
+                "--worker_number",
+                &worker_number,
+            ]);
+            //Use test keys in laps.py if running in test mode
+            if cfg!(test) {
+                command.push("--test");
+            }
+
+            //Setup the settings
+            let module_name = module.to_string();
+            let this_worker_name = format!("{}-{}", container_name, worker_number);
+            let spec = ContainerSpec {
+                name: &this_worker_name,
+                image: &module_name,
+                cmd: command,
+                env: settings.env.iter().map(|e| e.as_str()).collect(),
+                gpu: settings.gpu,
+            };
+            docker.create_container(spec).await?;
+            created.push(this_worker_name);
+        }
+    }
+
+    //Finally start all the containers:
+    for worker_number in 0..concurrent_workers {
+        let this_worker_name = format!("{}-{}", container_name, worker_number);
+        docker.start_container(&this_worker_name).await?;
+        debug!("Successfully started container {}", this_worker_name);
+    }
+
+    Ok(())
+}
+
+//Get how long, in seconds, the workers of `module` get to shut down before they are killed.
+async fn get_module_stop_timeout(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+) -> Result<u32, BackendError> {
+    get_module_timeout(
+        conn,
+        &util::get_module_stop_timeout_key(module),
+        crate::CONFIG.module.stop_timeout,
+    )
+    .await
+}
+
+//Stop every worker container of `module` concurrently, giving each `timeout` seconds to shut down.
+async fn stop_workers(
+    docker: &dyn DockerBackend,
+    module: &ModuleInfo,
+    workers: u8,
+    timeout: u32,
+) -> Result<(), BackendError> {
+    let container = module.to_string().replace(":", "-");
+    futures::stream::iter(0..workers)
+        .map(Ok)
+        .try_for_each_concurrent(None, |worker| {
+            let worker_container = format!("{}-{}", container, worker);
+            async move {
+                match docker
+                    .stop_container(&worker_container, timeout as i64)
+                    .await
+                {
+                    Ok(_) => {
+                        debug!("Stopped container {}", worker_container);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        error!("Failed to stop {}: {:?}", worker_container, e);
+                        Err(e)
+                    }
+                }
+            }
+        })
+        .await
+}
+
+//Get the counter stored at `key`, which is 0 if it doesn't exist.
+async fn get_counter(conn: &mut darkredis::Connection, key: &str) -> Result<i64, BackendError> {
+    Ok(conn
+        .get(key)
+        .await?
+        .map(|s| String::from_utf8_lossy(&s).parse::<i64>().unwrap_or(0))
+        .unwrap_or(0))
+}
+
+//Wait until the counter stored at `key`, such as the number of registered workers of a module, reaches `target`,
+//giving up after `timeout`. Returns whether it did so in time.
+async fn wait_for_counter(
+    pool: &ConnectionPool,
+    key: &str,
+    target: i64,
+    timeout: Duration,
+) -> Result<bool, BackendError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        //Don't hold on to the connection while sleeping.
+        let count = get_counter(&mut *pool.get().await, key).await?;
+        if count >= target {
+            return Ok(true);
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Ok(false);
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+}
+
+//The settings a module was uploaded with which are needed to start it.
+struct StartSettings {
+    workers: u8,
+    custom_dockerfile: bool,
+    gpu: bool,
+    command: Option<Vec<String>>,
+    //Environment variables of the containers as `KEY=VALUE`.
+    env: Vec<String>,
+}
+
+//Get the settings `module` was uploaded with which are needed to start it.
+async fn get_start_settings(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+) -> Result<StartSettings, BackendError> {
+    let workers = conn
+        .get(&util::get_module_workers_key(module))
+        .await?
+        .map(|s| String::from_utf8_lossy(&s).parse::<u8>().unwrap())
+        .expect("getting worker number field");
+    let custom_dockerfile = conn
+        .exists(&util::get_module_custom_dockerfile_key(module))
+        .await?;
+    let gpu = conn.exists(util::get_module_gpu_key(module)).await?;
+    let command = get_module_command(conn, module).await?;
+    let env = get_module_env(conn, module).await?;
+    Ok(StartSettings {
+        workers,
+        custom_dockerfile,
+        gpu,
+        command,
+        env,
+    })
+}
+
+//Start the workers of `module`, which must not be running, creating their containers if they don't exist yet.
+async fn start_module(
+    docker: &dyn DockerBackend,
+    module: &ModuleInfo,
+    settings: &StartSettings,
+) -> Result<(), BackendError> {
+    //If containers have already been created for the module, do not try to recreate them.
+    let container_name = module.to_string().replace(":", "-");
+    let containers_exist = docker
+        .list_containers(true, false)
+        .await?
+        .into_iter()
+        .any(|c| c.names.into_iter().any(|s| s.starts_with(&container_name)));
+    let mut created = Vec::new();
+    let result =
+        create_and_start_workers(docker, module, settings, !containers_exist, &mut created).await;
+    if let Err(e) = result {
+        //Don't leave the containers created by this attempt behind, but leave any which were already there alone.
+        error!("Failed to start module {}, rolling back: {}", module, e);
+        for container in &created {
+            match docker.remove_container(container, true).await {
+                Ok(()) => debug!("Removed container {}", container),
+                Err(e) => error!("Failed to remove container {}: {}", container, e),
+            }
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+//Start `module` because a job was submitted to it, and wait for it to register. Only one submission starts the
+//module, any others arriving meanwhile just wait for it. Returns whether the module registered in time.
+pub async fn auto_start_module(
+    docker: &dyn DockerBackend,
+    pool: &ConnectionPool,
+    module: &ModuleInfo,
+) -> Result<bool, BackendError> {
+    let timeout = crate::CONFIG.module.startup_timeout;
+    let starting_key = util::get_module_starting_key(module);
+    let start = {
+        let mut conn = pool.get().await;
+        let expiry = timeout.to_string();
+        let command = Command::new("SET")
+            .arg(&starting_key)
+            .arg(b"1")
+            .arg(b"NX")
+            .arg(b"EX")
+            .arg(&expiry);
+        //Nil means that the key already existed.
+        !matches!(conn.run_command(command).await?, Value::Nil)
+    };
+
+    //The containers may still be running even though the module hasn't registered yet, in which case it is coming up.
+    if start && !module_is_running(docker, module).await? {
+        info!("Starting module {} for a submitted job", module);
+        let settings = {
+            let mut conn = pool.get().await;
+            get_start_settings(&mut conn, module).await?
+        };
+        if let Err(e) = start_module(docker, module, &settings).await {
+            pool.get().await.del(&starting_key).await?;
+            return Err(e);
+        }
+    }
+
+    let registered = wait_for_counter(
+        pool,
+        &util::get_registered_module_workers_key(module),
+        1,
+        Duration::from_secs(timeout),
+    )
+    .await?;
+    //The module stays flagged as stopped for being idle until it is up, so that jobs submitted meanwhile wait for it
+    //too rather than being turned away.
+    if registered {
+        pool.get()
+            .await
+            .del(util::get_module_idle_stopped_key(module))
+            .await?;
+    }
+    if start {
+        pool.get().await.del(&starting_key).await?;
+    }
+    if !registered {
+        warn!(
+            "Module {} did not register within {} seconds of being started for a job",
+            module, timeout
+        );
+    }
+    Ok(registered)
+}
+
+//Restart a module, or start it if it isn't running. With `wait` set, only respond once every worker has
+//registered, so that the module is known to accept jobs.
+#[post("/module/<name>/<version>/restart?<wait>")]
 pub async fn restart_module(
     session: AdminSession,
     name: String,
     version: String,
-    docker: State<'_, Docker>,
+    wait: Option<bool>,
+    docker: State<'_, SharedDocker>,
     pool: State<'_, ConnectionPool>,
-) -> Result<Status, BackendError> {
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
     //First, verify that the requested module actually exists:
     let module = ModuleInfo { name, version };
-    if !module_exists(&docker, &module).await? {
-        return Ok(Status::NotFound);
+    if !module_exists(&**docker, &module).await? {
+        return Ok(module_not_found(&module, request_id).await);
     }
 
     //Get the number of concurrent workers allowed for this module without hogging the Redis connection.
-    let concurrent_workers = {
+    //Restarted workers register again while the count of running workers may still include the old ones, so the
+    //registrations are counted from before the restart instead.
+    let registrations_key = util::get_module_registrations_key(&module);
+    let (settings, stop_timeout, registrations) = {
         let mut conn = pool.get().await;
-        conn.get(&util::get_module_workers_key(&module))
-            .await?
-            .map(|s| String::from_utf8_lossy(&s).parse::<u8>().unwrap())
-            .expect("getting worker number field")
+        let settings = get_start_settings(&mut conn, &module).await?;
+        let stop_timeout = get_module_stop_timeout(&mut conn, &module).await?;
+        let registrations = get_counter(&mut conn, &registrations_key).await?;
+        (settings, stop_timeout, registrations)
     };
+    let concurrent_workers = settings.workers;
 
     //If the module is already running, use the restart_container method
     let container_name = module.to_string().replace(":", "-");
-    if module_is_running(&docker, &module).await? {
+    let status = if module_is_running(&**docker, &module).await? {
         //It might take a while to restart a module as it will have to have time to exit.
         //To get around this, perform each restart concurrently.
         futures::stream::iter(0..concurrent_workers)
             .map(Ok)
             .try_for_each_concurrent(None, |n| {
-                let docker = docker.clone();
+                let docker = &**docker;
                 let session = session.clone();
                 let module = module.clone();
                 let container_name = format!("{}-{}", container_name, n);
                 async move {
                     trace!("Restarting {} worker {}", session.username, &module);
-                    //Give the module 30s to shut down
-                    let options = RestartContainerOptions { t: 30 };
                     match docker
-                        .restart_container(&container_name, Some(options))
+                        .restart_container(&container_name, stop_timeout as i64)
                         .await
                     {
                         Ok(_) => {
@@ -483,95 +1472,62 @@ pub async fn restart_module(
                 }
             })
             .await?;
-        Ok(Status::NoContent)
+        Status::NoContent
     } else {
-        //If containers have already been created for the module, do not try to recreate them.
-        let options = ListContainersOptions::<String> {
-            all: true,
-            ..Default::default()
-        };
-        let containers_exist = docker
-            .list_containers(Some(options))
-            .await?
-            .into_iter()
-            .any(|c| {
-                //When we receive the container names from Docker, they all start with a `/` for some reason.
-                c.names
-                    .into_iter()
-                    .any(|s| s[1..].starts_with(&container_name))
-            });
-        if !containers_exist {
-            //No containers have been created yet, build them up
-            debug!("Creating containers for module {}", container_name);
-            let redis = &crate::CONFIG.redis.address;
-            //For Redis to succeed in connecting the format of the address field must be <host>:<port>
-            let split = redis.find(':').unwrap();
-            let redis_host = &redis[..split];
-            let redis_port = &redis[split + 1..];
-
-            for worker_number in (0..concurrent_workers).map(|w| w.to_string()) {
-                //Run it with a default set of commands
-                let mut command = vec![
-                    "python3",
-                    "main.py",
-                    &module.name,
-                    &module.version,
-                    "--redis_host",
-                    redis_host,
-                    "--port",
-                    redis_port,
-                    "--worker_number",
-                    &worker_number,
-                ];
-                //Use test keys in laps.py if running in test mode
-                if cfg!(test) {
-                    command.push("--test");
-                }
-
-                //Setup the settings
-                let module_name = module.to_string();
-                let host_config = HostConfig {
-                    network_mode: Some("host"),
-                    ..Default::default()
-                };
-                let config = Config {
-                    image: Some(module_name.as_str()),
-                    cmd: Some(command),
-                    host_config: Some(host_config),
-                    stop_signal: Some("SIGINT"),
-                    ..Default::default()
-                };
-                let this_worker_name = format!("{}-{}", container_name, worker_number);
-                let options = CreateContainerOptions {
-                    name: &this_worker_name,
-                };
-                //Print any warnings
-                let result = docker.create_container(Some(options), config).await?;
-                debug!(
-                    "Successfully created container {}:{}",
-                    this_worker_name, result.id
-                );
-                let id = &result.id;
-                if let Some(w) = result.warnings {
-                    w.into_iter().for_each(|w| warn!("Container {}: {}", id, w));
-                }
-            }
-        }
-
-        //Finally start all the containers:
-        for worker_number in 0..concurrent_workers {
-            let this_worker_name = format!("{}-{}", container_name, worker_number);
-            docker
-                .start_container(&this_worker_name, None::<StartContainerOptions<String>>)
-                .await?;
-            debug!("Successfully started container {}", this_worker_name);
-        }
+        start_module(&**docker, &module, &settings).await?;
         info!(
             "{} successfully started module {}",
             session.username, module
         );
-        Ok(Status::Created)
+        Status::Created
+    };
+
+    //Give the module a full idle timeout from now before it can be stopped again.
+    {
+        let mut conn = pool.get().await;
+        conn.set(
+            util::get_module_last_used_key(&module),
+            Utc::now().timestamp().to_string(),
+        )
+        .await?;
+        conn.del(util::get_module_idle_stopped_key(&module)).await?;
+        //Restarted workers are no longer paused.
+        conn.del(util::get_module_paused_key(&module)).await?;
+    }
+
+    if wait.unwrap_or(false) {
+        let timeout = Duration::from_secs(crate::CONFIG.module.startup_timeout);
+        let target = registrations + concurrent_workers as i64;
+        if !wait_for_counter(&pool, &registrations_key, target, timeout).await? {
+            //Don't leave a module which might never register running.
+            error!(
+                "Module {} did not register within {:?}, stopping it",
+                module, timeout
+            );
+            if let Err(e) = stop_workers(&**docker, &module, concurrent_workers, stop_timeout).await
+            {
+                error!("Failed to stop module {}: {}", module, e);
+            }
+            let message = format!(
+                "Module {} did not register all of its workers within {} seconds",
+                module,
+                timeout.as_secs()
+            );
+            return Ok(error_response(
+                Status::GatewayTimeout,
+                "module_startup_timeout",
+                &message,
+                Some(request_id),
+            )
+            .await);
+        }
+        debug!(
+            "All {} workers of {} registered",
+            concurrent_workers, module
+        );
     }
+
+    Ok(Response::build().status(status).finalize())
 }
 
 #[post("/module/<name>/<version>/stop")]
@@ -579,21 +1535,19 @@ pub async fn stop_module(
     session: AdminSession,
     name: String,
     version: String,
-    docker: State<'_, Docker>,
+    docker: State<'_, SharedDocker>,
     pool: State<'_, ConnectionPool>,
 ) -> Result<Status, BackendError> {
     //If the module doesn't exist, 404
     let module = ModuleInfo { name, version };
-    if !module_exists(&docker, &module).await? {
+    if !module_exists(&**docker, &module).await? {
         warn!("Couln't find module {}", module);
         Ok(Status::NotFound)
     } else {
         //If the module isn't running, don't bother stopping it
-        if !module_is_running(&docker, &module).await? {
+        if !module_is_running(&**docker, &module).await? {
             Ok(Status::BadRequest)
         } else {
-            let options = StopContainerOptions { t: 60 };
-            let container = module.to_string().replace(":", "-");
             let mut conn = pool.get().await;
             let num_workers = String::from_utf8_lossy(
                 &conn
@@ -603,121 +1557,489 @@ pub async fn stop_module(
             )
             .parse::<u8>()
             .unwrap();
-            for worker in 0..num_workers {
-                let worker_container = format!("{}-{}", container, worker);
-                match docker
-                    .stop_container(&worker_container, Some(options))
-                    .await
-                {
-                    Ok(_) => {
-                        debug!("Stopped container {}", worker_container);
-                    }
-                    Err(e) => {
-                        error!(
-                            "Failed attempt to stop {} by {}: {:?}",
-                            container, session.username, e
-                        );
-                        return Err(BackendError::Docker(e));
-                    }
-                }
+            let stop_timeout = get_module_stop_timeout(&mut conn, &module).await?;
+            if let Err(e) = stop_workers(&**docker, &module, num_workers, stop_timeout).await {
+                error!("Failed attempt to stop {} by {}", module, session.username);
+                return Err(e);
             }
-            info!("module {} stopped by {}", container, session.username);
+            //Stopped workers are no longer paused.
+            conn.del(util::get_module_paused_key(&module)).await?;
+            info!("module {} stopped by {}", module, session.username);
             Ok(Status::NoContent)
         }
     }
 }
 
-#[delete("/module/<name>/<version>")]
-pub async fn delete_module(
+//Get the names of the worker containers of `module` which are in `state`, such as "running" or "paused".
+async fn module_containers_in_state(
+    docker: &dyn DockerBackend,
+    module: &ModuleInfo,
+    state: &str,
+) -> Result<Vec<String>, BackendError> {
+    Ok(list_all_modules(docker)
+        .await?
+        .into_iter()
+        .filter(|(m, c)| m == module && c.state == state)
+        .map(|(_, c)| c.names[0].clone())
+        .collect())
+}
+
+//Get the ids of the jobs in the queue of `module`, skipping invalid entries.
+async fn queued_job_ids(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+) -> Result<Vec<i32>, BackendError> {
+    Ok(conn
+        .lrange(util::get_module_work_key(module), 0, -1)
+        .await?
+        .iter()
+        .filter_map(|entry| serde_json::from_slice::<JobInfo>(entry).ok())
+        .map(|job| job.job_id)
+        .collect())
+}
+
+//Pause every worker of a running module without losing its queue. Jobs submitted while the module is paused are
+//queued up, and don't start timing out until it is resumed. Jobs which a worker already took off the queue are frozen
+//along with the worker, but keep their deadline since nothing records which module they were sent to, so they are
+//failed if the module stays paused for longer than its job timeout.
+#[post("/module/<name>/<version>/pause")]
+pub async fn pause_module(
     session: AdminSession,
     name: String,
     version: String,
-    docker: State<'_, Docker>,
+    docker: State<'_, SharedDocker>,
     pool: State<'_, ConnectionPool>,
-) -> Result<Response<'static>, BackendError> {
-    //Refuse to delete a module if it does not exist or is currently running
+) -> Result<Status, BackendError> {
     let module = ModuleInfo { name, version };
-    if !module_exists(&docker, &module).await? {
-        return Ok(Response::build().status(Status::NotFound).finalize());
+    if !module_exists(&**docker, &module).await? {
+        warn!("Couln't find module {}", module);
+        return Ok(Status::NotFound);
     }
-    if module_is_running(&docker, &module).await? {
-        return Ok(Response::build()
-            .status(Status::BadRequest)
-            .sized_body(Cursor::new("Cannot delete a running module!"))
-            .await
-            .finalize());
+    let mut conn = pool.get().await;
+    let paused_key = util::get_module_paused_key(&module);
+    let workers = module_containers_in_state(&**docker, &module, "running").await?;
+    if workers.is_empty() || conn.exists(&paused_key).await? {
+        return Ok(Status::BadRequest);
     }
 
-    //Now we can delete the module. First off, the containers have to be deleted.
+    //Mark the module as paused first, so that it isn't reaped once its workers stop sending heartbeats.
+    conn.set(&paused_key, "1").await?;
+    for worker in &workers {
+        if let Err(e) = docker.pause_container(worker).await {
+            error!(
+                "Failed attempt to pause {} by {}: {}",
+                module, session.username, e
+            );
+            return Err(e);
+        }
+    }
+
+    //Nothing is working on the queued jobs, so they can't time out either.
+    for job_id in queued_job_ids(&mut conn, &module).await? {
+        clear_job_deadline(&mut conn, job_id).await?;
+    }
+    info!("module {} paused by {}", module, session.username);
+    Ok(Status::NoContent)
+}
+
+//Resume the workers of a paused module, which continue with the jobs queued up in the meantime.
+#[post("/module/<name>/<version>/resume")]
+pub async fn resume_module(
+    session: AdminSession,
+    name: String,
+    version: String,
+    docker: State<'_, SharedDocker>,
+    pool: State<'_, ConnectionPool>,
+) -> Result<Status, BackendError> {
+    let module = ModuleInfo { name, version };
+    if !module_exists(&**docker, &module).await? {
+        warn!("Couln't find module {}", module);
+        return Ok(Status::NotFound);
+    }
+    let mut conn = pool.get().await;
+    let paused_key = util::get_module_paused_key(&module);
+    if !conn.exists(&paused_key).await? {
+        return Ok(Status::BadRequest);
+    }
+
+    for worker in module_containers_in_state(&**docker, &module, "paused").await? {
+        if let Err(e) = docker.unpause_container(&worker).await {
+            error!(
+                "Failed attempt to resume {} by {}: {}",
+                module, session.username, e
+            );
+            return Err(e);
+        }
+    }
 
-    //Assume that if the first container exists that the rest do.
-    let result = docker
-        .inspect_container(
-            &format!("{}-{}-0", module.name, module.version),
-            None::<InspectContainerOptions>,
+    //Give the workers a full heartbeat period to catch up before they can be reaped. Modules which have never sent a
+    //heartbeat aren't reaped at all, and mustn't look like they have.
+    if conn
+        .sismember(util::get_heartbeat_modules_key(), module.to_string())
+        .await?
+    {
+        conn.set_and_expire_seconds(
+            util::get_module_heartbeat_key(&module),
+            Utc::now().timestamp().to_string(),
+            crate::CONFIG.module.heartbeat_timeout,
         )
-        .await;
-    let containers_exist = match result {
-        Ok(_) => true,
-        Err(e) => match e.kind() {
-            ErrorKind::DockerResponseNotFoundError { .. } => false,
-            _ => return Err(BackendError::Docker(e)),
-        },
-    };
+        .await?;
+    }
+    //The queued jobs start timing out from now.
+    let job_timeout = get_module_timeout(
+        &mut conn,
+        &util::get_module_job_timeout_key(&module),
+        crate::CONFIG.jobs.job_timeout,
+    )
+    .await?;
+    if job_timeout != 0 {
+        for job_id in queued_job_ids(&mut conn, &module).await? {
+            set_job_deadline(&mut conn, job_id, job_timeout).await?;
+        }
+    }
+    conn.del(&paused_key).await?;
+    info!("module {} resumed by {}", module, session.username);
+    Ok(Status::NoContent)
+}
 
-    //Delete the containers if they exist.
-    if containers_exist {
-        let workers = {
-            let mut conn = pool.get().await;
-            conn.get(util::get_module_workers_key(&module))
-                .await
-                .expect("getting desired worker count")
-                .map(|s| String::from_utf8_lossy(&s).parse::<u8>().unwrap())
-                .unwrap()
+//Stop every running module which opted in to being stopped while idle and hasn't been sent a job within its idle
+//timeout. Returns the modules which were stopped.
+pub async fn stop_idle_modules(
+    docker: &dyn DockerBackend,
+    conn: &mut darkredis::Connection,
+) -> Result<Vec<ModuleInfo>, BackendError> {
+    //Every worker shows up as its own container.
+    let mut modules: Vec<ModuleInfo> = Vec::new();
+    for module in running_modules(docker).await? {
+        if !modules.contains(&module) {
+            modules.push(module);
+        }
+    }
+
+    let now = Utc::now().timestamp();
+    let mut stopped = Vec::new();
+    for module in modules {
+        //Paused modules are left alone until they are resumed.
+        if conn.exists(util::get_module_paused_key(&module)).await? {
+            continue;
+        }
+        let idle_timeout = match conn.get(util::get_module_idle_timeout_key(&module)).await? {
+            Some(t) => String::from_utf8_lossy(&t).parse::<u32>().map_err(|e| {
+                BackendError::Other(format!("Invalid idle timeout for {}: {}", module, e))
+            })?,
+            None => continue,
+        };
+        let last_used_key = util::get_module_last_used_key(&module);
+        let last_used = match conn.get(&last_used_key).await? {
+            Some(t) => String::from_utf8_lossy(&t).parse::<i64>().unwrap_or(0),
+            //Modules which were started outside of LAPS have no record of being used, so start counting now.
+            None => {
+                conn.set(&last_used_key, now.to_string()).await?;
+                continue;
+            }
         };
-        for w in 0..workers {
-            let this_container = format!("{}-{}-{}", module.name, module.version, w);
-            docker
-                .remove_container(&this_container, None::<RemoveContainerOptions>)
-                .await?;
-            debug!("Removed container {}", this_container);
+        //A job may still be running long after it was submitted, so wait until it would have timed out too.
+        let job_timeout = get_module_timeout(
+            conn,
+            &util::get_module_job_timeout_key(&module),
+            crate::CONFIG.jobs.job_timeout,
+        )
+        .await?;
+        let idle = now - last_used;
+        if idle < idle_timeout.max(job_timeout) as i64 {
+            continue;
+        }
+        //Jobs which are still queued will be picked up eventually.
+        if conn
+            .llen(util::get_module_work_key(&module))
+            .await?
+            .unwrap_or(0)
+            > 0
+        {
+            continue;
+        }
+
+        let workers = conn
+            .get(util::get_module_workers_key(&module))
+            .await?
+            .map(|s| String::from_utf8_lossy(&s).parse::<u8>().unwrap())
+            .unwrap_or(0);
+        let stop_timeout = get_module_stop_timeout(conn, &module).await?;
+        if let Err(e) = stop_workers(docker, &module, workers, stop_timeout).await {
+            error!("Failed to stop idle module {}: {}", module, e);
+            continue;
+        }
+        conn.set(util::get_module_idle_stopped_key(&module), "1")
+            .await?;
+        info!(
+            "Stopped module {} after {} seconds without any jobs",
+            module, idle
+        );
+        stopped.push(module);
+    }
+    Ok(stopped)
+}
+
+//Periodically stop modules which haven't been sent any jobs for a while.
+pub async fn idle_module_stopper(pool: ConnectionPool, docker: SharedDocker) {
+    let mut conn = pool.spawn("idle-module-stopper").await.unwrap();
+
+    let interval = Duration::from_secs(crate::CONFIG.module.idle_check_interval);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = stop_idle_modules(&*docker, &mut conn).await {
+            error!("Failed to stop idle modules: {}", e);
+        }
+    }
+}
+
+//Delete every cached job submitted to a module, returning the number of deleted cache entries.
+#[post("/module/<name>/<version>/cache/flush")]
+pub async fn flush_module_cache(
+    session: AdminSession,
+    name: String,
+    version: String,
+    pool: State<'_, ConnectionPool>,
+) -> Result<Json<usize>, BackendError> {
+    let module = ModuleInfo { name, version };
+    let mut conn = pool.get().await;
+    let deleted =
+        util::delete_matching_keys(&mut conn, &util::get_module_cache_pattern(&module)).await?;
+    info!(
+        "{} flushed {} cache entries for module {}",
+        session.username, deleted, module
+    );
+    Ok(Json(deleted))
+}
+
+//Cancel every job queued for a module without stopping its workers, returning the number of cancelled jobs.
+#[post("/module/<name>/<version>/drain")]
+pub async fn drain_module(
+    session: AdminSession,
+    name: String,
+    version: String,
+    pool: State<'_, ConnectionPool>,
+) -> Result<Json<usize>, BackendError> {
+    let module = ModuleInfo { name, version };
+    let mut conn = pool.get().await;
+    let cancelled = cancel_queued_jobs(&mut conn, &module).await?;
+    info!(
+        "{} drained module {}, cancelling {} queued jobs",
+        session.username, module, cancelled
+    );
+    Ok(Json(cancelled))
+}
+
+//The number of queued jobs listed when no limit is given.
+const DEFAULT_QUEUE_LIMIT: usize = 100;
+
+//List up to `limit` of the jobs queued for a module, in the order workers will pick them up. Entries which aren't
+//valid jobs are logged and left out, as workers would fail to read them too.
+#[get("/module/<name>/<version>/queue/jobs?<limit>")]
+pub async fn get_queued_jobs(
+    _session: AdminSession,
+    name: String,
+    version: String,
+    limit: Option<usize>,
+    pool: State<'_, ConnectionPool>,
+) -> Result<Json<Vec<JobInfo>>, BackendError> {
+    let limit = limit.unwrap_or(DEFAULT_QUEUE_LIMIT);
+    if limit == 0 {
+        return Ok(Json(Vec::new()));
+    }
+    let module = ModuleInfo { name, version };
+    let mut conn = pool.get().await;
+    let queued = conn
+        .lrange(util::get_module_work_key(&module), 0, limit as isize - 1)
+        .await?;
+
+    let mut jobs = Vec::with_capacity(queued.len());
+    for (index, entry) in queued.iter().enumerate() {
+        match serde_json::from_slice(entry) {
+            Ok(job) => jobs.push(job),
+            Err(e) => warn!(
+                "Skipping invalid job at position {} in the queue of {}: {}",
+                index, module, e
+            ),
+        }
+    }
+    Ok(Json(jobs))
+}
+
+//Remove every worker container of `module`, including any left behind by failed operations.
+//Returns the number of containers removed.
+async fn remove_module_containers(
+    docker: &dyn DockerBackend,
+    module: &ModuleInfo,
+) -> Result<usize, BackendError> {
+    let prefix = format!("{}-", module.to_string().replace(":", "-"));
+    let containers = docker.list_containers(true, false).await?;
+
+    let mut removed = 0;
+    for container in containers {
+        //Only match `<name>-<version>-<worker>` exactly, as names and versions may contain dashes themselves.
+        let is_worker = container.names.iter().any(|n| {
+            n.starts_with(&prefix)
+                && n.len() > prefix.len()
+                && n[prefix.len()..].chars().all(|c| c.is_ascii_digit())
+        });
+        if is_worker {
+            docker.remove_container(&container.id, true).await?;
+            debug!("Removed container {}", container.id);
+            removed += 1;
         }
     }
+    Ok(removed)
+}
+
+//Remove the image of `module`.
+//Every database key holding state or settings of `module`.
+fn module_keys(module: &ModuleInfo) -> Vec<String> {
+    vec![
+        util::get_module_log_key(module),
+        util::get_module_workers_key(module),
+        util::get_registered_module_workers_key(module),
+        util::get_module_registrations_key(module),
+        util::get_module_work_key(module),
+        util::get_module_cache_ttl_key(module),
+        util::get_module_job_timeout_key(module),
+        util::get_module_stop_timeout_key(module),
+        util::get_module_idle_timeout_key(module),
+        util::get_module_last_used_key(module),
+        util::get_module_idle_stopped_key(module),
+        util::get_module_paused_key(module),
+        util::get_module_custom_dockerfile_key(module),
+        util::get_module_gpu_key(module),
+        util::get_module_command_key(module),
+        util::get_module_env_key(module),
+        util::get_module_map_types_key(module),
+    ]
+}
+
+async fn remove_module_image(
+    docker: &dyn DockerBackend,
+    module: &ModuleInfo,
+) -> Result<(), BackendError> {
+    docker.remove_image(&module.to_string()).await
+}
+
+//The result of pruning module containers.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct PruneResult {
+    //How many containers were removed.
+    pub containers: usize,
+    //How much disk space was freed by removing them, if Docker reported it.
+    pub reclaimed_bytes: Option<u64>,
+}
+
+//Remove the stopped and failed containers of every module which isn't running. Images are left alone.
+//A module counts as running if any of its workers are, so the containers of partially exited modules are kept.
+#[post("/module/prune")]
+pub async fn prune_modules(
+    session: AdminSession,
+    docker: State<'_, SharedDocker>,
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
+    if !session.permissions().can_prune_modules {
+        warn!(
+            "Non-super admin {} attempted to prune module containers",
+            session.username
+        );
+        return Ok(error_response(
+            Status::Forbidden,
+            "forbidden",
+            "Only super admins can prune module containers",
+            Some(request_id),
+        )
+        .await);
+    }
+
+    //Ask for the container sizes as well in order to report how much space was reclaimed.
+    let containers: Vec<(ModuleInfo, Container)> = docker
+        .list_containers(true, true)
+        .await?
+        .into_iter()
+        .filter_map(|c| extract_module_info_from_tag(&c.image).map(|i| (i, c)))
+        .collect();
+    let running: Vec<&ModuleInfo> = containers
+        .iter()
+        .filter(|(_, c)| c.state == "running")
+        .map(|(m, _)| m)
+        .collect();
+
+    let mut pruned = 0;
+    let mut reclaimed_bytes = None;
+    for (module, container) in containers.iter() {
+        let stopped = container.state == "exited" || container.state == "dead";
+        if !stopped || running.contains(&module) {
+            continue;
+        }
+        docker.remove_container(&container.id, false).await?;
+        debug!(
+            "Pruned container {} of module {}",
+            container.names.join(", "),
+            module
+        );
+        pruned += 1;
+        if let Some(size) = container.size_rw {
+            reclaimed_bytes = Some(reclaimed_bytes.unwrap_or(0) + size);
+        }
+    }
+    info!(
+        "{} pruned {} module containers, reclaiming {} bytes",
+        session.username,
+        pruned,
+        reclaimed_bytes.unwrap_or(0)
+    );
+
+    let result = PruneResult {
+        containers: pruned,
+        reclaimed_bytes,
+    };
+    Ok(Response::build()
+        .header(ContentType::JSON)
+        .sized_body(Cursor::new(serde_json::to_string(&result).unwrap()))
+        .await
+        .finalize())
+}
+
+#[delete("/module/<name>/<version>")]
+pub async fn delete_module(
+    session: AdminSession,
+    name: String,
+    version: String,
+    docker: State<'_, SharedDocker>,
+    pool: State<'_, ConnectionPool>,
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
+    //Refuse to delete a module if it does not exist or is currently running
+    let module = ModuleInfo { name, version };
+    if !module_exists(&**docker, &module).await? {
+        return Ok(module_not_found(&module, request_id).await);
+    }
+    if module_is_running(&**docker, &module).await? {
+        return Ok(error_response(
+            Status::BadRequest,
+            "module_running",
+            "Cannot delete a running module!",
+            Some(request_id),
+        )
+        .await);
+    }
+
+    //Now we can delete the module. First off, the containers have to be deleted.
+    let removed = remove_module_containers(&**docker, &module).await?;
+    debug!("Removed {} containers of module {}", removed, module);
 
     //Remove all traces of the module from the database.
     {
         let mut conn = pool.get().await;
-        let keys = vec![
-            util::get_module_log_key(&module),
-            util::get_module_workers_key(&module),
-            util::get_registered_module_workers_key(&module),
-            util::get_module_work_key(&module),
-        ];
-        let deleted = conn.del_slice(&keys).await?;
+        let deleted = conn.del_slice(&module_keys(&module)).await?;
         debug!("Removed {} database entries related to {}", deleted, module);
     }
 
-    //Get the number of workers for this module
-    let options = RemoveImageOptions {
-        force: true,
-        noprune: false,
-    };
-    let image_deletions = docker
-        .remove_image(&module.to_string(), Some(options), None)
-        .await?;
-    //Output the deletions if debug log is active
-    if log_enabled!(log::Level::Debug) {
-        for deletion in image_deletions {
-            match deletion {
-                RemoveImageResults::RemoveImageUntagged { untagged } => {
-                    debug!("Untagged {}", untagged);
-                }
-                RemoveImageResults::RemoveImageDeleted { deleted } => {
-                    debug!("Deleted {}", deleted);
-                }
-            }
-        }
-    }
+    remove_module_image(&**docker, &module).await?;
 
     info!("Module {} deleted by {}", module, session.username);
 
@@ -0,0 +1,163 @@
+//src/web/admin/storage.rs: Report of the storage used by modules and maps.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use super::{
+    map::map_sizes,
+    modules::{extract_module_info_from_tag, is_ignored},
+    AdminSession,
+};
+use crate::{
+    docker::{DockerBackend, SharedDocker},
+    module_handling::ModuleInfo,
+    types::{error_response, BackendError},
+    util,
+    web::request_id::RequestId,
+};
+use chrono::Utc;
+use darkredis::ConnectionPool;
+use laps_convert::MapType;
+use rocket::{
+    http::{ContentType, Status},
+    request::State,
+    Response,
+};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+//How long, in seconds, a storage report is reused before it is computed again. Computing it means asking Docker for
+//every image and reading the size of every map.
+const STORAGE_REPORT_TTL: u32 = 60;
+
+//The size of the image of a module.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ModuleImageUsage {
+    #[serde(flatten)]
+    pub module: ModuleInfo,
+    pub bytes: u64,
+}
+
+//The log of a module.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ModuleLogUsage {
+    #[serde(flatten)]
+    pub module: ModuleInfo,
+    pub lines: isize,
+}
+
+//The storage used by modules and maps.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StorageReport {
+    //UNIX timestamp of when the report was computed.
+    pub computed: i64,
+    //The total size of every module image. Layers shared between images, such as the base image, are counted once
+    //for every image, so this is more than the disk space used.
+    pub module_bytes: u64,
+    pub module_images: Vec<ModuleImageUsage>,
+    pub map_count: usize,
+    //The bytes used by the images, slopes and metadata of every map.
+    pub map_bytes: u64,
+    pub log_lines: isize,
+    pub logs: Vec<ModuleLogUsage>,
+}
+
+//Compute the storage used right now.
+async fn compute_storage_report(
+    docker: &dyn DockerBackend,
+    conn: &mut darkredis::Connection,
+) -> Result<StorageReport, BackendError> {
+    let mut module_images: Vec<ModuleImageUsage> = docker
+        .list_image_sizes()
+        .await?
+        .into_iter()
+        .filter_map(|(tag, bytes)| {
+            extract_module_info_from_tag(&tag)
+                .filter(|m| !is_ignored(m))
+                .map(|module| ModuleImageUsage { module, bytes })
+        })
+        .collect();
+    module_images.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+
+    //Every type of map has its own ids, and they all count towards the storage used.
+    let mut map_count = 0;
+    let mut map_bytes = 0;
+    for map_type in &MapType::ALL {
+        let ids: Vec<String> = conn
+            .hkeys(util::get_mapdata_key(*map_type, "image"))
+            .await?
+            .into_iter()
+            .map(|id| String::from_utf8_lossy(&id).into_owned())
+            .collect();
+        map_count += ids.len();
+        map_bytes += map_sizes(conn, *map_type, &ids)
+            .await?
+            .into_iter()
+            .sum::<isize>() as u64;
+    }
+
+    let mut logs = Vec::with_capacity(module_images.len());
+    for image in &module_images {
+        let lines = conn
+            .llen(util::get_module_log_key(&image.module))
+            .await?
+            .unwrap_or(0);
+        logs.push(ModuleLogUsage {
+            module: image.module.clone(),
+            lines,
+        });
+    }
+
+    Ok(StorageReport {
+        computed: Utc::now().timestamp(),
+        module_bytes: module_images.iter().map(|i| i.bytes).sum(),
+        module_images,
+        map_count,
+        map_bytes,
+        log_lines: logs.iter().map(|l| l.lines).sum(),
+        logs,
+    })
+}
+
+//Report the storage used by module images, maps and module logs. Only super admins can see it.
+#[get("/admin/storage")]
+pub async fn storage_usage(
+    session: AdminSession,
+    docker: State<'_, SharedDocker>,
+    pool: State<'_, ConnectionPool>,
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
+    if !session.permissions().can_view_storage {
+        warn!(
+            "Non-super admin {} attempted to get the storage usage",
+            session.username
+        );
+        return Ok(error_response(
+            Status::Forbidden,
+            "forbidden",
+            "Only super admins can see the storage usage",
+            Some(request_id),
+        )
+        .await);
+    }
+
+    //Reuse a recent report, as the dashboard asks for it often.
+    let mut conn = pool.get().await;
+    let key = util::get_storage_report_key();
+    let body = match conn.get(&key).await? {
+        Some(report) => report,
+        None => {
+            let report = compute_storage_report(&**docker, &mut conn).await?;
+            let body = serde_json::to_vec(&report)?;
+            conn.set_and_expire_seconds(&key, &body, STORAGE_REPORT_TTL)
+                .await?;
+            body
+        }
+    };
+
+    Ok(Response::build()
+        .header(ContentType::JSON)
+        .sized_body(Cursor::new(body))
+        .await
+        .finalize())
+}
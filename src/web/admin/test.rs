@@ -4,16 +4,19 @@
 //Distributed under the zlib licence, see LICENCE.
 
 use super::*;
-use crate::{module_handling::ModuleInfo, util};
-use bollard::container::ListContainersOptions;
+use crate::{
+    docker::{FakeDocker, SharedDocker},
+    module_handling::ModuleInfo,
+    util,
+};
 use modules::{module_exists, module_is_running};
 use multipart::client::lazy::Multipart;
 use rocket::{
-    http::{ContentType, Cookie, Status},
+    http::{ContentType, Cookie, Method, Status},
     local::{Client, LocalResponse},
 };
 use serial_test::serial;
-use std::io::Read;
+use std::{io::Read, sync::Arc};
 
 //Create a test account using the initial setup handler. Will only work with that.
 async fn create_test_account(username: &str, password: &str, client: &Client) {
@@ -63,6 +66,7 @@ async fn map_manipulation() {
             "/",
             routes![new_map, login, delete_map, register_super_admin],
         )
+        .register(crate::web::catchers())
         .manage(redis.clone());
     let client = Client::new(rocket).unwrap();
     let mut conn = redis.get().await;
@@ -96,11 +100,15 @@ async fn map_manipulation() {
     request.set_body(form.as_slice());
     let mut response = request.dispatch().await;
     assert_eq!(response.status(), Status::BadRequest);
-    assert!(response
-        .body_string()
-        .await
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: serde_json::Value =
+        serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+    assert_eq!(body["error"]["code"], "module_import");
+    assert!(body["error"]["message"]
+        .as_str()
         .unwrap()
         .contains("Invalid Tiff header"));
+    assert!(body["error"]["request_id"].is_string());
 
     //Send a valid TIFF this time.
     let mut multipart = Multipart::new()
@@ -130,10 +138,12 @@ async fn map_manipulation() {
     let mut response = request.dispatch().await;
     assert_eq!(response.status(), Status::Ok);
     assert!(response.content_type().unwrap().is_json());
-    assert_eq!(
-        serde_json::from_slice::<u32>(&response.body_bytes().await.unwrap()).unwrap(),
-        1
-    );
+    //The metadata of the new map is included so that it doesn't have to be fetched separately.
+    let new_map: NewMap = serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(new_map.id, 1);
+    assert!(new_map.metadata.x_res > 0.0);
+    assert!(new_map.metadata.min_height < new_map.metadata.max_height);
+    assert!(!new_map.metadata.flat);
 
     //And create another to ensure that it gets the correct ID.
     let mut request = client
@@ -148,32 +158,74 @@ async fn map_manipulation() {
     let mut response = request.dispatch().await;
     assert_eq!(response.status(), Status::Ok);
     assert!(response.content_type().unwrap().is_json());
-    assert_eq!(
-        serde_json::from_slice::<u32>(&response.body_bytes().await.unwrap()).unwrap(),
-        2
-    );
+    let new_map: NewMap = serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(new_map.id, 2);
+
+    //The import stores both the image and the metadata, so that checking for their removal below means something.
+    let image_key = util::create_redis_key("mapdata.image");
+    let meta_key = util::create_redis_key("mapdata.meta");
+    assert!(conn.hget(&image_key, "2").await.unwrap().is_some());
+    assert!(conn.hget(&meta_key, "2").await.unwrap().is_some());
 
     //Test that deletion works.
     let request = client.delete("/map/2").cookies(response_cookies.clone());
     let response = request.dispatch().await;
     assert_eq!(response.status(), Status::NoContent);
 
-    //Check that the data is gone from Redis, as well as the metadata.
-    assert!(conn
-        .hget(util::create_redis_key("mapdata.image"), "2")
-        .await
-        .unwrap()
-        .is_none());
-    assert!(conn
-        .hget(util::create_redis_key("mapdata.meta"), "2")
-        .await
-        .unwrap()
-        .is_none());
+    //Check that the data is gone from Redis, as well as the metadata, while map 1 is left alone.
+    assert!(conn.hget(&image_key, "2").await.unwrap().is_none());
+    assert!(conn.hget(&meta_key, "2").await.unwrap().is_none());
+    assert!(conn.hget(&image_key, "1").await.unwrap().is_some());
+    assert!(conn.hget(&meta_key, "1").await.unwrap().is_some());
 
-    //Try to delete it again and fail.
-    let request = client.delete("/map/2").cookies(response_cookies);
+    //Every type of map has its own ids, so there is no orthophoto with the id of the elevation map.
+    let request = client
+        .delete("/map/1?type=rgb")
+        .cookies(response_cookies.clone());
     let response = request.dispatch().await;
     assert_eq!(response.status(), Status::NotFound);
+    assert!(conn.hget(&image_key, "1").await.unwrap().is_some());
+
+    //Try to delete it again and fail, with the same error body as any other error.
+    let request = client.delete("/map/2").cookies(response_cookies);
+    let mut response = request.dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: serde_json::Value =
+        serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+    assert_eq!(body["error"]["code"], "not_found");
+    assert!(body["error"]["request_id"].is_string());
+}
+
+#[tokio::test]
+async fn conversion_timeout() {
+    use laps_convert::ConvertError;
+    use std::{sync::mpsc, time::Duration};
+
+    //A conversion which never finishes on its own is cancelled once the timeout expires.
+    let (sender, receiver) = mpsc::channel();
+    let result = map::convert_with_timeout(
+        move |token| {
+            while !token.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            sender.send(()).unwrap();
+            Ok(())
+        },
+        Duration::from_millis(100),
+    )
+    .await;
+    match result {
+        Err(ConvertError::Timeout(t)) => assert_eq!(t, Duration::from_millis(100)),
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("conversion was not cancelled");
+
+    //Conversions finishing in time are returned as-is.
+    let result = map::convert_with_timeout(|_| Ok(1), Duration::from_secs(5)).await;
+    assert_eq!(result.unwrap(), 1);
 }
 
 #[tokio::test]
@@ -382,11 +434,10 @@ async fn module_logs() {
             ],
         )
         .manage(redis.clone())
-        .manage(crate::connect_to_docker().await);
+        .manage(crate::docker::shared(FakeDocker::default()));
     let client = Client::new(rocket).unwrap();
     let mut conn = redis.get().await;
     crate::test::clear_redis(&mut conn).await;
-    crate::test::clean_docker(&crate::connect_to_docker().await).await;
     tokio::spawn(crate::module_handling::run(redis.clone()));
 
     let cookies = create_test_account_and_login(&client).await;
@@ -422,26 +473,249 @@ async fn module_logs() {
     assert_eq!(response.status(), Status::Ok);
     assert!(response.body_string().await.unwrap().is_empty());
 
-    //Start up the test module
+    //Start up the test module and log its startup message the way its worker would.
     let response = client
         .post(format!("/module/{}/{}/restart", name, version))
         .cookies(cookies.clone())
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::Created);
+    let entry = serde_json::json!({
+        "module": { "name": name, "version": version },
+        "message": "Registered as worker 0",
+        "level": "info",
+        "instant": 0,
+        "worker": 0
+    });
+    conn.rpush(
+        util::create_redis_key("moduleLogs"),
+        serde_json::to_vec(&entry).unwrap(),
+    )
+    .await
+    .unwrap();
+
+    //Try to get the module logs, this time it should have the startup message once the listener has stored it.
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    loop {
+        let mut response = client
+            .get(format!("/module/{}/{}/logs", name, version))
+            .cookies(cookies.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().await.unwrap();
+        if body.contains("Registered as") {
+            break;
+        }
+        assert!(
+            std::time::Instant::now() < deadline,
+            "timed out storing logs"
+        );
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+    }
+}
+
+//Test fetching only the module log lines logged after a given time.
+#[tokio::test]
+#[serial]
+async fn module_logs_since() {
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, get_module_logs])
+        .manage(redis.clone())
+        .manage(crate::docker::shared(FakeDocker::with_images(&[
+            "laps-test:0.1.0",
+        ])));
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    let lines = [
+        "[2020-05-01T12:00:00Z info worker:0] first",
+        "[2020-05-01T12:00:01Z info worker:0] second",
+        "[2020-05-01T12:00:01Z info worker:1] third",
+        "[2020-05-01T12:00:02Z info worker:0] fourth",
+    ];
+    for line in &lines {
+        conn.rpush(util::get_module_log_key(&module), line)
+            .await
+            .unwrap();
+    }
+
+    let get_logs = |since: &str| {
+        let uri = if since.is_empty() {
+            "/module/laps-test/0.1.0/logs".to_string()
+        } else {
+            format!("/module/laps-test/0.1.0/logs?since={}", since)
+        };
+        client.get(uri).cookies(cookies.clone()).dispatch()
+    };
+    async fn body_lines(response: &mut LocalResponse<'_>) -> Vec<String> {
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().await.unwrap();
+        body.lines().map(|l| l.to_string()).collect()
+    }
+
+    //Without a timestamp every line is returned, along with the time of the newest one.
+    let mut response = get_logs("").await;
+    assert_eq!(
+        response.headers().get_one("X-Last-Log-Time"),
+        Some("2020-05-01T12:00:02Z")
+    );
+    assert_eq!(body_lines(&mut response).await, lines);
+
+    //Lines logged at or after the timestamp are returned, so that lines logged later in the same second aren't missed.
+    let mut response = get_logs("2020-05-01T12:00:01Z").await;
+    assert_eq!(body_lines(&mut response).await, &lines[1..]);
+    let mut response = get_logs("2020-05-01T12:00:00Z").await;
+    assert_eq!(body_lines(&mut response).await, lines);
+    let mut response = get_logs("2020-05-01T11:59:59Z").await;
+    assert_eq!(body_lines(&mut response).await, lines);
+    //Passing back the time of the newest line returns it again, but nothing older.
+    let mut response = get_logs("2020-05-01T12:00:02Z").await;
+    assert_eq!(
+        response.headers().get_one("X-Last-Log-Time"),
+        Some("2020-05-01T12:00:02Z")
+    );
+    assert_eq!(body_lines(&mut response).await, &lines[3..]);
+    let mut response = get_logs("2020-05-01T12:00:03Z").await;
+    assert!(body_lines(&mut response).await.is_empty());
+
+    let response = get_logs("yesterday").await;
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[tokio::test]
+#[serial]
+//Fails if login test fails
+async fn restart_wait_timeout() {
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![upload_module, login, register_super_admin, restart_module],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    tokio::spawn(crate::module_handling::run(redis.clone()));
+
+    let cookies = create_test_account_and_login(&client).await;
+    let module = ModuleInfo {
+        name: "laps-failing-test".into(),
+        version: "0.1.0".into(),
+    };
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        crate::test::INSTANTLY_FAILING_TEST_CONTAINER,
+        &module.name,
+        &module.version,
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
 
-    //Sleep for a bit to let the module start up, but more importantly yield control to the module handling task.
-    tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+    //Without waiting, starting the module succeeds even though it never registers.
+    let response = client
+        .post(format!(
+            "/module/{}/{}/restart",
+            module.name, module.version
+        ))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Created);
 
-    //Try to get the module logs, this time it should have the startup message.
+    //When waiting, we give up once the startup timeout runs out
     let mut response = client
-        .get(format!("/module/{}/{}/logs", name, version))
+        .post(format!(
+            "/module/{}/{}/restart?wait=true",
+            module.name, module.version
+        ))
         .cookies(cookies.clone())
         .dispatch()
         .await;
-    assert_eq!(response.status(), Status::Ok);
-    let body = response.body_string().await.unwrap();
-    assert!(body.contains("Registered as"));
+    assert_eq!(response.status(), Status::GatewayTimeout);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+    assert_eq!(body["error"]["code"], "module_startup_timeout");
+    assert!(body["error"]["request_id"].is_string());
+}
+
+#[tokio::test]
+#[serial]
+//Fails if login test fails
+async fn restart_waits_for_new_workers() {
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![upload_module, login, register_super_admin, restart_module],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    tokio::spawn(crate::module_handling::run(redis.clone()));
+
+    let cookies = create_test_account_and_login(&client).await;
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        &module.name,
+        &module.version,
+        Some(1),
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let url = format!(
+        "/module/{}/{}/restart?wait=true",
+        module.name, module.version
+    );
+
+    //The worker registers a little while after being started.
+    let register = |delay: u64| {
+        let redis = redis.clone();
+        let data = serde_json::to_vec(&module).unwrap();
+        tokio::spawn(async move {
+            tokio::time::delay_for(std::time::Duration::from_millis(delay)).await;
+            let key = util::create_redis_backend_key("register-module");
+            redis.get().await.rpush(key, data).await.unwrap();
+        })
+    };
+    register(300);
+    let response = client.post(&url).cookies(cookies.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Created);
+    let active = conn
+        .get(util::get_registered_module_workers_key(&module))
+        .await
+        .unwrap();
+    assert_eq!(active, Some(b"1".to_vec()));
+
+    //Restarting waits for the worker to register again, even though the old worker is still counted as running.
+    register(300);
+    let response = client.post(&url).cookies(cookies.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::NoContent);
+
+    //If it doesn't, the restart times out.
+    let response = client.post(&url).cookies(cookies.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::GatewayTimeout);
 }
 
 #[tokio::test]
@@ -450,29 +724,28 @@ async fn module_logs() {
 async fn get_modules() {
     //Setup rocket instance
     let redis = crate::create_redis_pool().await;
-    let docker = crate::connect_to_docker().await;
+    let docker = Arc::new(FakeDocker::default());
     let rocket = rocket::ignite()
         .mount(
             "/",
             routes![
                 login,
                 get_all_modules,
+                get_latest_module,
+                get_module,
                 upload_module,
                 register_super_admin,
                 restart_module
             ],
         )
         .manage(redis.clone())
-        .manage(crate::connect_to_docker().await);
+        .manage(docker.clone() as SharedDocker);
     let client = Client::new(rocket).unwrap();
     let mut conn = redis.get().await;
     crate::test::clear_redis(&mut conn).await;
 
     let cookies = create_test_account_and_login(&client).await;
 
-    //Remove the test image if it exists
-    crate::test::clean_docker(&docker).await;
-
     //Upload the test image using the endpoint
     let module = ModuleInfo {
         name: "laps-test".into(),
@@ -499,14 +772,49 @@ async fn get_modules() {
     assert_eq!(response.content_type().unwrap(), ContentType::JSON);
     let images: Vec<PathModule> =
         serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
-    assert!(
-        images
-            .into_iter()
-            .find(|m| &m.module == &module)
-            .unwrap()
-            .state
-            == ModuleState::Stopped
-    );
+    let listed = images.into_iter().find(|m| &m.module == &module).unwrap();
+    assert_eq!(listed.state, ModuleState::Stopped);
+    assert_eq!(listed.configured_workers, 1);
+    assert_eq!(listed.active_workers, 0);
+
+    //The only version of the module is also the latest one.
+    let mut response = client
+        .get(format!("/module/{}/latest", module.name))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let latest: ModuleInfo = serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(latest, module);
+    let response = client
+        .get("/module/does-not-exist/latest")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    //Get the details of the module
+    let mut response = client
+        .get(format!("/module/{}/{}", module.name, module.version))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let details: ModuleDetails =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(details.module, module);
+    assert_eq!(details.state, ModuleState::Stopped);
+    assert_eq!(details.workers, 1);
+    assert_eq!(details.active_workers, 0);
+    assert_eq!(details.queued_jobs, 0);
+    assert!(!details.ignored);
+    assert!(details.environment.is_empty());
+    let response = client
+        .get(format!("/module/{}/9.9.9", module.name))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
 
     //Try to add the module again, should fail as we already have a module with the same name and version.
     let response = crate::test::upload_test_image(
@@ -520,18 +828,6 @@ async fn get_modules() {
     .await;
     assert_eq!(response.status(), Status::BadRequest);
 
-    //Upload an invalid module.
-    let response = crate::test::upload_test_image(
-        &client,
-        &cookies,
-        &[0u8],
-        "some-unique-name",
-        &module.version,
-        None,
-    )
-    .await;
-    assert_eq!(response.status(), Status::BadRequest);
-
     //Now test that a failing module shows up as failing...
     //First, upload a module which fails immediately:
     let failing_module = ModuleInfo {
@@ -570,8 +866,8 @@ async fn get_modules() {
         .await;
     assert_eq!(response.status(), Status::Created);
 
-    //Sleep for a bit to let the module start up, but more importantly yield control to the module handling task.
-    tokio::time::delay_for(std::time::Duration::from_millis(300)).await;
+    //The failing module exits right away.
+    docker.exit_container("laps-failing-test-0.1.0-0", 1);
 
     //Now ensure that they are both returned by the /module/all endpoint and that their states are correct:
     let mut response = client
@@ -583,7 +879,17 @@ async fn get_modules() {
     assert_eq!(response.content_type().unwrap(), ContentType::JSON);
     let images: Vec<PathModule> =
         serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
-    assert!(images.iter().find(|m| m.module == module).unwrap().state == ModuleState::Running);
+    let listed = images.iter().find(|m| m.module == module).unwrap();
+    assert_eq!(listed.state, ModuleState::Running);
+    assert_eq!(listed.configured_workers, 1);
+    assert_eq!(
+        listed.active_workers,
+        conn.get(util::get_registered_module_workers_key(&module))
+            .await
+            .unwrap()
+            .map(|s| String::from_utf8_lossy(&s).parse::<i64>().unwrap())
+            .unwrap_or(0)
+    );
     assert!(
         images
             .iter()
@@ -592,6 +898,23 @@ async fn get_modules() {
             .state
             == ModuleState::Failed { exit_code: 1 }
     );
+
+    //The modules are sorted by name and version.
+    let position = |module: &ModuleInfo| images.iter().position(|m| &m.module == module).unwrap();
+    assert!(position(&failing_module) < position(&module));
+
+    //Upload an invalid module, which fails to build.
+    docker.fail_builds(false, "invalid tarball");
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        &[0u8],
+        "some-unique-name",
+        &module.version,
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), Status::BadRequest);
 }
 
 #[tokio::test]
@@ -599,7 +922,7 @@ async fn get_modules() {
 async fn start_stop_module() {
     //Setup rocket instance
     let redis = crate::create_redis_pool().await;
-    let docker = crate::connect_to_docker().await;
+    let docker = Arc::new(FakeDocker::default());
     let rocket = rocket::ignite()
         .mount(
             "/",
@@ -613,23 +936,20 @@ async fn start_stop_module() {
             ],
         )
         .manage(redis.clone())
-        .manage(crate::connect_to_docker().await);
+        .manage(docker.clone() as SharedDocker);
     let client = Client::new(rocket).unwrap();
     let mut conn = redis.get().await;
     crate::test::clear_redis(&mut conn).await;
 
     let cookies = create_test_account_and_login(&client).await;
 
-    //Remove any old images if they exist and the container
-    crate::test::clean_docker(&docker).await;
-
     //Check that the module doesn't exist from before
     let module = ModuleInfo {
         name: "laps-test".into(),
         version: "0.1.0".into(),
     };
-    assert!(!module_exists(&docker, &module).await.unwrap());
-    assert!(!module_is_running(&docker, &module).await.unwrap());
+    assert!(!module_exists(&*docker, &module).await.unwrap());
+    assert!(!module_is_running(&*docker, &module).await.unwrap());
 
     //Upload the test image
     let response = crate::test::upload_test_image(
@@ -642,8 +962,8 @@ async fn start_stop_module() {
     )
     .await;
     assert_eq!(response.status(), Status::Created);
-    assert!(module_exists(&docker, &module).await.unwrap());
-    assert!(!module_is_running(&docker, &module).await.unwrap());
+    assert!(module_exists(&*docker, &module).await.unwrap());
+    assert!(!module_is_running(&*docker, &module).await.unwrap());
 
     //Interresting part: Start the module and check that it's running
     let response = client
@@ -655,7 +975,7 @@ async fn start_stop_module() {
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::Created);
-    assert!(module_is_running(&docker, &module).await.unwrap());
+    assert!(module_is_running(&*docker, &module).await.unwrap());
 
     //Restart the module, verify that it was restarted and not started.
     let response = client
@@ -667,7 +987,7 @@ async fn start_stop_module() {
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::NoContent);
-    assert!(module_is_running(&docker, &module).await.unwrap());
+    assert!(module_is_running(&*docker, &module).await.unwrap());
 
     //Now kill the laps-test module.
     let response = client
@@ -676,7 +996,7 @@ async fn start_stop_module() {
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::NoContent);
-    assert!(!module_is_running(&docker, &module).await.unwrap());
+    assert!(!module_is_running(&*docker, &module).await.unwrap());
 
     //Start it back up, verifying that it was started up again.
     let response = client
@@ -688,7 +1008,7 @@ async fn start_stop_module() {
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::Created);
-    assert!(module_is_running(&docker, &module).await.unwrap());
+    assert!(module_is_running(&*docker, &module).await.unwrap());
 
     //Kill it again
     let response = client
@@ -697,7 +1017,7 @@ async fn start_stop_module() {
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::NoContent);
-    assert!(!module_is_running(&docker, &module).await.unwrap());
+    assert!(!module_is_running(&*docker, &module).await.unwrap());
 
     //Try to kill a stopped module, which should fail
     let response = client
@@ -706,7 +1026,7 @@ async fn start_stop_module() {
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::BadRequest);
-    assert!(!module_is_running(&docker, &module).await.unwrap());
+    assert!(!module_is_running(&*docker, &module).await.unwrap());
 }
 
 #[tokio::test]
@@ -715,18 +1035,17 @@ async fn start_stop_module() {
 async fn ignored_modules() {
     //setup rocket instance
     let redis = crate::create_redis_pool().await;
-    let docker = crate::connect_to_docker().await;
+    let docker = Arc::new(FakeDocker::default());
     let rocket = rocket::ignite()
         .mount(
             "/",
             routes![get_all_modules, login, upload_module, register_super_admin,],
         )
         .manage(redis.clone())
-        .manage(crate::connect_to_docker().await);
+        .manage(docker.clone() as SharedDocker);
     let client = Client::new(rocket).unwrap();
     let mut conn = redis.get().await;
     crate::test::clear_redis(&mut conn).await;
-    crate::test::clean_docker(&docker).await;
     let cookies = create_test_account_and_login(&client).await;
 
     //Upload a test module which we should be able to see.
@@ -786,16 +1105,12 @@ async fn ignored_modules() {
         serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
     assert!(modules.contains(&PathModule {
         module: visible_module.clone(),
-        state: ModuleState::Stopped
-    }));
-    assert!(!modules.contains(&PathModule {
-        module: hidden_module_1.clone(),
-        state: ModuleState::Stopped
-    }));
-    assert!(!modules.contains(&PathModule {
-        module: hidden_module_2.clone(),
-        state: ModuleState::Stopped
+        state: ModuleState::Stopped,
+        configured_workers: 1,
+        active_workers: 0,
     }));
+    assert!(!modules.iter().any(|m| m.module == hidden_module_1));
+    assert!(!modules.iter().any(|m| m.module == hidden_module_2));
 }
 
 #[tokio::test]
@@ -804,7 +1119,7 @@ async fn ignored_modules() {
 async fn concurrent_module_start() {
     //setup rocket instance
     let redis = crate::create_redis_pool().await;
-    let docker = crate::connect_to_docker().await;
+    let docker = Arc::new(FakeDocker::default());
     let rocket = rocket::ignite()
         .mount(
             "/",
@@ -817,11 +1132,10 @@ async fn concurrent_module_start() {
             ],
         )
         .manage(redis.clone())
-        .manage(crate::connect_to_docker().await);
+        .manage(docker.clone() as SharedDocker);
     let client = Client::new(rocket).unwrap();
     let mut conn = redis.get().await;
     crate::test::clear_redis(&mut conn).await;
-    crate::test::clean_docker(&docker).await;
     let cookies = create_test_account_and_login(&client).await;
 
     //Upload and start a module with two workers.
@@ -851,43 +1165,111 @@ async fn concurrent_module_start() {
 
     //Verify that there actually are two running containers from the same module image.
     let containers: Vec<String> = docker
-        .list_containers(None::<ListContainersOptions<String>>)
-        .await
-        .unwrap()
+        .containers()
         .into_iter()
-        .map(|c| c.names)
-        .flatten()
+        .filter(|c| c.state == "running")
+        .map(|c| c.names[0].clone())
         .collect();
-    //Container names start with a /
-    assert!(containers.contains(&"/laps-test-0.1.0-0".to_string()));
-    assert!(containers.contains(&"/laps-test-0.1.0-1".to_string()));
+    assert!(containers.contains(&"laps-test-0.1.0-0".to_string()));
+    assert!(containers.contains(&"laps-test-0.1.0-1".to_string()));
+
+    //Both workers are reported in the module list.
+    let mut response = client
+        .get("/module/all")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let modules: Vec<PathModule> =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    let listed = modules.iter().find(|m| m.module == module).unwrap();
+    assert_eq!(listed.configured_workers, 2);
 }
 
-//Test that a module can be deleted.
+//Test that a module which fails to start partway through leaves none of the containers it created behind, but
+//doesn't touch the ones which were already there.
 #[tokio::test]
 #[serial]
-async fn module_deletion() {
+async fn failed_start_rollback() {
+    use crate::docker::{ContainerSpec, DockerBackend};
+
     //setup rocket instance
     let redis = crate::create_redis_pool().await;
-    let docker = crate::connect_to_docker().await;
+    let docker = Arc::new(FakeDocker::default());
     let rocket = rocket::ignite()
         .mount(
             "/",
-            routes![
-                login,
-                upload_module,
-                register_super_admin,
-                restart_module,
-                stop_module,
-                delete_module
-            ],
+            routes![login, upload_module, register_super_admin, restart_module],
         )
         .manage(redis.clone())
-        .manage(crate::connect_to_docker().await);
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    for module in &["laps-test", "laps-foo"] {
+        let response = crate::test::upload_test_image(
+            &client,
+            &cookies,
+            crate::test::TEST_CONTAINER,
+            module,
+            "0.1.0",
+            Some(2),
+        )
+        .await;
+        assert_eq!(response.status(), Status::Created);
+    }
+    let restart = |module: &str| {
+        let request = client
+            .post(format!("/module/{}/0.1.0/restart", module))
+            .cookies(cookies.clone());
+        async move { request.dispatch().await.status() }
+    };
+    let names = || -> Vec<String> {
+        docker
+            .containers()
+            .into_iter()
+            .map(|c| c.names[0].clone())
+            .collect()
+    };
+
+    //The second worker fails to be created, so the first one is removed again.
+    docker.fail_creating("laps-test-0.1.0-1");
+    assert_eq!(restart("laps-test").await, Status::InternalServerError);
+    assert!(names().is_empty());
+
+    //Leave only the second worker container of the other module behind, as if it was created by someone else.
+    //Starting the module then fails on the missing first worker, and the container is left alone.
+    let spec = ContainerSpec {
+        name: "laps-foo-0.1.0-1",
+        image: "laps-foo:0.1.0",
+        cmd: Vec::new(),
+        env: Vec::new(),
+        gpu: false,
+    };
+    docker.create_container(spec).await.unwrap();
+    assert_eq!(restart("laps-foo").await, Status::InternalServerError);
+    assert_eq!(names(), vec!["laps-foo-0.1.0-1".to_string()]);
+}
+
+//Test the limits on the number of workers a module can be uploaded with.
+#[tokio::test]
+#[serial]
+async fn module_worker_limits() {
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![login, upload_module, register_super_admin, get_module],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
     let client = Client::new(rocket).unwrap();
     let mut conn = redis.get().await;
     crate::test::clear_redis(&mut conn).await;
-    crate::test::clean_docker(&docker).await;
     let cookies = create_test_account_and_login(&client).await;
 
     let module = ModuleInfo {
@@ -895,14 +1277,373 @@ async fn module_deletion() {
         version: "0.1.0".into(),
     };
 
-    //Try to delete the module, which hasn't been created yet so it should fail
-    let response = client
-        .delete(format!("/module/{}/{}", module.name, module.version))
-        .cookies(cookies.clone())
-        .dispatch()
+    //Neither zero workers nor more than the configured maximum are allowed.
+    let max_workers = crate::CONFIG.module.max_workers_per_module;
+    for workers in &[0, max_workers + 1] {
+        let mut response = crate::test::upload_test_image(
+            &client,
+            &cookies,
+            crate::test::TEST_CONTAINER,
+            &module.name,
+            &module.version,
+            Some(*workers),
+        )
         .await;
-    assert_eq!(response.status(), Status::NotFound);
-
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("worker"));
+        assert!(!module_exists(&*docker, &module).await.unwrap());
+    }
+
+    //The maximum itself is fine.
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        &module.name,
+        &module.version,
+        Some(max_workers),
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let mut response = client
+        .get(format!("/module/{}/{}", module.name, module.version))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let details: ModuleDetails =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(details.workers, max_workers);
+}
+
+//Test uploading modules built from their own Dockerfile.
+#[tokio::test]
+#[serial]
+async fn custom_dockerfile() {
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, upload_module, register_super_admin])
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+
+    //A Dockerfile without a base image is rejected before anything is built.
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        &module.name,
+        &module.version,
+        &[("dockerfile", "# Nothing here\nRUN echo hello")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(!module_exists(&*docker, &module).await.unwrap());
+
+    //The same steps as the bundled Dockerfile, but with the module started through its entrypoint.
+    let dockerfile = concat!(
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/laps_module_runner/Dockerfile"
+        )),
+        "ENTRYPOINT [\"python3\", \"main.py\"]\n"
+    );
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        &module.name,
+        &module.version,
+        &[("dockerfile", dockerfile)],
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    assert!(module_exists(&*docker, &module).await.unwrap());
+    assert!(conn
+        .exists(&util::get_module_custom_dockerfile_key(&module))
+        .await
+        .unwrap());
+}
+
+//Test that environment variables given on upload are passed to the module's containers, but not returned.
+#[tokio::test]
+#[serial]
+async fn module_environment() {
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                upload_module,
+                register_super_admin,
+                restart_module,
+                stop_module,
+                get_module
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+
+    //Invalid variable names are rejected.
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        &module.name,
+        &module.version,
+        &[("env", "1NVALID=value")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let env = "# Comments are skipped\nLAPS_TEST_SECRET=very secret=value\n\nLAPS_TEST_OTHER=1";
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        &module.name,
+        &module.version,
+        &[("env", env)],
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let response = client
+        .post(format!(
+            "/module/{}/{}/restart",
+            module.name, module.version
+        ))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Created);
+
+    //The created container should have the variables set.
+    let container_env = docker.container_env("laps-test-0.1.0-0").unwrap();
+    assert!(container_env.contains(&"LAPS_TEST_SECRET=very secret=value".to_string()));
+    assert!(container_env.contains(&"LAPS_TEST_OTHER=1".to_string()));
+
+    //Only the names can be read back.
+    let mut response = client
+        .get(format!("/module/{}/{}", module.name, module.version))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.body_bytes().await.unwrap();
+    assert!(!String::from_utf8_lossy(&body).contains("very secret"));
+    let details: ModuleDetails = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        details.environment,
+        vec![
+            "LAPS_TEST_OTHER".to_string(),
+            "LAPS_TEST_SECRET".to_string()
+        ]
+    );
+
+    let response = client
+        .post(format!("/module/{}/{}/stop", module.name, module.version))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+}
+
+//Test that pruning removes the containers of stopped modules, but keeps the ones of running modules.
+#[tokio::test]
+#[serial]
+async fn prune_module_containers() {
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                upload_module,
+                register_admin,
+                register_super_admin,
+                restart_module,
+                stop_module,
+                prune_modules
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Start two modules with two workers each, then stop one of them.
+    let stopped_module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    let running_module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.2.0".into(),
+    };
+    for module in [&stopped_module, &running_module].iter() {
+        let response = crate::test::upload_test_image(
+            &client,
+            &cookies,
+            crate::test::TEST_CONTAINER,
+            &module.name,
+            &module.version,
+            Some(2),
+        )
+        .await;
+        assert_eq!(response.status(), Status::Created);
+        let response = client
+            .post(format!(
+                "/module/{}/{}/restart",
+                module.name, module.version
+            ))
+            .cookies(cookies.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Created);
+    }
+    let response = client
+        .post(format!(
+            "/module/{}/{}/stop",
+            stopped_module.name, stopped_module.version
+        ))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+
+    //Only super admins may prune containers.
+    let form = "username=second-admin&password=password";
+    let response = client
+        .post("/register")
+        .body(form)
+        .cookies(cookies.clone())
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Created);
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(form)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+    let second_cookies: Vec<Cookie<'static>> = response
+        .cookies()
+        .into_iter()
+        .map(|s| s.into_owned())
+        .collect();
+    let response = client
+        .post("/module/prune")
+        .cookies(second_cookies)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let mut response = client
+        .post("/module/prune")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type().unwrap(), ContentType::JSON);
+    let result: PruneResult =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(result.containers, 2);
+
+    //The containers of the running module and both images should be left alone.
+    let containers: Vec<String> = docker
+        .containers()
+        .into_iter()
+        .map(|c| c.names[0].clone())
+        .collect();
+    assert!(!containers.iter().any(|c| c.starts_with("laps-test-0.1.0-")));
+    assert!(containers.contains(&"laps-test-0.2.0-0".to_string()));
+    assert!(containers.contains(&"laps-test-0.2.0-1".to_string()));
+    assert!(module_exists(&*docker, &stopped_module).await.unwrap());
+    assert!(module_is_running(&*docker, &running_module).await.unwrap());
+
+    let response = client
+        .post(format!(
+            "/module/{}/{}/stop",
+            running_module.name, running_module.version
+        ))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+}
+
+//Test that a module can be deleted.
+#[tokio::test]
+#[serial]
+async fn module_deletion() {
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                upload_module,
+                register_super_admin,
+                restart_module,
+                stop_module,
+                delete_module
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+
+    //Try to delete the module, which hasn't been created yet so it should fail
+    let response = client
+        .delete(format!("/module/{}/{}", module.name, module.version))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
     //Upload and start a module.
     let response = crate::test::upload_test_image(
         &client,
@@ -931,9 +1672,16 @@ async fn module_deletion() {
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::BadRequest);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
     assert_eq!(
-        response.body_string().await.unwrap(),
-        "Cannot delete a running module!".to_string()
+        body,
+        serde_json::json!({
+            "error": {
+                "code": "module_running",
+                "message": "Cannot delete a running module!"
+            }
+        })
     );
 
     //Stop the module, delete it, and verify that the module is deleted:
@@ -943,11 +1691,2273 @@ async fn module_deletion() {
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::NoContent);
+    //Leftover state, such as from a module which was paused when its workers were removed, is deleted too.
+    conn.set(util::get_module_paused_key(&module), "1")
+        .await
+        .unwrap();
     let response = client
         .delete(format!("/module/{}/{}", module.name, module.version))
         .cookies(cookies.clone())
         .dispatch()
         .await;
     assert_eq!(response.status(), Status::NoContent);
-    assert!(!module_exists(&docker, &module).await.unwrap());
+    assert!(!module_exists(&*docker, &module).await.unwrap());
+    assert!(!conn
+        .exists(util::get_module_paused_key(&module))
+        .await
+        .unwrap());
+}
+
+//Test that the job cache can be flushed for both modules and maps.
+#[tokio::test]
+#[serial]
+async fn cache_flush() {
+    use crate::{
+        types::Vector,
+        web::job::{CoordinateSystem, JobSubmission},
+    };
+
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                register_super_admin,
+                flush_map_cache,
+                flush_module_cache
+            ],
+        )
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Fill the cache with jobs for two modules and two maps.
+    let first = ModuleInfo {
+        name: "first".into(),
+        version: "0.1.0".into(),
+    };
+    let second = ModuleInfo {
+        name: "second".into(),
+        version: "0.1.0".into(),
+    };
+    for algorithm in &[&first, &second] {
+        for map_id in 1..=2 {
+            let submission = JobSubmission {
+                map_id,
+                map_type: laps_convert::MapType::Elevation,
+                start: Vector { x: 1, y: 1 }.into(),
+                stop: Vector { x: 2, y: 2 }.into(),
+                algorithm: (*algorithm).clone(),
+                downsample: None,
+                tiles: Vec::new(),
+                coords: CoordinateSystem::Pixel,
+            };
+            conn.set(util::get_job_cache_key(&submission), b"")
+                .await
+                .unwrap();
+        }
+    }
+
+    //Flush the first module, which should only delete its two entries.
+    let mut response = client
+        .post(format!(
+            "/module/{}/{}/cache/flush",
+            first.name, first.version
+        ))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        serde_json::from_slice::<usize>(&response.body_bytes().await.unwrap()).unwrap(),
+        2
+    );
+
+    //Flush map 1, which only has a single entry left from the second module.
+    let mut response = client
+        .delete("/map/1/cache")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        serde_json::from_slice::<usize>(&response.body_bytes().await.unwrap()).unwrap(),
+        1
+    );
+
+    //Only the job for the second module on map 2 should remain.
+    let remaining = util::delete_matching_keys(&mut conn, &util::get_module_cache_pattern(&second))
+        .await
+        .unwrap();
+    assert_eq!(remaining, 1);
+
+    //Flushing requires a session.
+    let response = client.delete("/map/1/cache").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+//Test deleting several maps at once.
+#[tokio::test]
+#[serial]
+async fn bulk_map_deletion() {
+    use crate::{
+        types::Vector,
+        web::job::{CoordinateSystem, JobSubmission, MapTile},
+    };
+
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, delete_maps])
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Insert maps 1 and 3 directly, the contents don't matter here.
+    let image_key = util::create_redis_key("mapdata.image");
+    let meta_key = util::create_redis_key("mapdata.meta");
+    for id in &["1", "3"] {
+        conn.hset(&image_key, id, b"image").await.unwrap();
+        conn.hset(&meta_key, id, b"{}").await.unwrap();
+    }
+    //Cache a job on map 1, one spanning maps 3 and 4, and one on map 4 alone.
+    let submission = |map_id, tiles: Vec<MapTile>| JobSubmission {
+        map_id,
+        map_type: laps_convert::MapType::Elevation,
+        start: Vector { x: 1, y: 1 }.into(),
+        stop: Vector { x: 2, y: 2 }.into(),
+        algorithm: ModuleInfo {
+            name: "test".into(),
+            version: "0.1.0".into(),
+        },
+        downsample: None,
+        tiles,
+        coords: CoordinateSystem::Pixel,
+    };
+    let tile = MapTile {
+        map_id: 3,
+        offset: Vector { x: 100, y: 0 },
+    };
+    for job in &[
+        submission(1, Vec::new()),
+        submission(4, vec![tile]),
+        submission(4, Vec::new()),
+    ] {
+        conn.set(util::get_job_cache_key(job), b"").await.unwrap();
+    }
+
+    //Deleting requires a session.
+    let body = serde_json::json!({ "ids": [1, 2, 3, 1] }).to_string();
+    let response = client
+        .post("/maps/delete")
+        .header(ContentType::JSON)
+        .body(&body)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    //Repeated ids are only reported once.
+    let mut response = client
+        .post("/maps/delete")
+        .header(ContentType::JSON)
+        .body(&body)
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let results: Vec<MapDeletionResult> =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(
+        results,
+        vec![
+            MapDeletionResult {
+                id: 1,
+                deleted: true
+            },
+            MapDeletionResult {
+                id: 2,
+                deleted: false
+            },
+            MapDeletionResult {
+                id: 3,
+                deleted: true
+            },
+        ]
+    );
+
+    //Both the maps and their cached jobs are gone, but the job only on map 4 is left.
+    for id in &["1", "3"] {
+        assert!(conn.hget(&image_key, id).await.unwrap().is_none());
+        assert!(conn.hget(&meta_key, id).await.unwrap().is_none());
+    }
+    assert_eq!(
+        util::delete_matching_keys(
+            &mut conn,
+            &util::get_map_cache_pattern(laps_convert::MapType::Elevation, 1)
+        )
+        .await
+        .unwrap(),
+        0
+    );
+    assert_eq!(
+        util::delete_matching_keys(
+            &mut conn,
+            &util::get_map_cache_pattern(laps_convert::MapType::Elevation, 4)
+        )
+        .await
+        .unwrap(),
+        1
+    );
+}
+
+//Test that module endpoints report when Docker can't be reached.
+#[tokio::test]
+#[serial]
+async fn docker_unavailable() {
+    //Point the Docker client at a socket which doesn't exist, as if the daemon went away after startup.
+    let docker = bollard::Docker::connect_with_unix(
+        "/nonexistent/docker.sock",
+        5,
+        bollard::API_DEFAULT_VERSION,
+    )
+    .unwrap();
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![login, register_super_admin, get_all_modules, stop_module],
+        )
+        .manage(redis.clone())
+        .manage(crate::docker::shared(docker));
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    for (method, url) in &[
+        (Method::Get, "/module/all"),
+        (Method::Post, "/module/laps-test/0.1.0/stop"),
+    ] {
+        let mut response = client
+            .req(*method, *url)
+            .cookies(cookies.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let body: serde_json::Value =
+            serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+        assert_eq!(body["error"]["code"], "docker_unavailable");
+        assert_eq!(body["error"]["message"], "Docker daemon unavailable");
+    }
+}
+
+//Test that map imports respect the quota and that deleting maps frees up storage again.
+#[tokio::test]
+#[serial]
+async fn map_quota() {
+    use laps_convert::{ImportError, MapQuota};
+
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, delete_map])
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+    let convert = || laps_convert::convert_to_png("test_data/height_data/dtm1.tif").unwrap();
+
+    //The first map is counted towards the storage used
+    let (image, metadata) = convert();
+    let quota = MapQuota {
+        max_maps: Some(1),
+        max_map_bytes: None,
+    };
+    laps_convert::import_data_test(
+        &mut conn,
+        laps_convert::MapType::Elevation,
+        image,
+        metadata,
+        &quota,
+    )
+    .await
+    .unwrap();
+    let bytes_key = util::create_redis_key("mapdata.bytes");
+    let used: u64 = String::from_utf8(conn.get(&bytes_key).await.unwrap().unwrap())
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(used > 0);
+
+    //Too many maps
+    let (image, metadata) = convert();
+    match laps_convert::import_data_test(
+        &mut conn,
+        laps_convert::MapType::Elevation,
+        image,
+        metadata,
+        &quota,
+    )
+    .await
+    {
+        Err(ImportError::TooManyMaps(1)) => (),
+        other => panic!("Expected too many maps, got {:?}", other),
+    }
+
+    //Not enough room for another copy of the same map
+    let (image, metadata) = convert();
+    let quota = MapQuota {
+        max_maps: None,
+        max_map_bytes: Some(used * 3 / 2),
+    };
+    match laps_convert::import_data_test(
+        &mut conn,
+        laps_convert::MapType::Elevation,
+        image,
+        metadata,
+        &quota,
+    )
+    .await
+    {
+        Err(ImportError::StorageFull(u, size, _)) => assert_eq!((u, size), (used, used)),
+        other => panic!("Expected full storage, got {:?}", other),
+    }
+    //A map which could never fit
+    let (image, metadata) = convert();
+    let quota = MapQuota {
+        max_maps: None,
+        max_map_bytes: Some(used / 2),
+    };
+    match laps_convert::import_data_test(
+        &mut conn,
+        laps_convert::MapType::Elevation,
+        image,
+        metadata,
+        &quota,
+    )
+    .await
+    {
+        Err(ImportError::TooLarge(size, _)) => assert_eq!(size, used),
+        other => panic!("Expected too large, got {:?}", other),
+    }
+    //Nothing was imported by the failed attempts
+    assert_eq!(
+        conn.hkeys(util::create_redis_key("mapdata.image"))
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    //Deleting the map gives the storage back, making room for a new one
+    let response = client.delete("/map/1").cookies(cookies).dispatch().await;
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(conn.get(&bytes_key).await.unwrap(), Some(b"0".to_vec()));
+    let (image, metadata) = convert();
+    let quota = MapQuota {
+        max_maps: Some(1),
+        max_map_bytes: Some(used),
+    };
+    laps_convert::import_data_test(
+        &mut conn,
+        laps_convert::MapType::Elevation,
+        image,
+        metadata,
+        &quota,
+    )
+    .await
+    .unwrap();
+
+    //Imports running at the same time can't both take the last spot.
+    let quota = MapQuota {
+        max_maps: Some(2),
+        max_map_bytes: None,
+    };
+    let import = |redis: darkredis::ConnectionPool| async move {
+        let (image, metadata) = convert();
+        let mut conn = redis.spawn("map-import").await.unwrap();
+        laps_convert::import_data_test(
+            &mut conn,
+            laps_convert::MapType::Elevation,
+            image,
+            metadata,
+            &quota,
+        )
+        .await
+    };
+    let (first, second) = futures::join!(import(redis.clone()), import(redis.clone()));
+    assert!(first.is_ok() != second.is_ok());
+    assert_eq!(
+        conn.hkeys(util::create_redis_key("mapdata.image"))
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+//Test deleting every map in a group at once.
+#[tokio::test]
+#[serial]
+async fn map_group_deletion() {
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, delete_map_group])
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Map 1 is outside the group, maps 2 and 3 are in it.
+    crate::test::insert_test_mapdata(&mut conn).await;
+    let maps = (0..2)
+        .map(|_| laps_convert::convert_to_png("test_data/height_data/dtm1.tif").unwrap())
+        .collect();
+    laps_convert::import_group_test(
+        &mut conn,
+        laps_convert::MapType::Elevation,
+        maps,
+        "survey",
+        &Default::default(),
+    )
+    .await
+    .unwrap();
+
+    //Deleting requires a session.
+    let response = client.delete("/maps/group/survey").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let mut response = client
+        .delete("/maps/group/survey")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let mut results: Vec<MapDeletionResult> =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    results.sort_by_key(|r| r.id);
+    assert_eq!(
+        results,
+        vec![
+            MapDeletionResult {
+                id: 2,
+                deleted: true
+            },
+            MapDeletionResult {
+                id: 3,
+                deleted: true
+            },
+        ]
+    );
+
+    //Only the map outside the group is left, and the group is gone.
+    let image_key = util::create_redis_key("mapdata.image");
+    let group_key = util::create_redis_key("mapdata.group");
+    assert_eq!(conn.hkeys(&image_key).await.unwrap(), vec![b"1".to_vec()]);
+    assert!(conn.hkeys(&group_key).await.unwrap().is_empty());
+
+    //The group no longer exists
+    let response = client
+        .delete("/maps/group/survey")
+        .cookies(cookies)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+//Test enrolling in and logging in with two-factor authentication.
+#[tokio::test]
+#[serial]
+async fn two_factor_authentication() {
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![login, register_super_admin, enroll_2fa, verify_2fa],
+        )
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Get the code for `secret` `steps` time steps from the start of the test. Each code can only be used once, so
+    //the codes used below are taken from consecutive steps, which are all accepted because of the allowed drift.
+    let start = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let code = |secret: &str, steps: u64| {
+        twofactor::code_at(secret, start + steps * twofactor::TOTP_STEP).unwrap()
+    };
+
+    //Enrolling requires a session.
+    let response = client.post("/admin/2fa/enroll").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    //Verifying without enrolling first is not allowed.
+    let response = client
+        .post("/admin/2fa/verify")
+        .header(ContentType::Form)
+        .body("totp=123456")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let mut response = client
+        .post("/admin/2fa/enroll")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let enrollment: TotpEnrollment =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert!(enrollment.uri.starts_with("otpauth://totp/"));
+    assert!(enrollment.uri.contains(&enrollment.secret));
+
+    //Logging in still works without a code as the enrollment isn't confirmed yet.
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body("username=test-admin&password=password")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+
+    //Confirm with an incorrect code, then with the correct one.
+    let wrong_code =
+        if code(&enrollment.secret, 0) == "000000" || code(&enrollment.secret, 1) == "000000" {
+            "111111"
+        } else {
+            "000000"
+        };
+    let response = client
+        .post("/admin/2fa/verify")
+        .header(ContentType::Form)
+        .body(format!("totp={}", wrong_code))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = client
+        .post("/admin/2fa/verify")
+        .header(ContentType::Form)
+        .body(format!("totp={}", code(&enrollment.secret, 0)))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+
+    //Now a code is required to log in.
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body("username=test-admin&password=password")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert!(response.cookies().is_empty());
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!(
+            "username=test-admin&password=password&totp={}",
+            wrong_code
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(response.cookies().is_empty());
+    //The code used to confirm the enrollment can't be used to log in.
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!(
+            "username=test-admin&password=password&totp={}",
+            code(&enrollment.secret, 0)
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!(
+            "username=test-admin&password=password&totp={}",
+            code(&enrollment.secret, 1)
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(response.cookies().len(), 1);
+
+    //Nor can a code be used twice to log in.
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!(
+            "username=test-admin&password=password&totp={}",
+            code(&enrollment.secret, 1)
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(response.cookies().is_empty());
+
+    //The wrong password is still rejected even with a correct code.
+    let response = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!(
+            "username=test-admin&password=incorrect&totp={}",
+            code(&enrollment.secret, 1)
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+//Test that an active second factor can only be replaced with a code from it.
+#[tokio::test]
+#[serial]
+async fn two_factor_reenrollment() {
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![login, register_super_admin, enroll_2fa, verify_2fa],
+        )
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Enable two-factor authentication with a known secret, after logging in.
+    let admin_key = util::get_admin_key("test-admin");
+    let old_secret = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+    conn.hset(&admin_key, b"totp_secret", old_secret)
+        .await
+        .unwrap();
+    let start = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let code = |secret: &str, steps: u64| {
+        twofactor::code_at(secret, start + steps * twofactor::TOTP_STEP).unwrap()
+    };
+    let enroll = |body: Option<String>| {
+        let request = client.post("/admin/2fa/enroll").cookies(cookies.clone());
+        match body {
+            Some(body) => request.header(ContentType::Form).body(body),
+            None => request,
+        }
+        .dispatch()
+    };
+
+    //The session alone isn't enough, nor is a wrong code.
+    let response = enroll(None).await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    let wrong_code = if code(old_secret, 0) == "000000" {
+        "111111"
+    } else {
+        "000000"
+    };
+    let response = enroll(Some(format!("totp={}", wrong_code))).await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(conn
+        .hget(&admin_key, b"totp_pending")
+        .await
+        .unwrap()
+        .is_none());
+
+    //A code from the current secret starts the enrollment, but can't be used again.
+    let mut response = enroll(Some(format!("totp={}", code(old_secret, 0)))).await;
+    assert_eq!(response.status(), Status::Ok);
+    let enrollment: TotpEnrollment =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    let response = enroll(Some(format!("totp={}", code(old_secret, 0)))).await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    //Confirming the new secret replaces the old one.
+    let response = client
+        .post("/admin/2fa/verify")
+        .header(ContentType::Form)
+        .body(format!("totp={}", code(&enrollment.secret, 1)))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(
+        conn.hget(&admin_key, b"totp_secret").await.unwrap(),
+        Some(enrollment.secret.into_bytes())
+    );
+}
+
+//Test each of the password complexity rules.
+#[test]
+fn password_complexity() {
+    use login::validate_password;
+
+    let mut config = crate::LoginConfig {
+        session_timeout: 0,
+        minimum_password_length: 4,
+        maximum_password_length: 16,
+        require_mixed_case: false,
+        require_digit: false,
+        require_symbol: false,
+    };
+
+    //With every rule off anything within the length limits goes.
+    assert!(validate_password("aaaaaaaa", &config).is_ok());
+    assert!(validate_password("aaa", &config)
+        .unwrap_err()
+        .contains("too short"));
+    assert!(validate_password("aaaaaaaaaaaaaaaaa", &config)
+        .unwrap_err()
+        .contains("too long"));
+
+    config.require_mixed_case = true;
+    assert!(validate_password("aaaaaaaa", &config)
+        .unwrap_err()
+        .contains("uppercase and lowercase"));
+    assert!(validate_password("AAAAAAAA", &config)
+        .unwrap_err()
+        .contains("uppercase and lowercase"));
+    assert!(validate_password("aaaaAAAA", &config).is_ok());
+    config.require_mixed_case = false;
+
+    config.require_digit = true;
+    assert!(validate_password("aaaaaaaa", &config)
+        .unwrap_err()
+        .contains("digit"));
+    assert!(validate_password("aaaa1aaa", &config).is_ok());
+    config.require_digit = false;
+
+    config.require_symbol = true;
+    assert!(validate_password("aaaa1aaa", &config)
+        .unwrap_err()
+        .contains("symbol"));
+    assert!(validate_password("aaaa aaa", &config)
+        .unwrap_err()
+        .contains("symbol"));
+    assert!(validate_password("aaaa!aaa", &config).is_ok());
+
+    //All of them at once.
+    config.require_mixed_case = true;
+    config.require_digit = true;
+    assert!(validate_password("aaaa!aaa", &config).is_err());
+    assert!(validate_password("Aaa1!aaa", &config).is_ok());
+}
+
+//Test parsing the map types a module accepts.
+#[test]
+fn map_type_parsing() {
+    use laps_convert::MapType;
+
+    assert_eq!(
+        modules::parse_map_types("elevation").unwrap(),
+        vec![MapType::Elevation]
+    );
+    assert_eq!(
+        modules::parse_map_types(" RGB, slope ,rgb,").unwrap(),
+        vec![MapType::Rgb, MapType::Slope]
+    );
+    assert!(modules::parse_map_types("").is_err());
+    assert!(modules::parse_map_types(" , ").is_err());
+    assert!(modules::parse_map_types("elevation,height").is_err());
+}
+
+//Test that only module names and versions which are valid in Docker image tags are accepted.
+#[test]
+fn module_name_validation() {
+    use modules::{validate_module_name, validate_module_version};
+
+    for name in &[
+        "laps-test",
+        "a",
+        "astar.v2",
+        "dijkstra_fast",
+        "a__b",
+        "a---b",
+        "LAPS-Test",
+    ] {
+        assert!(validate_module_name(name).is_ok(), "{}", name);
+    }
+    let too_long = "a".repeat(65);
+    for name in &[
+        "",
+        too_long.as_str(),
+        "laps:test",
+        "laps/test",
+        "laps test",
+        "låps",
+        "-laps",
+        "laps.",
+        "laps..test",
+        "laps___test",
+        "laps.-test",
+    ] {
+        assert!(validate_module_name(name).is_err(), "{}", name);
+    }
+
+    for version in &["0.1.0", "latest", "_dev", "1.0-RC1", "v2__3"] {
+        assert!(validate_module_version(version).is_ok(), "{}", version);
+    }
+    let too_long = "1".repeat(129);
+    for version in &["", too_long.as_str(), "0.1:0", "0.1/0", ".1", "-rc", "1 0"] {
+        assert!(validate_module_version(version).is_err(), "{}", version);
+    }
+}
+
+//Test that module ignore entries work both as exact names and glob patterns.
+#[test]
+fn ignore_patterns() {
+    let patterns = vec!["python".to_string(), "laps-test-*".to_string()];
+    let ignored = modules::build_ignore_set(&patterns).unwrap();
+    assert!(ignored.is_match("python"));
+    assert!(!ignored.is_match("python3"));
+    assert!(ignored.is_match("laps-test-ignore"));
+    assert!(ignored.is_match("laps-test-"));
+    assert!(!ignored.is_match("laps-test"));
+    assert!(!ignored.is_match("other"));
+
+    //Invalid patterns are rejected
+    assert!(modules::build_ignore_set(&["laps-[".to_string()]).is_err());
+}
+
+//Test the module lifecycle against the in-memory Docker fake, so no Docker daemon is needed and nothing has to
+//wait for containers to change state.
+#[tokio::test]
+#[serial]
+async fn module_lifecycle_fake_docker() {
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                register_super_admin,
+                upload_module,
+                get_module,
+                restart_module,
+                stop_module,
+                prune_modules,
+                delete_module
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    let url = format!("/module/{}/{}", module.name, module.version);
+
+    //Uploading builds the image.
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        &module.name,
+        &module.version,
+        Some(2),
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    assert!(module_exists(&*docker, &module).await.unwrap());
+    assert!(!module_is_running(&*docker, &module).await.unwrap());
+
+    //Starting creates a container for every worker.
+    let response = client
+        .post(format!("{}/restart", url))
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Created);
+    let names: Vec<String> = docker
+        .containers()
+        .into_iter()
+        .map(|c| c.names[0].clone())
+        .collect();
+    assert_eq!(names, vec!["laps-test-0.1.0-0", "laps-test-0.1.0-1"]);
+    assert!(module_is_running(&*docker, &module).await.unwrap());
+
+    //A crashed worker shows up in the module state.
+    docker.exit_container("laps-test-0.1.0-1", 3);
+    let mut response = client.get(&url).cookies(cookies.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(body["state"], "other");
+    assert_eq!(
+        body["message"],
+        "1/2 running, 1 failures with exit codes [3]"
+    );
+
+    //Stop it, then start it again, which reuses the containers.
+    for (action, expected) in &[
+        ("stop", Status::NoContent),
+        ("restart", Status::Created),
+        ("stop", Status::NoContent),
+    ] {
+        let response = client
+            .post(format!("{}/{}", url, action))
+            .cookies(cookies.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), *expected);
+    }
+    assert_eq!(docker.containers().len(), 2);
+    assert!(!module_is_running(&*docker, &module).await.unwrap());
+
+    //Pruning removes the stopped containers but leaves the image.
+    let mut response = client
+        .post("/module/prune")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let result: PruneResult =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(
+        result,
+        PruneResult {
+            containers: 2,
+            reclaimed_bytes: Some(2048),
+        }
+    );
+    assert!(docker.containers().is_empty());
+    assert!(module_exists(&*docker, &module).await.unwrap());
+
+    //Finally delete it.
+    let response = client
+        .delete(&url)
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+    assert!(!module_exists(&*docker, &module).await.unwrap());
+}
+
+//Test that a failed build is reported and leaves nothing behind, using the Docker fake.
+#[tokio::test]
+#[serial]
+async fn module_build_failure_fake_docker() {
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, upload_module])
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    docker.fail_builds(
+        false,
+        "The command '/bin/sh -c false' returned a non-zero code: 1",
+    );
+    let upload = || {
+        crate::test::upload_test_image(
+            &client,
+            &cookies,
+            crate::test::TEST_CONTAINER,
+            "laps-test",
+            "0.1.0",
+            None,
+        )
+    };
+    let response = upload().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    //Errors in the module itself aren't retried.
+    assert_eq!(docker.builds(), 1);
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    assert!(!module_exists(&*docker, &module).await.unwrap());
+    //Nothing about the module should have been stored.
+    assert!(!conn
+        .exists(util::get_module_workers_key(&module))
+        .await
+        .unwrap());
+
+    //Transient errors are retried with an increasing delay before giving up.
+    docker.fail_builds(true, "net/http: TLS handshake timeout");
+    let attempts = crate::CONFIG.module.build_attempts as usize;
+    let delay = crate::CONFIG.module.build_retry_delay;
+    let started = std::time::Instant::now();
+    let response = upload().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(docker.builds(), 1 + attempts);
+    //The delays are doubled for every attempt, so they add up to `delay * (2^(attempts - 1) - 1)`.
+    let waited = delay * ((1 << (attempts - 1)) - 1);
+    assert!(started.elapsed() >= std::time::Duration::from_secs(waited));
+    assert!(!module_exists(&*docker, &module).await.unwrap());
+}
+
+//Test that an upload aborted by the request timeout while the image is being built leaves nothing behind.
+#[tokio::test]
+#[serial]
+async fn aborted_upload_cleanup() {
+    use crate::web::timeout::with_timeouts;
+
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let upload = with_timeouts(routes![upload_module], 1, &std::collections::HashMap::new());
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin])
+        .mount("/", upload)
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //The image is tagged, but the build doesn't finish before the request times out.
+    docker.slow_builds(std::time::Duration::from_secs(5));
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), Status::GatewayTimeout);
+
+    //The image is removed in the background.
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while module_exists(&*docker, &module).await.unwrap() {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "image was not removed"
+        );
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(!conn
+        .exists(util::get_module_workers_key(&module))
+        .await
+        .unwrap());
+}
+
+//Test that the stop timeout a module is uploaded with is used when stopping and restarting it.
+#[tokio::test]
+#[serial]
+async fn module_stop_timeout() {
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                register_super_admin,
+                upload_module,
+                restart_module,
+                stop_module
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Timeouts outside of the allowed range are rejected.
+    let max = crate::CONFIG.module.max_stop_timeout;
+    for timeout in &["0".to_string(), (max + 1).to_string()] {
+        let response = crate::test::upload_test_image_with(
+            &client,
+            &cookies,
+            crate::test::TEST_CONTAINER,
+            "laps-test",
+            "0.1.0",
+            &[("stop_timeout", timeout.as_str())],
+        )
+        .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    //One module with its own timeout and one using the default.
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        &[("workers", "2"), ("stop_timeout", "5")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-foo",
+        "0.1.0",
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+
+    //Start, restart and stop both of them.
+    for url in &["/module/laps-test/0.1.0", "/module/laps-foo/0.1.0"] {
+        for (action, expected) in &[
+            ("restart", Status::Created),
+            ("restart", Status::NoContent),
+            ("stop", Status::NoContent),
+        ] {
+            let response = client
+                .post(format!("{}/{}", url, action))
+                .cookies(cookies.clone())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), *expected);
+        }
+    }
+
+    let mut timeouts = docker.stop_timeouts();
+    timeouts.sort();
+    let default = crate::CONFIG.module.stop_timeout as i64;
+    assert_eq!(
+        timeouts,
+        vec![
+            ("laps-foo-0.1.0-0".to_string(), default),
+            ("laps-foo-0.1.0-0".to_string(), default),
+            ("laps-test-0.1.0-0".to_string(), 5),
+            ("laps-test-0.1.0-0".to_string(), 5),
+            ("laps-test-0.1.0-1".to_string(), 5),
+            ("laps-test-0.1.0-1".to_string(), 5),
+        ]
+    );
+}
+
+//Test that modules which opted in are stopped once they have been idle for long enough.
+#[tokio::test]
+#[serial]
+async fn idle_modules() {
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                register_super_admin,
+                upload_module,
+                restart_module,
+                get_module
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //An idle timeout of 0 would stop the module right away.
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        &[("idle_timeout", "0")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    //One module which opts in and one which doesn't.
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        &[("idle_timeout", "60")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-foo",
+        "0.1.0",
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let idle = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    let busy = ModuleInfo {
+        name: "laps-foo".into(),
+        version: "0.1.0".into(),
+    };
+    let restart = |module: &ModuleInfo| {
+        client
+            .post(format!(
+                "/module/{}/{}/restart",
+                module.name, module.version
+            ))
+            .cookies(cookies.clone())
+            .dispatch()
+    };
+    restart(&idle).await;
+    restart(&busy).await;
+    let get_details = || async {
+        let mut response = client
+            .get("/module/laps-test/0.1.0")
+            .cookies(cookies.clone())
+            .dispatch()
+            .await;
+        serde_json::from_slice::<ModuleDetails>(&response.body_bytes().await.unwrap()).unwrap()
+    };
+    let details = get_details().await;
+    assert_eq!(details.idle_timeout, Some(60));
+    assert!(!details.idle_stopped);
+
+    //Freshly started modules aren't idle.
+    assert!(stop_idle_modules(&*docker, &mut conn)
+        .await
+        .unwrap()
+        .is_empty());
+
+    //Pretend neither has been sent a job in a long time.
+    let long_ago = (chrono::Utc::now().timestamp() - 1000).to_string();
+    for module in &[&idle, &busy] {
+        conn.set(util::get_module_last_used_key(module), &long_ago)
+            .await
+            .unwrap();
+    }
+    //Modules with queued jobs are left alone.
+    let work_key = util::get_module_work_key(&idle);
+    conn.rpush(&work_key, "job").await.unwrap();
+    assert!(stop_idle_modules(&*docker, &mut conn)
+        .await
+        .unwrap()
+        .is_empty());
+    conn.del(&work_key).await.unwrap();
+
+    //Only the module which opted in is stopped.
+    assert_eq!(
+        stop_idle_modules(&*docker, &mut conn).await.unwrap(),
+        vec![idle.clone()]
+    );
+    assert!(!module_is_running(&*docker, &idle).await.unwrap());
+    assert!(module_is_running(&*docker, &busy).await.unwrap());
+    assert!(get_details().await.idle_stopped);
+
+    //Starting the module again clears the flag and resets the idle time.
+    restart(&idle).await;
+    assert!(!get_details().await.idle_stopped);
+    assert!(stop_idle_modules(&*docker, &mut conn)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+//Test that GPU modules only start on hosts which can give them the GPUs.
+#[tokio::test]
+#[serial]
+async fn gpu_modules() {
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                register_super_admin,
+                upload_module,
+                restart_module,
+                get_module
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Anything but true or false is rejected instead of quietly meaning no GPUs.
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        &[("gpu", "yes")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::BadRequest);
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    assert!(!module_exists(&*docker, &module).await.unwrap());
+
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        &[("gpu", "true")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let mut response = client
+        .get("/module/laps-test/0.1.0")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    let details: ModuleDetails =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert!(details.gpu);
+
+    //Without the GPU runtime, the module can't be started.
+    let restart = || {
+        client
+            .post("/module/laps-test/0.1.0/restart")
+            .cookies(cookies.clone())
+            .dispatch()
+    };
+    let mut response = restart().await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+    assert_eq!(body["error"]["code"], "gpu_unavailable");
+    assert!(docker.gpu_containers().is_empty());
+
+    //With it, every worker gets the GPUs.
+    docker.enable_gpus();
+    let response = restart().await;
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(
+        docker.gpu_containers(),
+        vec!["laps-test-0.1.0-0".to_string()]
+    );
+}
+
+//Test that modules can run their own command, with the arguments LAPS needs appended.
+#[tokio::test]
+#[serial]
+async fn custom_module_command() {
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                register_super_admin,
+                upload_module,
+                restart_module,
+                get_module
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Blank commands are refused.
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        &[("command", "  ")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        &[("command", " ./pathfinder  --threads 4 ")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let response = crate::test::upload_test_image(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-foo",
+        "0.1.0",
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+
+    let mut response = client
+        .get("/module/laps-test/0.1.0")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    let details: ModuleDetails =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    let expected: Vec<String> = vec!["./pathfinder".into(), "--threads".into(), "4".into()];
+    assert_eq!(details.command, Some(expected.clone()));
+
+    for module in &["laps-test", "laps-foo"] {
+        let response = client
+            .post(format!("/module/{}/0.1.0/restart", module))
+            .cookies(cookies.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Created);
+    }
+
+    //The custom command comes first, followed by the registration arguments.
+    let (redis_host, redis_port) = {
+        let address = &crate::CONFIG.redis.address;
+        let split = address.find(':').unwrap();
+        (&address[..split], &address[split + 1..])
+    };
+    let laps_args = |name: &str| -> Vec<String> {
+        vec![
+            name,
+            "0.1.0",
+            "--redis_host",
+            redis_host,
+            "--port",
+            redis_port,
+            "--worker_number",
+            "0",
+            "--test",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect()
+    };
+    let mut custom = expected;
+    custom.extend(laps_args("laps-test"));
+    assert_eq!(docker.container_command("laps-test-0.1.0-0"), Some(custom));
+    //Modules without one still run main.py.
+    let mut default: Vec<String> = vec!["python3".into(), "main.py".into()];
+    default.extend(laps_args("laps-foo"));
+    assert_eq!(docker.container_command("laps-foo-0.1.0-0"), Some(default));
+}
+
+//Test reporting the storage used by modules, maps and logs.
+#[tokio::test]
+#[serial]
+async fn storage_report() {
+    use laps_convert::MapType;
+
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::with_images(&[
+        "laps-test:0.1.0",
+        "laps-foo:0.2.0",
+        "python:3.8",
+    ]));
+    docker.set_image_size("laps-test:0.1.0", 1000);
+    docker.set_image_size("laps-foo:0.2.0", 3000);
+    docker.set_image_size("python:3.8", 500);
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![login, register_super_admin, register_admin, storage_usage],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Maps of every type count towards the storage used.
+    crate::test::insert_test_mapdata(&mut conn).await;
+    let (image, metadata) = laps_convert::convert_to_png("test_data/height_data/dtm1.tif").unwrap();
+    laps_convert::import_data_test(
+        &mut conn,
+        MapType::Rgb,
+        image,
+        metadata,
+        &Default::default(),
+    )
+    .await
+    .unwrap();
+    let mut map_bytes = 0;
+    for map_type in &[MapType::Elevation, MapType::Rgb] {
+        for part in &["image", "slope", "meta"] {
+            let key = util::get_mapdata_key(*map_type, part);
+            if let Some(value) = conn.hget(key, "1").await.unwrap() {
+                map_bytes += value.len() as u64;
+            }
+        }
+    }
+    let test_module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    for line in &["one", "two", "three"] {
+        conn.rpush(util::get_module_log_key(&test_module), line)
+            .await
+            .unwrap();
+    }
+
+    async fn get_report(client: &Client, cookies: Vec<Cookie<'static>>) -> StorageReport {
+        let mut response = client
+            .get("/admin/storage")
+            .cookies(cookies)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap()
+    }
+    let report = get_report(&client, cookies.clone()).await;
+    //Ignored images aren't modules, and the largest modules come first.
+    assert_eq!(report.module_bytes, 4000);
+    let images: Vec<(&str, u64)> = report
+        .module_images
+        .iter()
+        .map(|i| (i.module.name.as_str(), i.bytes))
+        .collect();
+    assert_eq!(images, vec![("laps-foo", 3000), ("laps-test", 1000)]);
+    assert_eq!(report.map_count, 2);
+    assert!(map_bytes > 0);
+    assert_eq!(report.map_bytes, map_bytes);
+    assert_eq!(report.log_lines, 3);
+    assert!(report.logs.contains(&ModuleLogUsage {
+        module: test_module.clone(),
+        lines: 3
+    }));
+
+    //The report is reused for a while.
+    conn.rpush(util::get_module_log_key(&test_module), "four")
+        .await
+        .unwrap();
+    assert_eq!(get_report(&client, cookies.clone()).await, report);
+    conn.del(util::get_storage_report_key()).await.unwrap();
+    assert_eq!(get_report(&client, cookies.clone()).await.log_lines, 4);
+
+    //Only super admins can see it.
+    let form = "username=regular-admin&password=password";
+    let response = client
+        .post("/register")
+        .body(form)
+        .cookies(cookies)
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Created);
+    let response = client
+        .post("/login")
+        .body(form)
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    let cookies = response.cookies();
+    let response = client
+        .get("/admin/storage")
+        .cookies(cookies)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+//Test that /admin/me tells super admins and other admins apart by what they can do.
+#[tokio::test]
+#[serial]
+async fn admin_permissions() {
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![login, register_super_admin, register_admin, get_me],
+        )
+        .manage(redis.clone());
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    async fn get_me(client: &Client, cookies: Vec<Cookie<'static>>) -> serde_json::Value {
+        let mut response = client.get("/admin/me").cookies(cookies).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap()
+    }
+
+    //The session fields are still there next to the permissions.
+    let me = get_me(&client, cookies.clone()).await;
+    assert_eq!(me["username"], "test-admin");
+    assert_eq!(me["is_super"], true);
+    let permissions: Permissions = serde_json::from_value(me["permissions"].clone()).unwrap();
+    assert_eq!(
+        permissions,
+        Permissions {
+            can_manage_users: true,
+            can_upload_modules: true,
+            can_delete_maps: true,
+            can_prune_modules: true,
+            can_view_storage: true,
+        }
+    );
+
+    let form = "username=regular-admin&password=password";
+    let response = client
+        .post("/register")
+        .body(form)
+        .cookies(cookies)
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Created);
+    let response = client
+        .post("/login")
+        .body(form)
+        .header(ContentType::Form)
+        .dispatch()
+        .await;
+    let cookies = response
+        .cookies()
+        .into_iter()
+        .map(|c| c.into_owned())
+        .collect();
+    let me = get_me(&client, cookies).await;
+    assert_eq!(me["is_super"], false);
+    let permissions: Permissions = serde_json::from_value(me["permissions"].clone()).unwrap();
+    assert_eq!(
+        permissions,
+        Permissions {
+            can_manage_users: false,
+            can_upload_modules: true,
+            can_delete_maps: true,
+            can_prune_modules: false,
+            can_view_storage: false,
+        }
+    );
+}
+
+//Test listing the most recently submitted jobs.
+#[tokio::test]
+#[serial]
+async fn job_history_listing() {
+    use crate::history::{record_submission, JobRecord};
+
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, job_history])
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    let length = crate::CONFIG.jobs.history_length as i32;
+    for job_id in 1..=length + 1 {
+        let record = JobRecord {
+            job_id,
+            map_id: 1,
+            algorithm: ModuleInfo {
+                name: "dummy".into(),
+                version: "0.1.0".into(),
+            },
+            submitted: 0,
+            outcome: None,
+            duration: None,
+            username: Some("test-admin".into()),
+        };
+        record_submission(&mut conn, &record).await.unwrap();
+    }
+    async fn get_history(
+        client: &Client,
+        cookies: &[Cookie<'static>],
+        url: &str,
+    ) -> Vec<JobRecord> {
+        let mut response = client.get(url).cookies(cookies.to_vec()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap()
+    }
+
+    //Newest first, with the oldest job dropped from the history.
+    let history = get_history(&client, &cookies, "/jobs/history").await;
+    let ids: Vec<i32> = history.iter().map(|r| r.job_id).collect();
+    assert_eq!(ids, (2..=length + 1).rev().collect::<Vec<_>>());
+    assert_eq!(history[0].username.as_deref(), Some("test-admin"));
+    //The limit picks the most recent jobs, and can't go beyond the history.
+    let history = get_history(&client, &cookies, "/jobs/history?limit=2").await;
+    let ids: Vec<i32> = history.iter().map(|r| r.job_id).collect();
+    assert_eq!(ids, vec![length + 1, length]);
+    assert_eq!(
+        get_history(&client, &cookies, "/jobs/history?limit=1000")
+            .await
+            .len(),
+        length as usize
+    );
+
+    //The history requires a session.
+    let response = client.get("/jobs/history").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+//Test cancelling the queued jobs of a module without stopping it.
+#[tokio::test]
+#[serial]
+async fn module_draining() {
+    use crate::{
+        types::{JobOutcome, JobResult, Vector},
+        web::job::JobInfo,
+    };
+
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, drain_module])
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    let module = ModuleInfo {
+        name: "dummy".into(),
+        version: "0.1.0".into(),
+    };
+    let jobs: Vec<Vec<u8>> = (1..=3)
+        .map(|job_id| {
+            serde_json::to_vec(&JobInfo {
+                job_id,
+                start: Vector { x: 1, y: 1 },
+                stop: Vector { x: 2, y: 2 },
+                map_id: 1,
+                map_type: laps_convert::MapType::Elevation,
+                downsample: None,
+                tiles: Vec::new(),
+            })
+            .unwrap()
+        })
+        .collect();
+    conn.rpush_slice(util::get_module_work_key(&module), &jobs)
+        .await
+        .unwrap();
+
+    let mut response = client
+        .post("/module/dummy/0.1.0/drain")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        serde_json::from_slice::<usize>(&response.body_bytes().await.unwrap()).unwrap(),
+        3
+    );
+
+    //The queue is empty, and every job got cancelled.
+    assert!(!conn
+        .exists(util::get_module_work_key(&module))
+        .await
+        .unwrap());
+    let results: Vec<JobResult> = conn
+        .lrange(util::create_redis_backend_key("path-results"), 0, -1)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| serde_json::from_slice(&r).unwrap())
+        .collect();
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|r| r.outcome == JobOutcome::Cancelled));
+
+    //Draining an empty queue does nothing.
+    let mut response = client
+        .post("/module/dummy/0.1.0/drain")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(
+        serde_json::from_slice::<usize>(&response.body_bytes().await.unwrap()).unwrap(),
+        0
+    );
+
+    //Draining requires a session.
+    let response = client.post("/module/dummy/0.1.0/drain").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+//Test listing the jobs queued for a module.
+#[tokio::test]
+#[serial]
+async fn queued_jobs() {
+    use crate::{types::Vector, web::job::JobInfo};
+
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, get_queued_jobs])
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    let module = ModuleInfo {
+        name: "dummy".into(),
+        version: "0.1.0".into(),
+    };
+    let jobs: Vec<JobInfo> = (1..=4)
+        .map(|job_id| JobInfo {
+            job_id,
+            start: Vector { x: 1, y: 1 },
+            stop: Vector { x: 2, y: 2 },
+            map_id: 1,
+            map_type: laps_convert::MapType::Elevation,
+            downsample: None,
+            tiles: Vec::new(),
+        })
+        .collect();
+    let mut entries: Vec<Vec<u8>> = jobs
+        .iter()
+        .map(|j| serde_json::to_vec(j).unwrap())
+        .collect();
+    //Garbage in the queue doesn't hide the jobs around it.
+    entries.insert(1, b"not a job".to_vec());
+    conn.rpush_slice(util::get_module_work_key(&module), &entries)
+        .await
+        .unwrap();
+
+    async fn get_jobs(client: &Client, cookies: &[Cookie<'static>], uri: &str) -> Vec<JobInfo> {
+        let mut response = client.get(uri).cookies(cookies.to_vec()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap()
+    }
+
+    //The jobs come in the order they will be picked up.
+    let listed = get_jobs(&client, &cookies, "/module/dummy/0.1.0/queue/jobs").await;
+    assert_eq!(listed, jobs);
+    let listed = get_jobs(&client, &cookies, "/module/dummy/0.1.0/queue/jobs?limit=3").await;
+    assert_eq!(listed, &jobs[..2]);
+    let listed = get_jobs(&client, &cookies, "/module/dummy/0.1.0/queue/jobs?limit=0").await;
+    assert!(listed.is_empty());
+    //Listing the queue leaves it alone.
+    assert_eq!(
+        conn.llen(util::get_module_work_key(&module)).await.unwrap(),
+        Some(5)
+    );
+    let listed = get_jobs(&client, &cookies, "/module/other/0.1.0/queue/jobs").await;
+    assert!(listed.is_empty());
+
+    //Listing the queue requires a session.
+    let response = client
+        .get("/module/dummy/0.1.0/queue/jobs")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+//Test pausing and resuming a module without losing its queue.
+#[tokio::test]
+#[serial]
+async fn pause_resume_module() {
+    use crate::{types::Vector, web::job::JobInfo};
+
+    let redis = crate::create_redis_pool().await;
+    let docker = Arc::new(FakeDocker::default());
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                register_super_admin,
+                upload_module,
+                restart_module,
+                pause_module,
+                resume_module,
+                get_module,
+                get_all_modules
+            ],
+        )
+        .manage(redis.clone())
+        .manage(docker.clone() as SharedDocker);
+    let client = Client::new(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    let response = crate::test::upload_test_image_with(
+        &client,
+        &cookies,
+        crate::test::TEST_CONTAINER,
+        "laps-test",
+        "0.1.0",
+        &[("workers", "2")],
+    )
+    .await;
+    assert_eq!(response.status(), Status::Created);
+    let module = ModuleInfo {
+        name: "laps-test".into(),
+        version: "0.1.0".into(),
+    };
+    let post = |action: &str| {
+        client
+            .post(format!("/module/laps-test/0.1.0/{}", action))
+            .cookies(cookies.clone())
+            .dispatch()
+    };
+    let get_details = || async {
+        let mut response = client
+            .get("/module/laps-test/0.1.0")
+            .cookies(cookies.clone())
+            .dispatch()
+            .await;
+        serde_json::from_slice::<ModuleDetails>(&response.body_bytes().await.unwrap()).unwrap()
+    };
+    async fn deadlines(conn: &mut darkredis::Connection) -> isize {
+        let key = util::get_job_deadlines_key();
+        let command = darkredis::Command::new("ZCARD").arg(&key);
+        conn.run_command(command).await.unwrap().unwrap_integer()
+    }
+
+    //Modules which aren't running can't be paused, and unknown modules don't exist.
+    assert_eq!(post("pause").await.status(), Status::BadRequest);
+    let response = client
+        .post("/module/laps-foo/0.1.0/pause")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(post("restart").await.status(), Status::Created);
+    assert_eq!(post("resume").await.status(), Status::BadRequest);
+
+    //Queue up a job with a deadline, as if it was submitted while the module was busy.
+    let job = JobInfo {
+        job_id: 1,
+        start: Vector { x: 1, y: 1 },
+        stop: Vector { x: 2, y: 2 },
+        map_id: 1,
+        map_type: laps_convert::MapType::Elevation,
+        downsample: None,
+        tiles: Vec::new(),
+    };
+    conn.rpush(
+        util::get_module_work_key(&module),
+        serde_json::to_vec(&job).unwrap(),
+    )
+    .await
+    .unwrap();
+    crate::module_handling::set_job_deadline(&mut conn, job.job_id, 60)
+        .await
+        .unwrap();
+
+    //Pausing keeps the queue, but the queued jobs stop timing out.
+    assert_eq!(post("pause").await.status(), Status::NoContent);
+    assert_eq!(post("pause").await.status(), Status::BadRequest);
+    assert!(docker.containers().iter().all(|c| c.state == "paused"));
+    let details = get_details().await;
+    assert_eq!(details.state, ModuleState::Paused);
+    assert_eq!(details.queued_jobs, 1);
+    assert_eq!(deadlines(&mut conn).await, 0);
+    let mut response = client
+        .get("/module/all")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    let modules: Vec<PathModule> =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    let listed = modules.iter().find(|m| m.module == module).unwrap();
+    assert_eq!(listed.state, ModuleState::Paused);
+
+    //Resuming picks up where the module left off, and the queued jobs time out again. The workers were sending
+    //heartbeats, so they get a new heartbeat period to send the next one.
+    conn.sadd(util::get_heartbeat_modules_key(), module.to_string())
+        .await
+        .unwrap();
+    assert_eq!(post("resume").await.status(), Status::NoContent);
+    assert!(docker.containers().iter().all(|c| c.state == "running"));
+    let details = get_details().await;
+    assert_eq!(details.state, ModuleState::Running);
+    assert_eq!(details.queued_jobs, 1);
+    assert_eq!(deadlines(&mut conn).await, 1);
+    assert!(!conn
+        .exists(util::get_module_paused_key(&module))
+        .await
+        .unwrap());
+    assert!(conn
+        .exists(util::get_module_heartbeat_key(&module))
+        .await
+        .unwrap());
+}
+
+//Test filling in the metadata of maps imported before some of its fields existed.
+#[tokio::test]
+#[serial]
+async fn map_metadata_recomputation() {
+    use laps_convert::{ImageMetadata, MapQuota};
+
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![login, register_super_admin, recompute_map_metadata],
+        )
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Import a map the way older versions did, without the dimensions and percentiles.
+    let (image, metadata) = laps_convert::convert_to_png("test_data/height_data/dtm1.tif").unwrap();
+    let (width, height) = (image.width, image.height);
+    let old = ImageMetadata {
+        median_height: None,
+        height_percentiles: Vec::new(),
+        width: None,
+        height: None,
+        ..metadata.clone()
+    };
+    let id = laps_convert::import_data_test(
+        &mut conn,
+        laps_convert::MapType::Elevation,
+        image,
+        old,
+        &MapQuota::default(),
+    )
+    .await
+    .unwrap();
+
+    let url = format!("/map/{}/recompute-meta", id);
+    let mut response = client.post(&url).cookies(cookies.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let recomputed: ImageMetadata =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(recomputed.width, Some(width));
+    assert_eq!(recomputed.height, Some(height));
+    assert!(recomputed.median_height.is_some());
+    assert!(!recomputed.height_percentiles.is_empty());
+    assert_eq!(recomputed.min_height, metadata.min_height);
+
+    //The stored metadata is updated too.
+    let stored = conn
+        .hget(util::create_redis_key("mapdata.meta"), id.to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    let stored: ImageMetadata = serde_json::from_slice(&stored).unwrap();
+    assert_eq!(stored.width, Some(width));
+    assert_eq!(stored.median_height, recomputed.median_height);
+
+    //Maps which don't exist can't be recomputed.
+    let response = client
+        .post("/map/1000/recompute-meta")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    //Recomputing requires a session.
+    let response = client.post(&url).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+//Test detecting maps whose stored data has been corrupted.
+#[tokio::test]
+#[serial]
+async fn map_integrity() {
+    use laps_convert::ImageMetadata;
+
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount(
+            "/",
+            routes![
+                login,
+                register_super_admin,
+                verify_map,
+                verify_maps,
+                recompute_map_metadata,
+                crate::web::map::get_map
+            ],
+        )
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //One map with a checksum, and one imported before checksums were recorded.
+    crate::test::insert_test_mapdata(&mut conn).await;
+    crate::test::insert_test_mapdata(&mut conn).await;
+    let image_key = util::create_redis_key("mapdata.image");
+    let meta_key = util::create_redis_key("mapdata.meta");
+    let meta = conn.hget(&meta_key, "2").await.unwrap().unwrap();
+    let mut old: ImageMetadata = serde_json::from_slice(&meta).unwrap();
+    assert!(old.checksum.is_some());
+    old.checksum = None;
+    conn.hset(&meta_key, "2", serde_json::to_vec(&old).unwrap())
+        .await
+        .unwrap();
+
+    let verify = |id: i32| {
+        client
+            .get(format!("/map/{}/verify", id))
+            .cookies(cookies.clone())
+            .dispatch()
+    };
+    let mut response = verify(1).await;
+    assert_eq!(response.status(), Status::Ok);
+    let result: MapIntegrityResult =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(result.integrity, MapIntegrity::Intact);
+    assert_eq!(client.get("/map/1").dispatch().await.status(), Status::Ok);
+    assert_eq!(verify(3).await.status(), Status::NotFound);
+
+    //Flip a bit in the stored image.
+    let mut image = conn.hget(&image_key, "1").await.unwrap().unwrap();
+    let middle = image.len() / 2;
+    image[middle] ^= 1;
+    conn.hset(&image_key, "1", image).await.unwrap();
+
+    let mut response = verify(1).await;
+    let result: MapIntegrityResult =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(result.integrity, MapIntegrity::Corrupted);
+    //Corrupted maps aren't handed out, while maps without a checksum still are.
+    let mut response = client.get("/map/1").dispatch().await;
+    assert_eq!(response.status(), Status::InternalServerError);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+    assert_eq!(body["error"]["code"], "internal_error");
+    assert_eq!(client.get("/map/2").dispatch().await.status(), Status::Ok);
+
+    let mut response = client
+        .get("/maps/verify")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    let results: Vec<MapIntegrityResult> =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(
+        results,
+        vec![
+            MapIntegrityResult {
+                id: "1".into(),
+                integrity: MapIntegrity::Corrupted
+            },
+            MapIntegrityResult {
+                id: "2".into(),
+                integrity: MapIntegrity::Unverified
+            },
+        ]
+    );
+
+    //Recomputing the metadata only fills in the checksum when the admin trusts the current data.
+    let recompute = |query: &str| {
+        client
+            .post(format!("/map/2/recompute-meta{}", query))
+            .cookies(cookies.clone())
+            .dispatch()
+    };
+    assert_eq!(recompute("").await.status(), Status::Ok);
+    let mut response = verify(2).await;
+    let result: MapIntegrityResult =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(result.integrity, MapIntegrity::Unverified);
+    assert_eq!(recompute("?trust=true").await.status(), Status::Ok);
+    let mut response = verify(2).await;
+    let result: MapIntegrityResult =
+        serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+    assert_eq!(result.integrity, MapIntegrity::Intact);
+}
+
+//Test that replies to the map deletion transaction which don't have a result for every command are errors, rather
+//than panics.
+#[test]
+fn map_deletion_replies() {
+    use super::map::exec_integers;
+    use darkredis::Value;
+
+    let reply = Value::Array(vec![Value::Integer(3), Value::Integer(1)]);
+    assert_eq!(exec_integers(Some(reply), 2).unwrap(), vec![3, 1]);
+    //Aborted and missing transactions.
+    assert!(exec_integers(Some(Value::Nil), 2).is_err());
+    assert!(exec_integers(None, 2).is_err());
+    //Too few replies, or replies which aren't integers.
+    let reply = Value::Array(vec![Value::Integer(3)]);
+    assert!(exec_integers(Some(reply), 2).is_err());
+    let reply = Value::Array(vec![Value::Integer(3), Value::Ok]);
+    assert!(exec_integers(Some(reply), 2).is_err());
+}
+
+//Test that deleting a map removes it from every hash and releases exactly its storage, only once.
+#[tokio::test]
+#[serial]
+async fn complete_map_deletion() {
+    //setup rocket instance
+    let redis = crate::create_redis_pool().await;
+    let rocket = rocket::ignite()
+        .mount("/", routes![login, register_super_admin, delete_map])
+        .manage(redis.clone());
+    let client = Client::untracked(rocket).unwrap();
+    let mut conn = redis.get().await;
+    crate::test::clear_redis(&mut conn).await;
+    let cookies = create_test_account_and_login(&client).await;
+
+    //Insert map 5 into every hash, along with a storage total covering it and another map.
+    let keys: Vec<String> = laps_convert::MAP_HASHES
+        .iter()
+        .map(|suffix| util::create_redis_key(&format!("mapdata.{}", suffix)))
+        .collect();
+    for key in &keys {
+        conn.hset(key, "5", b"data").await.unwrap();
+    }
+    let bytes_key = util::create_redis_key("mapdata.bytes");
+    conn.set(&bytes_key, b"100").await.unwrap();
+
+    let response = client
+        .delete("/map/5")
+        .cookies(cookies.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+    for key in &keys {
+        assert!(conn.hget(key, "5").await.unwrap().is_none());
+    }
+    //Only the image, slope and metadata count towards the storage.
+    assert_eq!(conn.get(&bytes_key).await.unwrap(), Some(b"88".to_vec()));
+
+    //Deleting it again doesn't release anything.
+    let response = client.delete("/map/5").cookies(cookies).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(conn.get(&bytes_key).await.unwrap(), Some(b"88".to_vec()));
 }
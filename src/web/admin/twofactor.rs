@@ -0,0 +1,173 @@
+//src/web/admin/twofactor.rs: TOTP two-factor authentication for administrators.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use super::AdminSession;
+use crate::{types::BackendError, util};
+use darkredis::{Command, Connection, ConnectionPool};
+use rand::RngCore;
+use rocket::{
+    http::Status,
+    request::{Form, State},
+};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_lite::Sha1;
+
+//How long each code is valid for in seconds, and how many digits it has. These are what authenticator apps expect.
+pub(super) const TOTP_STEP: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+
+//The alphabet authenticator apps expect the secret to be encoded in.
+const SECRET_ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+//Generate the code for the base32 encoded `secret` at UNIX timestamp `time`.
+pub(super) fn code_at(secret: &str, time: u64) -> Option<String> {
+    let secret = base32::decode(SECRET_ALPHABET, secret)?;
+    Some(totp_lite::totp_custom::<Sha1>(
+        TOTP_STEP,
+        TOTP_DIGITS,
+        &secret,
+        time,
+    ))
+}
+
+//Check that `code` is valid for the base32 encoded `secret` right now, returning the time step it belongs to.
+pub fn verify_code(secret: &str, code: &str) -> Option<u64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("getting current time")
+        .as_secs();
+    //Accept the codes right before and after the current one as well to allow for clock drift.
+    [now - TOTP_STEP, now, now + TOTP_STEP]
+        .iter()
+        .find(|t| code_at(secret, **t).as_deref() == Some(code.trim()))
+        .map(|t| t / TOTP_STEP)
+}
+
+//Record that the admin at `key` used the code of time step `step`, returning false if that or a later code was used
+//already. This keeps an intercepted code from being replayed while it is still valid. The check and the update are
+//done in a script so that two logins can't both use the same code.
+pub(super) async fn use_step(
+    conn: &mut Connection,
+    key: &str,
+    step: u64,
+) -> Result<bool, BackendError> {
+    const SCRIPT: &str = r#"
+local last = tonumber(redis.call('HGET', KEYS[1], 'totp_last_step') or '-1')
+if tonumber(ARGV[1]) <= last then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'totp_last_step', ARGV[1])
+return 1
+"#;
+    let step = step.to_string();
+    let command = Command::new("EVAL")
+        .arg(&SCRIPT)
+        .arg(b"1")
+        .arg(&key)
+        .arg(&step);
+    Ok(conn.run_command(command).await?.unwrap_integer() == 1)
+}
+
+//The secret returned to an admin enrolling in two-factor authentication.
+#[derive(Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    //The base32 encoded secret.
+    pub secret: String,
+    //Provisioning URI which can be turned into a QR code for authenticator apps.
+    pub uri: String,
+}
+
+#[derive(FromForm)]
+pub struct TotpCode {
+    totp: String,
+}
+
+//Start enrolling in two-factor authentication. The secret is not used for logging in before it has been
+//confirmed with a code using the verify endpoint. Replacing an active second factor takes a code from it, as otherwise
+//a stolen session would be enough to take it over.
+#[post("/admin/2fa/enroll", data = "<current>")]
+pub async fn enroll_2fa(
+    pool: State<'_, ConnectionPool>,
+    session: AdminSession,
+    current: Option<Form<TotpCode>>,
+) -> Result<Result<Json<TotpEnrollment>, Status>, BackendError> {
+    let mut conn = pool.get().await;
+    let key = util::get_admin_key(&session.username);
+    if let Some(secret) = conn.hget(&key, b"totp_secret").await? {
+        let secret = String::from_utf8_lossy(&secret);
+        let step = match &current {
+            Some(code) => verify_code(&secret, &code.totp),
+            //Let the client know that it has to ask for a code, like when logging in.
+            None => return Ok(Err(Status::Unauthorized)),
+        };
+        //Codes can't be used twice here either.
+        let accepted = match step {
+            Some(step) => use_step(&mut conn, &key, step).await?,
+            None => false,
+        };
+        if !accepted {
+            warn!(
+                "Admin {} gave an invalid two-factor code while replacing it",
+                session.username
+            );
+            return Ok(Err(Status::Forbidden));
+        }
+    }
+
+    //Same size as recommended by RFC 4226.
+    let secret = {
+        let mut buffer = vec![0u8; 20];
+        rand::thread_rng().fill_bytes(&mut buffer);
+        base32::encode(SECRET_ALPHABET, &buffer)
+    };
+
+    conn.hset(&key, b"totp_pending", &secret).await?;
+    info!(
+        "Admin {} started enrolling in two-factor authentication",
+        session.username
+    );
+
+    let uri = format!(
+        "otpauth://totp/LAPS:{}?secret={}&issuer=LAPS",
+        session.username, secret
+    );
+    Ok(Ok(Json(TotpEnrollment { secret, uri })))
+}
+
+//Confirm a pending enrollment with a code from the authenticator, enabling two-factor authentication.
+#[post("/admin/2fa/verify", data = "<code>")]
+pub async fn verify_2fa(
+    pool: State<'_, ConnectionPool>,
+    session: AdminSession,
+    code: Form<TotpCode>,
+) -> Result<Status, BackendError> {
+    let mut conn = pool.get().await;
+    let key = util::get_admin_key(&session.username);
+    match conn.hget(&key, b"totp_pending").await? {
+        Some(secret) => {
+            let secret = String::from_utf8_lossy(&secret);
+            //The code confirming the enrollment can't be used to log in afterwards.
+            if let Some(step) = verify_code(&secret, &code.totp) {
+                use_step(&mut conn, &key, step).await?;
+                conn.hset(&key, b"totp_secret", secret.as_bytes()).await?;
+                conn.hdel(&key, b"totp_pending").await?;
+                info!(
+                    "Admin {} enabled two-factor authentication",
+                    session.username
+                );
+                Ok(Status::NoContent)
+            } else {
+                warn!(
+                    "Admin {} gave an invalid two-factor code while enrolling",
+                    session.username
+                );
+                Ok(Status::Forbidden)
+            }
+        }
+        None => Ok(Status::BadRequest),
+    }
+}
@@ -3,24 +3,29 @@
 //Copyright (c) 2020 LAPS Group
 //Distributed under the zlib licence, see LICENCE.
 
+use super::etag::Tagged;
 use crate::{module_handling::ModuleInfo, types::BackendError};
 use darkredis::ConnectionPool;
 use rocket::State;
-use rocket_contrib::json::Json;
 
 //Get a list of available algorithms
 #[get("/algorithms")]
-pub async fn list(pool: State<'_, ConnectionPool>) -> Result<Json<Vec<ModuleInfo>>, BackendError> {
+pub async fn list(
+    pool: State<'_, ConnectionPool>,
+) -> Result<Tagged<Vec<ModuleInfo>>, BackendError> {
     let mut conn = pool.get().await;
     let modules = crate::module_handling::get_registered_modules(&mut conn).await?;
-    Ok(Json(modules))
+    Ok(Tagged(modules))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::util::create_redis_backend_key;
-    use rocket::{http::Status, local::Client};
+    use rocket::{
+        http::{Header, Status},
+        local::Client,
+    };
     use serial_test::serial;
 
     //Test the listing of algorithms
@@ -80,5 +85,25 @@ mod test {
             .unwrap();
 
         check!(vec![dummy.clone(), second_dummy.clone()]);
+
+        //The listing is only sent again once it changes
+        let response = client.get("/algorithms").dispatch().await;
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        let response = client
+            .get("/algorithms")
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotModified);
+        conn.srem(&module_key, &serde_json::to_vec(&dummy).unwrap())
+            .await
+            .unwrap();
+        let response = client
+            .get("/algorithms")
+            .header(Header::new("If-None-Match", etag))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        check!(vec![second_dummy.clone()]);
     }
 }
@@ -0,0 +1,244 @@
+//src/web/assets.rs: Serving of the bundled frontend, either embedded in the binary or from a directory.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use super::etag::{compute_etag, IfNoneMatch};
+use rocket::{
+    http::{ContentType, Status},
+    response::{self, Responder},
+    Request, Response,
+};
+use std::{collections::HashMap, io::Cursor, path::Path, sync::Arc};
+
+//The files of the frontend embedded at build time, by their path relative to dist. Empty unless built with the
+//embed-assets feature.
+static EMBEDDED: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+lazy_static! {
+    //The bundled frontend by path, either the embedded copy or the contents of dist in builds without one. Loaded by
+    //`preload_assets` when the server starts.
+    static ref BUNDLED: HashMap<String, Asset> = load_bundled();
+}
+
+//A file of the frontend.
+#[derive(Clone)]
+pub struct Asset {
+    content_type: ContentType,
+    data: Arc<[u8]>,
+    etag: String,
+}
+
+impl Asset {
+    //Create the asset `path` containing `data`, with the content type given by its extension.
+    pub fn new(path: &str, data: impl Into<Arc<[u8]>>) -> Asset {
+        let content_type = Path::new(path)
+            .extension()
+            .and_then(|e| ContentType::from_extension(&e.to_string_lossy()))
+            .unwrap_or(ContentType::Binary);
+        let data = data.into();
+        let etag = compute_etag(&data);
+        Asset {
+            content_type,
+            data,
+            etag,
+        }
+    }
+}
+
+//Read the asset `path` from `dir`, returning None if it doesn't exist.
+pub async fn load_from_dir(dir: &Path, path: &str) -> Option<Asset> {
+    match tokio::fs::read(dir.join(path)).await {
+        Ok(data) => Some(Asset::new(path, data)),
+        Err(e) => {
+            debug!(
+                "Failed to read asset {} from {}: {}",
+                path,
+                dir.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+//Read every file below `dir` into `out`, by their path relative to `root`. Hidden files are left out, like they are
+//when embedding the frontend.
+fn read_dir_recursive(
+    root: &Path,
+    dir: &Path,
+    out: &mut HashMap<String, Asset>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            read_dir_recursive(root, &path, out)?;
+        } else {
+            let name = path
+                .strip_prefix(root)
+                .expect("path below root")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join("/");
+            let asset = Asset::new(&name, std::fs::read(&path)?);
+            out.insert(name, asset);
+        }
+    }
+    Ok(())
+}
+
+//Load the bundled frontend, computing the entity tags of every file up front.
+fn load_bundled() -> HashMap<String, Asset> {
+    if EMBEDDED.is_empty() {
+        let mut files = HashMap::new();
+        if let Err(e) = read_dir_recursive(Path::new("dist"), Path::new("dist"), &mut files) {
+            error!("Failed to read the frontend from dist: {}", e);
+        }
+        files
+    } else {
+        EMBEDDED
+            .iter()
+            .map(|(name, data)| (name.to_string(), Asset::new(name, *data)))
+            .collect()
+    }
+}
+
+//Load the bundled frontend ahead of the first request for it, unless the frontend is served from a directory.
+pub fn preload_assets() {
+    if crate::CONFIG.web.assets.directory.is_none() {
+        info!("Loaded {} frontend files", BUNDLED.len());
+    }
+}
+
+//Get the asset `path`, relative to the root of the bundled frontend. Assets come from the configured directory if
+//there is one, which is read on every request so that a rebuilt frontend shows up right away, or from the bundled
+//frontend loaded at startup.
+pub async fn load_asset(path: &str) -> Option<Asset> {
+    match &crate::CONFIG.web.assets.directory {
+        Some(dir) => load_from_dir(Path::new(dir), path).await,
+        None => BUNDLED.get(path).cloned(),
+    }
+}
+
+//The Cache-Control header of assets, letting browsers reuse them for `max_age` seconds before checking for a new
+//version. With no max age they always check, which is cheap thanks to the entity tag.
+fn cache_control(max_age: u32) -> String {
+    if max_age == 0 {
+        "no-cache".into()
+    } else {
+        format!("public, max-age={}", max_age)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r> for Asset {
+    async fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        let etag = self.etag;
+        let cache_control = cache_control(crate::CONFIG.web.assets.max_age);
+        let response = if IfNoneMatch::of(request).matches(&etag) {
+            Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", etag)
+                .raw_header("Cache-Control", cache_control)
+                .finalize()
+        } else {
+            Response::build()
+                .header(self.content_type)
+                .raw_header("ETag", etag)
+                .raw_header("Cache-Control", cache_control)
+                .sized_body(Cursor::new(self.data))
+                .await
+                .finalize()
+        };
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rocket::{http::Header, local::Client};
+
+    #[get("/asset")]
+    fn asset() -> Asset {
+        Asset::new("admin.js", &b"console.log(1);"[..])
+    }
+
+    #[test]
+    fn content_types() {
+        let data = || &b""[..];
+        assert_eq!(
+            Asset::new("index.html", data()).content_type,
+            ContentType::HTML
+        );
+        assert_eq!(
+            Asset::new("login.js", data()).content_type,
+            ContentType::JavaScript
+        );
+        assert_eq!(
+            Asset::new("images/logo.0a1b2c.png", data()).content_type,
+            ContentType::PNG
+        );
+        assert_eq!(
+            Asset::new("LICENCE", data()).content_type,
+            ContentType::Binary
+        );
+        assert_eq!(cache_control(0), "no-cache");
+        assert_eq!(cache_control(60), "public, max-age=60");
+    }
+
+    #[tokio::test]
+    async fn asset_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("images")).unwrap();
+        std::fs::write(dir.path().join("images/logo.png"), b"not really a png").unwrap();
+
+        let asset = load_from_dir(dir.path(), "images/logo.png").await.unwrap();
+        assert_eq!(asset.content_type, ContentType::PNG);
+        assert_eq!(&asset.data[..], b"not really a png");
+        assert!(load_from_dir(dir.path(), "index.html").await.is_none());
+
+        //The bundled frontend is read all at once, leaving out hidden files.
+        std::fs::write(dir.path().join(".gitignore"), b"*").unwrap();
+        let mut files = HashMap::new();
+        read_dir_recursive(dir.path(), dir.path(), &mut files).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files["images/logo.png"].etag,
+            compute_etag(b"not really a png")
+        );
+    }
+
+    #[tokio::test]
+    async fn asset_responses() {
+        let rocket = rocket::ignite().mount("/", routes![asset]);
+        let client = Client::new(rocket).unwrap();
+
+        let mut response = client.get("/asset").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JavaScript));
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("no-cache")
+        );
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        assert_eq!(etag, compute_etag(b"console.log(1);"));
+        assert_eq!(response.body_string().await.unwrap(), "console.log(1);");
+
+        //Browsers which already have the asset are told to keep using it.
+        let mut response = client
+            .get("/asset")
+            .header(Header::new("If-None-Match", etag))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotModified);
+        assert!(response.body_bytes().await.is_none());
+    }
+}
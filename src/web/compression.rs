@@ -0,0 +1,326 @@
+//src/web/compression.rs: Compression of response bodies for clients which accept it.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::ContentType,
+    response::Body,
+    Request, Response,
+};
+use std::io::{Cursor, Write};
+
+//A content coding which responses can be compressed with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    //Parse the name of an encoding as used in the Accept-Encoding and Content-Encoding headers.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    //Compress `data` with this encoding.
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    //Quality 5 compresses almost as well as the maximum at a fraction of the cost, which matters
+                    //as every response is compressed on the fly.
+                    let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    writer.write_all(data)?;
+                }
+                Ok(out)
+            }
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            //Deflate in HTTP means zlib wrapped deflate data.
+            Encoding::Deflate => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+//Pick the first encoding in `enabled` which the client accepts according to its Accept-Encoding `headers`.
+pub fn choose_encoding<'a>(
+    headers: impl Iterator<Item = &'a str>,
+    enabled: &[Encoding],
+) -> Option<Encoding> {
+    //Each entry looks like "gzip" or "gzip;q=0.5", where a weight of 0 means that the encoding is not accepted.
+    let accepted: Vec<(String, f32)> = headers
+        .flat_map(|h| h.split(','))
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let name = parts.next()?.trim().to_lowercase();
+            if name.is_empty() {
+                return None;
+            }
+            let weight = parts
+                .filter_map(|p| {
+                    p.trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((name, weight))
+        })
+        .collect();
+    let weight_of = |name: &str| accepted.iter().find(|(n, _)| n == name).map(|(_, w)| *w);
+
+    //An encoding listed by name takes precedence over the wildcard.
+    enabled.iter().copied().find(|e| {
+        weight_of(e.name())
+            .or_else(|| weight_of("*"))
+            .map(|w| w > 0.0)
+            .unwrap_or(false)
+    })
+}
+
+//Whether responses of `content_type` benefit from being compressed. Images such as the map PNGs are already
+//compressed, so only text based formats are.
+fn is_compressible(content_type: &ContentType) -> bool {
+    let top = content_type.top().as_str().to_lowercase();
+    let sub = content_type.sub().as_str().to_lowercase();
+    match top.as_str() {
+        //Event streams are sent piece by piece, which compression would hold back.
+        "text" => sub != "event-stream",
+        "application" => {
+            sub == "json" || sub == "javascript" || sub == "xml" || sub.ends_with("+json")
+        }
+        _ => false,
+    }
+}
+
+//Fairing which compresses response bodies of at least `min_size` bytes with the first of `encodings` the client
+//accepts.
+pub struct CompressionFairing {
+    pub min_size: usize,
+    //The encodings to use, in order of preference.
+    pub encodings: Vec<Encoding>,
+}
+
+#[rocket::async_trait]
+impl Fairing for CompressionFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if self.encodings.is_empty() || response.headers().contains("Content-Encoding") {
+            return;
+        }
+        let compressible = response
+            .content_type()
+            .map(|c| is_compressible(&c))
+            .unwrap_or(false);
+        if !compressible {
+            return;
+        }
+        //Streamed bodies are left alone, as they would have to be buffered in full.
+        let size = match response.body() {
+            Some(Body::Sized(_, size)) => size as usize,
+            _ => return,
+        };
+        if size < self.min_size {
+            return;
+        }
+
+        //Caches must not give a compressed response to clients which don't accept it, or the other way around.
+        response.adjoin_raw_header("Vary", "Accept-Encoding");
+        let encoding =
+            match choose_encoding(request.headers().get("Accept-Encoding"), &self.encodings) {
+                Some(e) => e,
+                None => return,
+            };
+
+        let body = match response.body_bytes().await {
+            Some(b) => b,
+            None => return,
+        };
+        match encoding.compress(&body) {
+            Ok(compressed) => {
+                trace!(
+                    "Compressed {} byte response to {} bytes with {}",
+                    body.len(),
+                    compressed.len(),
+                    encoding.name()
+                );
+                response.set_raw_header("Content-Encoding", encoding.name());
+                //The entity tag was computed from the uncompressed body, so it only holds for the content.
+                if let Some(etag) = response.headers().get_one("ETag").map(|e| e.to_string()) {
+                    if !etag.starts_with("W/") {
+                        response.set_raw_header("ETag", format!("W/{}", etag));
+                    }
+                }
+                response.set_sized_body(Cursor::new(compressed)).await;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to compress response with {}: {}",
+                    encoding.name(),
+                    e
+                );
+                response.set_sized_body(Cursor::new(body)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use rocket::{http::Header, local::Client};
+    use std::io::Read;
+
+    //A JSON body large enough to be compressed.
+    fn large_json() -> String {
+        serde_json::to_string(&vec!["compress me"; 100]).unwrap()
+    }
+
+    #[get("/large")]
+    fn large() -> rocket::response::content::Json<String> {
+        rocket::response::content::Json(large_json())
+    }
+
+    #[get("/small")]
+    fn small() -> rocket::response::content::Json<&'static str> {
+        rocket::response::content::Json("[]")
+    }
+
+    #[get("/image")]
+    fn image() -> rocket::response::Content<Vec<u8>> {
+        rocket::response::Content(ContentType::PNG, vec![0; 2048])
+    }
+
+    #[test]
+    fn encoding_negotiation() {
+        let all = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+        let choose =
+            |header: &str, enabled: &[Encoding]| choose_encoding(std::iter::once(header), enabled);
+
+        //Our preference wins among the accepted encodings.
+        assert_eq!(choose("gzip, deflate, br", &all), Some(Encoding::Brotli));
+        assert_eq!(choose("gzip, deflate", &all), Some(Encoding::Gzip));
+        assert_eq!(
+            choose("gzip, deflate, br", &[Encoding::Deflate]),
+            Some(Encoding::Deflate)
+        );
+        //Case and whitespace don't matter.
+        assert_eq!(choose(" GZIP ;q=0.8", &all), Some(Encoding::Gzip));
+        //A weight of 0 refuses an encoding, even if the wildcard accepts everything.
+        assert_eq!(choose("br;q=0, *", &all), Some(Encoding::Gzip));
+        assert_eq!(choose("*;q=0", &all), None);
+        assert_eq!(choose("identity", &all), None);
+        assert_eq!(choose("", &all), None);
+        assert_eq!(choose("gzip", &[]), None);
+        //Multiple headers are combined.
+        assert_eq!(
+            choose_encoding(vec!["identity", "deflate"].into_iter(), &all),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn compressible_types() {
+        assert!(is_compressible(&ContentType::JSON));
+        assert!(is_compressible(&ContentType::Plain));
+        assert!(is_compressible(&ContentType::JavaScript));
+        assert!(is_compressible(&ContentType::new(
+            "application",
+            "problem+json"
+        )));
+        assert!(!is_compressible(&ContentType::PNG));
+        assert!(!is_compressible(&ContentType::new("text", "event-stream")));
+        assert!(!is_compressible(&ContentType::Binary));
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = large_json().into_bytes();
+        let mut out = Vec::new();
+        brotli::Decompressor::new(&Encoding::Brotli.compress(&data).unwrap()[..], 4096)
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+        let mut out = Vec::new();
+        flate2::read::ZlibDecoder::new(&Encoding::Deflate.compress(&data).unwrap()[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[tokio::test]
+    async fn fairing() {
+        let rocket = rocket::ignite()
+            .mount("/", routes![large, small, image])
+            .attach(CompressionFairing {
+                min_size: 1024,
+                encodings: vec![Encoding::Gzip],
+            });
+        let client = Client::new(rocket).unwrap();
+
+        //Large JSON is compressed for clients which accept it.
+        let mut response = client
+            .get("/large")
+            .header(Header::new("Accept-Encoding", "gzip, deflate"))
+            .dispatch()
+            .await;
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let body = response.body_bytes().await.unwrap();
+        assert!(body.len() < large_json().len());
+        let mut decompressed = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, large_json());
+
+        //But not for clients which don't.
+        let mut response = client.get("/large").dispatch().await;
+        assert!(response.headers().get_one("Content-Encoding").is_none());
+        assert_eq!(response.body_string().await.unwrap(), large_json());
+
+        //Small bodies and images aren't worth compressing.
+        for url in &["/small", "/image"] {
+            let response = client
+                .get(*url)
+                .header(Header::new("Accept-Encoding", "gzip"))
+                .dispatch()
+                .await;
+            assert!(response.headers().get_one("Content-Encoding").is_none());
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//src/web/etag.rs: Entity tags and conditional requests, letting clients skip downloading unchanged responses.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use crate::types::BackendError;
+use rocket::{
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome, Request},
+    response::{self, Responder},
+    Response,
+};
+use serde::Serialize;
+use std::io::Cursor;
+
+//The entity tags listed in the If-None-Match header of a request, if any.
+pub struct IfNoneMatch(Vec<String>);
+
+impl IfNoneMatch {
+    //Get the tags of `request`.
+    pub fn of(request: &Request<'_>) -> IfNoneMatch {
+        let tags = request
+            .headers()
+            .get("If-None-Match")
+            .flat_map(|h| h.split(','))
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        IfNoneMatch(tags)
+    }
+
+    //Check if the client already has the version of a resource tagged with `etag`.
+    pub fn matches(&self, etag: &str) -> bool {
+        //Weak comparison is used as per RFC 7232, so a W/ prefix is ignored.
+        self.0
+            .iter()
+            .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch {
+    type Error = ();
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IfNoneMatch::of(request))
+    }
+}
+
+//Create the entity tag of a response body. The tag has to stay the same across restarts and builds of the backend,
+//or clients would download everything again after every update, so a CRC32 of the body is used along with its length.
+pub fn compute_etag(data: &[u8]) -> String {
+    format!("\"{:08x}-{:x}\"", crc32fast::hash(data), data.len())
+}
+
+//A JSON response tagged with a hash of its body. If the client already has the same body, 304 Not Modified is
+//returned instead.
+pub struct Tagged<T>(pub T);
+
+#[rocket::async_trait]
+impl<'r, T: Serialize + Send + 'r> Responder<'r> for Tagged<T> {
+    async fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        let body = match serde_json::to_vec(&self.0) {
+            Ok(b) => b,
+            Err(e) => return BackendError::from(e).respond_to(request).await,
+        };
+        let etag = compute_etag(&body);
+        let response = if IfNoneMatch::of(request).matches(&etag) {
+            Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", etag)
+                .finalize()
+        } else {
+            Response::build()
+                .header(ContentType::JSON)
+                .raw_header("ETag", etag)
+                .sized_body(Cursor::new(body))
+                .await
+                .finalize()
+        };
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rocket::{http::Header, local::Client};
+
+    #[get("/tagged")]
+    fn tagged() -> Tagged<Vec<u32>> {
+        Tagged(vec![1, 2, 3])
+    }
+
+    #[tokio::test]
+    async fn conditional_requests() {
+        let rocket = rocket::ignite().mount("/", routes![tagged]);
+        let client = Client::new(rocket).unwrap();
+
+        let mut response = client.get("/tagged").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        //The tag only depends on the body, so it is the same for every build of the backend.
+        assert_eq!(etag, "\"fd6db3e9-7\"");
+        assert_eq!(response.body_string().await.unwrap(), "[1,2,3]");
+
+        //Any matching tag in the list gives 304
+        for tag in &[
+            etag.clone(),
+            format!("W/{}", etag),
+            format!("\"abc\", {}", etag),
+            "*".to_string(),
+        ] {
+            let mut response = client
+                .get("/tagged")
+                .header(Header::new("If-None-Match", tag.clone()))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::NotModified);
+            assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+            assert!(response.body_bytes().await.is_none());
+        }
+
+        let response = client
+            .get("/tagged")
+            .header(Header::new("If-None-Match", "\"abc\""))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
@@ -3,20 +3,45 @@
 //Copyright (c) 2020 LAPS Group
 //Distributed under the zlib licence, see LICENCE.
 
+use super::{
+    admin::{auto_start_module, module_exists, AdminSession},
+    request_id::RequestId,
+};
 use crate::{
+    docker::SharedDocker,
+    history::{get_record, record_submission, JobRecord},
     module_handling::ModuleInfo,
-    types::{BackendError, JobOutcome, JobResult, Vector},
+    types::{
+        error_response, BackendError, JobOutcome, JobProgress, JobResult, JobStatus, MapExtent,
+        Point, Vector,
+    },
     util,
 };
 use futures::TryStreamExt;
+use laps_convert::MapType;
 use rand::RngCore;
 use rocket::{
     http::{ContentType, Status},
+    request::{FromRequest, Outcome, Request},
     Response, State,
 };
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
-use std::io::Cursor;
+use std::{
+    io::Cursor,
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+//Job requests are shared with clients.
+pub use laps_types::{CoordinateSystem, JobSubmission, MapTile, LATEST_VERSION};
+
+//The largest factor a map can be downsampled by for preview jobs.
+const MAX_DOWNSAMPLE: u32 = 64;
+//The largest number of additional map tiles a job can span.
+const MAX_TILES: usize = 8;
+//The largest number of modules which can be compared at once.
+const MAX_COMPARED_ALGORITHMS: usize = 8;
 
 //The job message which gets sent to a pathfinding module.
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -25,90 +50,408 @@ pub struct JobInfo {
     pub start: Vector,
     pub stop: Vector,
     pub map_id: i32,
+    //The type of `map_id` and the tiles, which tells the module which namespace to read the maps from.
+    #[serde(default, skip_serializing_if = "MapType::is_elevation")]
+    pub map_type: MapType,
+    //If set, the module should pathfind on the map decimated by this factor. Modules may ignore this. The start and
+    //stop points are still in full resolution pixels, so the module divides them by the factor itself, while the
+    //points of its path are on the downsampled map and are multiplied by the factor before the client gets them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downsample: Option<u32>,
+    //Additional maps making up the grid together with `map_id`. The points are in grid coordinates.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tiles: Vec<MapTile>,
 }
 
-//A job request from the frontend.
-#[derive(Deserialize, Serialize)]
-pub struct JobSubmission {
-    pub start: Vector,
-    pub stop: Vector,
-    pub map_id: i32,
-    pub algorithm: ModuleInfo,
+//If the latest version of the module was requested in `job`, replace it with the actual version.
+//Leaves the algorithm untouched if no version of the module is registered.
+pub async fn resolve_algorithm(
+    job: &mut JobSubmission,
+    redis: &mut darkredis::Connection,
+) -> Result<(), BackendError> {
+    if job.algorithm.version == LATEST_VERSION {
+        let modules = crate::module_handling::get_registered_modules(redis).await?;
+        if let Some(m) = crate::module_handling::find_latest_version(&modules, &job.algorithm.name)
+        {
+            job.algorithm.version = m.version.clone();
+        }
+    }
+    Ok(())
 }
 
-impl JobSubmission {
-    //Return the job cache key for this submission, without any prefixes.
-    //Each field is written out explicitly such that each field has a defined ordering.
-    pub fn cache_key(&self) -> String {
-        let start_string = format!("({},{})", self.start.x, self.start.y);
-        let stop_string = format!("({},{})", self.start.x, self.start.y);
-        format!(
-            "{}.{}.{}.{}",
-            self.algorithm, self.map_id, start_string, stop_string
-        )
+//Get the width and height of the map of `map_type` with `map_id`, or None if it doesn't exist.
+async fn get_map_dimensions(
+    redis: &mut darkredis::Connection,
+    map_type: MapType,
+    map_id: i32,
+) -> Result<Option<(u32, u32)>, BackendError> {
+    let mapdata_key = util::get_mapdata_key(map_type, "image");
+    match redis.hget(mapdata_key, map_id.to_string()).await? {
+        Some(data) => {
+            //Only the header is read, whether the map is stored as PNG or WebP.
+            let dimensions = laps_convert::map_dimensions(&data)
+                .map_err(|e| BackendError::Other(format!("Invalid map {}: {}", map_id, e)))?;
+            Ok(Some(dimensions))
+        }
+        None => Ok(None),
     }
 }
 
-impl JobSubmission {
-    //Check if `self` is a valid job. Returns (isvalid, errormessage).
-    pub async fn validity_check(
-        &self,
-        redis: &mut darkredis::Connection,
-    ) -> Result<(bool, &'static str), BackendError> {
-        //Check that the start and end points are not the same
-        if self.start == self.stop {
-            return Ok((false, "Start and end points are equal"));
+//Check if `job` is a valid job. Returns (isvalid, errormessage).
+pub async fn validity_check(
+    job: &mut JobSubmission,
+    redis: &mut darkredis::Connection,
+) -> Result<(bool, &'static str), BackendError> {
+    resolve_algorithm(job, redis).await?;
+    let modules = crate::module_handling::get_registered_modules(redis).await?;
+    check_job(job, redis, &modules).await
+}
+
+//Check `job` like `validity_check`, but with `modules` as the modules which can run jobs, and without resolving the
+//latest version of the module.
+async fn check_job(
+    job: &mut JobSubmission,
+    redis: &mut darkredis::Connection,
+    modules: &[ModuleInfo],
+) -> Result<(bool, &'static str), BackendError> {
+    //Points in world coordinates are converted first, as two of them may end up in the same pixel.
+    let world_coords = job.coords == CoordinateSystem::World;
+    if world_coords {
+        if let Err(msg) = convert_world_coordinates(job, redis).await? {
+            return Ok((false, msg));
+        }
+    }
+
+    //Pixels are whole and can't be negative, so jobs in pixel coordinates which aren't are turned away here.
+    let (start, stop) = match (job.start.to_pixel(), job.stop.to_pixel()) {
+        (Some(start), Some(stop)) => (start, stop),
+        _ => {
+            return Ok((
+                false,
+                "Pixel coordinates must be whole, non-negative numbers",
+            ))
         }
+    };
 
-        //Check that the algorithm requested actually exists
-        let modules = crate::module_handling::get_registered_modules(redis).await?;
-        if !modules.contains(&self.algorithm) {
-            return Ok((false, "Module does not exist"));
-        }
-
-        let mapdata_key = util::create_redis_key("mapdata.image");
-        //Check that the requested map actually exists.
-        if let Some(data) = redis.hget(mapdata_key, self.map_id.to_string()).await? {
-            //Verify that the job is within the bounds of the map
-            let decoder = png::Decoder::new(data.as_slice());
-
-            let (info, _) = decoder
-                .read_info()
-                .map_err(|s| BackendError::Other(format!("PNG error: {}", s)))?;
-            //No need to check if they're negative as the type only allows for u32.
-            //Only check the biggest one
-            let max_x = self.start.x.max(self.stop.x);
-            let max_y = self.start.y.max(self.stop.y);
-            let out = info.width > max_x && info.height > max_y;
-            if out {
-                Ok((true, ""))
-            } else {
-                Ok((false, "Points are out of bounds"))
+    //Check that the start and end points are not the same
+    if start == stop {
+        return Ok((false, "Start and end points are equal"));
+    }
+
+    if let Some(factor) = job.downsample {
+        if !factor.is_power_of_two() || factor > MAX_DOWNSAMPLE {
+            return Ok((
+                false,
+                "Downsample factor must be a power of two no larger than 64",
+            ));
+        }
+    }
+
+    //Check that the algorithm requested actually exists
+    if !modules.contains(&job.algorithm) {
+        return Ok((false, "Module does not exist"));
+    }
+
+    if job.tiles.len() > MAX_TILES {
+        return Ok((false, "Too many map tiles"));
+    }
+
+    let extents = match map_extents(job, redis).await? {
+        Ok(e) => e,
+        Err(msg) => return Ok((false, msg)),
+    };
+
+    if !map_types_supported(job, redis).await? {
+        return Ok((false, "The module does not support this type of map"));
+    }
+
+    //Tiles have to be adjacent, not on top of each other.
+    for (i, a) in extents.iter().enumerate() {
+        if extents[i + 1..].iter().any(|b| a.overlaps(b)) {
+            return Ok((false, "Map tiles overlap"));
+        }
+    }
+
+    //Verify that both points are within the bounds of one of the maps.
+    let in_bounds = |point: &Vector| extents.iter().any(|e| e.contains(point));
+    if in_bounds(&start) && in_bounds(&stop) {
+        Ok((true, ""))
+    } else if world_coords {
+        Ok((
+            false,
+            "Points are outside the georeferenced extent of the maps",
+        ))
+    } else {
+        Ok((false, "Points are out of bounds"))
+    }
+}
+
+//Convert the points of a job in world coordinates to the nearest pixels of its main map, such that modules only ever
+//see pixel coordinates. Returns an error message if the points can't be converted.
+async fn convert_world_coordinates(
+    job: &mut JobSubmission,
+    redis: &mut darkredis::Connection,
+) -> Result<Result<(), &'static str>, BackendError> {
+    let meta_key = util::get_mapdata_key(job.map_type, "meta");
+    let metadata: laps_convert::ImageMetadata =
+        match redis.hget(&meta_key, job.map_id.to_string()).await? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Ok(Err("Invalid map id")),
+        };
+
+    for point in &mut [&mut job.start, &mut job.stop] {
+        let (x, y) = match metadata.world_to_pixel(point.x, point.y) {
+            Some(p) => p,
+            None => return Ok(Err("The map is not georeferenced")),
+        };
+        //Points before the origin can't be represented at all, the ones past the end are caught by the bounds check.
+        let max = u32::MAX as f64;
+        if x < 0.0 || y < 0.0 || x > max || y > max {
+            return Ok(Err(
+                "Points are outside the georeferenced extent of the maps",
+            ));
+        }
+        **point = Point { x, y };
+    }
+    job.coords = CoordinateSystem::Pixel;
+    Ok(Ok(()))
+}
+
+//Check that the module accepts the type of the maps of this job. Modules which don't limit the map types are always
+//accepted.
+async fn map_types_supported(
+    job: &JobSubmission,
+    redis: &mut darkredis::Connection,
+) -> Result<bool, BackendError> {
+    match crate::module_handling::get_module_map_types(redis, &job.algorithm).await? {
+        Some(supported) => Ok(supported.contains(&job.map_type)),
+        None => Ok(true),
+    }
+}
+
+//Find the extent of every map in the grid of this job, checking that each map actually exists among the maps of its
+//type.
+//Returns an error message if a map is missing or used more than once.
+pub async fn map_extents(
+    job: &JobSubmission,
+    redis: &mut darkredis::Connection,
+) -> Result<Result<Vec<MapExtent>, &'static str>, BackendError> {
+    let mut extents = Vec::with_capacity(job.tiles.len() + 1);
+    let main_tile = MapTile {
+        map_id: job.map_id,
+        offset: Vector { x: 0, y: 0 },
+    };
+    let tiles: Vec<&MapTile> = std::iter::once(&main_tile)
+        .chain(job.tiles.iter())
+        .collect();
+    for (i, tile) in tiles.iter().enumerate() {
+        if tiles[..i].iter().any(|t| t.map_id == tile.map_id) {
+            return Ok(Err("The same map is used more than once"));
+        }
+        match get_map_dimensions(redis, job.map_type, tile.map_id).await? {
+            Some((width, height)) => extents.push(MapExtent {
+                offset: tile.offset,
+                width,
+                height,
+            }),
+            None => return Ok(Err("Invalid map id")),
+        }
+    }
+    Ok(Ok(extents))
+}
+
+//Get a timeout in seconds which a module may override in `key`, using `default` if it doesn't.
+pub async fn get_module_timeout(
+    conn: &mut darkredis::Connection,
+    key: &str,
+    default: u32,
+) -> Result<u32, BackendError> {
+    match conn.get(key).await? {
+        Some(s) => String::from_utf8_lossy(&s)
+            .parse::<u32>()
+            .map_err(|e| BackendError::Other(format!("Invalid timeout in {}: {}", key, e))),
+        None => Ok(default),
+    }
+}
+
+//Get how long the jobs of `module` are cached in seconds. 0 means they aren't cached at all.
+pub async fn get_module_cache_ttl(
+    conn: &mut darkredis::Connection,
+    module: &ModuleInfo,
+) -> Result<u32, BackendError> {
+    get_module_timeout(
+        conn,
+        &util::get_module_cache_ttl_key(module),
+        crate::CONFIG.jobs.token_timeout,
+    )
+    .await
+}
+
+//The client submitting a job, which submissions are rate limited by. Admins are told apart by their account and
+//everyone else by their IP address. The X-Real-IP header is only honoured from the configured reverse proxies, as
+//anyone else could dodge the limit by making up a new address for every submission.
+pub struct Submitter(Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Submitter {
+    type Error = ();
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let ip = match request.remote().map(|a| a.ip()) {
+            Some(proxy) if crate::CONFIG.web.trusted_proxies.contains(&proxy) => {
+                Some(request.real_ip().unwrap_or(proxy))
             }
-        } else {
-            Ok((false, "Invalid map id"))
+            remote => remote,
+        };
+        Outcome::Success(Submitter(ip))
+    }
+}
+
+impl Submitter {
+    fn identity(&self, session: Option<&AdminSession>) -> String {
+        match (session, self.0) {
+            (Some(s), _) => format!("admin:{}", s.username),
+            (None, Some(ip)) => ip.to_string(),
+            (None, None) => "unknown".into(),
         }
     }
 }
 
-#[post("/job", format = "json", data = "<job>")]
-pub async fn submit(
+//Count a submission by `client` towards the submission limit. Submissions are counted in fixed windows of a minute.
+//Returns how many seconds the client has to wait if it has submitted too many jobs.
+async fn check_submission_rate(
+    conn: &mut darkredis::Connection,
+    client: &str,
+) -> Result<Option<u32>, BackendError> {
+    let limit = crate::CONFIG.jobs.max_submissions_per_minute;
+    if limit == 0 {
+        return Ok(None);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let key = util::get_submission_rate_key(client, now / 60);
+    let commands = darkredis::CommandList::new("MULTI")
+        .command("INCR")
+        .arg(&key)
+        .command("EXPIRE")
+        .arg(&key)
+        .arg(b"60")
+        .command("EXEC");
+    let results: Vec<darkredis::Value> = conn.run_commands(commands).await?.try_collect().await?;
+    //The EXEC result is last, and holds the results of INCR and EXPIRE.
+    let count = results
+        .into_iter()
+        .last()
+        .map(darkredis::Value::unwrap_array)
+        .and_then(|r| r.into_iter().next())
+        .map(darkredis::Value::unwrap_integer)
+        .unwrap_or(0);
+    if count > limit as isize {
+        Ok(Some((60 - now % 60) as u32))
+    } else {
+        Ok(None)
+    }
+}
+
+//The outcome of validating a job without submitting it.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct JobValidation {
+    pub valid: bool,
+    //Why the job is invalid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+//Check if a job would be accepted, without submitting it. Lets clients give feedback while a job is being set up.
+#[post("/job/validate", format = "json", data = "<job>")]
+pub async fn validate(
     pool: State<'_, darkredis::ConnectionPool>,
-    job: Json<JobSubmission>,
-) -> Result<Response<'_>, BackendError> {
+    mut job: Json<JobSubmission>,
+) -> Result<Json<JobValidation>, BackendError> {
+    let mut conn = pool.get().await;
+    let (valid, reason) = validity_check(&mut job, &mut conn).await?;
+    Ok(Json(JobValidation {
+        valid,
+        reason: if valid { None } else { Some(reason.into()) },
+    }))
+}
+
+//What became of a job submission.
+enum Submission {
+    //The job was queued, or was already in the cache. Its result can be polled for with the token.
+    Accepted(String),
+    //The job was turned away.
+    Rejected(Rejection),
+}
+
+//Why a job was turned away, which is sent to the client as an error response.
+struct Rejection {
+    status: Status,
+    code: &'static str,
+    message: String,
+    //Seconds until the client may submit jobs again, if it was rate limited.
+    retry_after: Option<u32>,
+}
+
+impl Rejection {
+    fn new(status: Status, code: &'static str, message: &str) -> Self {
+        Rejection {
+            status,
+            code,
+            message: message.to_string(),
+            retry_after: None,
+        }
+    }
+
+    async fn into_response(self, request_id: &RequestId) -> Response<'static> {
+        let mut response =
+            error_response(self.status, self.code, &self.message, Some(request_id)).await;
+        if let Some(retry_after) = self.retry_after {
+            response.set_raw_header("Retry-After", retry_after.to_string());
+        }
+        response
+    }
+}
+
+//Generate a random token which the user can get the result of a job with.
+fn generate_token() -> String {
+    let mut buffer = vec![0u8; 64];
+    rand::thread_rng().fill_bytes(&mut buffer);
+    base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD)
+}
+
+//Submit `job`, shared by clients which poll for the result themselves, those which wait for it and comparisons.
+async fn submit_job(
+    pool: &darkredis::ConnectionPool,
+    docker: &SharedDocker,
+    session: Option<&AdminSession>,
+    submitter: &Submitter,
+    job: &mut JobSubmission,
+) -> Result<Submission, BackendError> {
     let mut conn = pool.get().await;
 
+    //Resolve the module version first, so that jobs for the latest version are cached under the actual version.
+    resolve_algorithm(job, &mut conn).await?;
+
+    //Modules can override how long their jobs are cached. A timeout of 0 disables the cache entirely for the module.
+    let cache_ttl = get_module_cache_ttl(&mut conn, &job.algorithm).await?;
+
     //Try to find the job in the cache. If it is in the cache, we can assume that the job submission has been validated already.
-    let cache_key = util::get_job_cache_key(&job);
-    if let Some(v) = conn.get(&cache_key).await? {
+    let cache_key = util::get_job_cache_key(job);
+    let cached = if cache_ttl != 0 {
+        conn.get(&cache_key).await?
+    } else {
+        None
+    };
+    if let Some(v) = cached {
         //Already cached, just return the job token we have stored instead of performing the job again.
 
-        //Reset the time to live of the job mapping
+        //Reset the time to live of the cache entry, keeping the one of the module, and of the job mapping.
+        let cache_ttl = cache_ttl.to_string();
         let job_timeout = crate::CONFIG.jobs.result_timeout.to_string();
         let job_mapping_key = util::get_job_mapping_key(&*String::from_utf8_lossy(&v));
         let mut commands = darkredis::CommandList::new("EXPIRE")
             .arg(&cache_key)
-            .arg(&job_timeout)
+            .arg(&cache_ttl)
             .command("EXPIRE")
             .arg(&job_mapping_key)
             .arg(&job_timeout);
@@ -126,23 +469,39 @@ pub async fn submit(
             .try_collect::<Vec<darkredis::Value>>()
             .await?;
 
-        return Ok(Response::build()
-            .status(Status::Accepted)
-            .header(ContentType::Plain)
-            .sized_body(Cursor::new(v))
-            .await
-            .finalize());
+        return Ok(Submission::Accepted(
+            String::from_utf8_lossy(&v).into_owned(),
+        ));
+    }
+
+    //Resubmitting cached jobs is cheap, so only jobs which will actually be run count towards the rate limit.
+    let client = submitter.identity(session);
+    if let Some(retry_after) = check_submission_rate(&mut conn, &client).await? {
+        warn!("Rate limited job submissions from {}", client);
+        let message = format!(
+            "Too many jobs submitted, try again in {} seconds",
+            retry_after
+        );
+        let mut rejection = Rejection::new(Status::TooManyRequests, "rate_limited", &message);
+        rejection.retry_after = Some(retry_after);
+        return Ok(Submission::Rejected(rejection));
+    }
+
+    //Modules are registered while they have workers. Jobs for a module which isn't would never be picked up, so a
+    //module which is stopped is checked against like any other, and either started or turned away below.
+    let mut modules = crate::module_handling::get_registered_modules(&mut conn).await?;
+    let stopped =
+        !modules.contains(&job.algorithm) && module_exists(&**docker, &job.algorithm).await?;
+    if stopped {
+        modules.push(job.algorithm.clone());
     }
 
     //Before we do anything, verify that the request is actually valid.
-    match job.validity_check(&mut conn).await {
+    match check_job(job, &mut conn, &modules).await {
         Ok((true, _)) => (),
         Ok((false, msg)) => {
-            return Ok(Response::build()
-                .status(Status::BadRequest)
-                .sized_body(std::io::Cursor::new(msg))
-                .await
-                .finalize())
+            let rejection = Rejection::new(Status::BadRequest, "invalid_job", msg);
+            return Ok(Submission::Rejected(rejection));
         }
         Err(e) => {
             error!("Failed to check job validity {}", &e);
@@ -150,6 +509,31 @@ pub async fn submit(
         }
     }
 
+    //Only modules which were stopped for being idle are started again, the others were stopped by an admin.
+    if stopped {
+        let idle_stopped = conn
+            .exists(util::get_module_idle_stopped_key(&job.algorithm))
+            .await?;
+        let started = if crate::CONFIG.module.auto_start_modules && idle_stopped {
+            match auto_start_module(&**docker, pool, &job.algorithm).await {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Failed to start module {}: {}", job.algorithm, e);
+                    false
+                }
+            }
+        } else {
+            false
+        };
+        if !started {
+            return Ok(Submission::Rejected(Rejection::new(
+                Status::ServiceUnavailable,
+                "no_workers",
+                "No workers are available for this module",
+            )));
+        }
+    }
+
     //TODO Find a random job id
     let job_id = conn
         .incr(util::create_redis_backend_key("job_id"))
@@ -158,20 +542,79 @@ pub async fn submit(
 
     let key = util::get_module_work_key(&job.algorithm);
 
+    //The validity check only lets jobs with pixel coordinates through.
     let info = JobInfo {
         job_id: job_id as i32,
-        start: job.start,
-        stop: job.stop,
+        start: job.start.to_pixel().expect("validated start point"),
+        stop: job.stop.to_pixel().expect("validated stop point"),
         map_id: job.map_id,
+        map_type: job.map_type,
+        downsample: job.downsample,
+        tiles: job.tiles.clone(),
     };
+    //Set the deadline before sending the job so that a quick result can't arrive before it.
+    let job_timeout = get_module_timeout(
+        &mut conn,
+        &util::get_module_job_timeout_key(&job.algorithm),
+        crate::CONFIG.jobs.job_timeout,
+    )
+    .await?;
+    //Jobs queued while the module is paused get their deadline once it is resumed.
+    let paused = conn
+        .exists(util::get_module_paused_key(&job.algorithm))
+        .await?;
+    if job_timeout != 0 && !paused {
+        crate::module_handling::set_job_deadline(&mut conn, info.job_id, job_timeout).await?;
+    }
+
+    //Remember the extents of the maps so that the result of the module can be checked against them.
+    if let Ok(extents) = map_extents(job, &mut conn).await? {
+        conn.set_and_expire_seconds(
+            util::get_job_extents_key(info.job_id),
+            serde_json::to_vec(&extents).unwrap(),
+            crate::CONFIG.jobs.token_timeout,
+        )
+        .await?;
+    }
+
+    //Remember the downsample factor so the result can be scaled back up to full resolution. It expires along with the
+    //token of the job, like the extents, so that jobs which never finish don't leave it behind. Once the result
+    //arrives it is kept for as long as the result instead.
+    if let Some(factor) = job.downsample {
+        conn.set_and_expire_seconds(
+            util::get_job_downsample_key(info.job_id),
+            factor.to_string(),
+            crate::CONFIG.jobs.token_timeout,
+        )
+        .await?;
+    }
+
+    //Add the job to the history before sending it, so that its result always has a record to complete.
+    let record = JobRecord {
+        job_id: info.job_id,
+        map_id: job.map_id,
+        algorithm: job.algorithm.clone(),
+        submitted: chrono::Utc::now().timestamp_millis(),
+        outcome: None,
+        duration: None,
+        username: session.map(|s| s.username.clone()),
+    };
+    if let Err(e) = record_submission(&mut conn, &record).await {
+        error!("Failed to add job {} to the job history: {}", job_id, e);
+    }
+
     debug!("Sending job: {:?}", info);
     conn.rpush(&key, serde_json::to_string(&info).unwrap())
         .await?;
+    //Keep the module from being stopped for being idle.
+    conn.set(
+        util::get_module_last_used_key(&job.algorithm),
+        chrono::Utc::now().timestamp().to_string(),
+    )
+    .await?;
 
     //Job submitted, now generate a token the user can use to get the result
-    let mut buffer = vec![0u8; 64];
-    rand::thread_rng().fill_bytes(&mut buffer);
-    let token = base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD);
+    let token = generate_token();
 
     //Create a mapping from user token to a job id
     let map_key = util::get_job_mapping_key(&token);
@@ -184,34 +627,114 @@ pub async fn submit(
     .unwrap();
 
     //Create a cache element such that the job is already in the cache.
-    let token_clone = token.clone();
-    conn.set_and_expire_seconds(cache_key, token_clone, crate::CONFIG.jobs.token_timeout)
-        .await?;
+    if cache_ttl != 0 {
+        let token_clone = token.clone();
+        conn.set_and_expire_seconds(cache_key, token_clone, cache_ttl)
+            .await?;
+    }
 
-    //All is good, do things
-    let response = Response::build()
-        .status(Status::Accepted)
-        .header(ContentType::Plain)
-        .sized_body(Cursor::new(token))
-        .await
-        .finalize();
-    Ok(response)
+    Ok(Submission::Accepted(token))
+}
+
+#[post("/job", format = "json", data = "<job>")]
+pub async fn submit(
+    pool: State<'_, darkredis::ConnectionPool>,
+    docker: State<'_, SharedDocker>,
+    session: Option<AdminSession>,
+    submitter: Submitter,
+    mut job: Json<JobSubmission>,
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
+    match submit_job(&pool, &docker, session.as_ref(), &submitter, &mut job).await? {
+        Submission::Accepted(token) => Ok(Response::build()
+            .status(Status::Accepted)
+            .header(ContentType::Plain)
+            .sized_body(Cursor::new(token))
+            .await
+            .finalize()),
+        Submission::Rejected(rejection) => Ok(rejection.into_response(request_id).await),
+    }
+}
+
+//Submit a job and wait for its result, for clients which would rather not poll for it. Responds like `result` once
+//the result is ready. If it isn't ready within `timeout` seconds, clamped like when polling, the response is 408 and
+//the job keeps running, so its result can still be polled for at the Location header.
+#[post("/job/sync?<timeout>", format = "json", data = "<job>")]
+pub async fn submit_and_wait(
+    pool: State<'_, darkredis::ConnectionPool>,
+    result_pool: State<'_, ResultConnectionPool>,
+    docker: State<'_, SharedDocker>,
+    session: Option<AdminSession>,
+    submitter: Submitter,
+    timeout: Option<u32>,
+    mut job: Json<JobSubmission>,
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
+    let token = match submit_job(&pool, &docker, session.as_ref(), &submitter, &mut job).await? {
+        Submission::Accepted(token) => token,
+        Submission::Rejected(rejection) => return Ok(rejection.into_response(request_id).await),
+    };
+
+    //Waiting for the result takes up a polling connection just like polling for it does.
+    let _polling = result_pool.start_polling();
+    let mut conn = result_pool.get().await;
+    let timeout = poll_timeout(timeout, crate::CONFIG.jobs.poll_timeout);
+    match await_result(&mut conn, &token, timeout, request_id).await? {
+        Some(response) => Ok(response),
+        None => {
+            let message = format!("The job didn't finish within {} seconds", timeout);
+            let mut response = error_response(
+                Status::RequestTimeout,
+                "result_timeout",
+                &message,
+                Some(request_id),
+            )
+            .await;
+            response.set_raw_header("Location", format!("/job/{}", token));
+            Ok(response)
+        }
+    }
 }
 
-//Typed connection pool for use with getting job results.
-pub struct ResultConnectionPool(darkredis::ConnectionPool);
+//Typed connection pool for use with getting job results. Also keeps count of the clients polling for results.
+pub struct ResultConnectionPool {
+    pool: darkredis::ConnectionPool,
+    polling: AtomicUsize,
+}
 
 impl std::ops::Deref for ResultConnectionPool {
     type Target = darkredis::ConnectionPool;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.pool
     }
 }
 
 impl std::ops::DerefMut for ResultConnectionPool {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.pool
+    }
+}
+
+impl ResultConnectionPool {
+    //Count a client as polling until the returned guard is dropped.
+    fn start_polling(&self) -> PollingGuard<'_> {
+        self.polling.fetch_add(1, Ordering::SeqCst);
+        PollingGuard(&self.polling)
+    }
+
+    //Get how many clients are polling right now, including the ones waiting for a connection.
+    pub fn polling_clients(&self) -> usize {
+        self.polling.load(Ordering::SeqCst)
+    }
+}
+
+//Stops counting a client as polling when dropped, so that clients which disconnect are accounted for.
+struct PollingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for PollingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
@@ -229,7 +752,10 @@ pub async fn create_result_redis_pool() -> ResultConnectionPool {
     match pool {
         Ok(p) => {
             info!("Successfully connected to Redis!");
-            ResultConnectionPool(p)
+            ResultConnectionPool {
+                pool: p,
+                polling: AtomicUsize::new(0),
+            }
         }
         Err(e) => {
             error!("Failed to connect to Redis: {:?}", e);
@@ -241,53 +767,94 @@ pub async fn create_result_redis_pool() -> ResultConnectionPool {
 pub enum JobPoll {
     Ready { result: JobResult },
     Pending,
+    //The stored result is corrupt, and will never be readable.
+    Error { message: String },
 }
 
-//Repeatedly try to get a job result using the system configuration.
+//Wait for up to `poll_timeout` seconds for the result of a job. Redis blocks until the result is pushed, so it's
+//returned as soon as it arrives.
 pub async fn try_poll_job_result(
     redis: &mut darkredis::Connection,
     job_id: i32,
+    poll_timeout: u32,
 ) -> Result<JobPoll, BackendError> {
     //BRPOPLPUSH keeps the expiry of a list even when there's just a single element in it, so use that to poll.
     let key = util::get_job_key(job_id);
-    let poll_timeout = crate::CONFIG.jobs.poll_timeout.to_string();
+    let poll_timeout = poll_timeout.to_string();
     let command = darkredis::Command::new("BRPOPLPUSH")
         .arg(&key)
         .arg(&key)
         .arg(&poll_timeout);
     let result = redis.run_command(command).await?;
 
-    match result
-        .optional_string()
-        .map(|s| serde_json::from_slice::<JobResult>(&s).unwrap())
-    {
-        Some(result) => Ok(JobPoll::Ready { result }),
+    match result.optional_string() {
+        Some(data) => match serde_json::from_slice::<JobResult>(&data) {
+            Ok(result) => Ok(JobPoll::Ready { result }),
+            Err(e) => Ok(JobPoll::Error {
+                message: e.to_string(),
+            }),
+        },
         None => Ok(JobPoll::Pending),
     }
 }
 
-//Get the result of a pathfinding job
-#[get("/job/<token>")]
-pub async fn result(
-    pool: State<'_, ResultConnectionPool>,
-    token: String,
-) -> Result<Response<'_>, BackendError> {
-    //Because other clients may be polling at once, there's a possibility that acquiring this connection
-    //will take a while, but that's okay because it cannot take much longer than the poll timeout.
-    //This means that the theoretical maximum time this handler can take is just shy of 2*poll_timeout.
-    let mut conn = pool.get().await;
+//Scale the path of a preview job back to full resolution. Does nothing if the job isn't downsampled.
+async fn scale_path(
+    conn: &mut darkredis::Connection,
+    job_id: i32,
+    points: &mut [Vector],
+) -> Result<(), BackendError> {
+    if let Some(factor) = conn.get(util::get_job_downsample_key(job_id)).await? {
+        let factor = String::from_utf8_lossy(&factor)
+            .parse::<u32>()
+            .map_err(|e| BackendError::Other(format!("Invalid downsample factor: {}", e)))?;
+        for point in points.iter_mut() {
+            point.x = point.x.saturating_mul(factor);
+            point.y = point.y.saturating_mul(factor);
+        }
+    }
+    Ok(())
+}
+
+//Get the poll timeout in seconds for a client which asked for `requested` seconds, with `max` being the configured
+//timeout. The result is always returned as soon as it's ready, so a shorter timeout only makes pending jobs return
+//sooner. Clamped to `max`, and always to at least a second because a timeout of 0 makes Redis wait forever.
+fn poll_timeout(requested: Option<u32>, max: u32) -> u32 {
+    requested.unwrap_or(max).min(max).max(1)
+}
+
+//The response for a token which doesn't belong to a job, or whose job has expired.
+async fn job_not_found(request_id: &RequestId) -> Response<'static> {
+    error_response(
+        Status::NotFound,
+        "not_found",
+        "No job with that token, it may have expired",
+        Some(request_id),
+    )
+    .await
+}
 
-    let key = util::get_job_mapping_key(&token);
+//Wait for up to `timeout` seconds for the result of the job behind `token`, and build the response for the client.
+//Returns None if the result isn't ready by then.
+async fn await_result(
+    conn: &mut darkredis::Connection,
+    token: &str,
+    timeout: u32,
+    request_id: &RequestId,
+) -> Result<Option<Response<'static>>, BackendError> {
+    let key = util::get_job_mapping_key(token);
     match conn.get(key).await? {
         Some(k) => {
             //Poll for a result on this job
             let job_id = String::from_utf8_lossy(&k).parse::<i32>().unwrap();
 
             //See if the result is ready
-            match try_poll_job_result(&mut conn, job_id).await? {
-                JobPoll::Ready { result } => {
+            match try_poll_job_result(conn, job_id, timeout).await? {
+                JobPoll::Ready { mut result } => {
                     let response = match result.outcome {
                         JobOutcome::Success => {
+                            scale_path(conn, job_id, &mut result.points).await?;
+
                             //Hide the job_id field from the user
                             let json = Cursor::new(
                                 serde_json::json!({
@@ -302,13 +869,38 @@ pub async fn result(
                                 .await
                                 .finalize()
                         }
-                        JobOutcome::Failure => Response::build()
-                            .status(Status::InternalServerError)
-                            .sized_body(Cursor::new(
-                                "A pathfinding module failed to complete this job!",
-                            ))
-                            .await
-                            .finalize(),
+                        JobOutcome::Failure => {
+                            //If the module reported a path before failing or timing out, give the user that instead.
+                            let partial = match conn.get(util::get_job_progress_key(job_id)).await?
+                            {
+                                Some(p) => serde_json::from_slice::<JobProgress>(&p)?.points,
+                                None => Vec::new(),
+                            };
+                            if partial.is_empty() {
+                                error_response(
+                                    Status::InternalServerError,
+                                    "job_failed",
+                                    "A pathfinding module failed to complete this job!",
+                                    Some(request_id),
+                                )
+                                .await
+                            } else {
+                                let mut points = partial;
+                                scale_path(conn, job_id, &mut points).await?;
+                                let json = Cursor::new(
+                                    serde_json::json!({
+                                        "outcome": "failure", "partial": true, "points": points
+                                    })
+                                    .to_string(),
+                                );
+                                Response::build()
+                                    .status(Status::PartialContent)
+                                    .header(ContentType::JSON)
+                                    .sized_body(json)
+                                    .await
+                                    .finalize()
+                            }
+                        }
                         JobOutcome::Cancelled => {
                             let json = Cursor::new(
                                 serde_json::json!({
@@ -326,29 +918,456 @@ pub async fn result(
                         }
                     };
 
-                    Ok(response)
+                    Ok(Some(response))
                 }
                 //Not ready yet
-                JobPoll::Pending => Ok(Response::build().status(Status::GatewayTimeout).finalize()),
+                JobPoll::Pending => Ok(None),
+                JobPoll::Error { message } => {
+                    error!("Invalid result stored for job {}: {}", job_id, message);
+                    Ok(Some(
+                        error_response(
+                            Status::InternalServerError,
+                            "invalid_result",
+                            "The result of this job could not be read",
+                            Some(request_id),
+                        )
+                        .await,
+                    ))
+                }
             }
         }
-        None => Ok(Response::build().status(Status::NotFound).finalize()),
+        None => Ok(Some(job_not_found(request_id).await)),
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::{
-        module_handling::ModuleInfo, types::JobResult, util::create_redis_backend_key, web,
+//Get the result of a pathfinding job. `timeout` optionally shortens how long to wait for a result, in seconds.
+#[get("/job/<token>?<timeout>")]
+pub async fn result(
+    pool: State<'_, ResultConnectionPool>,
+    token: String,
+    timeout: Option<u32>,
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
+    //Because other clients may be polling at once, there's a possibility that acquiring this connection
+    //will take a while, but that's okay because it cannot take much longer than the poll timeout.
+    //This means that the theoretical maximum time this handler can take is just shy of 2*poll_timeout.
+    let _polling = pool.start_polling();
+    let mut conn = pool.get().await;
+
+    let timeout = poll_timeout(timeout, crate::CONFIG.jobs.poll_timeout);
+    match await_result(&mut conn, &token, timeout, request_id).await? {
+        Some(response) => Ok(response),
+        //Not ready yet
+        None => Ok(Response::build().status(Status::GatewayTimeout).finalize()),
+    }
+}
+
+//How many clients can poll for job results at once, and how many are doing so.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PollingCapacity {
+    pub max: usize,
+    //May exceed `max` when clients are waiting for their turn to poll.
+    pub in_use: usize,
+    pub available: usize,
+}
+
+impl PollingCapacity {
+    fn new(max: usize, in_use: usize) -> Self {
+        PollingCapacity {
+            max,
+            in_use,
+            available: max.saturating_sub(in_use),
+        }
+    }
+}
+
+//Get the polling capacity, so that clients can back off before the server gets congested.
+#[get("/job/capacity")]
+pub fn capacity(pool: State<'_, ResultConnectionPool>) -> Json<PollingCapacity> {
+    let max = crate::CONFIG.jobs.max_polling_clients as usize;
+    Json(PollingCapacity::new(max, pool.polling_clients()))
+}
+
+//Get the latest progress reported for a job which is still running.
+#[get("/job/<token>/progress")]
+pub async fn progress(
+    pool: State<'_, darkredis::ConnectionPool>,
+    token: String,
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
+    let mut conn = pool.get().await;
+
+    let job_id = match conn.get(util::get_job_mapping_key(&token)).await? {
+        Some(k) => String::from_utf8_lossy(&k).parse::<i32>().unwrap(),
+        None => return Ok(job_not_found(request_id).await),
     };
-    use rocket::{
-        http::{Cookie, Status},
-        local::Client,
+
+    match conn.get(util::get_job_progress_key(job_id)).await? {
+        Some(p) => {
+            //Validate the progress report before handing it to the user.
+            let progress: JobProgress = serde_json::from_slice(&p)?;
+            let json = Cursor::new(serde_json::to_string(&progress)?);
+            Ok(Response::build()
+                .status(Status::Ok)
+                .header(ContentType::JSON)
+                .sized_body(json)
+                .await
+                .finalize())
+        }
+        //The module hasn't reported any progress yet.
+        None => Ok(Response::build().status(Status::NoContent).finalize()),
+    }
+}
+
+//Check whether a job has finished without waiting for it. Unlike polling for the result, this doesn't block
+//and doesn't count against the polling clients, so it's suitable for frequent checks.
+#[get("/job/<token>/status")]
+pub async fn status(
+    pool: State<'_, darkredis::ConnectionPool>,
+    token: String,
+) -> Result<Response<'_>, BackendError> {
+    let mut conn = pool.get().await;
+
+    let (status, job_status) = match conn.get(util::get_job_mapping_key(&token)).await? {
+        Some(k) => {
+            let job_id = String::from_utf8_lossy(&k).parse::<i32>().unwrap();
+            //The result is the only element in the job list, so look at it without popping it.
+            let key = util::get_job_key(job_id);
+            let command = darkredis::Command::new("LINDEX").arg(&key).arg(b"-1");
+            let job_status = match conn.run_command(command).await?.optional_string() {
+                Some(r) => match serde_json::from_slice::<JobResult>(&r)?.outcome {
+                    JobOutcome::Success | JobOutcome::Cancelled => JobStatus::Ready,
+                    JobOutcome::Failure => JobStatus::Error,
+                },
+                None => JobStatus::Pending,
+            };
+            (Status::Ok, job_status)
+        }
+        None => (Status::NotFound, JobStatus::NotFound),
     };
-    use serial_test::serial;
 
-    #[tokio::test]
+    let json = Cursor::new(serde_json::json!({ "status": job_status }).to_string());
+    Ok(Response::build()
+        .status(status)
+        .header(ContentType::JSON)
+        .sized_body(json)
+        .await
+        .finalize())
+}
+
+//A request to run the same job with several modules, so that their results can be compared. Like a job
+//submission, but with a list of modules instead of a single one.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JobComparison {
+    pub start: Point,
+    pub stop: Point,
+    pub map_id: i32,
+    #[serde(default, skip_serializing_if = "MapType::is_elevation")]
+    pub map_type: MapType,
+    #[serde(deserialize_with = "deserialize_algorithms")]
+    pub algorithms: Vec<ModuleInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downsample: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tiles: Vec<MapTile>,
+    #[serde(default, skip_serializing_if = "CoordinateSystem::is_pixel")]
+    pub coords: CoordinateSystem,
+}
+
+//Deserialize the compared modules, using the latest version of those without one like job submissions do.
+fn deserialize_algorithms<'de, D>(deserializer: D) -> Result<Vec<ModuleInfo>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    fn latest() -> String {
+        LATEST_VERSION.to_string()
+    }
+
+    #[derive(Deserialize)]
+    struct Algorithm {
+        name: String,
+        #[serde(default = "latest")]
+        version: String,
+    }
+
+    let algorithms = Vec::<Algorithm>::deserialize(deserializer)?;
+    Ok(algorithms
+        .into_iter()
+        .map(|a| ModuleInfo {
+            name: a.name,
+            version: a.version,
+        })
+        .collect())
+}
+
+impl JobComparison {
+    //The job which `algorithm` is given in this comparison.
+    fn job(&self, algorithm: &ModuleInfo) -> JobSubmission {
+        JobSubmission {
+            start: self.start,
+            stop: self.stop,
+            map_id: self.map_id,
+            map_type: self.map_type,
+            algorithm: algorithm.clone(),
+            downsample: self.downsample,
+            tiles: self.tiles.clone(),
+            coords: self.coords,
+        }
+    }
+}
+
+//A module in a comparison, along with the token of its job or why the job was turned away.
+#[derive(Debug, Deserialize, Serialize)]
+struct ComparedJob {
+    algorithm: ModuleInfo,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+//Run the same job with several modules so that their results can be compared. Every job is submitted as if on its
+//own, and the modules which turn theirs away are reported along with the results. Responds with a token for getting
+//the results, unless every job was turned away, in which case it responds like the first rejected submission.
+#[post("/job/compare", format = "json", data = "<comparison>")]
+pub async fn submit_comparison(
+    pool: State<'_, darkredis::ConnectionPool>,
+    docker: State<'_, SharedDocker>,
+    session: Option<AdminSession>,
+    submitter: Submitter,
+    comparison: Json<JobComparison>,
+    request_id: &RequestId,
+) -> Result<Response<'static>, BackendError> {
+    //Comparing a module with itself would only give the same result twice. The versions are resolved first so that
+    //asking for the latest version of a module as well as that version by name counts as the same module.
+    let mut algorithms: Vec<ModuleInfo> = Vec::new();
+    {
+        let mut conn = pool.get().await;
+        for algorithm in &comparison.algorithms {
+            let mut job = comparison.job(algorithm);
+            resolve_algorithm(&mut job, &mut conn).await?;
+            if !algorithms.contains(&job.algorithm) {
+                algorithms.push(job.algorithm);
+            }
+        }
+    }
+    if algorithms.len() < 2 || algorithms.len() > MAX_COMPARED_ALGORITHMS {
+        let message = format!(
+            "Between 2 and {} different modules can be compared",
+            MAX_COMPARED_ALGORITHMS
+        );
+        return Ok(error_response(
+            Status::BadRequest,
+            "invalid_comparison",
+            &message,
+            Some(request_id),
+        )
+        .await);
+    }
+
+    let mut jobs = Vec::with_capacity(algorithms.len());
+    let mut first_rejection = None;
+    for algorithm in &algorithms {
+        let mut job = comparison.job(algorithm);
+        let submission = submit_job(&pool, &docker, session.as_ref(), &submitter, &mut job).await?;
+        let (token, error) = match submission {
+            Submission::Accepted(token) => (Some(token), None),
+            Submission::Rejected(rejection) => {
+                let error = rejection.message.clone();
+                first_rejection.get_or_insert(rejection);
+                (None, Some(error))
+            }
+        };
+        //The version is resolved by now, so the results show which version of the module was compared.
+        jobs.push(ComparedJob {
+            algorithm: job.algorithm,
+            token,
+            error,
+        });
+    }
+    match first_rejection {
+        Some(rejection) if jobs.iter().all(|j| j.token.is_none()) => {
+            return Ok(rejection.into_response(request_id).await)
+        }
+        _ => (),
+    }
+
+    let token = generate_token();
+    let mut conn = pool.get().await;
+    conn.set_and_expire_seconds(
+        util::get_job_comparison_key(&token),
+        serde_json::to_vec(&jobs)?,
+        crate::CONFIG.jobs.token_timeout,
+    )
+    .await?;
+
+    Ok(Response::build()
+        .status(Status::Accepted)
+        .header(ContentType::Plain)
+        .sized_body(Cursor::new(token))
+        .await
+        .finalize())
+}
+
+//How the job of a single module in a comparison is doing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ComparisonStatus {
+    Pending,
+    Success,
+    Failure,
+    Cancelled,
+    //The job was turned away when the comparison was submitted.
+    Rejected,
+    //The job token expired, so the result is gone.
+    Expired,
+}
+
+//The result of a single module in a comparison.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ComparisonResult {
+    pub algorithm: ModuleInfo,
+    pub status: ComparisonStatus,
+    //The path found, or the best path found before the job failed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<Vector>,
+    //The length of `points` in pixels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+    //Milliseconds from submitting the job until its result arrived, as long as the job is in the job history.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<i64>,
+    //Why the job was turned away, or why its result couldn't be read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+//The results of a comparison so far.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ComparisonReport {
+    //Whether every job has finished one way or another, such that the results won't change anymore.
+    pub complete: bool,
+    pub results: Vec<ComparisonResult>,
+}
+
+//The length of the path through `points`, in pixels.
+fn path_length(points: &[Vector]) -> f64 {
+    points
+        .windows(2)
+        .map(|w| {
+            let dx = f64::from(w[1].x) - f64::from(w[0].x);
+            let dy = f64::from(w[1].y) - f64::from(w[0].y);
+            dx.hypot(dy)
+        })
+        .sum()
+}
+
+//Check how the job of a module in a comparison is doing, without waiting for it.
+async fn compared_result(
+    conn: &mut darkredis::Connection,
+    job: ComparedJob,
+) -> Result<ComparisonResult, BackendError> {
+    let mut out = ComparisonResult {
+        algorithm: job.algorithm,
+        status: ComparisonStatus::Rejected,
+        points: Vec::new(),
+        distance: None,
+        duration: None,
+        error: job.error,
+    };
+    let token = match job.token {
+        Some(t) => t,
+        None => return Ok(out),
+    };
+    let job_id = match conn.get(util::get_job_mapping_key(&token)).await? {
+        Some(k) => String::from_utf8_lossy(&k).parse::<i32>().unwrap(),
+        None => {
+            out.status = ComparisonStatus::Expired;
+            return Ok(out);
+        }
+    };
+
+    //The result is the only element in the job list, so look at it without popping it.
+    let key = util::get_job_key(job_id);
+    let command = darkredis::Command::new("LINDEX").arg(&key).arg(b"-1");
+    let result = match conn.run_command(command).await?.optional_string() {
+        Some(r) => serde_json::from_slice::<JobResult>(&r),
+        None => {
+            out.status = ComparisonStatus::Pending;
+            return Ok(out);
+        }
+    };
+    match result {
+        Ok(result) => match result.outcome {
+            JobOutcome::Success => {
+                out.status = ComparisonStatus::Success;
+                out.points = result.points;
+            }
+            JobOutcome::Failure => {
+                out.status = ComparisonStatus::Failure;
+                //Like when getting the result on its own, include the best path found before the job failed.
+                if let Some(p) = conn.get(util::get_job_progress_key(job_id)).await? {
+                    out.points = serde_json::from_slice::<JobProgress>(&p)?.points;
+                }
+            }
+            JobOutcome::Cancelled => out.status = ComparisonStatus::Cancelled,
+        },
+        //A corrupt result shouldn't keep the other modules from being compared.
+        Err(e) => {
+            error!("Invalid result stored for job {}: {}", job_id, e);
+            out.status = ComparisonStatus::Failure;
+            out.error = Some("The result of this job could not be read".into());
+        }
+    }
+
+    if !out.points.is_empty() {
+        scale_path(conn, job_id, &mut out.points).await?;
+        out.distance = Some(path_length(&out.points));
+    }
+    out.duration = get_record(conn, job_id).await?.and_then(|r| r.duration);
+    Ok(out)
+}
+
+//Get the results of a comparison so far, without waiting for the jobs which are still running. Ranked apart from
+//the progress and status of single jobs, which it would otherwise collide with.
+#[get("/job/compare/<token>", rank = 1)]
+pub async fn comparison_results(
+    pool: State<'_, darkredis::ConnectionPool>,
+    token: String,
+) -> Result<Option<Json<ComparisonReport>>, BackendError> {
+    let mut conn = pool.get().await;
+    let jobs: Vec<ComparedJob> = match conn.get(util::get_job_comparison_key(&token)).await? {
+        Some(j) => serde_json::from_slice(&j)?,
+        None => return Ok(None),
+    };
+
+    let mut results = Vec::with_capacity(jobs.len());
+    for job in jobs {
+        results.push(compared_result(&mut conn, job).await?);
+    }
+    let complete = results
+        .iter()
+        .all(|r| r.status != ComparisonStatus::Pending);
+    Ok(Some(Json(ComparisonReport { complete, results })))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        docker::{FakeDocker, SharedDocker},
+        module_handling::ModuleInfo,
+        types::JobResult,
+        util::create_redis_backend_key,
+        web,
+    };
+    use rocket::{
+        http::{Cookie, Status},
+        local::Client,
+    };
+    use serial_test::serial;
+
+    #[tokio::test]
     #[serial]
     //High-level test for job submission through laps.py.
     async fn job_submission_integration() {
@@ -373,7 +1392,7 @@ mod test {
                 ],
             )
             .manage(redis_result_pool)
-            .manage(docker)
+            .manage(crate::docker::shared(docker))
             .manage(redis_pool.clone());
         let client = Client::new(rocket).unwrap();
         crate::test::clear_redis(&mut conn).await;
@@ -502,7 +1521,8 @@ mod test {
         let rocket = rocket::ignite()
             .mount("/", routes![submit, result])
             .manage(redis_result_pool)
-            .manage(redis_pool.clone());
+            .manage(redis_pool.clone())
+            .manage(crate::docker::shared(FakeDocker::default()));
         let client = Client::new(rocket).unwrap();
         crate::test::clear_redis(&mut conn).await;
         crate::test::insert_test_mapdata(&mut conn).await;
@@ -560,6 +1580,10 @@ mod test {
         let uri = format!("/job/{}", token);
         let response = client.get(&uri).dispatch().await;
         assert_eq!(response.status(), Status::GatewayTimeout);
+        //A timeout of 0 is raised to a second instead of waiting forever.
+        let uri = format!("/job/{}?timeout=0", token);
+        let response = client.get(&uri).dispatch().await;
+        assert_eq!(response.status(), Status::GatewayTimeout);
 
         //Complete the job. Because we cleared the job id counter earlier, the job id is guaranteed to be 1.
         let job_id = 1;
@@ -591,39 +1615,33 @@ mod test {
         );
     }
 
-    //Test that we avoid unnecesarry calculations of the same job.
+    //Test that the path of a preview job is scaled back up to the full resolution of the map.
     #[tokio::test]
     #[serial]
-    async fn job_cache() {
-        //setup
+    async fn downsampled_result() {
+        let redis_result_pool = create_result_redis_pool().await;
         let redis_pool = crate::create_redis_pool().await;
         let mut conn = redis_pool.get().await;
         let rocket = rocket::ignite()
-            .mount("/", routes![submit])
-            .manage(redis_pool.clone());
+            .mount("/", routes![submit, result])
+            .manage(redis_result_pool)
+            .manage(redis_pool.clone())
+            .manage(crate::docker::shared(FakeDocker::default()));
         let client = Client::new(rocket).unwrap();
         crate::test::clear_redis(&mut conn).await;
         crate::test::insert_test_mapdata(&mut conn).await;
-
-        //Register a fake module
-        let algorithm_key = create_redis_backend_key("registered_modules");
         let algorithm = ModuleInfo {
             name: "dummy".to_string(),
             version: "0.0.0".to_string(),
         };
-        let json = serde_json::to_vec(&algorithm).unwrap();
-        conn.sadd(algorithm_key, json).await.unwrap();
+        crate::test::register_module(&mut conn, &algorithm).await;
 
-        //Submit a job
         let job = serde_json::json!({
-          "map_id": 1,
-          "start": {
-              "x": 1, "y": 2
-          },
-          "stop": {
-              "x": 2, "y": 1
-          },
-          "algorithm": algorithm
+            "map_id": 1,
+            "start": { "x": 1, "y": 2 },
+            "stop": { "x": 2, "y": 1 },
+            "algorithm": algorithm,
+            "downsample": 4
         });
         let mut response = client
             .post("/job")
@@ -632,111 +1650,1349 @@ mod test {
             .dispatch()
             .await;
         assert_eq!(response.status(), Status::Accepted);
-        let first_token = response.body_bytes().await.unwrap();
+        let token = response.body_string().await.unwrap();
 
-        //Submit the job again and verify that it maps to the same token
-        let mut response = client
-            .post("/job")
+        //The factor expires along with the token of the job, in case the job never finishes.
+        let downsample_key = util::get_job_downsample_key(1);
+        let command = darkredis::Command::new("TTL").arg(&downsample_key);
+        let ttl = conn.run_command(command).await.unwrap().unwrap_integer();
+        assert!(ttl > 0 && ttl <= crate::CONFIG.jobs.token_timeout as isize);
+
+        //The module works on the downsampled map, and the client gets the path on the full map.
+        let points = vec![Vector { x: 1, y: 2 }, Vector { x: 2, y: 1 }];
+        crate::test::complete_next_job(&mut conn, &algorithm, JobOutcome::Success, points).await;
+        let mut response = client.get(format!("/job/{}", token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+        assert_eq!(
+            body["points"],
+            serde_json::json!([{ "x": 4, "y": 8 }, { "x": 8, "y": 4 }])
+        );
+    }
+
+    //Test submitting a job and waiting for its result in a single request.
+    #[tokio::test]
+    #[serial]
+    async fn synchronous_submission() {
+        let redis_result_pool = create_result_redis_pool().await;
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![submit_and_wait, result])
+            .manage(redis_result_pool)
+            .manage(redis_pool.clone())
+            .manage(crate::docker::shared(FakeDocker::default()));
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+        crate::test::insert_test_mapdata(&mut conn).await;
+
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        crate::test::register_module(&mut conn, &algorithm).await;
+        let job = |x: u32| {
+            serde_json::to_vec(&serde_json::json!({
+                "map_id": 1,
+                "start": { "x": x, "y": 2 },
+                "stop": { "x": 2, "y": 1 },
+                "algorithm": algorithm
+            }))
+            .unwrap()
+        };
+
+        //Invalid jobs are rejected like when submitting them normally.
+        let mut invalid: serde_json::Value = serde_json::from_slice(&job(1)).unwrap();
+        invalid["start"] = invalid["stop"].clone();
+        let response = client
+            .post("/job/sync")
             .header(ContentType::JSON)
-            .body(&serde_json::to_vec(&job).unwrap())
+            .body(&serde_json::to_vec(&invalid).unwrap())
             .dispatch()
             .await;
-        assert_eq!(response.status(), Status::Accepted);
-        assert_eq!(response.body_bytes().await.unwrap(), first_token);
+        assert_eq!(response.status(), Status::BadRequest);
 
-        //Submit a new job and verify that it actually sends it.
-        let job = serde_json::json!({
-            "map_id": 1,
-            "start": {
-                "x": 2, "y": 1
-            },
-            "stop": {
-                "x": 1, "y": 2
-            },
-            "algorithm": algorithm
+        //Pretend to be a module which completes the job right away.
+        let mut worker = redis_pool.spawn("fake-worker").await.unwrap();
+        let module = algorithm.clone();
+        let worker = tokio::spawn(async move {
+            let points = vec![Vector { x: 1, y: 2 }, Vector { x: 2, y: 1 }];
+            crate::test::complete_next_job(&mut worker, &module, JobOutcome::Success, points).await;
         });
         let mut response = client
-            .post("/job")
+            .post("/job/sync")
             .header(ContentType::JSON)
-            .body(&serde_json::to_vec(&job).unwrap())
+            .body(&job(1))
             .dispatch()
             .await;
-        assert_eq!(response.status(), Status::Accepted);
-        assert_ne!(response.body_bytes().await.unwrap(), first_token);
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "outcome": "success",
+                "points": [{ "x": 1, "y": 2 }, { "x": 2, "y": 1 }]
+            })
+        );
+        worker.await.unwrap();
+
+        //Without a module to complete it the job times out, but its result can still be polled for.
+        let response = client
+            .post("/job/sync?timeout=1")
+            .header(ContentType::JSON)
+            .body(&job(2))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::RequestTimeout);
+        let location = response.headers().get_one("Location").unwrap().to_string();
+        let result = JobResult {
+            job_id: 2,
+            outcome: JobOutcome::Cancelled,
+            points: Vec::new(),
+        };
+        conn.lpush(util::get_job_key(2), serde_json::to_vec(&result).unwrap())
+            .await
+            .unwrap();
+        let response = client.get(location).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
     }
 
+    //Test comparing the results of several modules on the same job, as they complete.
     #[tokio::test]
     #[serial]
-    async fn job_validation() {
-        //Setup
+    async fn job_comparison() {
         let redis_pool = crate::create_redis_pool().await;
-        let mut redis = redis_pool.get().await;
-        crate::test::clear_redis(&mut redis).await;
-
-        //Insert test mapdata
-        let (width, height) = crate::test::insert_test_mapdata(&mut redis).await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![submit_comparison, comparison_results])
+            .manage(redis_pool.clone())
+            .manage(crate::docker::shared(FakeDocker::default()));
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+        crate::test::insert_test_mapdata(&mut conn).await;
 
-        //Insert a module
-        let algorithm_key = create_redis_backend_key("registered_modules");
-        let algorithm = ModuleInfo {
-            name: "dummy".to_string(),
+        let module = |name: &str| ModuleInfo {
+            name: name.to_string(),
             version: "0.0.0".to_string(),
         };
-        let json = serde_json::to_vec(&algorithm).unwrap();
-        redis.sadd(algorithm_key, json).await.unwrap();
+        let (fast, slow, missing) = (module("fast"), module("slow"), module("missing"));
+        for algorithm in &[&fast, &slow] {
+            crate::test::register_module(&mut conn, algorithm).await;
+        }
+        let comparison = |start: u32, algorithms: &[&ModuleInfo]| {
+            serde_json::to_vec(&serde_json::json!({
+                "map_id": 1,
+                "start": { "x": start, "y": 1 },
+                "stop": { "x": 4, "y": 1 },
+                "algorithms": algorithms
+            }))
+            .unwrap()
+        };
 
-        let mut job_submission = JobSubmission {
-            start: Vector { x: 0, y: 100 },
-            stop: Vector { x: 0, y: 100 },
-            map_id: 1,
-            algorithm,
+        //A module can't be compared with itself.
+        let response = client
+            .post("/job/compare")
+            .header(ContentType::JSON)
+            .body(&comparison(1, &[&fast, &fast]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        //Not even when asking for its latest version along with that same version.
+        let latest = ModuleInfo {
+            name: fast.name.clone(),
+            version: LATEST_VERSION.to_string(),
         };
+        let response = client
+            .post("/job/compare")
+            .header(ContentType::JSON)
+            .body(&comparison(1, &[&latest, &fast]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
 
-        macro_rules! check_valid {
-            () => {
-                assert!(job_submission.validity_check(&mut redis).await.unwrap().0);
-            };
-        }
-        macro_rules! check_invalid {
-            () => {
-                assert!(!job_submission.validity_check(&mut redis).await.unwrap().0);
-            };
+        //If every job is turned away there's nothing to compare.
+        let response = client
+            .post("/job/compare")
+            .header(ContentType::JSON)
+            .body(&comparison(4, &[&fast, &slow]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        //Modules which turn their job away are reported, but don't keep the others from being compared.
+        let mut response = client
+            .post("/job/compare")
+            .header(ContentType::JSON)
+            .body(&comparison(1, &[&fast, &slow, &missing]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Accepted);
+        let uri = format!("/job/compare/{}", response.body_string().await.unwrap());
+
+        async fn report(client: &Client, uri: &str) -> ComparisonReport {
+            let mut response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap()
         }
+        let statuses = |report: &ComparisonReport| -> Vec<ComparisonStatus> {
+            report.results.iter().map(|r| r.status).collect()
+        };
+        let before = report(&client, &uri).await;
+        assert!(!before.complete);
+        assert_eq!(
+            statuses(&before),
+            vec![
+                ComparisonStatus::Pending,
+                ComparisonStatus::Pending,
+                ComparisonStatus::Rejected
+            ]
+        );
+        assert_eq!(before.results[2].algorithm, missing);
+        assert_eq!(
+            before.results[2].error.as_deref(),
+            Some("Module does not exist")
+        );
 
-        //Equal start and stop points
-        check_invalid!();
-        job_submission.stop.y = 50;
+        //Complete the job of the fast module, recording how long it took like the module handler does.
+        let points = vec![Vector { x: 1, y: 4 }, Vector { x: 4, y: 0 }];
+        let result =
+            crate::test::complete_next_job(&mut conn, &fast, JobOutcome::Success, points).await;
+        crate::history::record_result(&mut conn, result.job_id, JobOutcome::Success)
+            .await
+            .unwrap();
 
-        //Map Id is valid
-        check_valid!();
+        let partial = report(&client, &uri).await;
+        assert!(!partial.complete);
+        assert_eq!(
+            statuses(&partial),
+            vec![
+                ComparisonStatus::Success,
+                ComparisonStatus::Pending,
+                ComparisonStatus::Rejected
+            ]
+        );
+        assert_eq!(partial.results[0].points, result.points);
+        assert_eq!(partial.results[0].distance, Some(5.0));
+        assert!(partial.results[0].duration.is_some());
 
-        //Invalid module
-        job_submission.algorithm.version = "0.1.0".to_string();
-        check_invalid!();
+        //The comparison is complete once the slow module fails as well.
+        crate::test::complete_next_job(&mut conn, &slow, JobOutcome::Failure, Vec::new()).await;
 
-        //Invalid Map ID
-        job_submission.map_id = 2;
-        job_submission.algorithm.version = "0.0.0".to_string();
-        check_invalid!();
+        let after = report(&client, &uri).await;
+        assert!(after.complete);
+        assert_eq!(
+            statuses(&after),
+            vec![
+                ComparisonStatus::Success,
+                ComparisonStatus::Failure,
+                ComparisonStatus::Rejected
+            ]
+        );
+        assert_eq!(after.results[1].distance, None);
+
+        //Unknown comparisons don't exist.
+        let response = client.get("/job/compare/nothing").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn poll_timeouts() {
+        let max = crate::CONFIG.jobs.poll_timeout;
+        assert_eq!(poll_timeout(None, max), max);
+        assert_eq!(poll_timeout(Some(0), max), 1);
+        assert_eq!(poll_timeout(Some(1), max), 1);
+        assert_eq!(poll_timeout(Some(max + 100), max), max);
+        //Never wait forever, even if the configured timeout is 0.
+        assert_eq!(poll_timeout(None, 0), 1);
+        assert_eq!(poll_timeout(Some(10), 0), 1);
+    }
+
+    //Test that a result arriving while a client is polling is returned right away, not when the poll times out.
+    #[tokio::test]
+    #[serial]
+    async fn prompt_results() {
+        let pool = crate::create_redis_pool().await;
+        let mut conn = pool.get().await;
+        crate::test::clear_redis(&mut conn).await;
+        let job_id = 42;
+
+        let mut writer = pool.spawn("result-writer").await.unwrap();
+        let push = tokio::spawn(async move {
+            tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+            let result = JobResult {
+                job_id,
+                outcome: JobOutcome::Success,
+                points: vec![Vector { x: 1, y: 2 }],
+            };
+            writer
+                .lpush(
+                    util::get_job_key(job_id),
+                    serde_json::to_vec(&result).unwrap(),
+                )
+                .await
+                .unwrap();
+        });
+
+        let start = std::time::Instant::now();
+        match try_poll_job_result(&mut conn, job_id, 10).await.unwrap() {
+            JobPoll::Ready { result } => assert_eq!(result.points, vec![Vector { x: 1, y: 2 }]),
+            JobPoll::Pending => panic!("Expected the result to be ready"),
+            JobPoll::Error { message } => panic!("Unexpected error: {}", message),
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+        push.await.unwrap();
+
+        //The result stays available to poll again.
+        assert!(matches!(
+            try_poll_job_result(&mut conn, job_id, 1).await.unwrap(),
+            JobPoll::Ready { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn polling_capacity() {
+        let rocket = rocket::ignite()
+            .mount("/", routes![capacity])
+            .manage(create_result_redis_pool().await);
+        let client = Client::new(rocket).unwrap();
+        let max = crate::CONFIG.jobs.max_polling_clients as usize;
+
+        let get_capacity = || async {
+            let mut response = client.get("/job/capacity").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_slice::<PollingCapacity>(&response.body_bytes().await.unwrap())
+                .unwrap()
+        };
+        assert_eq!(get_capacity().await, PollingCapacity::new(max, 0));
+
+        //Clients are counted while polling, even past the maximum.
+        let pool = client.rocket().state::<ResultConnectionPool>().unwrap();
+        let guards: Vec<_> = (0..max + 1).map(|_| pool.start_polling()).collect();
+        assert_eq!(
+            get_capacity().await,
+            PollingCapacity {
+                max,
+                in_use: max + 1,
+                available: 0
+            }
+        );
+        drop(guards);
+        assert_eq!(get_capacity().await.available, max);
+    }
+
+    //Test that failed jobs return the last path reported by the module, if any.
+    #[tokio::test]
+    #[serial]
+    async fn partial_result() {
+        let redis_result_pool = create_result_redis_pool().await;
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![result])
+            .manage(redis_result_pool);
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        let job_id = 1;
+        conn.set(util::get_job_mapping_key("token"), job_id.to_string())
+            .await
+            .unwrap();
+        let failure = serde_json::to_vec(&JobResult {
+            job_id,
+            outcome: JobOutcome::Failure,
+            points: Vec::new(),
+        })
+        .unwrap();
+
+        //Without any progress, the job just fails.
+        conn.lpush(util::get_job_key(job_id), &failure)
+            .await
+            .unwrap();
+        let response = client.get("/job/token").dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+
+        //With a reported path, return it as a partial result.
+        let progress = JobProgress {
+            fraction: 0.5,
+            path_length: Some(1.0),
+            points: vec![Vector { x: 0, y: 0 }, Vector { x: 1, y: 0 }],
+        };
+        conn.set(
+            util::get_job_progress_key(job_id),
+            serde_json::to_vec(&progress).unwrap(),
+        )
+        .await
+        .unwrap();
+        let mut response = client.get("/job/token").dispatch().await;
+        assert_eq!(response.status(), Status::PartialContent);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "outcome": "failure",
+                "partial": true,
+                "points": [{ "x": 0, "y": 0 }, { "x": 1, "y": 0 }]
+            })
+        );
+    }
+
+    //Test that a corrupt result fails the poll instead of taking the handler down with it.
+    #[tokio::test]
+    #[serial]
+    async fn malformed_result() {
+        let redis_result_pool = create_result_redis_pool().await;
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![result])
+            .manage(redis_result_pool);
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        let job_id = 1;
+        conn.set(util::get_job_mapping_key("token"), job_id.to_string())
+            .await
+            .unwrap();
+        conn.lpush(util::get_job_key(job_id), b"not a result")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            try_poll_job_result(&mut conn, job_id, 1).await.unwrap(),
+            JobPoll::Error { .. }
+        ));
+        let mut response = client.get("/job/token").dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+        assert_eq!(body["error"]["code"], "invalid_result");
+    }
+
+    //Test that progress reports from modules can be retrieved.
+    #[tokio::test]
+    #[serial]
+    async fn job_progress() {
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![progress])
+            .manage(redis_pool.clone());
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        //Pretend a job has been submitted
+        let job_id = 1;
+        conn.set(util::get_job_mapping_key("token"), job_id.to_string())
+            .await
+            .unwrap();
+
+        //Unknown tokens don't have any progress
+        let response = client.get("/job/fake/progress").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        //Nothing has been reported yet
+        let response = client.get("/job/token/progress").dispatch().await;
+        assert_eq!(response.status(), Status::NoContent);
+
+        //Report some progress
+        let progress = JobProgress {
+            fraction: 0.5,
+            path_length: Some(42.0),
+            points: Vec::new(),
+        };
+        conn.set(
+            util::get_job_progress_key(job_id),
+            serde_json::to_vec(&progress).unwrap(),
+        )
+        .await
+        .unwrap();
+        let mut response = client.get("/job/token/progress").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<JobProgress>(&body).unwrap(),
+            progress
+        );
+    }
+
+    //Test that job statuses can be checked without waiting or taking up a polling slot.
+    #[tokio::test]
+    #[serial]
+    async fn job_status() {
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![status])
+            .manage(redis_pool.clone())
+            .manage(create_result_redis_pool().await);
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        let result_pool = client.rocket().state::<ResultConnectionPool>().unwrap();
+        let get_status = |token: &'static str| async move {
+            let mut response = client
+                .get(format!("/job/{}/status", token))
+                .dispatch()
+                .await;
+            let status = response.status();
+            let body: serde_json::Value =
+                serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+            assert_eq!(result_pool.polling_clients(), 0);
+            (status, body["status"].as_str().unwrap().to_string())
+        };
+
+        assert_eq!(
+            get_status("token").await,
+            (Status::NotFound, "not_found".to_string())
+        );
+
+        let job_id = 1;
+        conn.set(util::get_job_mapping_key("token"), job_id.to_string())
+            .await
+            .unwrap();
+        //Even with every polling connection taken, the status is returned right away.
+        let mut busy = Vec::new();
+        for _ in 0..crate::CONFIG.jobs.max_polling_clients {
+            busy.push(result_pool.get().await);
+        }
+        let start = std::time::Instant::now();
+        assert_eq!(
+            get_status("token").await,
+            (Status::Ok, "pending".to_string())
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        drop(busy);
+
+        for (outcome, expected) in &[
+            (JobOutcome::Failure, "error"),
+            (JobOutcome::Success, "ready"),
+            (JobOutcome::Cancelled, "ready"),
+        ] {
+            let result = JobResult {
+                job_id,
+                outcome: *outcome,
+                points: Vec::new(),
+            };
+            conn.del(util::get_job_key(job_id)).await.unwrap();
+            conn.lpush(
+                util::get_job_key(job_id),
+                serde_json::to_vec(&result).unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                get_status("token").await,
+                (Status::Ok, expected.to_string())
+            );
+        }
+        //Checking the status leaves the result in place.
+        assert_eq!(conn.llen(util::get_job_key(job_id)).await.unwrap(), Some(1));
+    }
+
+    //Test that we avoid unnecesarry calculations of the same job.
+    #[tokio::test]
+    #[serial]
+    async fn job_cache() {
+        //setup
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![submit])
+            .manage(redis_pool.clone())
+            .manage(crate::docker::shared(FakeDocker::default()));
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+        crate::test::insert_test_mapdata(&mut conn).await;
+
+        //Register a fake module
+        let algorithm_key = create_redis_backend_key("registered_modules");
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        let json = serde_json::to_vec(&algorithm).unwrap();
+        conn.sadd(algorithm_key, json).await.unwrap();
+
+        //Submit a job
+        let job = serde_json::json!({
+          "map_id": 1,
+          "start": {
+              "x": 1, "y": 2
+          },
+          "stop": {
+              "x": 2, "y": 1
+          },
+          "algorithm": algorithm
+        });
+        let mut response = client
+            .post("/job")
+            .header(ContentType::JSON)
+            .body(&serde_json::to_vec(&job).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Accepted);
+        let first_token = response.body_bytes().await.unwrap();
+
+        //Submit the job again and verify that it maps to the same token
+        let mut response = client
+            .post("/job")
+            .header(ContentType::JSON)
+            .body(&serde_json::to_vec(&job).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Accepted);
+        assert_eq!(response.body_bytes().await.unwrap(), first_token);
+
+        //Submit a new job and verify that it actually sends it.
+        let job = serde_json::json!({
+            "map_id": 1,
+            "start": {
+                "x": 2, "y": 1
+            },
+            "stop": {
+                "x": 1, "y": 2
+            },
+            "algorithm": algorithm
+        });
+        let mut response = client
+            .post("/job")
+            .header(ContentType::JSON)
+            .body(&serde_json::to_vec(&job).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Accepted);
+        assert_ne!(response.body_bytes().await.unwrap(), first_token);
+    }
+
+    //Test that jobs which only differ in their map or end point get different cache keys.
+    #[test]
+    fn cache_keys() {
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        let first = JobSubmission {
+            start: Vector { x: 1, y: 2 }.into(),
+            stop: Vector { x: 3, y: 4 }.into(),
+            map_id: 1,
+            map_type: MapType::Elevation,
+            algorithm: algorithm.clone(),
+            downsample: None,
+            tiles: Vec::new(),
+            coords: CoordinateSystem::Pixel,
+        };
+        let second = JobSubmission {
+            start: Vector { x: 1, y: 2 }.into(),
+            stop: Vector { x: 3, y: 4 }.into(),
+            map_id: 11,
+            map_type: MapType::Elevation,
+            algorithm: algorithm.clone(),
+            downsample: None,
+            tiles: Vec::new(),
+            coords: CoordinateSystem::Pixel,
+        };
+        let third = JobSubmission {
+            start: Vector { x: 1, y: 2 }.into(),
+            stop: Vector { x: 4, y: 3 }.into(),
+            map_id: 1,
+            map_type: MapType::Elevation,
+            algorithm: algorithm.clone(),
+            downsample: None,
+            tiles: Vec::new(),
+            coords: CoordinateSystem::Pixel,
+        };
+        let fourth = JobSubmission {
+            start: Vector { x: 1, y: 2 }.into(),
+            stop: Vector { x: 3, y: 4 }.into(),
+            map_id: 1,
+            map_type: MapType::Elevation,
+            algorithm,
+            downsample: Some(4),
+            tiles: Vec::new(),
+            coords: CoordinateSystem::Pixel,
+        };
+        assert_ne!(
+            util::get_job_cache_key(&first),
+            util::get_job_cache_key(&second)
+        );
+        assert_ne!(
+            util::get_job_cache_key(&first),
+            util::get_job_cache_key(&third)
+        );
+        assert_ne!(
+            util::get_job_cache_key(&first),
+            util::get_job_cache_key(&fourth)
+        );
+        assert!(util::get_job_cache_key(&first).contains(".map-1."));
+        assert!(util::get_job_cache_key(&second).contains(".map-11."));
+        //The same id of another type of map is another map.
+        let rgb = JobSubmission {
+            map_type: MapType::Rgb,
+            ..first.clone()
+        };
+        assert!(util::get_job_cache_key(&rgb).contains(".map-rgb-1."));
+        //Jobs are cached across restarts, so the format has to stay the same.
+        assert_eq!(
+            util::get_job_cache_key(&fourth),
+            create_redis_backend_key("cache.dummy:0.0.0.map-1.(1,2).(3,4).downsample-4")
+        );
+    }
+
+    //Test that a module with a cache TTL of 0 never has its jobs cached.
+    #[tokio::test]
+    #[serial]
+    async fn zero_ttl_cache() {
+        //setup
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![submit])
+            .manage(redis_pool.clone())
+            .manage(crate::docker::shared(FakeDocker::default()));
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+        crate::test::insert_test_mapdata(&mut conn).await;
+
+        //Register a fake module which has caching disabled
+        let algorithm_key = create_redis_backend_key("registered_modules");
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        let json = serde_json::to_vec(&algorithm).unwrap();
+        conn.sadd(algorithm_key, json).await.unwrap();
+        conn.set(util::get_module_cache_ttl_key(&algorithm), "0")
+            .await
+            .unwrap();
+
+        //Submit the same job twice and verify that it was sent both times.
+        let job = serde_json::json!({
+          "map_id": 1,
+          "start": {
+              "x": 1, "y": 2
+          },
+          "stop": {
+              "x": 2, "y": 1
+          },
+          "algorithm": algorithm
+        });
+        let mut response = client
+            .post("/job")
+            .header(ContentType::JSON)
+            .body(&serde_json::to_vec(&job).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Accepted);
+        let first_token = response.body_bytes().await.unwrap();
+
+        let mut response = client
+            .post("/job")
+            .header(ContentType::JSON)
+            .body(&serde_json::to_vec(&job).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Accepted);
+        assert_ne!(response.body_bytes().await.unwrap(), first_token);
+
+        //Both jobs should be in the queue, and nothing should be in the cache.
+        let work_key = util::get_module_work_key(&algorithm);
+        assert_eq!(conn.llen(&work_key).await.unwrap(), Some(2));
+        let submission: JobSubmission = serde_json::from_value(job).unwrap();
+        assert!(!conn
+            .exists(util::get_job_cache_key(&submission))
+            .await
+            .unwrap());
+    }
+
+    //Test that finding a job in the cache keeps the cache TTL of its module.
+    #[tokio::test]
+    #[serial]
+    async fn cache_hit_keeps_module_ttl() {
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![submit])
+            .manage(redis_pool.clone())
+            .manage(crate::docker::shared(FakeDocker::default()));
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+        crate::test::insert_test_mapdata(&mut conn).await;
+
+        //Cache the jobs of the module for much longer than results are kept.
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        crate::test::register_module(&mut conn, &algorithm).await;
+        conn.set(util::get_module_cache_ttl_key(&algorithm), "100")
+            .await
+            .unwrap();
+        assert!(crate::CONFIG.jobs.result_timeout < 90);
+
+        let job = serde_json::json!({
+          "map_id": 1,
+          "start": { "x": 1, "y": 2 },
+          "stop": { "x": 2, "y": 1 },
+          "algorithm": algorithm
+        });
+        let mut tokens = Vec::new();
+        for _ in 0..2 {
+            let mut response = client
+                .post("/job")
+                .header(ContentType::JSON)
+                .body(&serde_json::to_vec(&job).unwrap())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Accepted);
+            tokens.push(response.body_bytes().await.unwrap());
+        }
+        assert_eq!(tokens[0], tokens[1]);
+
+        let submission: JobSubmission = serde_json::from_value(job).unwrap();
+        let cache_key = util::get_job_cache_key(&submission);
+        let command = darkredis::Command::new("TTL").arg(&cache_key);
+        let ttl = conn.run_command(command).await.unwrap().unwrap_integer();
+        assert!(ttl > 90 && ttl <= 100, "Unexpected TTL {}", ttl);
+    }
+
+    //Test that clients which submit too many jobs are told to slow down.
+    #[tokio::test]
+    #[serial]
+    async fn submission_rate_limit() {
+        //setup
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![submit])
+            .manage(redis_pool.clone())
+            .manage(crate::docker::shared(FakeDocker::default()));
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+        crate::test::insert_test_mapdata(&mut conn).await;
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        crate::test::register_module(&mut conn, &algorithm).await;
+
+        //Submissions are counted per minute, so don't start right before the count is reset.
+        let second = chrono::Utc::now().timestamp() % 60;
+        if second >= 55 {
+            tokio::time::delay_for(std::time::Duration::from_secs(61 - second as u64)).await;
+        }
+
+        //Submit a job from `remote`, which claims to be submitting for `real_ip` if set.
+        let submit_via = |x: i32, remote: &str, real_ip: Option<&str>| {
+            let job = serde_json::json!({
+                "map_id": 1,
+                "start": { "x": x, "y": 2 },
+                "stop": { "x": 2, "y": 1 },
+                "algorithm": algorithm
+            });
+            let mut request = client
+                .post("/job")
+                .remote(format!("{}:8000", remote).parse().unwrap())
+                .header(ContentType::JSON)
+                .body(serde_json::to_vec(&job).unwrap());
+            if let Some(ip) = real_ip {
+                request.add_header(rocket::http::Header::new("X-Real-IP", ip.to_string()));
+            }
+            request.dispatch()
+        };
+        let submit_from = |x: i32, ip: &str| submit_via(x, ip, None);
+        let limit = crate::CONFIG.jobs.max_submissions_per_minute as i32;
+        for x in 1..=limit {
+            let response = submit_from(x, "10.0.0.1").await;
+            assert_eq!(response.status(), Status::Accepted);
+        }
+
+        //One job too many.
+        let mut response = submit_from(limit + 1, "10.0.0.1").await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after: u32 = response
+            .headers()
+            .get_one("Retry-After")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+        let body: serde_json::Value =
+            serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+        assert_eq!(body["error"]["code"], "rate_limited");
+        assert!(body["error"]["request_id"].is_string());
+        assert_eq!(
+            conn.llen(util::get_module_work_key(&algorithm))
+                .await
+                .unwrap(),
+            Some(limit as isize)
+        );
+
+        //Cached jobs can still be resubmitted, and other clients have their own limit.
+        let response = submit_from(1, "10.0.0.1").await;
+        assert_eq!(response.status(), Status::Accepted);
+        let response = submit_from(limit + 1, "10.0.0.2").await;
+        assert_eq!(response.status(), Status::Accepted);
+
+        //Only trusted reverse proxies can say who the client is, so making up an address doesn't get around the limit.
+        let response = submit_via(limit + 2, "10.0.0.1", Some("10.0.0.3")).await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = submit_via(limit + 2, "127.0.0.1", Some("10.0.0.1")).await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = submit_via(limit + 2, "127.0.0.1", Some("10.0.0.3")).await;
+        assert_eq!(response.status(), Status::Accepted);
+    }
+
+    //Test that modules stopped for being idle are started when a job is submitted to them.
+    #[tokio::test]
+    #[serial]
+    async fn auto_start() {
+        let redis_pool = crate::create_redis_pool().await;
+        let mut conn = redis_pool.get().await;
+        let docker = std::sync::Arc::new(FakeDocker::with_images(&[
+            "laps-test:0.1.0",
+            "laps-foo:0.1.0",
+        ]));
+        let rocket = rocket::ignite()
+            .mount("/", routes![submit])
+            .manage(redis_pool.clone())
+            .manage(docker.clone() as SharedDocker);
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+        crate::test::insert_test_mapdata(&mut conn).await;
+
+        let module = ModuleInfo {
+            name: "laps-test".to_string(),
+            version: "0.1.0".to_string(),
+        };
+        let silent = ModuleInfo {
+            name: "laps-foo".to_string(),
+            version: "0.1.0".to_string(),
+        };
+        for m in &[&module, &silent] {
+            conn.set(util::get_module_workers_key(m), "1")
+                .await
+                .unwrap();
+        }
+
+        //Register the module like its worker would once its container is running.
+        let mut worker_conn = redis_pool.get().await;
+        let worker_docker = docker.clone();
+        let registration = serde_json::to_vec(&module).unwrap();
+        let worker_module = module.clone();
+        let worker = tokio::spawn(async move {
+            while !worker_docker
+                .containers()
+                .iter()
+                .any(|c| c.names[0] == "laps-test-0.1.0-0" && c.state == "running")
+            {
+                tokio::time::delay_for(std::time::Duration::from_millis(50)).await;
+            }
+            worker_conn
+                .incr(util::get_registered_module_workers_key(&worker_module))
+                .await
+                .unwrap();
+            worker_conn
+                .sadd(create_redis_backend_key("registered_modules"), registration)
+                .await
+                .unwrap();
+        });
+
+        let submit_at = |algorithm: &ModuleInfo, stop: (u32, u32)| {
+            let job = serde_json::json!({
+                "map_id": 1,
+                "start": { "x": 1, "y": 2 },
+                "stop": { "x": stop.0, "y": stop.1 },
+                "algorithm": algorithm
+            });
+            client
+                .post("/job")
+                .header(ContentType::JSON)
+                .body(serde_json::to_vec(&job).unwrap())
+                .dispatch()
+        };
+        let submit_to = |algorithm: &ModuleInfo| submit_at(algorithm, (2, 1));
+
+        //Modules stopped by an admin are left alone.
+        let response = submit_to(&module).await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert!(docker.containers().is_empty());
+
+        //Only modules stopped for being idle are started, and only for valid jobs.
+        for m in &[&module, &silent] {
+            conn.set(util::get_module_idle_stopped_key(m), "1")
+                .await
+                .unwrap();
+        }
+        let response = submit_at(&module, (1, 2)).await;
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(docker.containers().is_empty());
+
+        let response = submit_to(&module).await;
+        assert_eq!(response.status(), Status::Accepted);
+        worker.await.unwrap();
+        assert_eq!(
+            conn.llen(util::get_module_work_key(&module)).await.unwrap(),
+            Some(1)
+        );
+
+        //Once the module is up, it isn't started again.
+        assert!(!conn
+            .exists(util::get_module_idle_stopped_key(&module))
+            .await
+            .unwrap());
+        let response = submit_to(&module).await;
+        assert_eq!(response.status(), Status::Accepted);
+        assert_eq!(docker.containers().len(), 1);
+
+        //Modules which never register are given up on.
+        let mut response = submit_to(&silent).await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let body: serde_json::Value =
+            serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+        assert_eq!(body["error"]["code"], "no_workers");
+        assert!(conn
+            .llen(util::get_module_work_key(&silent))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    //The same checks as `job_validation`, but through the HTTP endpoint.
+    #[tokio::test]
+    #[serial]
+    async fn validation_endpoint() {
+        let redis_pool = crate::create_redis_pool().await;
+        let mut redis = redis_pool.get().await;
+        crate::test::clear_redis(&mut redis).await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![validate])
+            .manage(redis_pool.clone());
+        let client = Client::new(rocket).unwrap();
+        let (width, height) = crate::test::insert_test_mapdata(&mut redis).await;
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        crate::test::register_module(&mut redis, &algorithm).await;
+
+        let check = |job: serde_json::Value| {
+            let client = &client;
+            async move {
+                let mut response = client
+                    .post("/job/validate")
+                    .header(ContentType::JSON)
+                    .body(job.to_string())
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                serde_json::from_slice::<JobValidation>(&response.body_bytes().await.unwrap())
+                    .unwrap()
+            }
+        };
+        let job = |start: (u32, u32), stop: (u32, u32), map_id: i32, version: &str| {
+            serde_json::json!({
+                "start": { "x": start.0, "y": start.1 },
+                "stop": { "x": stop.0, "y": stop.1 },
+                "map_id": map_id,
+                "algorithm": { "name": "dummy", "version": version }
+            })
+        };
+        let invalid = |reason: &str| JobValidation {
+            valid: false,
+            reason: Some(reason.to_string()),
+        };
+
+        assert_eq!(
+            check(job((0, 0), (0, 50), 1, "0.0.0")).await,
+            JobValidation {
+                valid: true,
+                reason: None
+            }
+        );
+        //The reason is left out of valid jobs.
+        let mut response = client
+            .post("/job/validate")
+            .header(ContentType::JSON)
+            .body(job((0, 0), (0, 50), 1, "0.0.0").to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.body_string().await.unwrap(), r#"{"valid":true}"#);
+
+        assert_eq!(
+            check(job((0, 50), (0, 50), 1, "0.0.0")).await,
+            invalid("Start and end points are equal")
+        );
+        assert_eq!(
+            check(job((0, 0), (0, 50), 1, "0.1.0")).await,
+            invalid("Module does not exist")
+        );
+        assert_eq!(
+            check(job((0, 0), (0, 50), 2, "0.0.0")).await,
+            invalid("Invalid map id")
+        );
+        for (start, stop) in &[
+            ((width + 200, 0), (0, 50)),
+            ((0, height + 300), (0, 50)),
+            ((0, 0), (width + 200, 50)),
+            ((0, 0), (0, height + 300)),
+        ] {
+            assert_eq!(
+                check(job(*start, *stop, 1, "0.0.0")).await,
+                invalid("Points are out of bounds")
+            );
+        }
+
+        //Nothing was submitted
+        assert!(redis
+            .llen(util::get_module_work_key(&algorithm))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn job_validation() {
+        //Setup
+        let redis_pool = crate::create_redis_pool().await;
+        let mut redis = redis_pool.get().await;
+        crate::test::clear_redis(&mut redis).await;
+
+        //Insert test mapdata
+        let (width, height) = crate::test::insert_test_mapdata(&mut redis).await;
+
+        //Insert a module
+        let algorithm_key = create_redis_backend_key("registered_modules");
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        let json = serde_json::to_vec(&algorithm).unwrap();
+        redis.sadd(algorithm_key, json).await.unwrap();
+
+        let mut job_submission = JobSubmission {
+            start: Vector { x: 0, y: 100 }.into(),
+            stop: Vector { x: 0, y: 100 }.into(),
+            map_id: 1,
+            map_type: MapType::Elevation,
+            algorithm,
+            downsample: None,
+            tiles: Vec::new(),
+            coords: CoordinateSystem::Pixel,
+        };
+
+        macro_rules! check_valid {
+            () => {
+                assert!(
+                    validity_check(&mut job_submission, &mut redis)
+                        .await
+                        .unwrap()
+                        .0
+                );
+            };
+        }
+        macro_rules! check_invalid {
+            () => {
+                assert!(
+                    !validity_check(&mut job_submission, &mut redis)
+                        .await
+                        .unwrap()
+                        .0
+                );
+            };
+        }
+
+        //Equal start and stop points
+        check_invalid!();
+        job_submission.stop.y = 50.0;
+
+        //Map Id is valid
+        check_valid!();
+
+        //Invalid module
+        job_submission.algorithm.version = "0.1.0".to_string();
+        check_invalid!();
+
+        //Invalid Map ID
+        job_submission.map_id = 2;
+        job_submission.algorithm.version = "0.0.0".to_string();
+        check_invalid!();
 
         //Out of bounds
         job_submission.map_id = 1;
         check_valid!(); //Check that it's ok again
-        job_submission.start.x = width + 200;
+        job_submission.start.x = (width + 200) as f64;
         check_invalid!();
-        job_submission.start.x = 0;
+        job_submission.start.x = 0.0;
         check_valid!(); //Check that it's ok again
-        job_submission.start.y = height + 300;
+        job_submission.start.y = (height + 300) as f64;
         check_invalid!();
-        job_submission.start.y = 0;
+        job_submission.start.y = 0.0;
         check_valid!(); //Check that it's ok again
+                        //Pixels can't be fractional or negative.
+        job_submission.start.x = 0.5;
+        check_invalid!();
+        job_submission.start.x = -1.0;
+        check_invalid!();
+        job_submission.start.x = 0.0;
 
         //Out of bounds, but this time for the stop point
-        job_submission.stop.x = width + 200;
+        job_submission.stop.x = (width + 200) as f64;
+        check_invalid!();
+        job_submission.stop.x = 0.0;
+        check_valid!(); //Check that it's ok again
+        job_submission.stop.y = (height + 300) as f64;
         check_invalid!();
-        job_submission.stop.x = 0;
+        job_submission.stop.y = 50.0;
         check_valid!(); //Check that it's ok again
-        job_submission.stop.y = height + 300;
+
+        //Downsampling factors
+        job_submission.downsample = Some(4);
+        check_valid!();
+        job_submission.downsample = Some(3);
+        check_invalid!();
+        job_submission.downsample = Some(128);
+        check_invalid!();
+        job_submission.downsample = None;
+
+        //The latest version of the module
+        job_submission.algorithm.version = LATEST_VERSION.to_string();
+        check_valid!();
+        assert_eq!(job_submission.algorithm.version, "0.0.0");
+        job_submission.algorithm = ModuleInfo {
+            name: "does-not-exist".to_string(),
+            version: LATEST_VERSION.to_string(),
+        };
+        check_invalid!();
+        job_submission.algorithm.name = "dummy".to_string();
+
+        //Leaving out the version means the latest version
+        let submission: JobSubmission = serde_json::from_value(serde_json::json!({
+            "map_id": 1,
+            "start": { "x": 1, "y": 2 },
+            "stop": { "x": 2, "y": 1 },
+            "algorithm": { "name": "dummy" }
+        }))
+        .unwrap();
+        assert_eq!(submission.algorithm.version, LATEST_VERSION);
+
+        //Map tiles. Import the same map again and place it to the right of the first one.
+        crate::test::insert_test_mapdata(&mut redis).await;
+        job_submission.stop.x = (width + 10) as f64;
+        check_invalid!();
+        job_submission.tiles.push(MapTile {
+            map_id: 2,
+            offset: Vector { x: width, y: 0 },
+        });
+        check_valid!();
+        //Overlapping tiles
+        job_submission.tiles[0].offset.x = width - 1;
+        check_invalid!();
+        //A gap between the tiles leaves the stop point outside both
+        job_submission.tiles[0].offset.x = width + 20;
+        check_invalid!();
+        //Tiles which don't exist
+        job_submission.tiles[0].offset.x = width;
+        job_submission.tiles[0].map_id = 3;
         check_invalid!();
+        //The main map can't also be a tile
+        job_submission.tiles[0].map_id = 1;
+        check_invalid!();
+        job_submission.tiles.clear();
+        job_submission.stop.x = 0.0;
+        check_valid!();
+
+        //Modules can limit which kinds of maps they accept, and the test maps are elevation data.
+        let map_types_key = util::get_module_map_types_key(&job_submission.algorithm);
+        redis.set(&map_types_key, r#"["rgb"]"#).await.unwrap();
+        assert_eq!(
+            validity_check(&mut job_submission, &mut redis)
+                .await
+                .unwrap(),
+            (false, "The module does not support this type of map")
+        );
+        redis
+            .set(&map_types_key, r#"["rgb", "elevation"]"#)
+            .await
+            .unwrap();
+        check_valid!();
+
+        //Jobs on other types of maps look for them among the maps of that type, which have their own ids.
+        redis.set(&map_types_key, r#"["rgb"]"#).await.unwrap();
+        job_submission.map_type = MapType::Rgb;
+        assert_eq!(
+            validity_check(&mut job_submission, &mut redis)
+                .await
+                .unwrap(),
+            (false, "Invalid map id")
+        );
+        let (image, metadata) =
+            laps_convert::convert_to_png("test_data/height_data/dtm1.tif").unwrap();
+        laps_convert::import_data_test(
+            &mut redis,
+            MapType::Rgb,
+            image,
+            metadata,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        check_valid!();
+        job_submission.map_type = MapType::Elevation;
+        redis.del(&map_types_key).await.unwrap();
+
+        let meta_key = util::get_mapdata_key(MapType::Elevation, "meta");
+
+        //Points can be given in world coordinates, which are rounded to the nearest pixels.
+        let data = redis.hget(&meta_key, "1").await.unwrap().unwrap();
+        let metadata: laps_convert::ImageMetadata = serde_json::from_slice(&data).unwrap();
+        let (origin_x, origin_y) = metadata.origin.unwrap();
+        let world = |x: u32, y: u32| Point {
+            x: origin_x + (x as f64 + 0.25) * metadata.x_res,
+            y: origin_y + (y as f64 - 0.25) * metadata.y_res,
+        };
+        let (start, stop) = (world(10, 20), world(30, 40));
+        job_submission.start = start;
+        job_submission.stop = stop;
+        job_submission.coords = CoordinateSystem::World;
+        check_valid!();
+        //Modules only ever get pixel coordinates.
+        assert_eq!(job_submission.coords, CoordinateSystem::Pixel);
+        assert_eq!(job_submission.start, Vector { x: 10, y: 20 }.into());
+        assert_eq!(job_submission.stop, Vector { x: 30, y: 40 }.into());
+
+        //Points past the end of the map are rejected.
+        job_submission.start = start;
+        job_submission.stop = world(width + 100, 40);
+        job_submission.coords = CoordinateSystem::World;
+        assert_eq!(
+            validity_check(&mut job_submission, &mut redis)
+                .await
+                .unwrap(),
+            (
+                false,
+                "Points are outside the georeferenced extent of the maps"
+            )
+        );
+
+        //Maps imported before the origin was recorded can't be used with world coordinates.
+        let mut metadata: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        metadata.as_object_mut().unwrap().remove("origin");
+        redis
+            .hset(&meta_key, "1", metadata.to_string())
+            .await
+            .unwrap();
+        job_submission.start = start;
+        job_submission.stop = stop;
+        job_submission.coords = CoordinateSystem::World;
+        assert_eq!(
+            validity_check(&mut job_submission, &mut redis)
+                .await
+                .unwrap(),
+            (false, "The map is not georeferenced")
+        );
     }
 }
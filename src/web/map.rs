@@ -3,29 +3,104 @@
 //Copyright (c) 2020 LAPS Group
 //Distributed under the zlib licence, see LICENCE.
 
-use crate::{types::BackendError, util::create_redis_key};
-use rocket::{http::ContentType, Response, State};
+use super::{
+    etag::{compute_etag, IfNoneMatch, Tagged},
+    range::{ByteRange, RangeHeader},
+};
+use crate::{
+    types::{BackendError, UserError},
+    util::get_mapdata_key,
+};
+use darkredis::{Command, Value};
+use laps_convert::{ImageMetadata, MapType, OutputFormat};
+use rocket::{
+    http::{ContentType, Status},
+    request::LenientForm,
+    Response, State,
+};
 use rocket_contrib::{json, json::JsonValue};
 use std::io::Cursor;
 
-//Endpoint for getting map data
-#[get("/map/<id>")]
-pub async fn get_map(
-    pool: State<'_, darkredis::ConnectionPool>,
+//Selects the type of map an endpoint works on with `?type=`, elevation if left out, as that was the only type of map
+//before maps had types. `type` is a keyword, hence the rename.
+#[derive(FromForm)]
+pub struct MapTypeSelector {
+    #[form(field = "type")]
+    map_type: Option<String>,
+}
+
+impl MapTypeSelector {
+    //Get the selected type. Unknown types are rejected rather than finding nothing, so that typos don't go unnoticed.
+    pub fn map_type(&self) -> Result<MapType, UserError> {
+        match &self.map_type {
+            Some(name) => name
+                .to_lowercase()
+                .parse()
+                .map_err(|name| UserError::BadType(name, MapType::names())),
+            None => Ok(MapType::default()),
+        }
+    }
+}
+
+//Build the response for a map image, shared by GET and HEAD. The body is stripped by Rocket for HEAD requests,
+//leaving the Content-Length intact. Only the part of the image in `range` is sent, if it asks for one.
+async fn map_image_response(
+    pool: &darkredis::ConnectionPool,
+    map_type: MapType,
     id: i32,
-) -> Result<Option<Response<'_>>, BackendError> {
+    if_none_match: &IfNoneMatch,
+    range: &RangeHeader,
+) -> Result<Option<Response<'static>>, BackendError> {
     let mut conn = pool.get().await;
     match conn
-        .hget(&create_redis_key("mapdata.image"), &id.to_string())
+        .hget(&get_mapdata_key(map_type, "image"), &id.to_string())
         .await?
     {
         Some(data) => {
             trace!("Found map");
-            let response = Response::build()
-                .header(ContentType::from_extension("png").unwrap())
-                .sized_body(Cursor::new(data))
-                .await
-                .finalize();
+            //Rather fail than hand out corrupted maps, which would only fail later on in confusing ways.
+            if crate::CONFIG.maps.verify_checksums
+                && check_map_integrity(&mut conn, map_type, &id.to_string(), &data).await?
+                    == Some(false)
+            {
+                return Err(BackendError::CorruptMap(id.to_string()));
+            }
+            //Maps are stored as PNG or WebP, which can be told apart by their first bytes.
+            let format = OutputFormat::detect(&data).unwrap_or(OutputFormat::Png);
+            let content_type = ContentType::parse_flexible(format.content_type()).unwrap();
+            //Map images never change once imported, so a hash of the data is enough.
+            let etag = compute_etag(&data);
+            let len = data.len();
+            let response = if if_none_match.matches(&etag) {
+                Response::build()
+                    .status(Status::NotModified)
+                    .raw_header("ETag", etag)
+                    .finalize()
+            } else {
+                match range.resolve(len, &etag) {
+                    ByteRange::Full => Response::build()
+                        .header(content_type)
+                        .raw_header("ETag", etag)
+                        .raw_header("Accept-Ranges", "bytes")
+                        .sized_body(Cursor::new(data))
+                        .await
+                        .finalize(),
+                    ByteRange::Partial(first, last) => Response::build()
+                        .status(Status::PartialContent)
+                        .header(content_type)
+                        .raw_header("ETag", etag)
+                        .raw_header("Accept-Ranges", "bytes")
+                        .raw_header("Content-Range", format!("bytes {}-{}/{}", first, last, len))
+                        .sized_body(Cursor::new(data[first..=last].to_vec()))
+                        .await
+                        .finalize(),
+                    ByteRange::Unsatisfiable => Response::build()
+                        .status(Status::RangeNotSatisfiable)
+                        .raw_header("Accept-Ranges", "bytes")
+                        .raw_header("Content-Range", format!("bytes */{}", len))
+                        .finalize(),
+                }
+            };
 
             Ok(Some(response))
         }
@@ -36,32 +111,123 @@ pub async fn get_map(
     }
 }
 
-//Endpoint for listning available maps.
-#[get("/maps")]
-pub async fn get_maps(pool: State<'_, darkredis::ConnectionPool>) -> JsonValue {
+//Check the stored image `data` of map `id` of `map_type` against the checksum in its metadata. Returns None if the
+//map has no checksum, as it was imported before they were recorded.
+pub async fn check_map_integrity(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    id: &str,
+    data: &[u8],
+) -> Result<Option<bool>, BackendError> {
+    match conn.hget(&get_mapdata_key(map_type, "meta"), id).await? {
+        Some(meta) => {
+            let metadata: ImageMetadata = serde_json::from_slice(&meta)?;
+            Ok(metadata.verify_checksum(data))
+        }
+        None => Ok(None),
+    }
+}
+
+//Endpoint for getting map data
+#[get("/map/<id>?<selector..>")]
+pub async fn get_map(
+    pool: State<'_, darkredis::ConnectionPool>,
+    id: i32,
+    selector: LenientForm<MapTypeSelector>,
+    if_none_match: IfNoneMatch,
+    range: RangeHeader,
+) -> Result<Option<Response<'static>>, UserError> {
+    let map_type = selector.map_type()?;
+    Ok(map_image_response(&pool, map_type, id, &if_none_match, &range).await?)
+}
+
+//Check if a map exists and get its size without downloading it.
+#[head("/map/<id>?<selector..>")]
+pub async fn head_map(
+    pool: State<'_, darkredis::ConnectionPool>,
+    id: i32,
+    selector: LenientForm<MapTypeSelector>,
+    if_none_match: IfNoneMatch,
+    range: RangeHeader,
+) -> Result<Option<Response<'static>>, UserError> {
+    let map_type = selector.map_type()?;
+    Ok(map_image_response(&pool, map_type, id, &if_none_match, &range).await?)
+}
+
+//Get the ids of every map of `map_type` in `group`.
+pub async fn get_group_maps(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    group: &str,
+) -> Result<Vec<String>, darkredis::Error> {
+    let command = Command::new("HGETALL").arg(get_mapdata_key(map_type, "group"));
+    let values = conn.run_command(command).await?.unwrap_array();
+    //The values come as a flat list of id, group pairs.
+    Ok(values
+        .chunks(2)
+        .filter_map(|pair| match pair {
+            [Value::String(id), Value::String(g)] if g.as_slice() == group.as_bytes() => {
+                Some(String::from_utf8_lossy(id).into_owned())
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+//Get the ids of the maps of `map_type` in `group`, or every map of the type if no group is given.
+async fn list_maps(
+    conn: &mut darkredis::Connection,
+    map_type: MapType,
+    group: Option<&str>,
+) -> Result<Vec<String>, BackendError> {
+    if let Some(group) = group {
+        Ok(get_group_maps(conn, map_type, group).await?)
+    } else {
+        //Return an empty list if none are available
+        let keys = conn.hkeys(&get_mapdata_key(map_type, "image")).await?;
+
+        //Convert each key to UTF-8, lossy in order to ignore errors
+        Ok(keys
+            .iter()
+            .map(|s| String::from_utf8_lossy(&s).into_owned())
+            .collect())
+    }
+}
+
+//Endpoint for listning available maps of a type, elevation by default, optionally only the ones in a group.
+#[get("/maps?<group>&<selector..>")]
+pub async fn get_maps(
+    pool: State<'_, darkredis::ConnectionPool>,
+    group: Option<String>,
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Tagged<JsonValue>, UserError> {
+    let map_type = selector.map_type()?;
+
     let mut conn = pool.get().await;
-    trace!("Listing maps");
-    //Return an empty list if none are available
-    let keys = conn
-        .hkeys(&create_redis_key("mapdata.image"))
-        .await
-        .unwrap();
+    trace!("Listing {} maps", map_type);
+    let converted = list_maps(&mut conn, map_type, group.as_deref()).await?;
 
-    //Convert each key to UTF-8, lossy in order to ignore errors
-    let converted: Vec<std::borrow::Cow<'_, str>> =
-        keys.iter().map(|s| String::from_utf8_lossy(&s)).collect();
+    Ok(Tagged(json!({ "maps": converted })))
+}
 
-    json!({ "maps": converted })
+//Get the `part` of map `id` of `map_type`, such as its metadata.
+async fn get_map_part(
+    pool: &darkredis::ConnectionPool,
+    map_type: MapType,
+    part: &str,
+    id: &str,
+) -> Result<Option<Vec<u8>>, BackendError> {
+    let mut conn = pool.get().await;
+    Ok(conn.hget(&get_mapdata_key(map_type, part), id).await?)
 }
 
-#[get("/map/<id>/meta")]
+#[get("/map/<id>/meta?<selector..>")]
 pub async fn get_map_metadata(
     pool: State<'_, darkredis::ConnectionPool>,
     id: String,
-) -> Result<Option<Response<'_>>, BackendError> {
-    let mut conn = pool.get().await;
-    let key = create_redis_key("mapdata.meta");
-    match conn.hget(&key, id).await? {
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Option<Response<'_>>, UserError> {
+    match get_map_part(&pool, selector.map_type()?, "meta", &id).await? {
         Some(s) => Ok(Some(
             Response::build()
                 .header(ContentType::JSON)
@@ -73,11 +239,29 @@ pub async fn get_map_metadata(
     }
 }
 
+//Get the slope of a map, if it was computed when the map was imported.
+#[get("/map/<id>/slope?<selector..>")]
+pub async fn get_map_slope(
+    pool: State<'_, darkredis::ConnectionPool>,
+    id: i32,
+    selector: LenientForm<MapTypeSelector>,
+) -> Result<Option<Response<'_>>, UserError> {
+    match get_map_part(&pool, selector.map_type()?, "slope", &id.to_string()).await? {
+        Some(data) => Ok(Some(
+            Response::build()
+                .header(ContentType::from_extension("png").unwrap())
+                .sized_body(Cursor::new(data))
+                .await
+                .finalize(),
+        )),
+        None => Ok(None),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use laps_convert::ImageMetadata;
-    use rocket::{http::Status, local::Client};
+    use rocket::{http::Header, local::Client};
     use serial_test::serial;
 
     //Test the listing of available maps and getting of map data
@@ -144,6 +328,302 @@ mod test {
         );
     }
 
+    //Test listing only the maps in a group.
+    #[tokio::test]
+    #[serial]
+    async fn map_groups() {
+        let redis = crate::create_redis_pool().await;
+        let mut conn = redis.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![get_maps])
+            .manage(redis.clone());
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        //Groups with no maps are empty
+        let mut response = client.get("/maps?group=survey").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.body_string().await,
+            Some(r#"{"maps":[]}"#.to_string())
+        );
+
+        //Maps outside of any group are not part of the group
+        crate::test::insert_test_mapdata(&mut conn).await;
+        let maps = (0..2)
+            .map(|_| laps_convert::convert_to_png("test_data/height_data/dtm1.tif").unwrap())
+            .collect();
+        let ids = laps_convert::import_group_test(
+            &mut conn,
+            laps_convert::MapType::Elevation,
+            maps,
+            "survey",
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids, vec![2, 3]);
+
+        let mut response = client.get("/maps?group=survey").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let value: serde_json::Value =
+            serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+        let mut maps: Vec<&str> = value["maps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m.as_str().unwrap())
+            .collect();
+        maps.sort_unstable();
+        assert_eq!(maps, vec!["2", "3"]);
+
+        //Every map is still listed without a filter
+        let mut response = client.get("/maps").dispatch().await;
+        let value: serde_json::Value =
+            serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+        assert_eq!(value["maps"].as_array().unwrap().len(), 3);
+
+        let mut response = client.get("/maps?group=other").dispatch().await;
+        assert_eq!(
+            response.body_string().await,
+            Some(r#"{"maps":[]}"#.to_string())
+        );
+    }
+
+    //Test that every type of map has its own ids and listing.
+    #[tokio::test]
+    #[serial]
+    async fn map_types() {
+        use laps_convert::MapType;
+
+        let redis = crate::create_redis_pool().await;
+        let mut conn = redis.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![get_map, get_maps, get_map_metadata])
+            .manage(redis.clone());
+        let client = Client::untracked(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        //Maps 1 and 2 are elevation maps, while the orthophoto gets its own map 1.
+        for _ in 0..2 {
+            crate::test::insert_test_mapdata(&mut conn).await;
+        }
+        let (image, metadata) =
+            laps_convert::convert_to_png("test_data/height_data/dtm1.tif").unwrap();
+        let rgb_data = image.data.clone();
+        let id = laps_convert::import_data_test(
+            &mut conn,
+            MapType::Rgb,
+            image,
+            metadata,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(id, 1);
+
+        async fn list(client: &Client, uri: &str) -> Vec<String> {
+            let mut response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let value: serde_json::Value =
+                serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+            let mut maps: Vec<String> = value["maps"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|m| m.as_str().unwrap().to_string())
+                .collect();
+            maps.sort_unstable();
+            maps
+        }
+        //Elevation maps are listed by default.
+        assert_eq!(list(&client, "/maps").await, vec!["1", "2"]);
+        assert_eq!(list(&client, "/maps?type=elevation").await, vec!["1", "2"]);
+        assert_eq!(list(&client, "/maps?type=RGB").await, vec!["1"]);
+        assert!(list(&client, "/maps?type=slope").await.is_empty());
+
+        //Maps are looked up in the namespace of the type.
+        let mut response = client.get("/map/1?type=rgb").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_bytes().await.unwrap(), rgb_data);
+        let response = client.get("/map/2?type=rgb").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/map/2").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let mut response = client.get("/map/1/meta?type=rgb").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let metadata: ImageMetadata =
+            serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+        assert_eq!(metadata.map_type, Some(MapType::Rgb));
+
+        //The type applies within groups too.
+        let maps = (0..2)
+            .map(|_| laps_convert::convert_to_png("test_data/height_data/dtm1.tif").unwrap())
+            .collect();
+        let ids = laps_convert::import_group_test(
+            &mut conn,
+            MapType::Rgb,
+            maps,
+            "survey",
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(
+            list(&client, "/maps?group=survey&type=rgb").await,
+            vec!["2", "3"]
+        );
+        assert!(list(&client, "/maps?group=survey").await.is_empty());
+
+        //Unknown types are rejected rather than matching nothing.
+        for uri in &["/maps?type=lidar", "/map/1?type=lidar"] {
+            let mut response = client.get(*uri).dispatch().await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let body: serde_json::Value =
+                serde_json::from_slice(&response.body_bytes().await.unwrap()).unwrap();
+            assert_eq!(body["error"]["code"], "bad_type");
+        }
+    }
+
+    //Test HEAD requests and conditional requests for map images.
+    #[tokio::test]
+    #[serial]
+    async fn map_caching() {
+        let redis = crate::create_redis_pool().await;
+        let mut conn = redis.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![get_map, head_map])
+            .manage(redis.clone());
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        //Maps which don't exist don't exist for HEAD either
+        let response = client.head("/map/1").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        crate::test::insert_test_mapdata(&mut conn).await;
+        let data = conn
+            .hget(&get_mapdata_key(MapType::Elevation, "image"), "1")
+            .await
+            .unwrap()
+            .unwrap();
+
+        //The GET response is tagged with a hash of the map data
+        let mut response = client.get("/map/1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        assert_eq!(etag, compute_etag(&data));
+        assert_eq!(response.body_bytes().await.unwrap(), data);
+
+        //HEAD gives the same headers, but no body
+        let mut response = client.head("/map/1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.content_type().unwrap().is_png());
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+        assert!(response
+            .body_bytes()
+            .await
+            .map(|b| b.is_empty())
+            .unwrap_or(true));
+
+        //Requesting the map again with the tag should not send the map again
+        for tag in &[etag.clone(), format!("W/{}", etag), "*".into()] {
+            let mut response = client
+                .get("/map/1")
+                .header(Header::new("If-None-Match", tag.clone()))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::NotModified);
+            assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+            assert!(response.body_bytes().await.is_none());
+        }
+
+        //A stale tag gives the full map
+        let response = client
+            .get("/map/1")
+            .header(Header::new("If-None-Match", "\"0000000000000000\", \"1\""))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    //Test downloading only part of a map.
+    #[tokio::test]
+    #[serial]
+    async fn map_ranges() {
+        let redis = crate::create_redis_pool().await;
+        let mut conn = redis.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![get_map])
+            .manage(redis.clone());
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        crate::test::insert_test_mapdata(&mut conn).await;
+        let data = conn
+            .hget(&get_mapdata_key(MapType::Elevation, "image"), "1")
+            .await
+            .unwrap()
+            .unwrap();
+        let len = data.len();
+
+        //Ranges are advertised, but the whole map is sent without one.
+        let mut response = client.get("/map/1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Accept-Ranges"), Some("bytes"));
+        assert!(response.headers().get_one("Content-Range").is_none());
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        assert_eq!(response.body_bytes().await.unwrap(), data);
+
+        let get_range = |range: String| {
+            client
+                .get("/map/1")
+                .header(Header::new("Range", range))
+                .dispatch()
+        };
+        let mut response = get_range("bytes=8-23".into()).await;
+        assert_eq!(response.status(), Status::PartialContent);
+        assert!(response.content_type().unwrap().is_png());
+        assert_eq!(
+            response.headers().get_one("Content-Range"),
+            Some(format!("bytes 8-23/{}", len).as_str())
+        );
+        assert_eq!(response.body_bytes().await.unwrap(), &data[8..24]);
+
+        //Resuming a download from the middle.
+        let middle = len / 2;
+        let mut response = get_range(format!("bytes={}-", middle)).await;
+        assert_eq!(response.status(), Status::PartialContent);
+        assert_eq!(response.body_bytes().await.unwrap(), &data[middle..]);
+
+        let mut response = get_range(format!("bytes={}-", len)).await;
+        assert_eq!(response.status(), Status::RangeNotSatisfiable);
+        assert_eq!(
+            response.headers().get_one("Content-Range"),
+            Some(format!("bytes */{}", len).as_str())
+        );
+        assert!(response.body_bytes().await.is_none());
+
+        //Ranges of another version of the map give the whole map.
+        let mut response = client
+            .get("/map/1")
+            .header(Header::new("Range", "bytes=0-9"))
+            .header(Header::new("If-Range", "\"0000000000000000\""))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_bytes().await.unwrap(), data);
+        let mut response = client
+            .get("/map/1")
+            .header(Header::new("Range", "bytes=0-9"))
+            .header(Header::new("If-Range", etag))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::PartialContent);
+        assert_eq!(response.body_bytes().await.unwrap(), &data[..10]);
+    }
+
     #[tokio::test]
     #[serial]
     async fn get_map_metadata() {
@@ -167,4 +647,131 @@ mod test {
         //Map data has an x_res of 1.
         approx::assert_relative_eq!(metadata.x_res, 1.0);
     }
+
+    #[tokio::test]
+    #[serial]
+    async fn get_map_slope() {
+        let redis = crate::create_redis_pool().await;
+        let mut conn = redis.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![get_map_slope])
+            .manage(redis.clone());
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        //Maps don't have a slope unless it's requested
+        crate::test::insert_test_mapdata(&mut conn).await;
+        let response = client.get("/map/1/slope").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let options = laps_convert::ConvertOptions {
+            slope: true,
+            ..Default::default()
+        };
+        let (image, metadata) =
+            laps_convert::convert("test_data/height_data/dtm1.tif", &options).unwrap();
+        let map_id = laps_convert::import_data_test(
+            &mut conn,
+            laps_convert::MapType::Elevation,
+            image,
+            metadata,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        let mut response = client
+            .get(format!("/map/{}/slope", map_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.content_type().unwrap().is_png());
+        assert_eq!(
+            &response.body_bytes().await.unwrap()[..8],
+            &[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]
+        );
+    }
+
+    //Test that maps stored as WebP are served and used like PNG maps.
+    #[tokio::test]
+    #[serial]
+    async fn webp_maps() {
+        use crate::{
+            module_handling::ModuleInfo,
+            types::Vector,
+            web::job::{validity_check, CoordinateSystem, JobSubmission},
+        };
+
+        let redis = crate::create_redis_pool().await;
+        let mut conn = redis.get().await;
+        let rocket = rocket::ignite()
+            .mount("/", routes![get_map, get_map_metadata])
+            .manage(redis.clone());
+        let client = Client::new(rocket).unwrap();
+        crate::test::clear_redis(&mut conn).await;
+
+        let options = laps_convert::ConvertOptions {
+            format: OutputFormat::WebP,
+            ..Default::default()
+        };
+        let (image, metadata) =
+            laps_convert::convert_to_png_with("test_data/height_data/dtm1.tif", &options).unwrap();
+        let (width, height, data) = (image.width as u32, image.height as u32, image.data.clone());
+        let map_id = laps_convert::import_data_test(
+            &mut conn,
+            laps_convert::MapType::Elevation,
+            image,
+            metadata,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+
+        //The map is sent as it is stored, with the matching content type.
+        let webp = ContentType::parse_flexible("image/webp");
+        let mut response = client.get(format!("/map/{}", map_id)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), webp.clone());
+        assert_eq!(response.body_bytes().await.unwrap(), data);
+        let response = client
+            .get(format!("/map/{}", map_id))
+            .header(Header::new("Range", "bytes=0-11"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::PartialContent);
+        assert_eq!(response.content_type(), webp);
+        let mut response = client.get(format!("/map/{}/meta", map_id)).dispatch().await;
+        let metadata: serde_json::Value =
+            serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+        assert_eq!(metadata["format"], "webp");
+
+        //Jobs are checked against the dimensions of the WebP.
+        let algorithm = ModuleInfo {
+            name: "dummy".to_string(),
+            version: "0.0.0".to_string(),
+        };
+        crate::test::register_module(&mut conn, &algorithm).await;
+        let mut job = JobSubmission {
+            start: Vector { x: 0, y: 0 }.into(),
+            stop: Vector {
+                x: width - 1,
+                y: height - 1,
+            }
+            .into(),
+            map_id: map_id as i32,
+            map_type: MapType::Elevation,
+            algorithm,
+            downsample: None,
+            tiles: Vec::new(),
+            coords: CoordinateSystem::Pixel,
+        };
+        assert_eq!(
+            validity_check(&mut job, &mut conn).await.unwrap(),
+            (true, "")
+        );
+        job.stop.x = width as f64;
+        assert_eq!(
+            validity_check(&mut job, &mut conn).await.unwrap(),
+            (false, "Points are out of bounds")
+        );
+    }
 }
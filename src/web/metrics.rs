@@ -0,0 +1,349 @@
+//src/web/metrics.rs: Per-endpoint request metrics, exposed in the Prometheus text format.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use super::admin::AdminSession;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{ContentType, Method, Status},
+    request::{FromRequest, Outcome},
+    Data, Request, Response, State,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::Cursor,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Instant,
+};
+
+//Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+//The label used for requests which didn't match any route.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+//Status codes are grouped by their class to keep the number of series down.
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+//Get the index into `STATUS_CLASSES` of `status`.
+fn status_class(status: Status) -> usize {
+    (status.code as usize / 100).clamp(1, 5) - 1
+}
+
+//A latency histogram which can be updated without locking.
+#[derive(Default)]
+struct Histogram {
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+    //The number of requests in each bucket. Cumulative counts are computed when rendering.
+    buckets: [AtomicU64; BUCKETS.len()],
+}
+
+impl Histogram {
+    fn observe(&self, seconds: f64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add((seconds * 1e6) as u64, Ordering::Relaxed);
+        //Requests slower than the last bucket are only counted in the implicit +Inf bucket.
+        if let Some(i) = BUCKETS.iter().position(|b| seconds <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+//The histograms of a single route, one per status class.
+#[derive(Default)]
+struct RouteMetrics {
+    classes: [Histogram; STATUS_CLASSES.len()],
+}
+
+//Registry of the request metrics of every route, keyed by path and method.
+//Entries are only allocated the first time a route is seen, so recording a request doesn't allocate.
+#[derive(Default)]
+pub struct Metrics {
+    routes: RwLock<HashMap<String, Vec<(Method, RouteMetrics)>>>,
+    //Bearer token which lets scrapers read the metrics without an admin session.
+    token: Option<String>,
+}
+
+impl Metrics {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token,
+            ..Self::default()
+        }
+    }
+
+    //Record that a request to `route` with `method` got `status` after `seconds`.
+    pub fn record(&self, method: Method, route: &str, status: Status, seconds: f64) {
+        let class = status_class(status);
+        {
+            let routes = self.routes.read().unwrap();
+            if let Some((_, m)) = routes
+                .get(route)
+                .and_then(|r| r.iter().find(|(m, _)| *m == method))
+            {
+                m.classes[class].observe(seconds);
+                return;
+            }
+        }
+
+        //First time this route is seen, another thread may have added it since the read lock was released.
+        let mut routes = self.routes.write().unwrap();
+        let methods = routes.entry(route.to_string()).or_insert_with(Vec::new);
+        let index = match methods.iter().position(|(m, _)| *m == method) {
+            Some(i) => i,
+            None => {
+                methods.push((method, RouteMetrics::default()));
+                methods.len() - 1
+            }
+        };
+        methods[index].1.classes[class].observe(seconds);
+    }
+
+    //Render every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let routes = self.routes.read().unwrap();
+        let mut out = String::new();
+        out += "# HELP laps_http_request_duration_seconds Time from receiving a request to responding to it.\n";
+        out += "# TYPE laps_http_request_duration_seconds histogram\n";
+
+        //Sort the routes to keep the output stable.
+        let mut sorted: Vec<(&String, &Vec<(Method, RouteMetrics)>)> = routes.iter().collect();
+        sorted.sort_by_key(|(route, _)| *route);
+        for (route, methods) in sorted {
+            for (method, metrics) in methods {
+                for (class, histogram) in STATUS_CLASSES.iter().zip(metrics.classes.iter()) {
+                    let count = histogram.count.load(Ordering::Relaxed);
+                    if count == 0 {
+                        continue;
+                    }
+                    let labels = format!(
+                        "method=\"{}\",route=\"{}\",status=\"{}\"",
+                        method,
+                        route.replace('\\', "\\\\").replace('"', "\\\""),
+                        class
+                    );
+                    let mut cumulative = 0;
+                    for (bound, bucket) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                        cumulative += bucket.load(Ordering::Relaxed);
+                        writeln!(
+                            out,
+                            "laps_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                            labels, bound, cumulative
+                        )
+                        .unwrap();
+                    }
+                    writeln!(
+                        out,
+                        "laps_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                        labels, count
+                    )
+                    .unwrap();
+                    writeln!(
+                        out,
+                        "laps_http_request_duration_seconds_sum{{{}}} {}",
+                        labels,
+                        histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+                    )
+                    .unwrap();
+                    writeln!(
+                        out,
+                        "laps_http_request_duration_seconds_count{{{}}} {}",
+                        labels, count
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        out
+    }
+}
+
+//When a request was received, stored in the request's local cache.
+struct RequestStart(Instant);
+
+//Fairing which records the latency and status of every request into `Metrics`.
+pub struct MetricsFairing(pub Arc<Metrics>);
+
+#[rocket::async_trait]
+impl Fairing for MetricsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        //Label by the route pattern rather than the actual path, such that every job token doesn't get its own series.
+        let route = request
+            .route()
+            .map(|r| r.uri.path())
+            .unwrap_or(UNMATCHED_ROUTE);
+        self.0.record(
+            request.method(),
+            route,
+            response.status(),
+            start.0.elapsed().as_secs_f64(),
+        );
+    }
+}
+
+//Request guard for reading the metrics, which requires either an admin session or the configured bearer token.
+pub struct MetricsAccess;
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for MetricsAccess {
+    type Error = ();
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let metrics = match request.guard::<State<'_, Arc<Metrics>>>().await {
+            Outcome::Success(m) => m,
+            //We always expect to be able to retrieve state.
+            _ => panic!("Expected metrics state"),
+        };
+        if let Some(token) = &metrics.token {
+            let authorization = request.headers().get_one("Authorization");
+            if let Some(given) = authorization.and_then(|a| a.strip_prefix("Bearer ")) {
+                if tokens_match(given, token) {
+                    return Outcome::Success(MetricsAccess);
+                }
+            }
+        }
+
+        match request.guard::<AdminSession>().await {
+            Outcome::Success(_) => Outcome::Success(MetricsAccess),
+            Outcome::Failure((status, _)) => Outcome::Failure((status, ())),
+            Outcome::Forward(()) => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+//Compare a given bearer token with the configured one in time independent of where they differ, so the token can't be
+//guessed byte by byte from response times.
+fn tokens_match(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+//Get the request metrics for scraping by Prometheus.
+#[get("/metrics")]
+pub fn metrics(
+    metrics: State<'_, Arc<Metrics>>,
+    _access: MetricsAccess,
+) -> rocket::response::Content<String> {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    rocket::response::Content(content_type, metrics.render())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rocket::{http::Header, local::Client};
+
+    #[get("/slow/<id>")]
+    fn slow(id: u32) -> Result<String, Status> {
+        if id == 0 {
+            Err(Status::NotFound)
+        } else {
+            Ok(id.to_string())
+        }
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secreT", "secret"));
+        assert!(!tokens_match("secre", "secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+
+    #[test]
+    fn histograms() {
+        let metrics = Metrics::new(None);
+        metrics.record(Method::Get, "/job/<token>", Status::Ok, 0.003);
+        metrics.record(Method::Get, "/job/<token>", Status::Ok, 0.2);
+        metrics.record(Method::Get, "/job/<token>", Status::GatewayTimeout, 500.0);
+        let out = metrics.render();
+        let labels = "method=\"GET\",route=\"/job/<token>\"";
+        assert!(out.contains(&format!(
+            "laps_http_request_duration_seconds_bucket{{{},status=\"2xx\",le=\"0.005\"}} 1",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "laps_http_request_duration_seconds_bucket{{{},status=\"2xx\",le=\"0.25\"}} 2",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "laps_http_request_duration_seconds_count{{{},status=\"2xx\"}} 2",
+            labels
+        )));
+        //Requests slower than every bucket are only in +Inf.
+        assert!(out.contains(&format!(
+            "laps_http_request_duration_seconds_bucket{{{},status=\"5xx\",le=\"120\"}} 0",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "laps_http_request_duration_seconds_bucket{{{},status=\"5xx\",le=\"+Inf\"}} 1",
+            labels
+        )));
+        //Classes without requests are left out.
+        assert!(!out.contains("status=\"4xx\""));
+    }
+
+    #[tokio::test]
+    async fn fairing() {
+        let metrics = Arc::new(Metrics::new(Some("secret".into())));
+        let rocket = rocket::ignite()
+            .mount("/", routes![slow, super::metrics])
+            .attach(MetricsFairing(metrics.clone()))
+            .manage(metrics);
+        let client = Client::new(rocket).unwrap();
+        for id in &[1, 2, 0] {
+            client.get(format!("/slow/{}", id)).dispatch().await;
+        }
+        client.get("/does/not/exist").dispatch().await;
+
+        //The metrics are only shown with the right token.
+        let response = client.get("/metrics").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/metrics")
+            .header(Header::new("Authorization", "Bearer wrong"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let mut response = client
+            .get("/metrics")
+            .header(Header::new("Authorization", "Bearer secret"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let out = response.body_string().await.unwrap();
+        //Requests are labelled with the route, not the path they were made to.
+        assert!(out.contains(
+            "laps_http_request_duration_seconds_count{method=\"GET\",route=\"/slow/<id>\",status=\"2xx\"} 2"
+        ));
+        assert!(out.contains(
+            "laps_http_request_duration_seconds_count{method=\"GET\",route=\"/slow/<id>\",status=\"4xx\"} 1"
+        ));
+        assert!(out.contains("route=\"unmatched\",status=\"4xx\""));
+        assert!(!out.contains("/slow/1"));
+    }
+}
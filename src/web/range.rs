@@ -0,0 +1,149 @@
+//src/web/range.rs: Range requests, letting clients download parts of large responses such as maps.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use rocket::request::{FromRequest, Outcome, Request};
+
+//The part of a response a client asked for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteRange {
+    //The whole response.
+    Full,
+    //The bytes from the first up to and including the last.
+    Partial(usize, usize),
+    //A range which lies entirely outside of the response.
+    Unsatisfiable,
+}
+
+//The Range and If-Range headers of a request, if any.
+pub struct RangeHeader {
+    range: Option<String>,
+    if_range: Option<String>,
+}
+
+impl RangeHeader {
+    //Get the headers of `request`.
+    pub fn of(request: &Request<'_>) -> RangeHeader {
+        let headers = request.headers();
+        RangeHeader {
+            range: headers.get_one("Range").map(|r| r.trim().to_string()),
+            if_range: headers.get_one("If-Range").map(|r| r.trim().to_string()),
+        }
+    }
+
+    //Resolve the requested range of a response of `len` bytes tagged with `etag`. Anything but a single range of
+    //bytes gets the whole response, which is always allowed, as does a range for an older version of the response.
+    pub fn resolve(&self, len: usize, etag: &str) -> ByteRange {
+        let range = match &self.range {
+            Some(r) => r,
+            None => return ByteRange::Full,
+        };
+        //If-Range needs a strong comparison, so weak tags never match.
+        if let Some(tag) = &self.if_range {
+            if tag != etag {
+                return ByteRange::Full;
+            }
+        }
+        //Multiple ranges would need a multipart response.
+        if !range.starts_with("bytes=") || range.contains(',') {
+            return ByteRange::Full;
+        }
+        let spec = range[6..].trim();
+        let split = match spec.find('-') {
+            Some(s) => s,
+            None => return ByteRange::Full,
+        };
+        let (first, last) = (spec[..split].trim(), spec[split + 1..].trim());
+
+        if first.is_empty() {
+            //"-n" is the last n bytes.
+            return match last.parse::<usize>() {
+                Ok(0) => ByteRange::Unsatisfiable,
+                Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+                Ok(n) => ByteRange::Partial(len.saturating_sub(n), len - 1),
+                Err(_) => ByteRange::Full,
+            };
+        }
+        let first = match first.parse::<usize>() {
+            Ok(f) => f,
+            Err(_) => return ByteRange::Full,
+        };
+        let last = if last.is_empty() {
+            None
+        } else {
+            match last.parse::<usize>() {
+                Ok(l) if l >= first => Some(l),
+                _ => return ByteRange::Full,
+            }
+        };
+        if first >= len {
+            return ByteRange::Unsatisfiable;
+        }
+        //Ranges reaching past the end are cut short.
+        let last = last.map(|l| l.min(len - 1)).unwrap_or(len - 1);
+        ByteRange::Partial(first, last)
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for RangeHeader {
+    type Error = ();
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RangeHeader::of(request))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn resolve(range: &str, len: usize) -> ByteRange {
+        let header = RangeHeader {
+            range: Some(range.to_string()),
+            if_range: None,
+        };
+        header.resolve(len, "\"tag\"")
+    }
+
+    #[test]
+    fn range_parsing() {
+        assert_eq!(resolve("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(resolve("bytes=90-", 100), ByteRange::Partial(90, 99));
+        assert_eq!(resolve("bytes=-10", 100), ByteRange::Partial(90, 99));
+        assert_eq!(resolve("bytes= 5 - 5 ", 100), ByteRange::Partial(5, 5));
+        //Ranges are cut to the length of the response.
+        assert_eq!(resolve("bytes=50-1000", 100), ByteRange::Partial(50, 99));
+        assert_eq!(resolve("bytes=-1000", 100), ByteRange::Partial(0, 99));
+        //Ranges outside of it can't be served.
+        assert_eq!(resolve("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(resolve("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(resolve("bytes=0-", 0), ByteRange::Unsatisfiable);
+        //Anything else gets the whole response.
+        for range in &[
+            "bytes=9-0",
+            "bytes=0-9,20-29",
+            "items=0-9",
+            "bytes=a-b",
+            "bytes=",
+            "bytes=-",
+        ] {
+            assert_eq!(resolve(range, 100), ByteRange::Full, "{}", range);
+        }
+
+        let none = RangeHeader {
+            range: None,
+            if_range: None,
+        };
+        assert_eq!(none.resolve(100, "\"tag\""), ByteRange::Full);
+        //Ranges of other versions of the response are ignored.
+        let mut header = RangeHeader {
+            range: Some("bytes=0-9".into()),
+            if_range: Some("\"tag\"".into()),
+        };
+        assert_eq!(header.resolve(100, "\"tag\""), ByteRange::Partial(0, 9));
+        assert_eq!(header.resolve(100, "\"other\""), ByteRange::Full);
+        header.if_range = Some("W/\"tag\"".into());
+        assert_eq!(header.resolve(100, "\"tag\""), ByteRange::Full);
+    }
+}
@@ -0,0 +1,70 @@
+//src/web/request_id.rs: Request ids used to correlate log messages and errors with requests.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use rand::RngCore;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    request::{FromRequest, Outcome},
+    Data, Request, Response,
+};
+use std::fmt;
+
+//The header the request id is returned to the client in.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+//A short random id which is unique to a single request.
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> Self {
+        let mut buffer = [0u8; 4];
+        rand::thread_rng().fill_bytes(&mut buffer);
+        RequestId(buffer.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    //Get the id of `request`, generating one if it doesn't have one yet.
+    pub fn of<'a>(request: &'a Request<'_>) -> &'a RequestId {
+        request.local_cache(RequestId::generate)
+    }
+}
+
+//Lets handlers include the request id in the error responses they build themselves.
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for &'a RequestId {
+    type Error = ();
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(request))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+//Fairing which gives every request an id, logs it and returns it in the response headers.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &Data) {
+        let id = RequestId::of(request);
+        debug!("[{}] {} {}", id, request.method(), request.uri());
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let id = RequestId::of(request);
+        debug!("[{}] Responded with {}", id, response.status());
+        response.set_raw_header(REQUEST_ID_HEADER, id.to_string());
+    }
+}
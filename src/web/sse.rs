@@ -0,0 +1,111 @@
+//src/web/sse.rs: Server-sent event streams.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use rocket::{http::ContentType, Response};
+use std::{
+    io::{self, Cursor, Read},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{io::AsyncRead, sync::mpsc};
+
+//How often to send a keepalive comment on streams without any events, such that proxies don't close the connection
+//for being idle, and such that a client which has gone away is noticed.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+//A response body which sends every event it receives to the client as it arrives.
+//The stream ends once every sender is dropped.
+pub struct EventStream {
+    events: mpsc::Receiver<Vec<u8>>,
+    //The part of the current event which hasn't been read yet.
+    current: Cursor<Vec<u8>>,
+}
+
+impl EventStream {
+    //Create a new event stream, along with the sender used to publish events on it.
+    pub fn new() -> (mpsc::Sender<Vec<u8>>, EventStream) {
+        //A small buffer is enough, as clients which can't keep up should slow down the sender.
+        let (tx, rx) = mpsc::channel(16);
+        let stream = EventStream {
+            events: rx,
+            current: Cursor::new(Vec::new()),
+        };
+        (tx, stream)
+    }
+
+    //Turn the stream into a text/event-stream response.
+    pub fn into_response(self) -> Response<'static> {
+        Response::build()
+            .header(ContentType::new("text", "event-stream"))
+            .raw_header("Cache-Control", "no-cache")
+            .streamed_body(self)
+            .finalize()
+    }
+}
+
+//Format `data` as a single event. `data` must not contain any newlines.
+pub fn format_event(data: &str) -> Vec<u8> {
+    format!("data: {}\n\n", data).into_bytes()
+}
+
+//A comment, which clients ignore, to keep the connection alive.
+pub fn keepalive() -> Vec<u8> {
+    b": keepalive\n\n".to_vec()
+}
+
+impl AsyncRead for EventStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            //Finish sending the current event before waiting for the next one.
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Poll::Ready(Ok(read));
+            }
+            match self.events.poll_recv(cx) {
+                Poll::Ready(Some(event)) => self.current = Cursor::new(event),
+                //Every sender is gone, so there will be no more events.
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn event_stream() {
+        assert_eq!(format_event("{}"), b"data: {}\n\n".to_vec());
+
+        let (mut tx, mut stream) = EventStream::new();
+        tx.send(format_event("first")).await.unwrap();
+        tx.send(keepalive()).await.unwrap();
+        tx.send(format_event("second")).await.unwrap();
+        drop(tx);
+
+        //Events are read in order, even with a buffer smaller than an event, and the stream ends with the sender.
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..read]);
+        }
+        assert_eq!(
+            out,
+            b"data: first\n\n: keepalive\n\ndata: second\n\n".to_vec()
+        );
+    }
+}
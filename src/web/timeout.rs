@@ -0,0 +1,148 @@
+//src/web/timeout.rs: Deadlines for how long a request may take.
+//Author: Håkon Jordet
+//Copyright (c) 2020 LAPS Group
+//Distributed under the zlib licence, see LICENCE.
+
+use super::request_id::RequestId;
+use crate::types::error_response;
+use rocket::{
+    handler::{Handler, HandlerFuture, Outcome},
+    http::Status,
+    Data, Request, Route,
+};
+use std::{collections::HashMap, time::Duration};
+
+//Handler which aborts the handler it wraps if it doesn't finish within `timeout`, responding with 504 Gateway Timeout.
+//Aborting drops the handler future, so guards held by the handler, such as the one counting polling clients, still
+//clean up after themselves.
+#[derive(Clone)]
+struct TimeoutHandler {
+    handler: Box<dyn Handler>,
+    timeout: Duration,
+}
+
+impl Handler for TimeoutHandler {
+    fn handle<'r, 's: 'r>(&'s self, request: &'r Request<'_>, data: Data) -> HandlerFuture<'r> {
+        Box::pin(async move {
+            match tokio::time::timeout(self.timeout, self.handler.handle(request, data)).await {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    let id = RequestId::of(request);
+                    warn!(
+                        "[{}] {} {} timed out after {} seconds",
+                        id,
+                        request.method(),
+                        request.uri(),
+                        self.timeout.as_secs()
+                    );
+                    let message = format!(
+                        "The request did not complete within {} seconds",
+                        self.timeout.as_secs()
+                    );
+                    let response = error_response(
+                        Status::GatewayTimeout,
+                        "request_timeout",
+                        &message,
+                        Some(id),
+                    )
+                    .await;
+                    Outcome::Success(response)
+                }
+            }
+        })
+    }
+}
+
+//Limit how long each of `routes` may take to `default` seconds, or the timeout in `overrides` for routes whose handler
+//has that name. A timeout of 0 leaves the route without a limit.
+pub fn with_timeouts(
+    routes: Vec<Route>,
+    default: u64,
+    overrides: &HashMap<String, u64>,
+) -> Vec<Route> {
+    for name in overrides.keys() {
+        if !routes.iter().any(|r| r.name == Some(name.as_str())) {
+            warn!(
+                "There is no route called {} to override the timeout of",
+                name
+            );
+        }
+    }
+
+    routes
+        .into_iter()
+        .map(|mut route| {
+            let timeout = route
+                .name
+                .and_then(|n| overrides.get(n))
+                .copied()
+                .unwrap_or(default);
+            if timeout != 0 {
+                route.handler = Box::new(TimeoutHandler {
+                    handler: route.handler,
+                    timeout: Duration::from_secs(timeout),
+                });
+            }
+            route
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rocket::local::Client;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    //Counts the handlers which are running, like the guard counting polling clients.
+    struct RunningGuard(Arc<AtomicUsize>);
+
+    impl Drop for RunningGuard {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[get("/slow")]
+    async fn slow(running: rocket::State<'_, Arc<AtomicUsize>>) -> &'static str {
+        running.fetch_add(1, Ordering::SeqCst);
+        let _guard = RunningGuard(running.inner().clone());
+        tokio::time::delay_for(Duration::from_secs(5)).await;
+        "done"
+    }
+
+    #[get("/patient")]
+    async fn patient() -> &'static str {
+        tokio::time::delay_for(Duration::from_millis(1500)).await;
+        "done"
+    }
+
+    #[tokio::test]
+    async fn request_timeouts() {
+        let mut overrides = HashMap::new();
+        overrides.insert("patient".to_string(), 10);
+        let running = Arc::new(AtomicUsize::new(0));
+        let rocket = rocket::ignite()
+            .mount("/", with_timeouts(routes![slow, patient], 1, &overrides))
+            .manage(running.clone());
+        let client = Client::new(rocket).unwrap();
+
+        //Handlers which take too long are aborted, dropping whatever they held on to.
+        let start = std::time::Instant::now();
+        let mut response = client.get("/slow").dispatch().await;
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert_eq!(response.status(), Status::GatewayTimeout);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.body_string().await.unwrap()).unwrap();
+        assert_eq!(body["error"]["code"], "request_timeout");
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+
+        //Routes with a longer timeout get to finish.
+        let mut response = client.get("/patient").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string().await.unwrap(), "done");
+    }
+}